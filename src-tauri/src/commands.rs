@@ -1,34 +1,131 @@
-use crate::imap::client as imap_client;
+use tauri::ipc::Channel;
+use tauri::{AppHandle, State};
+
+use crate::error::{ErrorKind, MailError};
+use crate::imap::client::{self as imap_client, Fetched};
+use crate::imap::download::{self, DownloadSink};
+use crate::imap::idle::IdleManager;
+use crate::imap::pool::ImapPool;
+use crate::imap::sync::SyncRegistry;
 use crate::imap::types::{
-    DeltaCheckRequest, DeltaCheckResult, ImapConfig, ImapFetchResult, ImapFolder,
-    ImapFolderStatus, ImapFolderSyncResult, ImapMessage,
+    DeltaCheckRequest, DeltaCheckResult, DownloadResult, ImapAppendResult, ImapBodyFetchResult, ImapCapabilities,
+    ImapConfig, ImapCopyResult, ImapFetchResult, ImapFolder, ImapFolderStatus, ImapFolderSyncResult,
+    ImapFolderSyncSummary, ImapMessage, ImapMessageBatch, ImapSearchQuery, ImapSortKey, ImapThreadNode,
 };
 use crate::smtp::client as smtp_client;
+use crate::proxy::{self, ProxyConfig};
 use crate::smtp::types::{SmtpConfig, SmtpSendResult};
+use crate::tls::ServerCertificate;
+use crate::trace;
 
 // ---------- IMAP commands ----------
 
 #[tauri::command]
-pub async fn imap_test_connection(config: ImapConfig) -> Result<String, String> {
+pub async fn imap_test_connection(config: ImapConfig) -> Result<String, MailError> {
     imap_client::test_connection(&config).await
 }
 
+/// The server's TLS certificate and its fingerprint, to show before pinning
+/// it as the account's `pinned_cert_sha256`.
 #[tauri::command]
-pub async fn imap_list_folders(config: ImapConfig) -> Result<Vec<ImapFolder>, String> {
-    let mut session = imap_client::connect(&config).await?;
-    let folders = imap_client::list_folders(&mut session).await?;
-    let _ = session.logout().await;
-    Ok(folders)
+pub async fn imap_fetch_certificate(config: ImapConfig) -> Result<ServerCertificate, MailError> {
+    imap_client::fetch_certificate(&config).await
+}
+
+#[tauri::command]
+pub async fn imap_list_folders(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+) -> Result<Vec<ImapFolder>, MailError> {
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::list_folders(&mut session, &caps).await;
+    session.finish(result)
+}
+
+/// Extensions and limits the server advertises, as cached for pooled sessions.
+#[tauri::command]
+pub async fn imap_get_capabilities(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+) -> Result<ImapCapabilities, MailError> {
+    let session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    session.finish(Ok(caps.as_ref().clone()))
+}
+
+/// Create a folder `name` (UTF-8) under the `parent` raw path, optionally
+/// marked with a special-use attribute such as `\Archive`.
+#[tauri::command]
+pub async fn imap_create_folder(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    parent: Option<String>,
+    name: String,
+    special_use: Option<String>,
+) -> Result<ImapFolder, MailError> {
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::create_folder(&mut session, &caps, parent.as_deref(), &name, special_use.as_deref()).await;
+    session.finish(result)
+}
+
+#[tauri::command]
+pub async fn imap_rename_folder(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    raw_path: String,
+    new_name: String,
+) -> Result<ImapFolder, MailError> {
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::rename_folder(&mut session, &caps, &raw_path, &new_name).await;
+    session.finish(result)
+}
+
+#[tauri::command]
+pub async fn imap_delete_folder(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    raw_path: String,
+) -> Result<(), MailError> {
+    let mut session = pool.get(&config).await?;
+    let result = imap_client::delete_folder(&mut session, &raw_path).await;
+    session.finish(result)
+}
+
+#[tauri::command]
+pub async fn imap_subscribe(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    raw_path: String,
+) -> Result<(), MailError> {
+    let mut session = pool.get(&config).await?;
+    let result = imap_client::set_subscribed(&mut session, &raw_path, true).await;
+    session.finish(result)
+}
+
+#[tauri::command]
+pub async fn imap_unsubscribe(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    raw_path: String,
+) -> Result<(), MailError> {
+    let mut session = pool.get(&config).await?;
+    let result = imap_client::set_subscribed(&mut session, &raw_path, false).await;
+    session.finish(result)
 }
 
 #[tauri::command]
 pub async fn imap_fetch_messages(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
     uids: Vec<u32>,
-) -> Result<ImapFetchResult, String> {
+    headers_only: Option<bool>,
+) -> Result<ImapFetchResult, MailError> {
     if uids.is_empty() {
-        return Err("No UIDs provided".to_string());
+        return Err(MailError::new(ErrorKind::Other, "No UIDs provided"));
     }
 
     // Build a UID set string like "1,5,10,20"
@@ -38,81 +135,192 @@ pub async fn imap_fetch_messages(
         .collect::<Vec<_>>()
         .join(",");
 
-    let mut session = imap_client::connect(&config).await?;
-    let result = imap_client::fetch_messages(&mut session, &folder, &uid_set).await;
-    let _ = session.logout().await;
-
-    match result {
-        Ok(r) => Ok(r),
-        Err(e) if e.starts_with("ASYNC_IMAP_EMPTY:") => {
-            // async-imap can't parse this server's responses — use raw TCP fallback
-            log::info!("Falling back to raw TCP fetch for folder {folder}");
-            imap_client::raw_fetch_messages(&config, &folder, &uid_set).await
+    let headers_only = headers_only.unwrap_or(false);
+
+    let mut session = pool.get(&config).await?;
+    if !session.raw_fetch() {
+        let caps = session.capabilities();
+        let result = imap_client::fetch_messages(&mut session, &caps, &folder, &uid_set, headers_only).await;
+        match result {
+            Ok(Fetched::Messages(r)) => return Ok(r),
+            Ok(Fetched::Empty) => log::info!("Falling back to raw fetch for folder {folder}"),
+            Ok(Fetched::Unparsable) => {
+                // Later fetches from this server skip async-imap, so they
+                // keep their session.
+                log::info!("Falling back to raw fetch for folder {folder} and later fetches from this server");
+                session.set_raw_fetch();
+                session.discard();
+                drop(session);
+                session = pool.get(&config).await?;
+            }
+            Err(e) => return session.finish(Err(e)),
         }
-        Err(e) => Err(e),
     }
+
+    let result = imap_client::raw_fetch_messages(&mut session, &folder, &uid_set, headers_only).await;
+    session.finish(result)
 }
 
 #[tauri::command]
 pub async fn imap_fetch_new_uids(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
     since_uid: u32,
-) -> Result<Vec<u32>, String> {
-    let mut session = imap_client::connect(&config).await?;
-    let uids = imap_client::fetch_new_uids(&mut session, &folder, since_uid).await?;
-    let _ = session.logout().await;
-    Ok(uids)
+) -> Result<Vec<u32>, MailError> {
+    let mut session = pool.get(&config).await?;
+    let result = imap_client::fetch_new_uids(&mut session, &folder, since_uid).await;
+    session.finish(result)
 }
 
 #[tauri::command]
 pub async fn imap_search_all_uids(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
-) -> Result<Vec<u32>, String> {
-    let mut session = imap_client::connect(&config).await?;
-    let uids = imap_client::search_all_uids(&mut session, &folder).await?;
-    let _ = session.logout().await;
-    Ok(uids)
+) -> Result<Vec<u32>, MailError> {
+    let mut session = pool.get(&config).await?;
+    let result = imap_client::search_all_uids(&mut session, &folder).await;
+    session.finish(result)
+}
+
+/// Server-side search with structured criteria; returns matching UIDs.
+#[tauri::command]
+pub async fn imap_search(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    folder: String,
+    query: ImapSearchQuery,
+) -> Result<Vec<u32>, MailError> {
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::search(&mut session, &caps, &folder, &query).await;
+    session.finish(result)
+}
+
+/// Server-side SORT (RFC 5256); returns matching UIDs in sorted order,
+/// newest arrival first when `sort` is empty.
+#[tauri::command]
+pub async fn imap_sort(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    folder: String,
+    sort: Vec<ImapSortKey>,
+    query: Option<ImapSearchQuery>,
+) -> Result<Vec<u32>, MailError> {
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::sort(&mut session, &caps, &folder, &sort, &query.unwrap_or_default()).await;
+    session.finish(result)
+}
+
+/// Server-side THREAD (RFC 5256); returns one UID tree per conversation.
+#[tauri::command]
+pub async fn imap_thread(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    folder: String,
+    algorithm: Option<String>,
+    query: Option<ImapSearchQuery>,
+) -> Result<Vec<ImapThreadNode>, MailError> {
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::thread(
+        &mut session,
+        &caps,
+        &folder,
+        algorithm.as_deref(),
+        &query.unwrap_or_default(),
+    )
+    .await;
+    session.finish(result)
 }
 
 #[tauri::command]
 pub async fn imap_fetch_message_body(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
     uid: u32,
-) -> Result<ImapMessage, String> {
-    let mut session = imap_client::connect(&config).await?;
-    let message = imap_client::fetch_message_body(&mut session, &folder, uid).await?;
-    let _ = session.logout().await;
-    Ok(message)
+) -> Result<ImapMessage, MailError> {
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::fetch_message_body(&mut session, &caps, &folder, uid).await;
+    session.finish(result)
+}
+
+/// Download bodies for header-only synced messages, stopping once their
+/// combined size would exceed `max_bytes`.
+#[tauri::command]
+pub async fn imap_fetch_message_bodies(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    folder: String,
+    uids: Vec<u32>,
+    max_bytes: u64,
+) -> Result<ImapBodyFetchResult, MailError> {
+    if uids.is_empty() {
+        return Ok(ImapBodyFetchResult {
+            messages: vec![],
+            remaining_uids: vec![],
+        });
+    }
+
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::fetch_message_bodies(&mut session, &caps, &folder, &uids, max_bytes).await;
+    session.finish(result)
 }
 
 #[tauri::command]
 pub async fn imap_fetch_raw_message(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
     uid: u32,
-) -> Result<String, String> {
-    let mut session = imap_client::connect(&config).await?;
-    let raw = imap_client::fetch_raw_message(&mut session, &folder, uid).await?;
-    let _ = session.logout().await;
-    Ok(raw)
+) -> Result<String, MailError> {
+    let mut session = pool.get(&config).await?;
+    let result = imap_client::fetch_raw_message(&mut session, &folder, uid).await;
+    session.finish(result)
 }
 
 #[tauri::command]
 pub async fn imap_set_flags(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
     uids: Vec<u32>,
     flags: Vec<String>,
     add: bool,
-) -> Result<(), String> {
+) -> Result<(), MailError> {
     if uids.is_empty() {
         return Ok(());
     }
 
-    let mut session = imap_client::connect(&config).await?;
+    let uid_set: String = uids
+        .iter()
+        .map(|u| u.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut session = pool.get(&config).await?;
+    let result = imap_client::set_flags(&mut session, &folder, &uid_set, add, &flags).await;
+    session.finish(result)
+}
+
+/// Add or remove Gmail labels (X-GM-LABELS) on Gmail-over-IMAP accounts.
+#[tauri::command]
+pub async fn imap_set_gmail_labels(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    folder: String,
+    uids: Vec<u32>,
+    labels: Vec<String>,
+    add: bool,
+) -> Result<(), MailError> {
+    if uids.is_empty() {
+        return Ok(());
+    }
 
     let uid_set: String = uids
         .iter()
@@ -120,117 +328,146 @@ pub async fn imap_set_flags(
         .collect::<Vec<_>>()
         .join(",");
 
-    let flag_op = if add { "+FLAGS" } else { "-FLAGS" };
-
-    // Format flags like "(\Seen \Flagged)"
-    let flags_str = format!(
-        "({})",
-        flags
-            .iter()
-            .map(|f| {
-                // Ensure flags have the backslash prefix if they're standard flags
-                if f.starts_with('\\') {
-                    f.clone()
-                } else {
-                    format!("\\{f}")
-                }
-            })
-            .collect::<Vec<_>>()
-            .join(" ")
-    );
-
-    imap_client::set_flags(&mut session, &folder, &uid_set, flag_op, &flags_str).await?;
-    let _ = session.logout().await;
-    Ok(())
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::set_gmail_labels(&mut session, &caps, &folder, &uid_set, add, &labels).await;
+    session.finish(result)
 }
 
+/// Move messages; the result maps source UIDs to their new UIDs when the
+/// server supports UIDPLUS.
 #[tauri::command]
 pub async fn imap_move_messages(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
     uids: Vec<u32>,
     destination: String,
-) -> Result<(), String> {
+) -> Result<ImapCopyResult, MailError> {
     if uids.is_empty() {
-        return Ok(());
+        return Ok(ImapCopyResult::default());
     }
 
-    let mut session = imap_client::connect(&config).await?;
-
     let uid_set: String = uids
         .iter()
         .map(|u| u.to_string())
         .collect::<Vec<_>>()
         .join(",");
 
-    imap_client::move_messages(&mut session, &folder, &uid_set, &destination).await?;
-    let _ = session.logout().await;
-    Ok(())
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::move_messages(&mut session, &caps, &folder, &uid_set, &destination).await;
+    session.finish(result)
 }
 
+/// Delete messages and return the UIDs that were actually expunged.
 #[tauri::command]
 pub async fn imap_delete_messages(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
     uids: Vec<u32>,
-) -> Result<(), String> {
+) -> Result<Vec<u32>, MailError> {
     if uids.is_empty() {
-        return Ok(());
+        return Ok(Vec::new());
     }
 
-    let mut session = imap_client::connect(&config).await?;
-
     let uid_set: String = uids
         .iter()
         .map(|u| u.to_string())
         .collect::<Vec<_>>()
         .join(",");
 
-    imap_client::delete_messages(&mut session, &folder, &uid_set).await?;
-    let _ = session.logout().await;
-    Ok(())
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::delete_messages(&mut session, &caps, &folder, &uid_set).await;
+    session.finish(result)
 }
 
 #[tauri::command]
 pub async fn imap_get_folder_status(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
-) -> Result<ImapFolderStatus, String> {
-    let mut session = imap_client::connect(&config).await?;
-    let status = imap_client::get_folder_status(&mut session, &folder).await?;
-    let _ = session.logout().await;
-    Ok(status)
+) -> Result<ImapFolderStatus, MailError> {
+    let mut session = pool.get(&config).await?;
+    let result = imap_client::get_folder_status(&mut session, &folder).await;
+    session.finish(result)
 }
 
 #[tauri::command]
 pub async fn imap_fetch_attachment(
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    folder: String,
+    uid: u32,
+    part_id: String,
+) -> Result<String, MailError> {
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::fetch_attachment(&mut session, &caps, &folder, uid, &part_id).await;
+    session.finish(result)
+}
+
+/// Stream a decoded attachment to `destination`, or to `cache_key` in the
+/// app-data attachment cache, emitting `imap-download-progress` events.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn imap_download_attachment(
+    app: AppHandle,
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
     uid: u32,
     part_id: String,
-) -> Result<String, String> {
-    let mut session = imap_client::connect(&config).await?;
-    let data = imap_client::fetch_attachment(&mut session, &folder, uid, &part_id).await?;
-    let _ = session.logout().await;
-    Ok(data)
+    destination: Option<String>,
+    cache_key: Option<String>,
+) -> Result<DownloadResult, MailError> {
+    let path = download::resolve_target(&app, destination, cache_key)?;
+    let sink = DownloadSink::create(&app, path).await?;
+
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::download_attachment(&mut session, &caps, &folder, uid, &part_id, sink).await;
+    session.finish(result)
+}
+
+/// Stream the raw RFC822 source of a message to `destination`, or to
+/// `cache_key` in the app-data attachment cache.
+#[tauri::command]
+pub async fn imap_download_raw_message(
+    app: AppHandle,
+    pool: State<'_, ImapPool>,
+    config: ImapConfig,
+    folder: String,
+    uid: u32,
+    destination: Option<String>,
+    cache_key: Option<String>,
+) -> Result<DownloadResult, MailError> {
+    let path = download::resolve_target(&app, destination, cache_key)?;
+    let sink = DownloadSink::create(&app, path).await?;
+
+    let mut session = pool.get(&config).await?;
+    let result = imap_client::download_raw_message(&mut session, &folder, uid, sink).await;
+    session.finish(result)
 }
 
 #[tauri::command]
 pub async fn imap_append_message(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folder: String,
     flags: Option<String>,
     raw_message: String,
-) -> Result<(), String> {
-    let mut session = imap_client::connect(&config).await?;
-
+) -> Result<ImapAppendResult, MailError> {
     // raw_message is base64url-encoded; decode it
-    let raw_bytes = base64url_decode(&raw_message)?;
+    let raw_bytes = base64url_decode(&raw_message).map_err(|e| MailError::new(ErrorKind::Other, e))?;
 
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
     let flags_ref = flags.as_deref();
-    imap_client::append_message(&mut session, &folder, flags_ref, &raw_bytes).await?;
-    let _ = session.logout().await;
-    Ok(())
+    let result = imap_client::append_message(&mut session, &caps, &folder, flags_ref, &raw_bytes).await;
+    session.finish(result)
 }
 
 fn base64url_decode(input: &str) -> Result<Vec<u8>, String> {
@@ -241,16 +478,100 @@ fn base64url_decode(input: &str) -> Result<Vec<u8>, String> {
         .map_err(|e| format!("base64url decode failed: {e}"))
 }
 
+/// Emits `imap-sync-progress` after each batch. Passing a `sync_id` makes the
+/// sync cancellable via `imap_cancel_sync`; a cancelled sync fails with a
+/// `Cancelled` error.
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn imap_sync_folder(
+    app: AppHandle,
+    pool: State<'_, ImapPool>,
+    syncs: State<'_, SyncRegistry>,
+    config: ImapConfig,
+    folder: String,
+    batch_size: u32,
+    headers_only: Option<bool>,
+    sync_id: Option<String>,
+) -> Result<ImapFolderSyncResult, MailError> {
+    let mut progress = syncs.start(&app, sync_id, &folder);
+    // Connecting and logging in can take as long as a batch, so they are
+    // cancellable too.
+    let mut session = tokio::select! {
+        session = pool.get(&config) => session?,
+        _ = progress.cancelled() => return Err(progress.cancelled_error()),
+    };
+    let caps = session.capabilities();
+    let result = imap_client::sync_folder(
+        &mut session,
+        &caps,
+        &folder,
+        batch_size,
+        headers_only.unwrap_or(false),
+        &mut progress,
+    )
+    .await;
+    session.finish(result)
+}
+
+/// Streaming variant of `imap_sync_folder`: each batch of parsed messages is
+/// sent through `on_batch` as soon as it is fetched instead of being collected
+/// into one response. Fetches `uids` when given, otherwise every UID in the
+/// folder. Progress and cancellation work as for `imap_sync_folder`.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn imap_sync_folder_stream(
+    app: AppHandle,
+    pool: State<'_, ImapPool>,
+    syncs: State<'_, SyncRegistry>,
     config: ImapConfig,
     folder: String,
+    uids: Option<Vec<u32>>,
     batch_size: u32,
-) -> Result<ImapFolderSyncResult, String> {
-    let mut session = imap_client::connect(&config).await?;
-    let result = imap_client::sync_folder(&mut session, &folder, batch_size).await;
-    let _ = session.logout().await;
-    result
+    headers_only: Option<bool>,
+    sync_id: Option<String>,
+    on_batch: Channel<ImapMessageBatch>,
+) -> Result<ImapFolderSyncSummary, MailError> {
+    let mut progress = syncs.start(&app, sync_id, &folder);
+    let mut session = tokio::select! {
+        session = pool.get(&config) => session?,
+        _ = progress.cancelled() => return Err(progress.cancelled_error()),
+    };
+    let caps = session.capabilities();
+    let result = imap_client::sync_folder_batched(
+        &mut session,
+        &caps,
+        &folder,
+        uids,
+        batch_size,
+        headers_only.unwrap_or(false),
+        &mut progress,
+        |messages| {
+            on_batch
+                .send(ImapMessageBatch {
+                    folder: folder.clone(),
+                    messages,
+                })
+                .map_err(|e| MailError::new(ErrorKind::Other, format!("Failed to send sync batch: {e}")))
+        },
+    )
+    .await;
+    let (uids, folder_status) = session.finish(result)?;
+
+    Ok(ImapFolderSyncSummary {
+        uids,
+        folder_status,
+    })
+}
+
+/// Cancel a running `imap_sync_folder` or `imap_sync_folder_stream`, or all
+/// of them when `sync_id` is omitted (e.g. when switching accounts or quitting).
+#[tauri::command]
+pub async fn imap_cancel_sync(
+    syncs: State<'_, SyncRegistry>,
+    sync_id: Option<String>,
+) -> Result<(), MailError> {
+    syncs.cancel(sync_id.as_deref());
+    Ok(())
 }
 
 #[tauri::command]
@@ -258,19 +579,62 @@ pub async fn imap_raw_fetch_diagnostic(
     config: ImapConfig,
     folder: String,
     uid_range: String,
-) -> Result<String, String> {
+) -> Result<String, MailError> {
     imap_client::raw_fetch_diagnostic(&config, &folder, &uid_range).await
 }
 
 #[tauri::command]
 pub async fn imap_delta_check(
+    pool: State<'_, ImapPool>,
     config: ImapConfig,
     folders: Vec<DeltaCheckRequest>,
-) -> Result<Vec<DeltaCheckResult>, String> {
-    let mut session = imap_client::connect(&config).await?;
-    let results = imap_client::delta_check_folders(&mut session, &folders).await?;
-    let _ = session.logout().await;
-    Ok(results)
+) -> Result<Vec<DeltaCheckResult>, MailError> {
+    let mut session = pool.get(&config).await?;
+    let caps = session.capabilities();
+    let result = imap_client::delta_check_folders(&mut session, &caps, &folders).await;
+    session.finish(result)
+}
+
+/// Log out and drop any pooled connections for this account (e.g. after its
+/// credentials changed or the account was removed). Its IDLE watchers
+/// reconnect with `config`.
+#[tauri::command]
+pub async fn imap_close_connections(
+    app: AppHandle,
+    pool: State<'_, ImapPool>,
+    idle: State<'_, IdleManager>,
+    config: ImapConfig,
+) -> Result<(), MailError> {
+    pool.close_account(&config).await;
+    if let Some(account_id) = &config.account_id {
+        idle.reconfigure(&app, account_id, &config);
+    }
+    Ok(())
+}
+
+/// Start IDLE watchers that emit `imap-mailbox-changed` events for the given
+/// folders. Folders already being watched are restarted with `config`.
+#[tauri::command]
+pub async fn imap_idle_start(
+    app: AppHandle,
+    idle: State<'_, IdleManager>,
+    account_id: String,
+    config: ImapConfig,
+    folders: Vec<String>,
+) -> Result<(), MailError> {
+    idle.start(&app, &account_id, &config, &folders);
+    Ok(())
+}
+
+/// Stop the IDLE watcher for `folder`, or every watcher of the account.
+#[tauri::command]
+pub async fn imap_idle_stop(
+    idle: State<'_, IdleManager>,
+    account_id: String,
+    folder: Option<String>,
+) -> Result<(), MailError> {
+    idle.stop(&account_id, folder.as_deref());
+    Ok(())
 }
 
 // ---------- SMTP commands ----------
@@ -279,11 +643,71 @@ pub async fn imap_delta_check(
 pub async fn smtp_send_email(
     config: SmtpConfig,
     raw_email: String,
-) -> Result<SmtpSendResult, String> {
+) -> Result<SmtpSendResult, MailError> {
     smtp_client::send_raw_email(&config, &raw_email).await
 }
 
 #[tauri::command]
-pub async fn smtp_test_connection(config: SmtpConfig) -> Result<SmtpSendResult, String> {
+pub async fn smtp_test_connection(config: SmtpConfig) -> Result<SmtpSendResult, MailError> {
     smtp_client::test_connection(&config).await
 }
+
+/// The server's TLS certificate and its fingerprint, to show before pinning
+/// it as the account's `pinned_cert_sha256`.
+#[tauri::command]
+pub async fn smtp_fetch_certificate(config: SmtpConfig) -> Result<ServerCertificate, MailError> {
+    smtp_client::fetch_certificate(&config).await
+}
+
+// ---------- Proxy commands ----------
+
+/// Set or clear the proxy for accounts without one of their own and for OAuth
+/// requests. Pooled IMAP sessions are closed and IDLE watchers reconnected so
+/// that it applies right away.
+#[tauri::command]
+pub async fn proxy_set_global(
+    app: AppHandle,
+    pool: State<'_, ImapPool>,
+    idle: State<'_, IdleManager>,
+    proxy: Option<ProxyConfig>,
+) -> Result<(), MailError> {
+    proxy::set_global(proxy).map_err(|e| MailError::new(ErrorKind::Other, e))?;
+    pool.close_all().await;
+    idle.reconnect_all(&app);
+    Ok(())
+}
+
+// ---------- Protocol trace commands ----------
+
+/// Start recording the account's IMAP and SMTP connections, discarding any
+/// earlier trace. Only connections opened from now on are recorded, so the
+/// account's idle pooled sessions are closed to trace from a fresh login.
+#[tauri::command]
+pub async fn protocol_trace_start(
+    pool: State<'_, ImapPool>,
+    account_id: String,
+    max_entries: Option<usize>,
+) -> Result<(), MailError> {
+    trace::start(&account_id, max_entries).map_err(|e| MailError::new(ErrorKind::Other, e))?;
+    pool.close_account_id(&account_id).await;
+    Ok(())
+}
+
+/// Stop recording; the trace is kept for `protocol_trace_export`.
+#[tauri::command]
+pub async fn protocol_trace_stop(account_id: String) -> Result<(), MailError> {
+    trace::stop(&account_id);
+    Ok(())
+}
+
+/// The account's trace as text, with credentials and message contents removed.
+#[tauri::command]
+pub async fn protocol_trace_export(account_id: String) -> Result<String, MailError> {
+    trace::export(&account_id).map_err(|e| MailError::new(ErrorKind::Other, e))
+}
+
+#[tauri::command]
+pub async fn protocol_trace_clear(account_id: String) -> Result<(), MailError> {
+    trace::clear(&account_id);
+    Ok(())
+}
@@ -0,0 +1,310 @@
+//! Errors returned to the frontend by the IMAP, SMTP and OAuth commands.
+//!
+//! Each error carries a kind the frontend can act on (prompt for
+//! re-authentication, back off and retry, give up), the message for display
+//! and the server's own words where there are any. The clients build each
+//! error where the cause is known: a timeout, an I/O error, a TLS handshake, a
+//! NO to LOGIN or SELECT, an SMTP reply code.
+
+use std::{fmt, io};
+
+use serde::Serialize;
+
+/// What went wrong, serialized as `{"kind": "Timeout", "stage": "SELECT"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind")]
+pub enum ErrorKind {
+    /// The server rejected the credentials or token.
+    AuthFailed,
+    /// `stage` is "connect", "tls", "auth", "send", "token" or the IMAP
+    /// command that timed out, e.g. "SELECT" or "UID FETCH".
+    Timeout { stage: String },
+    /// Handshake failure, untrusted or changed certificate, bad client
+    /// certificate.
+    TlsError,
+    ConnectionRefused,
+    /// The connection failed or dropped for another reason: DNS, reset,
+    /// closed mid-response.
+    Network,
+    FolderNotFound,
+    QuotaExceeded,
+    /// The server is temporarily unavailable or has too many connections.
+    ServerBusy,
+    /// A response we couldn't make sense of.
+    ProtocolParse,
+    Cancelled,
+    /// No space left on the disk a download is written to.
+    DiskFull,
+    /// A local file can't be written, or the path is outside the folders
+    /// downloads may be saved to.
+    PermissionDenied,
+    /// Another error reading or writing a local file.
+    FileError,
+    Other,
+}
+
+impl ErrorKind {
+    fn retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorKind::Timeout { .. } | ErrorKind::ConnectionRefused | ErrorKind::Network | ErrorKind::ServerBusy
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MailError {
+    #[serde(flatten)]
+    pub kind: ErrorKind,
+    pub message: String,
+    pub server_response: Option<String>, // the server's reply text, e.g. "[AUTHENTICATIONFAILED] Invalid credentials"
+    pub retryable: bool,                 // worth trying again later without user action
+}
+
+impl MailError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        let retryable = kind.retryable();
+        Self {
+            kind,
+            message: message.into(),
+            server_response: None,
+            retryable,
+        }
+    }
+
+    pub fn timeout(stage: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Timeout { stage: stage.into() }, message)
+    }
+
+    /// A failed read, write or connect. The system only times out connects.
+    pub fn io(error: &io::Error, message: impl Into<String>) -> Self {
+        let kind = match error.kind() {
+            io::ErrorKind::ConnectionRefused => ErrorKind::ConnectionRefused,
+            io::ErrorKind::TimedOut => ErrorKind::Timeout { stage: "connect".to_string() },
+            _ => ErrorKind::Network,
+        };
+        Self::new(kind, message)
+    }
+
+    /// A failed local file operation, e.g. writing a download.
+    pub fn file(error: &io::Error, message: impl Into<String>) -> Self {
+        let kind = if is_disk_full(error) {
+            ErrorKind::DiskFull
+        } else if error.kind() == io::ErrorKind::PermissionDenied {
+            ErrorKind::PermissionDenied
+        } else {
+            ErrorKind::FileError
+        };
+        Self::new(kind, message)
+    }
+
+    /// A NO or BAD the server sent in reply to an IMAP command, e.g.
+    /// `[OVERQUOTA] Quota exceeded`. The RFC 5530 response code decides the
+    /// kind, `otherwise` applies without one.
+    pub fn imap_response(response: impl Into<String>, otherwise: ErrorKind, message: impl Into<String>) -> Self {
+        let response = response.into();
+        let kind = imap_response_kind(&response).unwrap_or(otherwise);
+        Self::new(kind, message).with_server_response(response)
+    }
+
+    /// A negative SMTP reply, e.g. `535 5.7.8 Bad credentials`.
+    pub fn smtp_reply(code: u16, response: impl Into<String>, message: impl Into<String>) -> Self {
+        let kind = smtp_reply_kind(code).unwrap_or(ErrorKind::Other);
+        let retryable = match kind {
+            // 452: insufficient storage right now, unlike 552.
+            ErrorKind::QuotaExceeded => code < 500,
+            _ => kind.retryable(),
+        };
+        Self {
+            kind,
+            message: message.into(),
+            server_response: Some(response.into()),
+            retryable,
+        }
+    }
+
+    pub fn with_server_response(mut self, response: impl Into<String>) -> Self {
+        self.server_response = Some(response.into());
+        self
+    }
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// `io::ErrorKind::StorageFull` needs a newer Rust than we support, so check
+/// the OS error: ENOSPC, or ERROR_HANDLE_DISK_FULL and ERROR_DISK_FULL.
+fn is_disk_full(error: &io::Error) -> bool {
+    let codes: &[i32] = if cfg!(windows) { &[39, 112] } else { &[28] };
+    error.raw_os_error().is_some_and(|code| codes.contains(&code))
+}
+
+/// The kind named by the RFC 5530 response code that opens an IMAP response,
+/// e.g. `NO [OVERQUOTA] Quota exceeded` or `* BYE [UNAVAILABLE] Maintenance`.
+fn imap_response_kind(response: &str) -> Option<ErrorKind> {
+    let kind = match response_code(response)?.to_ascii_uppercase().as_str() {
+        "UNAVAILABLE" | "INUSE" => ErrorKind::ServerBusy,
+        "AUTHENTICATIONFAILED" | "AUTHORIZATIONFAILED" | "EXPIRED" => ErrorKind::AuthFailed,
+        "NONEXISTENT" | "TRYCREATE" => ErrorKind::FolderNotFound,
+        "OVERQUOTA" => ErrorKind::QuotaExceeded,
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// The bracketed code at the start of a response text, after the tag and
+/// status if present. A bracket later in the text (a folder name, the
+/// server's prose) isn't a response code.
+fn response_code(response: &str) -> Option<&str> {
+    let is_status = |word: &str| ["OK", "NO", "BAD", "BYE", "PREAUTH"].iter().any(|s| word.eq_ignore_ascii_case(s));
+    let mut text = response.trim_start();
+    for _ in 0..2 {
+        match text.split_once(' ') {
+            Some((word, rest)) if is_status(word) => {
+                text = rest.trim_start();
+                break;
+            }
+            // A tag, e.g. "a1" or "*", followed by the status.
+            Some((_, rest)) if rest.split(' ').next().is_some_and(is_status) => text = rest.trim_start(),
+            _ => break,
+        }
+    }
+    text.strip_prefix('[')?.split([']', ' ']).next()
+}
+
+/// The kind named by an SMTP reply code (RFC 5321, RFC 4954).
+fn smtp_reply_kind(code: u16) -> Option<ErrorKind> {
+    match code {
+        530 | 534 | 535 => Some(ErrorKind::AuthFailed),
+        452 | 552 => Some(ErrorKind::QuotaExceeded),
+        400..=499 => Some(ErrorKind::ServerBusy),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_imap_response_codes() {
+        let e = MailError::imap_response(
+            "[AUTHENTICATIONFAILED] Invalid credentials (Failure)",
+            ErrorKind::AuthFailed,
+            "Login failed",
+        );
+        assert_eq!(e.kind, ErrorKind::AuthFailed);
+        assert!(!e.retryable);
+        assert_eq!(e.server_response.as_deref(), Some("[AUTHENTICATIONFAILED] Invalid credentials (Failure)"));
+
+        // The code overrides the kind the command would otherwise get.
+        let e = MailError::imap_response("[UNAVAILABLE] Try again later", ErrorKind::AuthFailed, "Login failed");
+        assert_eq!(e.kind, ErrorKind::ServerBusy);
+        assert!(e.retryable);
+
+        let e = MailError::imap_response("NO [OVERQUOTA] Quota exceeded", ErrorKind::Other, "APPEND to Sent failed");
+        assert_eq!(e.kind, ErrorKind::QuotaExceeded);
+        assert!(!e.retryable);
+
+        let e = MailError::imap_response("V1 NO [TRYCREATE] No such folder", ErrorKind::Other, "UID MOVE to Foo failed");
+        assert_eq!(e.kind, ErrorKind::FolderNotFound);
+
+        let e = MailError::imap_response("* BYE [UNAVAILABLE] Maintenance", ErrorKind::ServerBusy, "Server refused the connection");
+        assert_eq!(e.kind, ErrorKind::ServerBusy);
+
+        // Without a code the caller's kind applies.
+        let e = MailError::imap_response("NO Mailbox doesn't exist", ErrorKind::FolderNotFound, "SELECT Foo failed");
+        assert_eq!(e.kind, ErrorKind::FolderNotFound);
+    }
+
+    #[test]
+    fn test_response_code_position() {
+        // Brackets later in the text are a folder name or prose, not a code.
+        let e = MailError::imap_response("NO Can't open [OVERQUOTA] reports", ErrorKind::Other, "SELECT failed");
+        assert_eq!(e.kind, ErrorKind::Other);
+        let e = MailError::imap_response("NO Mailbox is locked", ErrorKind::Other, "APPEND to Quota reports failed");
+        assert_eq!(e.kind, ErrorKind::Other);
+        assert_eq!(response_code("a001 NO [INUSE] Mailbox in use"), Some("INUSE"));
+        assert_eq!(response_code("[ALERT] Hello"), Some("ALERT"));
+        assert_eq!(response_code("Mailbox [NONEXISTENT]"), None);
+    }
+
+    #[test]
+    fn test_smtp_reply_codes() {
+        let e = MailError::smtp_reply(535, "535 5.7.8 Bad credentials", "SMTP PLAIN authentication failed");
+        assert_eq!(e.kind, ErrorKind::AuthFailed);
+        assert!(!e.retryable);
+        assert_eq!(e.server_response.as_deref(), Some("535 5.7.8 Bad credentials"));
+
+        let e = MailError::smtp_reply(452, "452 4.2.2 Mailbox full", "SMTP send error");
+        assert_eq!(e.kind, ErrorKind::QuotaExceeded);
+        assert!(e.retryable);
+
+        let e = MailError::smtp_reply(552, "552 5.2.2 Mailbox full", "SMTP send error");
+        assert_eq!(e.kind, ErrorKind::QuotaExceeded);
+        assert!(!e.retryable);
+
+        let e = MailError::smtp_reply(421, "421 4.7.0 Try again later", "SMTP send error");
+        assert_eq!(e.kind, ErrorKind::ServerBusy);
+        assert!(e.retryable);
+
+        let e = MailError::smtp_reply(550, "550 5.1.1 No such user", "SMTP send error");
+        assert_eq!(e.kind, ErrorKind::Other);
+        assert!(!e.retryable);
+    }
+
+    #[test]
+    fn test_io_errors() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let e = MailError::io(&refused, "TCP connect failed");
+        assert_eq!(e.kind, ErrorKind::ConnectionRefused);
+        assert!(e.retryable);
+
+        let timed_out = io::Error::from(io::ErrorKind::TimedOut);
+        let e = MailError::io(&timed_out, "TCP connect failed");
+        assert_eq!(e.kind, ErrorKind::Timeout { stage: "connect".to_string() });
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(MailError::io(&reset, "IMAP read failed").kind, ErrorKind::Network);
+    }
+
+    #[test]
+    fn test_file_errors() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        let e = MailError::file(&denied, "Failed to create a.pdf");
+        assert_eq!(e.kind, ErrorKind::PermissionDenied);
+        assert!(!e.retryable);
+
+        #[cfg(unix)]
+        let full = io::Error::from_raw_os_error(28);
+        #[cfg(windows)]
+        let full = io::Error::from_raw_os_error(112);
+        assert_eq!(MailError::file(&full, "Failed to write a.pdf").kind, ErrorKind::DiskFull);
+
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(MailError::file(&missing, "Failed to create a.pdf").kind, ErrorKind::FileError);
+    }
+
+    #[test]
+    fn test_serialized_shape() {
+        let e = MailError::new(ErrorKind::Timeout { stage: "SELECT".to_string() }, "SELECT INBOX timed out");
+        let json = serde_json::to_value(&e).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "kind": "Timeout",
+                "stage": "SELECT",
+                "message": "SELECT INBOX timed out",
+                "server_response": null,
+                "retryable": true,
+            })
+        );
+
+        let json = serde_json::to_value(MailError::new(ErrorKind::AuthFailed, "nope")).unwrap();
+        assert_eq!(json["kind"], "AuthFailed");
+        assert!(json.get("stage").is_none());
+    }
+}
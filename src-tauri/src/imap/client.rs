@@ -1,13 +1,25 @@
-use async_imap::{types::Flag, Authenticator, Client, Session};
+use async_imap::imap_proto::{AttributeValue, MailboxDatum, Response, Status as ResponseStatus};
+use async_imap::types::{Capability, Flag, NameAttribute, StatusAttribute, UnsolicitedResponse};
+use async_imap::{Authenticator, Client, DeflateStream, Session};
 use base64::Engine;
 use futures::StreamExt;
 use mail_parser::{MessageParser, MimeHeaders};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tokio_native_tls::TlsStream;
 
+use super::download::DownloadSink;
+use super::response;
+use super::sync::SyncProgress;
 use super::types::*;
+use crate::error::{ErrorKind, MailError};
+use crate::proxy;
+use crate::sasl::{Mechanism, SaslClient};
+use crate::tls::{self, ServerCertificate};
+use crate::trace::{self, TracedStream};
 
 // ---------- Timeout constants ----------
 
@@ -19,6 +31,104 @@ const IMAP_FETCH_TIMEOUT: Duration = Duration::from_secs(120);
 const IMAP_SEARCH_TIMEOUT: Duration = Duration::from_secs(60);
 const OVERALL_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
 
+// ---------- Fetch items ----------
+
+/// Full download: every byte of every message, attachments included.
+const FULL_FETCH_ITEMS: &str = "UID FLAGS INTERNALDATE BODY.PEEK[]";
+/// Header-only sync: ENVELOPE and BODYSTRUCTURE cover almost everything the
+/// message list needs; the few extra headers we show are fetched by name.
+const HEADER_FETCH_ITEMS: &str = "(UID FLAGS INTERNALDATE RFC822.SIZE ENVELOPE BODYSTRUCTURE \
+    BODY.PEEK[HEADER.FIELDS (REFERENCES LIST-UNSUBSCRIBE LIST-UNSUBSCRIBE-POST AUTHENTICATION-RESULTS)])";
+/// Header-only items for `raw_fetch_messages`, which can't parse ENVELOPE or
+/// BODYSTRUCTURE and takes the whole header block instead.
+const RAW_HEADER_FETCH_ITEMS: &str = "UID FLAGS INTERNALDATE RFC822.SIZE BODY.PEEK[HEADER]";
+/// Messages per UID FETCH when downloading bodies in the background.
+const BODY_FETCH_BATCH: usize = 25;
+
+// ---------- Errors ----------
+
+/// `command` (e.g. "SELECT INBOX") didn't complete within `limit`.
+pub(crate) fn timed_out(command: impl std::fmt::Display, limit: Duration) -> MailError {
+    let command = command.to_string();
+    let mut words = command.split_whitespace();
+    let stage = match (words.next(), words.next()) {
+        (Some("UID"), Some(name)) => format!("UID {name}"),
+        (name, _) => name.unwrap_or_default().to_string(),
+    };
+    MailError::timeout(
+        stage,
+        format!("{command} timed out after {}s — check your server settings or network connection", limit.as_secs()),
+    )
+}
+
+/// An async-imap error from `command`. A NO without a response code saying
+/// otherwise is `no_kind`, e.g. `AuthFailed` for LOGIN.
+fn command_failed(command: impl std::fmt::Display, e: async_imap::error::Error, no_kind: ErrorKind) -> MailError {
+    use async_imap::error::Error;
+
+    let message = format!("{command} failed: {e}");
+    let (response, otherwise) = match e {
+        Error::Io(e) => return MailError::io(&e, message),
+        Error::ConnectionLost => return MailError::new(ErrorKind::Network, message),
+        Error::Parse(_) => return MailError::new(ErrorKind::ProtocolParse, message),
+        Error::No(response) => (response, no_kind),
+        Error::Bad(response) => (response, ErrorKind::Other),
+        _ => return MailError::new(ErrorKind::Other, message),
+    };
+    match response_info(&response) {
+        Some(info) => MailError::imap_response(info, otherwise, message),
+        None => MailError::new(otherwise, message),
+    }
+}
+
+/// The server's text from an async-imap NO or BAD, which only comes
+/// formatted as `code: None, info: Some("[OVERQUOTA] Quota exceeded")`.
+fn response_info(response: &str) -> Option<String> {
+    let quoted = response.split_once("info: Some(")?.1.strip_suffix(')')?;
+    serde_json::from_str(quoted)
+        .ok()
+        .or_else(|| Some(quoted.strip_prefix('"')?.strip_suffix('"')?.to_string()))
+}
+
+pub(crate) fn failed(command: impl std::fmt::Display, e: async_imap::error::Error) -> MailError {
+    command_failed(command, e, ErrorKind::Other)
+}
+
+/// SELECT and EXAMINE answer NO for a folder that doesn't exist, often
+/// without a response code.
+pub(crate) fn select_failed(folder: &str, e: async_imap::error::Error) -> MailError {
+    command_failed(format!("SELECT {folder}"), e, ErrorKind::FolderNotFound)
+}
+
+/// A tagged NO or BAD from `run_raw_command`, e.g. `NO [NONEXISTENT] Unknown
+/// Mailbox`.
+fn raw_status_failed(command: impl std::fmt::Display, status: &str, no_kind: ErrorKind) -> MailError {
+    let no = status.get(..2).is_some_and(|s| s.eq_ignore_ascii_case("NO"));
+    let otherwise = if no { no_kind } else { ErrorKind::Other };
+    MailError::imap_response(status, otherwise, format!("{command} failed: {status}"))
+}
+
+fn other_error(message: impl Into<String>) -> MailError {
+    MailError::new(ErrorKind::Other, message)
+}
+
+/// A greeting other than OK. A BYE usually means too many connections or
+/// maintenance, unless its response code says otherwise.
+fn greeting_refused(greeting: &str, message: String) -> MailError {
+    let otherwise = if strip_keyword(greeting, "BYE").is_some() { ErrorKind::ServerBusy } else { ErrorKind::ProtocolParse };
+    MailError::imap_response(greeting.trim_end(), otherwise, message)
+}
+
+/// A response we couldn't make sense of.
+fn parse_error(message: impl Into<String>) -> MailError {
+    MailError::new(ErrorKind::ProtocolParse, message)
+}
+
+/// The server hung up mid-command.
+fn closed_error(message: impl Into<String>) -> MailError {
+    MailError::new(ErrorKind::Network, message)
+}
+
 /// Configure TCP keepalive and nodelay on a connected socket.
 fn configure_tcp_socket(stream: &TcpStream) {
     // Set TCP nodelay via tokio's built-in API
@@ -36,38 +146,44 @@ fn configure_tcp_socket(stream: &TcpStream) {
     }
 }
 
-// ---------- XOAUTH2 authenticator ----------
+// ---------- SASL authenticator ----------
 
-struct XOAuth2 {
-    response: Vec<u8>,
-}
-
-impl XOAuth2 {
-    fn new(user: &str, access_token: &str) -> Self {
-        // XOAUTH2 format: "user=" {user} "\x01auth=Bearer " {token} "\x01\x01"
-        let s = format!("user={}\x01auth=Bearer {}\x01\x01", user, access_token);
-        Self {
-            response: s.into_bytes(),
-        }
-    }
-}
+/// Feeds async-imap's AUTHENTICATE handshake from a `SaslClient`, which is
+/// shared so the exchange can be checked once async-imap is done with it.
+struct SaslAuthenticator(Arc<Mutex<SaslClient>>);
 
-impl Authenticator for XOAuth2 {
+impl Authenticator for SaslAuthenticator {
     type Response = Vec<u8>;
-    fn process(&mut self, _challenge: &[u8]) -> Self::Response {
-        // Return the initial XOAUTH2 string on the first (empty) challenge.
-        // If the server sends a second challenge it means auth failed; we send
-        // an empty response to let the server return a proper error.
-        std::mem::take(&mut self.response)
+    fn process(&mut self, challenge: &[u8]) -> Self::Response {
+        self.0.lock().map(|mut sasl| sasl.respond(challenge)).unwrap_or_default()
     }
 }
 
 // ---------- Stream wrapper ----------
 
 /// Wrapper to unify TLS / plain streams so Session can be generic.
+/// `Deflate` wraps one of the others once COMPRESS DEFLATE is active;
+/// `Traced` wraps one while the account's protocol trace is running.
 pub(crate) enum ImapStream {
     Tls(TlsStream<TcpStream>),
     Plain(TcpStream),
+    Deflate(Box<DeflateStream<ImapStream>>),
+    Traced(Box<TracedStream<ImapStream>>),
+}
+
+impl ImapStream {
+    /// Wrap the stream for the account's protocol trace, if one is running.
+    fn traced(self, config: &ImapConfig) -> Self {
+        let server = format!("{}:{} ({})", config.host, config.port, config.security);
+        match trace::recorder(config.account_id.as_deref(), trace::Protocol::Imap, &server) {
+            Some(recorder) => ImapStream::Traced(Box::new(TracedStream::new(self, recorder))),
+            None => self,
+        }
+    }
+
+    pub(crate) fn is_traced(&self) -> bool {
+        matches!(self, ImapStream::Traced(_))
+    }
 }
 
 impl tokio::io::AsyncRead for ImapStream {
@@ -79,6 +195,8 @@ impl tokio::io::AsyncRead for ImapStream {
         match self.get_mut() {
             ImapStream::Tls(s) => std::pin::Pin::new(s).poll_read(cx, buf),
             ImapStream::Plain(s) => std::pin::Pin::new(s).poll_read(cx, buf),
+            ImapStream::Deflate(s) => std::pin::Pin::new(s.as_mut()).poll_read(cx, buf),
+            ImapStream::Traced(s) => std::pin::Pin::new(s.as_mut()).poll_read(cx, buf),
         }
     }
 }
@@ -92,6 +210,8 @@ impl tokio::io::AsyncWrite for ImapStream {
         match self.get_mut() {
             ImapStream::Tls(s) => std::pin::Pin::new(s).poll_write(cx, buf),
             ImapStream::Plain(s) => std::pin::Pin::new(s).poll_write(cx, buf),
+            ImapStream::Deflate(s) => std::pin::Pin::new(s.as_mut()).poll_write(cx, buf),
+            ImapStream::Traced(s) => std::pin::Pin::new(s.as_mut()).poll_write(cx, buf),
         }
     }
 
@@ -102,6 +222,8 @@ impl tokio::io::AsyncWrite for ImapStream {
         match self.get_mut() {
             ImapStream::Tls(s) => std::pin::Pin::new(s).poll_flush(cx),
             ImapStream::Plain(s) => std::pin::Pin::new(s).poll_flush(cx),
+            ImapStream::Deflate(s) => std::pin::Pin::new(s.as_mut()).poll_flush(cx),
+            ImapStream::Traced(s) => std::pin::Pin::new(s.as_mut()).poll_flush(cx),
         }
     }
 
@@ -112,6 +234,8 @@ impl tokio::io::AsyncWrite for ImapStream {
         match self.get_mut() {
             ImapStream::Tls(s) => std::pin::Pin::new(s).poll_shutdown(cx),
             ImapStream::Plain(s) => std::pin::Pin::new(s).poll_shutdown(cx),
+            ImapStream::Deflate(s) => std::pin::Pin::new(s.as_mut()).poll_shutdown(cx),
+            ImapStream::Traced(s) => std::pin::Pin::new(s.as_mut()).poll_shutdown(cx),
         }
     }
 }
@@ -121,122 +245,531 @@ impl std::fmt::Debug for ImapStream {
         match self {
             ImapStream::Tls(_) => write!(f, "ImapStream::Tls"),
             ImapStream::Plain(_) => write!(f, "ImapStream::Plain"),
+            ImapStream::Deflate(s) => write!(f, "ImapStream::Deflate({:?})", s.get_ref()),
+            ImapStream::Traced(s) => write!(f, "ImapStream::Traced({:?})", s.get_ref()),
         }
     }
 }
 
 // ---------- TLS helper ----------
 
-/// Build a TLS connector, optionally accepting invalid certificates
-/// (for local mail bridges like ProtonMail Bridge with self-signed certs).
-fn build_tls_connector(accept_invalid_certs: bool) -> Result<native_tls::TlsConnector, String> {
-    let mut builder = native_tls::TlsConnector::builder();
-    if accept_invalid_certs {
-        builder.danger_accept_invalid_certs(true);
-        builder.danger_accept_invalid_hostnames(true);
+/// How to check the server's certificate: the pinned one only, if the
+/// account has a pin, otherwise against the system's and the account's CAs
+/// unless the account accepts invalid certificates (for local mail bridges
+/// like ProtonMail Bridge with self-signed certs).
+fn tls_options(config: &ImapConfig) -> tls::TlsOptions<'_> {
+    tls::TlsOptions {
+        accept_invalid_certs: config.accept_invalid_certs,
+        pinned_sha256: config.pinned_cert_sha256.as_deref(),
+        ca_certificates_pem: config.ca_certificates_pem.as_deref(),
+        client_identity_pkcs12: config.client_identity_pkcs12.as_deref(),
+        client_identity_password: config.client_identity_password.as_deref(),
+        min_tls_version: config.min_tls_version.as_deref(),
     }
-    builder.build().map_err(|e| format!("Failed to create TLS connector: {e}"))
+}
+
+/// TLS handshake over `tcp`, as configured for the account.
+async fn tls_handshake(config: &ImapConfig, tcp: TcpStream) -> Result<TlsStream<TcpStream>, MailError> {
+    tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls::handshake(&config.host, tcp, tls_options(config)))
+        .await
+        .map_err(|_| {
+            MailError::timeout(
+                "tls",
+                format!(
+                    "TLS handshake with {} timed out after {}s — check your server settings or network connection",
+                    config.host,
+                    TLS_HANDSHAKE_TIMEOUT.as_secs()
+                ),
+            )
+        })?
+}
+
+/// The certificate the server presents, for the user to review and pin.
+pub async fn fetch_certificate(config: &ImapConfig) -> Result<ServerCertificate, MailError> {
+    let fetch = async {
+        match config.security.as_str() {
+            "tls" => tls::fetch_certificate(&config.host, tls_options(config), || tcp_connect(config)).await,
+            "starttls" => tls::fetch_certificate(&config.host, tls_options(config), || starttls_tcp(config)).await,
+            _ => Err(other_error("The connection isn't encrypted, so there is no certificate to check")),
+        }
+    };
+    tokio::time::timeout(OVERALL_CONNECT_TIMEOUT, fetch)
+        .await
+        .map_err(|_| connect_timed_out(config))?
+}
+
+fn connect_timed_out(config: &ImapConfig) -> MailError {
+    MailError::timeout(
+        "connect",
+        format!(
+            "IMAP connection to {}:{} timed out after {}s — check your server settings or network connection",
+            config.host,
+            config.port,
+            OVERALL_CONNECT_TIMEOUT.as_secs()
+        ),
+    )
+}
+
+fn auth_timed_out() -> MailError {
+    MailError::timeout(
+        "auth",
+        format!(
+            "IMAP authentication timed out after {}s — check your server settings or network connection",
+            AUTH_TIMEOUT.as_secs()
+        ),
+    )
 }
 
 // ---------- Public API ----------
 
-type ImapSession = Session<ImapStream>;
+pub(crate) type ImapSession = Session<ImapStream>;
 
 /// Establish an IMAP connection and authenticate.
 ///
 /// Supports TLS (direct), STARTTLS (upgrade), and plain connections.
-/// Auth methods: "password" (LOGIN) or "oauth2" (XOAUTH2).
+/// Auth methods: "password" (SCRAM-SHA-256, PLAIN or LOGIN, as offered) or
+/// "oauth2" (OAUTHBEARER or XOAUTH2), or a specific mechanism.
 ///
 /// Wraps the entire connection + auth sequence in a 60s overall timeout.
-pub async fn connect(config: &ImapConfig) -> Result<ImapSession, String> {
+pub async fn connect(config: &ImapConfig) -> Result<ImapSession, MailError> {
+    connect_with_auth_mechanisms(config).await.map(|(session, _)| session)
+}
+
+/// Like `connect`, also returning the SASL mechanisms the server offered
+/// before login. Most servers drop `AUTH=` from CAPABILITY once logged in,
+/// so `get_capabilities` alone usually reports none.
+pub async fn connect_with_auth_mechanisms(config: &ImapConfig) -> Result<(ImapSession, Vec<String>), MailError> {
     tokio::time::timeout(OVERALL_CONNECT_TIMEOUT, connect_inner(config))
         .await
-        .map_err(|_| format!(
-            "IMAP connection to {}:{} timed out after {}s — check your server settings or network connection",
-            config.host, config.port, OVERALL_CONNECT_TIMEOUT.as_secs()
-        ))?
+        .map_err(|_| connect_timed_out(config))?
 }
 
-async fn connect_inner(config: &ImapConfig) -> Result<ImapSession, String> {
+async fn connect_inner(config: &ImapConfig) -> Result<(ImapSession, Vec<String>), MailError> {
     if config.security == "starttls" {
         return connect_starttls(config).await;
     }
 
-    let stream = connect_stream(config).await?;
+    let mut stream = connect_stream(config).await?.traced(config);
+    let capabilities = pre_auth_capabilities(&mut stream, true).await?;
     let client = Client::new(stream);
 
-    tokio::time::timeout(AUTH_TIMEOUT, authenticate(client, config))
+    let session = tokio::time::timeout(AUTH_TIMEOUT, authenticate(client, config, &capabilities))
         .await
-        .map_err(|_| format!(
-            "IMAP authentication timed out after {}s — check your server settings or network connection",
-            AUTH_TIMEOUT.as_secs()
-        ))?
+        .map_err(|_| auth_timed_out())??;
+    Ok((session, parse_capabilities(capabilities).auth_mechanisms))
 }
 
 /// List all IMAP folders/mailboxes.
-pub async fn list_folders(session: &mut ImapSession) -> Result<Vec<ImapFolder>, String> {
-    let names_stream = tokio::time::timeout(IMAP_CMD_TIMEOUT, session.list(Some(""), Some("*")))
-        .await
-        .map_err(|_| format!("LIST timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("LIST failed: {e}"))?;
+///
+/// With LIST-STATUS (RFC 5819) the counts come back with the LIST in one
+/// round-trip; otherwise STATUS is pipelined for all folders at once.
+/// Subscription and hierarchy attributes use LIST-EXTENDED (RFC 5258)
+/// return options where available, or LSUB and the listed paths otherwise.
+pub async fn list_folders(session: &mut ImapSession, caps: &ImapCapabilities) -> Result<Vec<ImapFolder>, MailError> {
+    let status_items = status_items(caps);
+    let command = if caps.list_extended {
+        let mut options = vec!["SUBSCRIBED".to_string(), "CHILDREN".to_string()];
+        if caps.special_use {
+            options.push("SPECIAL-USE".to_string());
+        }
+        if caps.list_status {
+            options.push(format!("STATUS {status_items}"));
+        }
+        format!("LIST \"\" \"*\" RETURN ({})", options.join(" "))
+    } else {
+        "LIST \"\" \"*\"".to_string()
+    };
 
-    let names: Vec<_> = tokio::time::timeout(IMAP_CMD_TIMEOUT, names_stream.collect::<Vec<_>>())
-        .await
-        .map_err(|_| format!("LIST stream timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .into_iter()
-        .filter_map(|r| r.ok())
-        .collect();
+    let (mut entries, mut statuses) = run_list(session, &command).await?;
+    // RETURN (SUBSCRIBED) also reports subscriptions to deleted mailboxes.
+    entries.retain(|e| !e.has_attribute("\\NonExistent"));
 
-    let mut folders = Vec::new();
-    for name in &names {
-        let raw_path = name.name().to_string();
-        let delimiter = name.delimiter().unwrap_or("/").to_string();
-
-        // Decode modified UTF-7 (RFC 3501 §5.1.3) to UTF-8 for display
-        let path = utf7_imap::decode_utf7_imap(raw_path.clone());
-
-        // Extract display name (last segment after delimiter)
-        let display_name = path
-            .rsplit_once(&delimiter)
-            .map(|(_, last)| last.to_string())
-            .unwrap_or_else(|| path.clone());
-
-        // Detect special-use from attributes (RFC 6154)
-        let special_use = detect_special_use(name);
-
-        // Get message counts via STATUS — use raw_path for IMAP commands
-        let (exists, unseen) = match tokio::time::timeout(
-            IMAP_CMD_TIMEOUT,
-            session.status(&raw_path, "(MESSAGES UNSEEN)"),
-        ).await {
-            Ok(Ok(mailbox)) => (mailbox.exists, mailbox.unseen.unwrap_or(0)),
-            _ => (0, 0),
-        };
+    let subscribed: Option<HashSet<String>> = if caps.list_extended {
+        None
+    } else {
+        let (subscribed, _) = run_list(session, "LSUB \"\" \"*\"").await?;
+        Some(subscribed.into_iter().map(|e| e.name).collect())
+    };
 
-        folders.push(ImapFolder {
-            path,
-            raw_path,
-            name: display_name,
-            delimiter,
-            special_use,
-            exists,
-            unseen,
-        });
+    let missing: Vec<&str> = entries
+        .iter()
+        .filter(|e| e.selectable() && !statuses.contains_key(&e.name))
+        .map(|e| e.name.as_str())
+        .collect();
+    if !missing.is_empty() {
+        statuses.extend(status_pipelined(session, &missing, &status_items).await?);
     }
 
+    let folders = entries
+        .iter()
+        .map(|entry| {
+            let is_subscribed = match &subscribed {
+                Some(names) => names.contains(&entry.name),
+                None => entry.has_attribute("\\Subscribed"),
+            };
+            // Without CHILDREN (RFC 3348) children are inferred from the listing.
+            let has_children = entry.has_attribute("\\HasChildren")
+                || (!entry.has_attribute("\\HasNoChildren")
+                    && entry.delimiter.as_deref().is_some_and(|d| {
+                        let prefix = format!("{}{d}", entry.name);
+                        entries.iter().any(|other| other.name.starts_with(&prefix))
+                    }));
+            build_folder(entry, statuses.get(&entry.name), is_subscribed, has_children)
+        })
+        .collect();
+
     Ok(folders)
 }
 
+/// Attributes requested from STATUS, by LIST-STATUS or the fallback.
+fn status_items(caps: &ImapCapabilities) -> String {
+    if caps.condstore {
+        "(MESSAGES UNSEEN UIDNEXT UIDVALIDITY HIGHESTMODSEQ)".to_string()
+    } else {
+        "(MESSAGES UNSEEN UIDNEXT UIDVALIDITY)".to_string()
+    }
+}
+
+/// One `* LIST` (or `* LSUB`) response.
+struct ListEntry {
+    name: String,
+    delimiter: Option<String>,
+    attributes: Vec<NameAttribute<'static>>,
+}
+
+impl ListEntry {
+    fn has_attribute(&self, attribute: &str) -> bool {
+        self.attributes
+            .iter()
+            .any(|a| matches!(a, NameAttribute::Extension(ext) if ext.eq_ignore_ascii_case(attribute)))
+    }
+
+    fn selectable(&self) -> bool {
+        !self.attributes.iter().any(|a| matches!(a, NameAttribute::NoSelect))
+    }
+}
+
+/// Build an `ImapFolder` from a LIST entry and its STATUS attributes.
+fn build_folder(
+    entry: &ListEntry,
+    status: Option<&Vec<StatusAttribute>>,
+    subscribed: bool,
+    has_children: bool,
+) -> ImapFolder {
+    let raw_path = entry.name.clone();
+    let delimiter = entry.delimiter.clone().unwrap_or_else(|| "/".to_string());
+
+    // Decode modified UTF-7 (RFC 3501 §5.1.3) to UTF-8 for display
+    let path = utf7_imap::decode_utf7_imap(raw_path.clone());
+
+    // Extract display name (last segment after delimiter)
+    let display_name = path
+        .rsplit_once(&delimiter)
+        .map(|(_, last)| last.to_string())
+        .unwrap_or_else(|| path.clone());
+
+    // Detect special-use from attributes (RFC 6154)
+    let special_use = detect_special_use(&entry.attributes, &raw_path);
+
+    let mut folder = ImapFolder {
+        path,
+        raw_path,
+        name: display_name,
+        delimiter,
+        special_use,
+        exists: 0,
+        unseen: 0,
+        uidnext: None,
+        uidvalidity: None,
+        highest_modseq: None,
+        no_select: !entry.selectable(),
+        has_children,
+        subscribed,
+    };
+    for attribute in status.into_iter().flatten() {
+        match *attribute {
+            StatusAttribute::Messages(n) => folder.exists = n,
+            StatusAttribute::Unseen(n) => folder.unseen = n,
+            StatusAttribute::UidNext(n) => folder.uidnext = Some(n),
+            StatusAttribute::UidValidity(n) => folder.uidvalidity = Some(n),
+            StatusAttribute::HighestModSeq(n) => folder.highest_modseq = Some(n),
+            _ => {}
+        }
+    }
+    folder
+}
+
+/// Run a LIST or LSUB command and collect the mailboxes it returns, plus any
+/// STATUS responses (LIST-STATUS) keyed by mailbox.
+///
+/// async-imap's `list` hands STATUS responses to the unsolicited channel and
+/// quotes nothing, so the responses are read here directly.
+async fn run_list(
+    session: &mut ImapSession,
+    command: &str,
+) -> Result<(Vec<ListEntry>, HashMap<String, Vec<StatusAttribute>>), MailError> {
+    let label = command.split(' ').next().unwrap_or(command);
+    let tag = tokio::time::timeout(IMAP_CMD_TIMEOUT, session.run_command(command))
+        .await
+        .map_err(|_| timed_out(label, IMAP_CMD_TIMEOUT))?
+        .map_err(|e| failed(label, e))?;
+
+    let mut entries = Vec::new();
+    let mut statuses = HashMap::new();
+    loop {
+        let response = tokio::time::timeout(IMAP_CMD_TIMEOUT, session.read_response())
+            .await
+            .map_err(|_| timed_out(label, IMAP_CMD_TIMEOUT))?
+            .ok_or_else(|| closed_error(format!("{label}: connection closed")))?
+            .map_err(|e| MailError::io(&e, format!("{label} failed: {e}")))?;
+
+        match response.parsed() {
+            Response::MailboxData(MailboxDatum::List { name_attributes, delimiter, name }) => {
+                entries.push(ListEntry {
+                    name: name.to_string(),
+                    delimiter: delimiter.as_ref().map(|d| d.to_string()),
+                    attributes: name_attributes.iter().cloned().map(NameAttribute::into_owned).collect(),
+                });
+            }
+            Response::MailboxData(MailboxDatum::Status { mailbox, status }) => {
+                statuses.insert(mailbox.to_string(), status.clone());
+            }
+            Response::Done { tag: done, status, information, .. } if *done == tag => {
+                if *status != ResponseStatus::Ok {
+                    let status = format!("{status:?} {}", information.as_deref().unwrap_or(""));
+                    return Err(raw_status_failed(label, &status, ErrorKind::Other));
+                }
+                return Ok((entries, statuses));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Maximum STATUS commands in flight at once in `status_pipelined`.
+const STATUS_PIPELINE_DEPTH: usize = 50;
+
+/// STATUS for many mailboxes without waiting for each reply before sending
+/// the next command. Mailboxes the server refuses are left out.
+async fn status_pipelined(
+    session: &mut ImapSession,
+    mailboxes: &[&str],
+    items: &str,
+) -> Result<HashMap<String, Vec<StatusAttribute>>, MailError> {
+    let mut statuses = HashMap::new();
+    for chunk in mailboxes.chunks(STATUS_PIPELINE_DEPTH) {
+        let mut pending = Vec::new();
+        for mailbox in chunk {
+            let command = format!("STATUS {} {items}", imap_string(mailbox, false));
+            let tag = tokio::time::timeout(IMAP_CMD_TIMEOUT, session.run_command(&command))
+                .await
+                .map_err(|_| timed_out("STATUS", IMAP_CMD_TIMEOUT))?
+                .map_err(|e| failed(format!("STATUS {mailbox}"), e))?;
+            pending.push(tag);
+        }
+
+        while !pending.is_empty() {
+            let response = tokio::time::timeout(IMAP_CMD_TIMEOUT, session.read_response())
+                .await
+                .map_err(|_| timed_out("STATUS", IMAP_CMD_TIMEOUT))?
+                .ok_or_else(|| closed_error("STATUS: connection closed"))?
+                .map_err(|e| MailError::io(&e, format!("STATUS failed: {e}")))?;
+
+            match response.parsed() {
+                Response::MailboxData(MailboxDatum::Status { mailbox, status }) => {
+                    statuses.insert(mailbox.to_string(), status.clone());
+                }
+                Response::Done { tag, status, information, .. } => {
+                    if *status != ResponseStatus::Ok {
+                        log::warn!("IMAP STATUS refused: {status:?} {}", information.as_deref().unwrap_or(""));
+                    }
+                    pending.retain(|t| t != tag);
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(statuses)
+}
+
+/// LIST a single mailbox by raw path; `""` returns the root, which carries
+/// the server's hierarchy delimiter.
+async fn list_one(session: &mut ImapSession, raw_path: &str) -> Result<Option<ListEntry>, MailError> {
+    // A name containing `%` or `*` still works as a pattern, so the exact
+    // entry is picked out below.
+    let command = format!("LIST \"\" {}", imap_string(raw_path, false));
+    let (entries, _) = run_list(session, &command).await?;
+    Ok(entries.into_iter().find(|e| e.name == raw_path))
+}
+
+/// Look up a folder that is expected to exist, e.g. right after creating it.
+async fn get_folder(session: &mut ImapSession, caps: &ImapCapabilities, raw_path: &str) -> Result<ImapFolder, MailError> {
+    let entry = list_one(session, raw_path)
+        .await?
+        .ok_or_else(|| MailError::new(ErrorKind::FolderNotFound, format!("Folder {raw_path} not found")))?;
+    let statuses = if entry.selectable() {
+        status_pipelined(session, &[raw_path], &status_items(caps)).await?
+    } else {
+        HashMap::new()
+    };
+    let has_children = entry.has_attribute("\\HasChildren");
+    let subscribed = entry.has_attribute("\\Subscribed");
+    Ok(build_folder(&entry, statuses.get(raw_path), subscribed, has_children))
+}
+
+/// Join an encoded folder name onto its parent using the server's hierarchy
+/// delimiter, rejecting names that contain the delimiter themselves.
+fn child_path(parent: Option<&str>, name: &str, delimiter: Option<&str>) -> Result<String, MailError> {
+    if name.is_empty() {
+        return Err(other_error("Folder name must not be empty"));
+    }
+    if let Some(delimiter) = delimiter.filter(|d| name.contains(d)) {
+        return Err(other_error(format!("Folder name {name:?} must not contain the hierarchy delimiter {delimiter:?}")));
+    }
+
+    let encoded = utf7_imap::encode_utf7_imap(name.to_string());
+    match parent {
+        Some(parent) => {
+            let delimiter = delimiter.ok_or_else(|| other_error("Server does not support nested folders"))?;
+            Ok(format!("{parent}{delimiter}{encoded}"))
+        }
+        None => Ok(encoded),
+    }
+}
+
+/// Create a folder named `name` (UTF-8) under `parent` (a raw path), or at
+/// the top level.
+///
+/// With `special_use` (e.g. `\Archive`) and CREATE-SPECIAL-USE (RFC 6154)
+/// the server marks the new folder accordingly; without the capability the
+/// folder is created plainly.
+pub async fn create_folder(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    parent: Option<&str>,
+    name: &str,
+    special_use: Option<&str>,
+) -> Result<ImapFolder, MailError> {
+    let root = list_one(session, parent.unwrap_or("")).await?;
+    if let (Some(parent), None) = (parent, &root) {
+        return Err(MailError::new(ErrorKind::FolderNotFound, format!("Parent folder {parent} not found")));
+    }
+    let delimiter = root.and_then(|entry| entry.delimiter);
+    let raw_path = child_path(parent, name, delimiter.as_deref())?;
+
+    let special_use = special_use
+        .map(|s| {
+            let attr = format!("\\{}", s.trim_start_matches('\\'));
+            const ALLOWED: [&str; 7] = ["\\All", "\\Archive", "\\Drafts", "\\Flagged", "\\Junk", "\\Sent", "\\Trash"];
+            ALLOWED
+                .iter()
+                .find(|a| a.eq_ignore_ascii_case(&attr))
+                .copied()
+                .ok_or_else(|| other_error(format!("Unsupported special-use attribute {s:?}")))
+        })
+        .transpose()?;
+
+    match special_use {
+        Some(attr) if caps.create_special_use => {
+            let command = format!("CREATE {} (USE ({attr}))", imap_string(&raw_path, false));
+            let (_, status) = run_raw_command(session, &command, IMAP_CMD_TIMEOUT).await?;
+            if !status.starts_with("OK") {
+                return Err(raw_status_failed(format_args!("CREATE {raw_path}"), &status, ErrorKind::Other));
+            }
+        }
+        _ => {
+            if let Some(attr) = special_use {
+                log::warn!("IMAP server lacks CREATE-SPECIAL-USE; creating {raw_path} without {attr}");
+            }
+            tokio::time::timeout(IMAP_CMD_TIMEOUT, session.create(&raw_path))
+                .await
+                .map_err(|_| timed_out("CREATE", IMAP_CMD_TIMEOUT))?
+                .map_err(|e| failed(format!("CREATE {raw_path}"), e))?;
+        }
+    }
+
+    log::info!("IMAP created folder {raw_path}");
+    get_folder(session, caps, &raw_path).await
+}
+
+/// Rename a folder in place (same parent) to `new_name` (UTF-8).
+pub async fn rename_folder(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    raw_path: &str,
+    new_name: &str,
+) -> Result<ImapFolder, MailError> {
+    let current = list_one(session, raw_path)
+        .await?
+        .ok_or_else(|| MailError::new(ErrorKind::FolderNotFound, format!("Folder {raw_path} not found")))?;
+    let delimiter = current.delimiter;
+    let parent = delimiter
+        .as_deref()
+        .and_then(|d| raw_path.rsplit_once(d))
+        .map(|(parent, _)| parent);
+    let new_path = child_path(parent, new_name, delimiter.as_deref())?;
+
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.rename(raw_path, &new_path))
+        .await
+        .map_err(|_| timed_out("RENAME", IMAP_CMD_TIMEOUT))?
+        .map_err(|e| failed(format!("RENAME {raw_path} to {new_path}"), e))?;
+
+    log::info!("IMAP renamed folder {raw_path} to {new_path}");
+    get_folder(session, caps, &new_path).await
+}
+
+/// Delete a folder. Servers refuse to delete INBOX and may refuse folders
+/// that still have children.
+pub async fn delete_folder(session: &mut ImapSession, raw_path: &str) -> Result<(), MailError> {
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.delete(raw_path))
+        .await
+        .map_err(|_| timed_out("DELETE", IMAP_CMD_TIMEOUT))?
+        .map_err(|e| failed(format!("DELETE {raw_path}"), e))?;
+
+    log::info!("IMAP deleted folder {raw_path}");
+    Ok(())
+}
+
+/// Add a folder to, or remove it from, the subscribed set (LSUB).
+pub async fn set_subscribed(session: &mut ImapSession, raw_path: &str, subscribed: bool) -> Result<(), MailError> {
+    let command = if subscribed { "SUBSCRIBE" } else { "UNSUBSCRIBE" };
+    let result = if subscribed {
+        tokio::time::timeout(IMAP_CMD_TIMEOUT, session.subscribe(raw_path)).await
+    } else {
+        tokio::time::timeout(IMAP_CMD_TIMEOUT, session.unsubscribe(raw_path)).await
+    };
+    result
+        .map_err(|_| timed_out(command, IMAP_CMD_TIMEOUT))?
+        .map_err(|e| failed(format!("{command} {raw_path}"), e))
+}
+
+/// What `fetch_messages` got back.
+pub enum Fetched {
+    Messages(ImapFetchResult),
+    /// The folder has messages but async-imap returned none of them. The
+    /// session is intact, so `raw_fetch_messages` can try on it.
+    Empty,
+    /// async-imap's parser failed on a FETCH response. Its reader keeps the
+    /// data it couldn't parse and fails on it again, so the session is
+    /// unusable; `raw_fetch_messages` needs another one.
+    Unparsable,
+}
+
 /// Fetch messages from a folder by UID range (e.g. "1:100" or "500:*").
+///
+/// With `headers_only`, only ENVELOPE/BODYSTRUCTURE and a few headers are
+/// downloaded and the returned messages have `body_loaded: false`.
 pub async fn fetch_messages(
     session: &mut ImapSession,
+    caps: &ImapCapabilities,
     folder: &str,
     uid_range: &str,
-) -> Result<ImapFetchResult, String> {
+    headers_only: bool,
+) -> Result<Fetched, MailError> {
     let mailbox = tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
         .await
-        .map_err(|_| format!("SELECT {folder} timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("SELECT {folder} failed: {e}"))?;
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
 
     let folder_status = ImapFolderStatus {
         uidvalidity: mailbox.uid_validity.unwrap_or(0),
@@ -253,35 +786,43 @@ pub async fn fetch_messages(
         mailbox.uid_next.unwrap_or(0),
     );
 
-    // Try UID FETCH first; if the stream is empty, fall back to sequence-number FETCH.
-    // Some IMAP servers return empty streams for UID FETCH despite valid UIDs.
+    let items = if headers_only { HEADER_FETCH_ITEMS } else { FULL_FETCH_ITEMS };
+
+    // A parse error repeats for as long as the stream is polled, so stop at
+    // the first one.
     let fetches = tokio::time::timeout(IMAP_FETCH_TIMEOUT, async {
-        let stream = session
-            .uid_fetch(uid_range, "UID FLAGS INTERNALDATE BODY.PEEK[]")
+        let mut stream = session
+            .uid_fetch(uid_range, items)
             .await
-            .map_err(|e| format!("UID FETCH {folder} uids={uid_range} failed: {e}"))?;
-        Ok::<_, String>(stream.collect::<Vec<_>>().await)
+            .map_err(|e| failed(format!("UID FETCH {folder} uids={uid_range}"), e))?;
+        let mut fetches = Vec::new();
+        while let Some(fetch) = stream.next().await {
+            match fetch {
+                Ok(fetch) => fetches.push(fetch),
+                // async-imap's reader reports responses it can't parse as
+                // `ErrorKind::Other`; the socket uses specific kinds.
+                Err(async_imap::error::Error::Io(e)) if e.kind() == std::io::ErrorKind::Other => {
+                    log::warn!("IMAP fetch stream error in {folder}: {e}");
+                    return Ok(None);
+                }
+                Err(e) => return Err(failed(format!("UID FETCH {folder} uids={uid_range}"), e)),
+            }
+        }
+        Ok::<_, MailError>(Some(fetches))
     })
     .await
-    .map_err(|_| format!("UID FETCH {folder} timed out after {}s — check your server settings or network connection", IMAP_FETCH_TIMEOUT.as_secs()))?;
+    .map_err(|_| timed_out(format!("UID FETCH {folder}"), IMAP_FETCH_TIMEOUT))??;
 
-    let raw_fetches: Vec<_> = fetches?;
-    let mut fetch_ok = 0u32;
-    let mut fetch_err = 0u32;
-    let mut fetches = Vec::new();
-    for r in raw_fetches {
-        match r {
-            Ok(f) => { fetch_ok += 1; fetches.push(f); }
-            Err(e) => { fetch_err += 1; log::warn!("IMAP fetch stream error in {folder}: {e}"); }
-        }
-    }
-    log::info!("IMAP FETCH {folder}: {fetch_ok} ok, {fetch_err} errors from uid_fetch");
+    let Some(fetches) = fetches else {
+        log::warn!("IMAP {folder}: async-imap can't parse the FETCH responses. Falling back to raw fetch...");
+        return Ok(Fetched::Unparsable);
+    };
+    log::info!("IMAP FETCH {folder}: {} responses from uid_fetch", fetches.len());
 
-    // If async-imap returned nothing but messages exist, fallback to raw TCP fetch
+    // If async-imap returned nothing but messages exist, fallback to raw fetch
     if fetches.is_empty() && mailbox.exists > 0 {
-        log::warn!("IMAP {folder}: async-imap returned 0 items but exists={}. Falling back to raw TCP fetch...", mailbox.exists);
-        // Return early with raw fetch result — caller doesn't need to know about the fallback
-        return Err(format!("ASYNC_IMAP_EMPTY:{folder}"));
+        log::warn!("IMAP {folder}: async-imap returned 0 items but exists={}. Falling back to raw fetch...", mailbox.exists);
+        return Ok(Fetched::Empty);
     }
 
     let parser = MessageParser::default();
@@ -292,6 +833,14 @@ pub async fn fetch_messages(
             None => { log::warn!("IMAP FETCH {folder}: response missing UID"); continue; }
         };
 
+        if headers_only {
+            match parse_header_fetch(&parser, fetch, uid, folder) {
+                Ok(msg) => messages.push(msg),
+                Err(e) => log::warn!("Failed to parse headers of UID {uid}: {e}"),
+            }
+            continue;
+        }
+
         let raw = match fetch.body() {
             Some(b) => b,
             None => { log::warn!("IMAP FETCH {folder}: UID {uid} has no body"); continue; }
@@ -300,49 +849,48 @@ pub async fn fetch_messages(
         let raw_size = raw.len() as u32;
 
         // Parse flags
-        let flags: Vec<_> = fetch.flags().collect();
-        let is_read = flags.iter().any(|f| matches!(f, Flag::Seen));
-        let is_starred = flags.iter().any(|f| matches!(f, Flag::Flagged));
-        let is_draft = flags.iter().any(|f| matches!(f, Flag::Draft));
+        let flags = MessageFlags::from_flags(fetch.flags());
 
         // Extract INTERNALDATE as fallback for messages with unparseable Date headers
         let internal_date = fetch.internal_date().map(|dt| dt.timestamp());
 
-        match parse_message(&parser, raw, uid, folder, raw_size, is_read, is_starred, is_draft, internal_date) {
+        match parse_message(&parser, raw, uid, folder, raw_size, flags, internal_date) {
             Ok(msg) => messages.push(msg),
             Err(e) => {
                 log::warn!("Failed to parse message UID {uid}: {e}");
             }
         }
     }
+    attach_gmail_attributes(session, caps, &mut messages).await?;
 
-    Ok(ImapFetchResult {
+    Ok(Fetched::Messages(ImapFetchResult {
         messages,
         folder_status,
-    })
+    }))
 }
 
 /// Fetch a single message body by UID.
 pub async fn fetch_message_body(
     session: &mut ImapSession,
+    caps: &ImapCapabilities,
     folder: &str,
     uid: u32,
-) -> Result<ImapMessage, String> {
+) -> Result<ImapMessage, MailError> {
     tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
         .await
-        .map_err(|_| format!("SELECT {folder} timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("SELECT {folder} failed: {e}"))?;
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
 
     let uid_str = uid.to_string();
     let fetches: Vec<_> = tokio::time::timeout(IMAP_FETCH_TIMEOUT, async {
         let stream = session
             .uid_fetch(&uid_str, "UID FLAGS BODY.PEEK[]")
             .await
-            .map_err(|e| format!("UID FETCH failed: {e}"))?;
-        Ok::<_, String>(stream.collect::<Vec<_>>().await)
+            .map_err(|e| failed("UID FETCH", e))?;
+        Ok::<_, MailError>(stream.collect::<Vec<_>>().await)
     })
     .await
-    .map_err(|_| format!("UID FETCH for UID {uid} timed out after {}s — check your server settings or network connection", IMAP_FETCH_TIMEOUT.as_secs()))?
+    .map_err(|_| timed_out(format!("UID FETCH for UID {uid}"), IMAP_FETCH_TIMEOUT))?
     ?
     .into_iter()
     .filter_map(|r| r.ok())
@@ -350,20 +898,119 @@ pub async fn fetch_message_body(
 
     let fetch = fetches
         .first()
-        .ok_or_else(|| format!("Message UID {uid} not found in {folder}"))?;
+        .ok_or_else(|| other_error(format!("Message UID {uid} not found in {folder}")))?;
 
     let raw = fetch
         .body()
-        .ok_or_else(|| format!("No body for UID {uid}"))?;
+        .ok_or_else(|| other_error(format!("No body for UID {uid}")))?;
 
     let raw_size = raw.len() as u32;
-    let flags: Vec<_> = fetch.flags().collect();
-    let is_read = flags.iter().any(|f| matches!(f, Flag::Seen));
-    let is_starred = flags.iter().any(|f| matches!(f, Flag::Flagged));
-    let is_draft = flags.iter().any(|f| matches!(f, Flag::Draft));
+    let flags = MessageFlags::from_flags(fetch.flags());
+
+    let parser = MessageParser::default();
+    let mut message = parse_message(&parser, raw, uid, folder, raw_size, flags, None)?;
+    attach_gmail_attributes(session, caps, std::slice::from_mut(&mut message)).await?;
+    Ok(message)
+}
+
+/// Download full bodies for messages previously synced header-only.
+///
+/// UIDs are taken in the given order until their combined RFC822.SIZE would
+/// exceed `max_bytes`; the rest come back in `remaining_uids` for a later pass.
+/// The first UID is always taken, so a message larger than `max_bytes` on its
+/// own still downloads.
+pub async fn fetch_message_bodies(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    folder: &str,
+    uids: &[u32],
+    max_bytes: u64,
+) -> Result<ImapBodyFetchResult, MailError> {
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
+        .await
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
+
+    let uid_set = uids.iter().map(|u| u.to_string()).collect::<Vec<_>>().join(",");
+    let sizes: std::collections::HashMap<u32, u64> = tokio::time::timeout(IMAP_FETCH_TIMEOUT, async {
+        let stream = session
+            .uid_fetch(&uid_set, "(UID RFC822.SIZE)")
+            .await
+            .map_err(|e| failed(format!("UID FETCH RFC822.SIZE {folder}"), e))?;
+        Ok::<_, MailError>(stream.collect::<Vec<_>>().await)
+    })
+    .await
+    .map_err(|_| timed_out(format!("UID FETCH RFC822.SIZE {folder}"), IMAP_FETCH_TIMEOUT))??
+    .into_iter()
+    .filter_map(|r| r.ok())
+    .filter_map(|f| Some((f.uid?, u64::from(f.size?))))
+    .collect();
+
+    for uid in uids.iter().filter(|uid| !sizes.contains_key(uid)) {
+        log::warn!("fetch_message_bodies: UID {uid} not found in {folder}");
+    }
+    let (selected, remaining_uids, budget_used) = take_within_budget(uids, &sizes, max_bytes);
+
+    log::info!(
+        "IMAP fetch_message_bodies {folder}: {} bodies ({budget_used} bytes), {} deferred",
+        selected.len(),
+        remaining_uids.len(),
+    );
 
     let parser = MessageParser::default();
-    parse_message(&parser, raw, uid, folder, raw_size, is_read, is_starred, is_draft, None)
+    let mut messages = Vec::with_capacity(selected.len());
+    for chunk in selected.chunks(BODY_FETCH_BATCH) {
+        let chunk_set = chunk.iter().map(|u| u.to_string()).collect::<Vec<_>>().join(",");
+        let fetches = tokio::time::timeout(IMAP_FETCH_TIMEOUT, async {
+            let stream = session
+                .uid_fetch(&chunk_set, FULL_FETCH_ITEMS)
+                .await
+                .map_err(|e| failed(format!("UID FETCH {folder} uids={chunk_set}"), e))?;
+            Ok::<_, MailError>(stream.collect::<Vec<_>>().await)
+        })
+        .await
+        .map_err(|_| timed_out(format!("UID FETCH {folder}"), IMAP_FETCH_TIMEOUT))??;
+
+        for fetch in fetches.into_iter().filter_map(|r| r.ok()) {
+            let (Some(uid), Some(raw)) = (fetch.uid, fetch.body()) else { continue };
+            let flags = MessageFlags::from_flags(fetch.flags());
+            let internal_date = fetch.internal_date().map(|dt| dt.timestamp());
+
+            match parse_message(&parser, raw, uid, folder, raw.len() as u32, flags, internal_date) {
+                Ok(msg) => messages.push(msg),
+                Err(e) => log::warn!("fetch_message_bodies: failed to parse UID {uid}: {e}"),
+            }
+        }
+    }
+    attach_gmail_attributes(session, caps, &mut messages).await?;
+
+    Ok(ImapBodyFetchResult {
+        messages,
+        remaining_uids,
+    })
+}
+
+/// Split `uids` into those whose sizes fit in `max_bytes`, in order, and the
+/// rest. The first UID is taken even if it alone exceeds the budget. UIDs
+/// without a size are dropped.
+fn take_within_budget(
+    uids: &[u32],
+    sizes: &std::collections::HashMap<u32, u64>,
+    max_bytes: u64,
+) -> (Vec<u32>, Vec<u32>, u64) {
+    let mut selected = Vec::new();
+    let mut remaining = Vec::new();
+    let mut budget_used = 0u64;
+    for &uid in uids {
+        let Some(&size) = sizes.get(&uid) else { continue };
+        if selected.is_empty() || budget_used + size <= max_bytes {
+            budget_used += size;
+            selected.push(uid);
+        } else {
+            remaining.push(uid);
+        }
+    }
+    (selected, remaining, budget_used)
 }
 
 /// Get UIDs of messages newer than `last_uid`.
@@ -371,17 +1018,17 @@ pub async fn fetch_new_uids(
     session: &mut ImapSession,
     folder: &str,
     last_uid: u32,
-) -> Result<Vec<u32>, String> {
+) -> Result<Vec<u32>, MailError> {
     tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
         .await
-        .map_err(|_| format!("SELECT {folder} timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("SELECT {folder} failed: {e}"))?;
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
 
     let query = format!("{}:*", last_uid + 1);
     let uids = tokio::time::timeout(IMAP_SEARCH_TIMEOUT, session.uid_search(&query))
         .await
-        .map_err(|_| format!("UID SEARCH timed out after {}s — check your server settings or network connection", IMAP_SEARCH_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("UID SEARCH failed: {e}"))?;
+        .map_err(|_| timed_out("UID SEARCH", IMAP_SEARCH_TIMEOUT))?
+        .map_err(|e| failed("UID SEARCH", e))?;
 
     // Filter out last_uid itself (IMAP returns it if it's the highest UID)
     let mut result: Vec<u32> = uids.into_iter().filter(|&u| u > last_uid).collect();
@@ -394,200 +1041,1191 @@ pub async fn fetch_new_uids(
 pub async fn search_all_uids(
     session: &mut ImapSession,
     folder: &str,
-) -> Result<Vec<u32>, String> {
+) -> Result<Vec<u32>, MailError> {
     tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
         .await
-        .map_err(|_| format!("SELECT {folder} timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("SELECT {folder} failed: {e}"))?;
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
 
     let uids = tokio::time::timeout(IMAP_SEARCH_TIMEOUT, session.uid_search("ALL"))
         .await
-        .map_err(|_| format!("UID SEARCH ALL timed out after {}s — check your server settings or network connection", IMAP_SEARCH_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("UID SEARCH ALL failed: {e}"))?;
+        .map_err(|_| timed_out("UID SEARCH ALL", IMAP_SEARCH_TIMEOUT))?
+        .map_err(|e| failed("UID SEARCH ALL", e))?;
 
     let mut result: Vec<u32> = uids.into_iter().collect();
     result.sort();
     Ok(result)
 }
 
-/// Set or remove flags on messages.
+/// Server-side `UID SEARCH` with structured criteria, so mail outside the
+/// locally synced window can be found.
 ///
-/// `flag_op`: "+FLAGS" to add, "-FLAGS" to remove
-/// `flags`: e.g. "(\\Seen)" or "(\\Flagged)"
-pub async fn set_flags(
+/// Non-ASCII strings are sent as literals with `CHARSET UTF-8`; with ESEARCH
+/// (RFC 4731) the matches come back as a compact UID set.
+pub async fn search(
     session: &mut ImapSession,
+    caps: &ImapCapabilities,
     folder: &str,
-    uid_set: &str,
-    flag_op: &str,
-    flags: &str,
-) -> Result<(), String> {
-    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
-        .await
-        .map_err(|_| format!("SELECT {folder} timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("SELECT {folder} failed: {e}"))?;
+    query: &ImapSearchQuery,
+) -> Result<Vec<u32>, MailError> {
+    if query.gmail_raw.is_some() && !caps.gmail {
+        return Err(other_error("X-GM-RAW search needs a Gmail server (X-GM-EXT-1)"));
+    }
 
-    let query = format!("{flag_op} {flags}");
-    tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
-        let stream = session
-            .uid_store(uid_set, &query)
-            .await
-            .map_err(|e| format!("UID STORE failed: {e}"))?;
-        let _: Vec<_> = stream.collect().await;
-        Ok::<_, String>(())
-    })
-    .await
-    .map_err(|_| format!("UID STORE timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
+    let (criteria, utf8) = build_search_criteria(query, caps.literal_plus)?;
+    let mut command = "UID SEARCH".to_string();
+    if caps.esearch {
+        command.push_str(" RETURN (ALL)");
+    }
+    if utf8 {
+        command.push_str(" CHARSET UTF-8");
+    }
+    command.push(' ');
+    command.push_str(&criteria);
+
+    // ESEARCH responses have no imap-proto grammar, so go through the raw path.
+    let responses = run_select_raw(session, folder, &command, "UID SEARCH").await?;
+
+    let mut uids = Vec::new();
+    for response in &responses {
+        let Some(data) = response.text.strip_prefix("* ") else { continue };
+        let upper = data.to_ascii_uppercase();
+        if upper.starts_with("SEARCH") {
+            uids.extend(data[6..].split_whitespace().filter_map(|n| n.parse::<u32>().ok()));
+        } else if upper.starts_with("ESEARCH") {
+            // * ESEARCH (TAG "V1") UID ALL 4:7,12 — no ALL means no matches.
+            let mut tokens = data.split_whitespace();
+            if tokens.by_ref().any(|t| t.eq_ignore_ascii_case("ALL")) {
+                if let Some(set) = tokens.next() {
+                    uids.extend(expand_uid_set(set)?);
+                }
+            }
+        }
+    }
+
+    uids.sort_unstable();
+    uids.dedup();
+    log::info!("IMAP search {folder}: {} matches", uids.len());
+    Ok(uids)
 }
 
-/// Move messages between folders.
-///
-/// Tries MOVE first; falls back to COPY + flag Deleted + EXPUNGE.
-pub async fn move_messages(
+/// Server-side `UID SORT` (RFC 5256): UIDs matching `query`, in the order of
+/// `keys` (newest arrival first when empty), so big folders can be paged
+/// in server order.
+pub async fn sort(
     session: &mut ImapSession,
-    source_folder: &str,
-    uid_set: &str,
-    dest_folder: &str,
-) -> Result<(), String> {
-    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(source_folder))
-        .await
-        .map_err(|_| format!("SELECT {source_folder} timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("SELECT {source_folder} failed: {e}"))?;
+    caps: &ImapCapabilities,
+    folder: &str,
+    keys: &[ImapSortKey],
+    query: &ImapSearchQuery,
+) -> Result<Vec<u32>, MailError> {
+    if !caps.sort {
+        return Err(other_error("Server does not support SORT"));
+    }
+    let program = build_sort_program(keys)?;
+    let (criteria, _) = build_search_criteria(query, caps.literal_plus)?;
+    let command = format!("UID SORT ({program}) UTF-8 {criteria}");
 
-    // Try MOVE extension first
-    match tokio::time::timeout(IMAP_CMD_TIMEOUT, session.uid_mv(uid_set, dest_folder)).await {
-        Ok(Ok(())) => return Ok(()),
-        _ => {
-            // Fallback: COPY, then mark Deleted, then EXPUNGE
-            tokio::time::timeout(IMAP_CMD_TIMEOUT, session.uid_copy(uid_set, dest_folder))
-                .await
-                .map_err(|_| format!("UID COPY timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-                .map_err(|e| format!("UID COPY failed: {e}"))?;
+    let responses = run_select_raw(session, folder, &command, "UID SORT").await?;
+    let uids: Vec<u32> = responses
+        .iter()
+        .filter_map(|r| strip_keyword(&r.text, "SORT"))
+        .flat_map(|data| data.split_whitespace().filter_map(|n| n.parse::<u32>().ok()))
+        .collect();
 
-            tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
-                let store_stream = session
-                    .uid_store(uid_set, "+FLAGS (\\Deleted)")
-                    .await
-                    .map_err(|e| format!("UID STORE +Deleted failed: {e}"))?;
-                let _: Vec<_> = store_stream.collect().await;
-                Ok::<_, String>(())
-            })
+    log::info!("IMAP sort {folder} ({program}): {} UIDs", uids.len());
+    Ok(uids)
+}
+
+/// Server-side `UID THREAD` (RFC 5256) over the messages matching `query`.
+///
+/// `algorithm` defaults to REFERENCES, falling back to whatever the server
+/// offers. Threads come back as trees of UIDs, including messages that were
+/// never synced locally.
+pub async fn thread(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    folder: &str,
+    algorithm: Option<&str>,
+    query: &ImapSearchQuery,
+) -> Result<Vec<ImapThreadNode>, MailError> {
+    let algorithm = match algorithm {
+        Some(a) if caps.thread.iter().any(|t| t.eq_ignore_ascii_case(a)) => a.to_ascii_uppercase(),
+        Some(a) => return Err(other_error(format!("Server does not support THREAD={a}"))),
+        None if caps.thread.iter().any(|t| t == "REFERENCES") => "REFERENCES".to_string(),
+        None => caps
+            .thread
+            .first()
+            .cloned()
+            .ok_or_else(|| other_error("Server does not support THREAD"))?,
+    };
+    let (criteria, _) = build_search_criteria(query, caps.literal_plus)?;
+    let command = format!("UID THREAD {algorithm} UTF-8 {criteria}");
+
+    let responses = run_select_raw(session, folder, &command, "UID THREAD").await?;
+    let mut threads = Vec::new();
+    for data in responses.iter().filter_map(|r| strip_keyword(&r.text, "THREAD")) {
+        threads.extend(parse_thread_list(data)?);
+    }
+
+    log::info!("IMAP thread {folder} ({algorithm}): {} threads", threads.len());
+    Ok(threads)
+}
+
+/// SELECT `folder`, then run `command` through the raw path, failing unless
+/// it completes with OK. For responses imap-proto can't parse.
+async fn run_select_raw(
+    session: &mut ImapSession,
+    folder: &str,
+    command: &str,
+    name: &str,
+) -> Result<Vec<RawUntagged>, MailError> {
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
+        .await
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
+
+    // Leftovers from SELECT would otherwise end up among the raw responses.
+    while session.unsolicited_responses.try_recv().is_ok() {}
+
+    let (responses, status) = run_raw_command(session, command, IMAP_SEARCH_TIMEOUT).await?;
+    if !status.starts_with("OK") {
+        return Err(raw_status_failed(format_args!("{name} {folder}"), &status, ErrorKind::Other));
+    }
+    Ok(responses)
+}
+
+/// The data of an untagged `* <keyword> ...` response, or `None` for other
+/// responses.
+fn strip_keyword<'a>(text: &'a str, keyword: &str) -> Option<&'a str> {
+    let data = text.strip_prefix("* ")?;
+    let head = data.get(..keyword.len())?;
+    let rest = &data[keyword.len()..];
+    (head.eq_ignore_ascii_case(keyword) && (rest.is_empty() || rest.starts_with(' '))).then_some(rest)
+}
+
+/// Sort keys as a SORT program, e.g. `REVERSE ARRIVAL SUBJECT`.
+fn build_sort_program(keys: &[ImapSortKey]) -> Result<String, MailError> {
+    const SORT_KEYS: [&str; 9] = ["ARRIVAL", "CC", "DATE", "FROM", "SIZE", "SUBJECT", "TO", "DISPLAYFROM", "DISPLAYTO"];
+
+    if keys.is_empty() {
+        return Ok("REVERSE ARRIVAL".to_string());
+    }
+    let mut program = Vec::new();
+    for key in keys {
+        let name = SORT_KEYS
+            .iter()
+            .find(|k| k.eq_ignore_ascii_case(&key.key))
+            .ok_or_else(|| other_error(format!("Unknown sort key {:?}", key.key)))?;
+        if key.reverse {
+            program.push("REVERSE");
+        }
+        program.push(name);
+    }
+    Ok(program.join(" "))
+}
+
+/// Parse the data of a THREAD response: `(2)(3 6 (4 23)(44 7 96))`.
+fn parse_thread_list(data: &str) -> Result<Vec<ImapThreadNode>, MailError> {
+    let bytes = data.as_bytes();
+    let mut pos = 0;
+    let mut threads = Vec::new();
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b' ' => pos += 1,
+            b'(' => threads.push(parse_thread(bytes, &mut pos)?),
+            _ => return Err(parse_error(format!("Malformed THREAD response at {pos}: {data:?}"))),
+        }
+    }
+    Ok(threads)
+}
+
+/// Parse one parenthesized thread starting at `bytes[*pos] == b'('`. A run
+/// of UIDs is a parent-child chain; the sub-threads that follow are the
+/// replies to its last message.
+fn parse_thread(bytes: &[u8], pos: &mut usize) -> Result<ImapThreadNode, MailError> {
+    let malformed = |pos: usize| parse_error(format!("Malformed THREAD response at {pos}"));
+
+    *pos += 1;
+    let mut chain = Vec::new();
+    let mut branches = Vec::new();
+    loop {
+        match bytes.get(*pos) {
+            Some(b' ') => *pos += 1,
+            Some(b'(') => branches.push(parse_thread(bytes, pos)?),
+            Some(b')') => {
+                *pos += 1;
+                break;
+            }
+            Some(c) if c.is_ascii_digit() && branches.is_empty() => {
+                let start = *pos;
+                while bytes.get(*pos).is_some_and(u8::is_ascii_digit) {
+                    *pos += 1;
+                }
+                let uid = std::str::from_utf8(&bytes[start..*pos])
+                    .ok()
+                    .and_then(|n| n.parse::<u32>().ok())
+                    .ok_or_else(|| malformed(start))?;
+                chain.push(uid);
+            }
+            _ => return Err(malformed(*pos)),
+        }
+    }
+
+    let mut chain = chain.into_iter().rev();
+    let Some(last) = chain.next() else {
+        return Ok(ImapThreadNode { uid: None, children: branches });
+    };
+    let mut node = ImapThreadNode { uid: Some(last), children: branches };
+    for uid in chain {
+        node = ImapThreadNode { uid: Some(uid), children: vec![node] };
+    }
+    Ok(node)
+}
+
+/// Translate a structured query into IMAP SEARCH criteria. Returns the
+/// criteria and whether any string needs `CHARSET UTF-8`.
+fn build_search_criteria(query: &ImapSearchQuery, literal_plus: bool) -> Result<(String, bool), MailError> {
+    let mut criteria = Vec::new();
+    let mut utf8 = false;
+
+    let mut string = |key: &str, value: &Option<String>, criteria: &mut Vec<String>| {
+        if let Some(value) = value.as_deref().filter(|v| !v.is_empty()) {
+            utf8 |= !value.is_ascii();
+            criteria.push(format!("{key} {}", imap_string(value, literal_plus)));
+        }
+    };
+    string("FROM", &query.from, &mut criteria);
+    string("TO", &query.to, &mut criteria);
+    string("SUBJECT", &query.subject, &mut criteria);
+    string("BODY", &query.body, &mut criteria);
+    string("X-GM-RAW", &query.gmail_raw, &mut criteria);
+
+    if let Some(since) = query.since {
+        criteria.push(format!("SINCE {}", format_imap_date(since)));
+    }
+    if let Some(before) = query.before {
+        criteria.push(format!("BEFORE {}", format_imap_date(before)));
+    }
+
+    let flags = [
+        (query.is_read, "SEEN", "UNSEEN"),
+        (query.is_starred, "FLAGGED", "UNFLAGGED"),
+        (query.is_answered, "ANSWERED", "UNANSWERED"),
+        (query.is_draft, "DRAFT", "UNDRAFT"),
+    ];
+    for (value, set, unset) in flags {
+        match value {
+            Some(true) => criteria.push(set.to_string()),
+            Some(false) => criteria.push(unset.to_string()),
+            None => {}
+        }
+    }
+
+    if let Some(larger) = query.larger {
+        criteria.push(format!("LARGER {larger}"));
+    }
+    if let Some(smaller) = query.smaller {
+        criteria.push(format!("SMALLER {smaller}"));
+    }
+
+    for (key, keywords) in [("KEYWORD", &query.keywords), ("UNKEYWORD", &query.not_keywords)] {
+        for keyword in keywords {
+            if !is_atom(keyword) {
+                return Err(other_error(format!("Invalid keyword {keyword:?}")));
+            }
+            criteria.push(format!("{key} {keyword}"));
+        }
+    }
+
+    if criteria.is_empty() {
+        criteria.push("ALL".to_string());
+    }
+    Ok((criteria.join(" "), utf8))
+}
+
+/// Quote a string argument, or send it as a literal when it can't be quoted
+/// (non-ASCII or control characters).
+fn imap_string(value: &str, literal_plus: bool) -> String {
+    if value.is_ascii() && !value.chars().any(|c| c.is_ascii_control()) {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        let plus = if literal_plus { "+" } else { "" };
+        format!("{{{}{plus}}}\r\n{value}", value.len())
+    }
+}
+
+/// Format a unix timestamp as an IMAP date (`7-Feb-2024`), in UTC.
+fn format_imap_date(timestamp: i64) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm).
+    let days = timestamp.div_euclid(86_400);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{day}-{}-{year}", MONTHS[(month - 1) as usize])
+}
+
+/// Expand a UID set like `4:7,12` into individual UIDs.
+fn expand_uid_set(set: &str) -> Result<Vec<u32>, MailError> {
+    let mut uids = Vec::new();
+    for part in set.split(',') {
+        let invalid = || parse_error(format!("Invalid UID set {set:?}"));
+        match part.split_once(':') {
+            Some((a, b)) => {
+                let a: u32 = a.parse().map_err(|_| invalid())?;
+                let b: u32 = b.parse().map_err(|_| invalid())?;
+                uids.extend(a.min(b)..=a.max(b));
+            }
+            None => uids.push(part.parse().map_err(|_| invalid())?),
+        }
+    }
+    Ok(uids)
+}
+
+/// Set or remove flags on messages.
+///
+/// `flags` may be system flags with or without the backslash (`Seen`,
+/// `\Flagged`) or keywords (`$Forwarded`, `$Junk`, `Work`). Adding a keyword
+/// the folder's PERMANENTFLAGS don't allow is an error rather than a change
+/// that silently disappears at the end of the session.
+pub async fn set_flags(
+    session: &mut ImapSession,
+    folder: &str,
+    uid_set: &str,
+    add: bool,
+    flags: &[String],
+) -> Result<(), MailError> {
+    let flags = flags
+        .iter()
+        .map(|f| normalize_flag(f))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if add {
+        // async-imap reports a missing PERMANENTFLAGS the same as an empty
+        // one, so SELECT directly to tell them apart.
+        let command = format!("SELECT {}", imap_string(folder, false));
+        let (responses, status) = run_raw_command(session, &command, IMAP_CMD_TIMEOUT).await?;
+        if !status.starts_with("OK") {
+            return Err(raw_status_failed(format_args!("SELECT {folder}"), &status, ErrorKind::FolderNotFound));
+        }
+        let permanent = responses
+            .iter()
+            .find_map(|r| r.text.strip_prefix("* ").and_then(parse_permanent_flags));
+        let rejected: Vec<&str> = flags
+            .iter()
+            .filter(|f| !flag_permitted(f, permanent.as_deref()))
+            .map(String::as_str)
+            .collect();
+        if !rejected.is_empty() {
+            return Err(other_error(format!("{folder} does not allow storing {}", rejected.join(" "))));
+        }
+    } else {
+        tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
             .await
-            .map_err(|_| format!("UID STORE +Deleted timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))??;
+            .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+            .map_err(|e| select_failed(folder, e))?;
+    }
 
-            tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
-                let expunge_stream = session
-                    .expunge()
-                    .await
-                    .map_err(|e| format!("EXPUNGE failed: {e}"))?;
-                let _: Vec<_> = expunge_stream.collect().await;
-                Ok::<_, String>(())
-            })
+    let flag_op = if add { "+FLAGS" } else { "-FLAGS" };
+    let query = format!("{flag_op} ({})", flags.join(" "));
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
+        let stream = session
+            .uid_store(uid_set, &query)
             .await
-            .map_err(|_| format!("EXPUNGE timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))??;
+            .map_err(|e| failed("UID STORE", e))?;
+        let _: Vec<_> = stream.collect().await;
+        Ok::<_, MailError>(())
+    })
+    .await
+    .map_err(|_| timed_out("UID STORE", IMAP_CMD_TIMEOUT))?
+}
+
+/// System flags that can be stored, without the leading backslash.
+const SYSTEM_FLAGS: [&str; 5] = ["Seen", "Answered", "Flagged", "Deleted", "Draft"];
+
+/// Canonicalize a flag for STORE: known system flags get their backslash,
+/// anything else must be a valid keyword atom.
+fn normalize_flag(flag: &str) -> Result<String, MailError> {
+    let bare = flag.strip_prefix('\\').unwrap_or(flag);
+    if let Some(system) = SYSTEM_FLAGS.iter().find(|s| s.eq_ignore_ascii_case(bare)) {
+        return Ok(format!("\\{system}"));
+    }
+    if flag.starts_with('\\') {
+        return Err(other_error(format!("Unknown system flag {flag:?}")));
+    }
+    if !is_atom(flag) {
+        return Err(other_error(format!("Invalid keyword {flag:?}")));
+    }
+    Ok(flag.to_string())
+}
+
+/// Whether `flag` can be stored permanently given the PERMANENTFLAGS list.
+/// `None` means the server didn't send one, so nothing is rejected; an empty
+/// list means no flag is kept (RFC 3501 §7.1).
+fn flag_permitted(flag: &str, permanent: Option<&[String]>) -> bool {
+    let Some(permanent) = permanent else { return true };
+    permanent.iter().any(|p| p.eq_ignore_ascii_case(flag))
+        || (!flag.starts_with('\\') && permanent.iter().any(|p| p == "\\*"))
+}
+
+/// The flags in a `[PERMANENTFLAGS (...)]` response code, if `text` has one.
+fn parse_permanent_flags(text: &str) -> Option<Vec<String>> {
+    let list = parse_response_code(text, "PERMANENTFLAGS")?;
+    let list = list.strip_prefix('(')?.strip_suffix(')')?;
+    Some(list.split_whitespace().map(str::to_string).collect())
+}
+
+/// Keywords are atoms: no spaces, quotes, parens, wildcards or `\`.
+fn is_atom(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"(){%*\"\\]".contains(c))
+}
+
+/// A fetched message's flags, split the way `ImapMessage` exposes them.
+#[derive(Debug, Clone, Default)]
+struct MessageFlags {
+    is_read: bool,
+    is_starred: bool,
+    is_draft: bool,
+    keywords: Vec<String>, // non-system flags, e.g. `$Forwarded` or `Work`
+}
+
+impl MessageFlags {
+    fn from_flags<'a>(flags: impl IntoIterator<Item = Flag<'a>>) -> Self {
+        let mut result = Self::default();
+        for flag in flags {
+            match flag {
+                Flag::Seen => result.is_read = true,
+                Flag::Flagged => result.is_starred = true,
+                Flag::Draft => result.is_draft = true,
+                Flag::Custom(name) if !name.starts_with('\\') => result.keywords.push(name.to_string()),
+                _ => {}
+            }
         }
+        result
     }
+}
 
+/// Add or remove Gmail labels (X-GM-EXT-1) on messages in `folder`.
+///
+/// System labels keep their backslash (`\Inbox`, `\Important`, `\Starred`);
+/// anything else is a user label, which Gmail creates on first use. Removing
+/// the label that `folder` stands for takes the messages out of it.
+pub async fn set_gmail_labels(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    folder: &str,
+    uid_set: &str,
+    add: bool,
+    labels: &[String],
+) -> Result<(), MailError> {
+    if !caps.gmail {
+        return Err(other_error("Labels need a Gmail server (X-GM-EXT-1)"));
+    }
+    let labels = labels
+        .iter()
+        .map(|l| encode_gmail_label(l))
+        .collect::<Result<Vec<_>, _>>()?;
+    if labels.is_empty() {
+        return Ok(());
+    }
+
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
+        .await
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
+
+    let op = if add { "+X-GM-LABELS.SILENT" } else { "-X-GM-LABELS.SILENT" };
+    store_silent(session, uid_set, &format!("{op} ({})", labels.join(" "))).await
+}
+
+/// Format a label for X-GM-LABELS: system labels as atoms, user labels as
+/// quoted modified UTF-7, the same encoding Gmail uses for folder names.
+fn encode_gmail_label(label: &str) -> Result<String, MailError> {
+    match label.strip_prefix('\\') {
+        Some(system) if is_atom(system) => Ok(label.to_string()),
+        Some(_) => Err(other_error(format!("Invalid Gmail label {label:?}"))),
+        None if label.is_empty() => Err(other_error("Gmail label can't be empty")),
+        None => Ok(imap_string(&utf7_imap::encode_utf7_imap(label.to_string()), false)),
+    }
+}
+
+/// Gmail's per-message labels and IDs.
+#[derive(Debug, Clone, Default)]
+struct GmailAttributes {
+    labels: Vec<String>,
+    thread_id: Option<u64>,
+    message_id: Option<u64>,
+}
+
+/// Fill in `gmail_labels`, `gmail_thread_id` and `gmail_message_id` on
+/// messages just fetched from the selected folder. Does nothing unless the
+/// server is Gmail. If the server refuses, the messages are kept without
+/// them; only a broken connection, which the next command would hit anyway,
+/// is an error.
+async fn attach_gmail_attributes(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    messages: &mut [ImapMessage],
+) -> Result<(), MailError> {
+    if !caps.gmail || messages.is_empty() {
+        return Ok(());
+    }
+    let uid_set = messages.iter().map(|m| m.uid.to_string()).collect::<Vec<_>>().join(",");
+    let mut attributes = match fetch_gmail_attributes(session, &uid_set).await {
+        Ok(attributes) => attributes,
+        Err(e) if e.server_response.is_some() => {
+            log::warn!("IMAP: no Gmail labels for UIDs {uid_set}: {e}");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    for message in messages {
+        if let Some(gmail) = attributes.remove(&message.uid) {
+            message.gmail_labels = gmail.labels;
+            message.gmail_thread_id = gmail.thread_id.map(|id| id.to_string());
+            message.gmail_message_id = gmail.message_id.map(|id| id.to_string());
+        }
+    }
     Ok(())
 }
 
-/// Flag messages as deleted and expunge them.
-pub async fn delete_messages(
+/// `UID FETCH (X-GM-LABELS X-GM-THRID X-GM-MSGID)`. imap-proto parses these
+/// but async-imap's `Fetch` doesn't expose them, so the responses are read
+/// directly.
+async fn fetch_gmail_attributes(session: &mut ImapSession, uid_set: &str) -> Result<HashMap<u32, GmailAttributes>, MailError> {
+    let command = format!("UID FETCH {uid_set} (UID X-GM-LABELS X-GM-THRID X-GM-MSGID)");
+    let tag = tokio::time::timeout(IMAP_FETCH_TIMEOUT, session.run_command(&command))
+        .await
+        .map_err(|_| timed_out("UID FETCH X-GM-LABELS", IMAP_FETCH_TIMEOUT))?
+        .map_err(|e| failed("UID FETCH X-GM-LABELS", e))?;
+
+    let mut attributes = HashMap::new();
+    loop {
+        let response = tokio::time::timeout(IMAP_FETCH_TIMEOUT, session.read_response())
+            .await
+            .map_err(|_| timed_out("UID FETCH X-GM-LABELS", IMAP_FETCH_TIMEOUT))?
+            .ok_or_else(|| closed_error("UID FETCH X-GM-LABELS: connection closed"))?
+            .map_err(|e| MailError::io(&e, format!("UID FETCH X-GM-LABELS failed: {e}")))?;
+
+        match response.parsed() {
+            Response::Fetch(_, attrs) => {
+                let mut uid = None;
+                let mut gmail = GmailAttributes::default();
+                for attr in attrs {
+                    match attr {
+                        AttributeValue::Uid(u) => uid = Some(*u),
+                        AttributeValue::GmailLabels(labels) => {
+                            gmail.labels = labels
+                                .iter()
+                                .map(|l| utf7_imap::decode_utf7_imap(l.to_string()))
+                                .collect();
+                        }
+                        AttributeValue::GmailThrId(id) => gmail.thread_id = Some(*id),
+                        AttributeValue::GmailMsgId(id) => gmail.message_id = Some(*id),
+                        _ => {}
+                    }
+                }
+                if let Some(uid) = uid {
+                    attributes.insert(uid, gmail);
+                }
+            }
+            Response::Done { tag: done, status, information, .. } if *done == tag => {
+                if *status != ResponseStatus::Ok {
+                    let status = format!("{status:?} {}", information.as_deref().unwrap_or(""));
+                    return Err(raw_status_failed("UID FETCH X-GM-LABELS", &status, ErrorKind::Other));
+                }
+                return Ok(attributes);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Move messages between folders, returning their UIDs in `dest_folder`
+/// where the server reports them (COPYUID).
+///
+/// Uses MOVE (RFC 6851) when the server advertises it; otherwise COPY + flag
+/// Deleted + an expunge of just those messages.
+pub async fn move_messages(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    source_folder: &str,
+    uid_set: &str,
+    dest_folder: &str,
+) -> Result<ImapCopyResult, MailError> {
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(source_folder))
+        .await
+        .map_err(|_| timed_out(format!("SELECT {source_folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(source_folder, e))?;
+
+    if caps.r#move {
+        return copy_or_move(session, "MOVE", uid_set, dest_folder).await;
+    }
+
+    let copied = copy_or_move(session, "COPY", uid_set, dest_folder).await?;
+    store_silent(session, uid_set, "+FLAGS.SILENT (\\Deleted)").await?;
+    expunge_uids(session, caps, uid_set).await?;
+
+    Ok(copied)
+}
+
+/// `UID COPY` or `UID MOVE` on the selected folder. The response code
+/// async-imap would discard is what carries COPYUID, so this goes through
+/// the raw path. MOVE sends it in an untagged OK ahead of the expunges
+/// (RFC 6851 §4.3), COPY in the tagged OK.
+async fn copy_or_move(
+    session: &mut ImapSession,
+    operation: &str,
+    uid_set: &str,
+    dest_folder: &str,
+) -> Result<ImapCopyResult, MailError> {
+    // Expunges from a MOVE come back as our own untagged responses.
+    while session.unsolicited_responses.try_recv().is_ok() {}
+
+    let command = format!("UID {operation} {uid_set} {}", imap_string(dest_folder, false));
+    let (responses, status) = run_raw_command(session, &command, IMAP_CMD_TIMEOUT).await?;
+    if !status.starts_with("OK") {
+        return Err(raw_status_failed(format_args!("UID {operation} to {dest_folder}"), &status, ErrorKind::Other));
+    }
+
+    let copy_uid = responses
+        .iter()
+        .filter_map(|r| r.text.strip_prefix("* "))
+        .chain(std::iter::once(status.as_str()))
+        .find_map(|text| parse_response_code(text, "COPYUID"));
+    let Some(copy_uid) = copy_uid else {
+        return Ok(ImapCopyResult::default());
+    };
+
+    // COPYUID <uidvalidity> <source set> <destination set>
+    let mut parts = copy_uid.split_whitespace();
+    let (Some(uidvalidity), Some(source), Some(dest)) = (parts.next(), parts.next(), parts.next()) else {
+        log::warn!("IMAP {operation}: malformed COPYUID {copy_uid:?}");
+        return Ok(ImapCopyResult::default());
+    };
+    let (source, dest) = (expand_uid_set(source)?, expand_uid_set(dest)?);
+    if source.len() != dest.len() {
+        log::warn!("IMAP {operation}: COPYUID sets differ in length: {copy_uid:?}");
+        return Ok(ImapCopyResult::default());
+    }
+
+    Ok(ImapCopyResult {
+        dest_uidvalidity: uidvalidity.parse().ok(),
+        uid_map: source
+            .into_iter()
+            .zip(dest)
+            .map(|(source_uid, dest_uid)| UidMapping { source_uid, dest_uid })
+            .collect(),
+    })
+}
+
+/// The arguments of a `[NAME ...]` response code in a status response, e.g.
+/// `"1 5:7 20:22"` for `OK [COPYUID 1 5:7 20:22] Done`.
+fn parse_response_code<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    let open = text.find('[')?;
+    let close = open + text[open..].find(']')?;
+    let code = &text[open + 1..close];
+    let (code_name, args) = code.split_once(' ')?;
+    code_name.eq_ignore_ascii_case(name).then_some(args.trim())
+}
+
+/// Flag messages as deleted and expunge them. Returns the UIDs that were
+/// actually removed; other `\Deleted` messages in the folder are left alone.
+pub async fn delete_messages(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    folder: &str,
+    uid_set: &str,
+) -> Result<Vec<u32>, MailError> {
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
+        .await
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
+
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
+        let store_stream = session
+            .uid_store(uid_set, "+FLAGS (\\Deleted)")
+            .await
+            .map_err(|e| failed("UID STORE +Deleted", e))?;
+        let _: Vec<_> = store_stream.collect().await;
+        Ok::<_, MailError>(())
+    })
+    .await
+    .map_err(|_| timed_out("UID STORE +Deleted", IMAP_CMD_TIMEOUT))??;
+
+    expunge_uids(session, caps, uid_set).await
+}
+
+/// Expunge exactly `uid_set`, already flagged `\Deleted`, from the selected
+/// folder and return the UIDs that were removed.
+///
+/// With UIDPLUS this is `UID EXPUNGE`. Without it a plain EXPUNGE would also
+/// remove messages another client flagged `\Deleted`, so their flag is
+/// cleared for the duration and restored afterwards. A message flagged by
+/// someone else in that window is still expunged; that can't be avoided
+/// without UIDPLUS.
+async fn expunge_uids(session: &mut ImapSession, caps: &ImapCapabilities, uid_set: &str) -> Result<Vec<u32>, MailError> {
+    // EXPUNGE responses carry sequence numbers, so note which ones are ours.
+    let targets: Vec<(u32, u32)> = tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
+        let stream = session
+            .uid_fetch(uid_set, "UID")
+            .await
+            .map_err(|e| failed(format!("UID FETCH {uid_set}"), e))?;
+        let fetches: Vec<_> = stream.collect().await;
+        Ok::<_, MailError>(fetches)
+    })
+    .await
+    .map_err(|_| timed_out("UID FETCH", IMAP_CMD_TIMEOUT))??
+    .into_iter()
+    .filter_map(|r| r.ok())
+    .filter_map(|fetch| Some((fetch.message, fetch.uid?)))
+    .collect();
+
+    if caps.uidplus {
+        let command = format!("UID EXPUNGE {uid_set}");
+        return run_expunge(session, &command, &targets).await;
+    }
+
+    let foreign = tokio::time::timeout(IMAP_SEARCH_TIMEOUT, session.uid_search(format!("DELETED NOT UID {uid_set}")))
+        .await
+        .map_err(|_| timed_out("UID SEARCH DELETED", IMAP_SEARCH_TIMEOUT))?
+        .map_err(|e| failed("UID SEARCH DELETED", e))?;
+    let mut foreign: Vec<u32> = foreign.into_iter().collect();
+    foreign.sort_unstable();
+    let foreign_set = foreign.iter().map(|u| u.to_string()).collect::<Vec<_>>().join(",");
+
+    if !foreign.is_empty() {
+        log::info!("IMAP expunge: keeping {} \\Deleted messages of other clients", foreign.len());
+        store_silent(session, &foreign_set, "-FLAGS.SILENT (\\Deleted)").await?;
+    }
+
+    let result = run_expunge(session, "EXPUNGE", &targets).await;
+
+    // A failed restore is only logged: the caller needs the expunge result.
+    if !foreign.is_empty() {
+        if let Err(e) = store_silent(session, &foreign_set, "+FLAGS.SILENT (\\Deleted)").await {
+            log::error!("IMAP expunge: failed to restore \\Deleted on UIDs {foreign_set}: {e}");
+        }
+    }
+
+    result
+}
+
+/// Run EXPUNGE or UID EXPUNGE and map what the server reports back to the
+/// UIDs in `targets` (sequence number, UID).
+async fn run_expunge(session: &mut ImapSession, command: &str, targets: &[(u32, u32)]) -> Result<Vec<u32>, MailError> {
+    while session.unsolicited_responses.try_recv().is_ok() {}
+
+    let expunged_seqs: Vec<u32> = tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
+        let stream = match command.strip_prefix("UID EXPUNGE ") {
+            Some(uid_set) => session.uid_expunge(uid_set).await.map(|s| s.boxed()),
+            None => session.expunge().await.map(|s| s.boxed()),
+        }
+        .map_err(|e| failed(command, e))?;
+        let seqs: Vec<_> = stream.filter_map(|r| async move { r.ok() }).collect().await;
+        Ok::<_, MailError>(seqs)
+    })
+    .await
+    .map_err(|_| timed_out(command, IMAP_CMD_TIMEOUT))??;
+
+    let mut removed = expunged_uids(targets, &expunged_seqs);
+
+    // With QRESYNC enabled the server reports VANISHED UIDs instead.
+    while let Ok(response) = session.unsolicited_responses.try_recv() {
+        if let UnsolicitedResponse::Other(data) = response {
+            if let Response::Vanished { uids, .. } = data.parsed() {
+                removed.extend(
+                    uids.iter()
+                        .flat_map(|range| range.clone())
+                        .filter(|uid| targets.iter().any(|(_, t)| t == uid)),
+                );
+            }
+        }
+    }
+
+    removed.sort_unstable();
+    removed.dedup();
+    Ok(removed)
+}
+
+/// Translate EXPUNGE sequence numbers into UIDs. Each EXPUNGE shifts the
+/// sequence numbers of the messages after it down by one.
+fn expunged_uids(targets: &[(u32, u32)], expunged_seqs: &[u32]) -> Vec<u32> {
+    let mut live = targets.to_vec();
+    let mut removed = Vec::new();
+    for &seq in expunged_seqs {
+        live.retain(|&(s, uid)| {
+            if s == seq {
+                removed.push(uid);
+            }
+            s != seq
+        });
+        for (s, _) in live.iter_mut().filter(|(s, _)| *s > seq) {
+            *s -= 1;
+        }
+    }
+    removed
+}
+
+/// `UID STORE`, discarding the FETCH responses.
+async fn store_silent(session: &mut ImapSession, uid_set: &str, query: &str) -> Result<(), MailError> {
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
+        let stream = session
+            .uid_store(uid_set, query)
+            .await
+            .map_err(|e| failed(format!("UID STORE {query}"), e))?;
+        let _: Vec<_> = stream.collect().await;
+        Ok::<_, MailError>(())
+    })
+    .await
+    .map_err(|_| timed_out("UID STORE", IMAP_CMD_TIMEOUT))?
+}
+
+/// Append a raw message to a folder (for saving sent mail or drafts),
+/// returning its UID there when the server reports it (APPENDUID).
+pub async fn append_message(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    folder: &str,
+    flags: Option<&str>,
+    raw_message: &[u8],
+) -> Result<ImapAppendResult, MailError> {
+    if let Some(limit) = caps.append_limit.filter(|&l| raw_message.len() as u64 > l) {
+        return Err(other_error(format!("Message is {} bytes; the server accepts at most {limit}", raw_message.len())));
+    }
+
+    // Sent through the raw path, since async-imap drops the APPENDUID code.
+    let mut command = format!("APPEND {}", imap_string(folder, false));
+    if let Some(flags) = flags {
+        command.push(' ');
+        command.push_str(flags);
+    }
+    let plus = if caps.literal_plus { "+" } else { "" };
+    command.push_str(&format!(" {{{}{plus}}}\r\n", raw_message.len()));
+    let command = [command.as_bytes(), raw_message].concat();
+
+    let (_, status) = run_raw_command(session, &command, IMAP_FETCH_TIMEOUT).await?;
+    if !status.starts_with("OK") {
+        return Err(raw_status_failed(format_args!("APPEND to {folder}"), &status, ErrorKind::Other));
+    }
+
+    // APPENDUID <uidvalidity> <uid>
+    let mut append_uid = parse_response_code(&status, "APPENDUID")
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|n| n.parse::<u32>().ok());
+    Ok(ImapAppendResult {
+        uidvalidity: append_uid.next().flatten(),
+        uid: append_uid.next().flatten(),
+    })
+}
+
+/// Get folder status (UIDVALIDITY, UIDNEXT, MESSAGES, UNSEEN).
+pub async fn get_folder_status(
+    session: &mut ImapSession,
+    folder: &str,
+) -> Result<ImapFolderStatus, MailError> {
+    let mailbox = tokio::time::timeout(
+        IMAP_CMD_TIMEOUT,
+        session.status(folder, "(UIDVALIDITY UIDNEXT MESSAGES UNSEEN)"),
+    )
+    .await
+    .map_err(|_| timed_out("STATUS", IMAP_CMD_TIMEOUT))?
+    .map_err(|e| failed("STATUS", e))?;
+
+    Ok(ImapFolderStatus {
+        uidvalidity: mailbox.uid_validity.unwrap_or(0),
+        uidnext: mailbox.uid_next.unwrap_or(0),
+        exists: mailbox.exists,
+        unseen: mailbox.unseen.unwrap_or(0),
+        highest_modseq: mailbox.highest_modseq,
+    })
+}
+
+/// Fetch a specific MIME part (attachment) by UID and part ID.
+/// Returns the decoded binary data as standard base64.
+///
+/// Only the requested section is downloaded: `BINARY.PEEK[part]` (RFC 3516)
+/// where the server supports it, otherwise `BODY.PEEK[part]` decoded locally
+/// according to the part's MIME headers. Fetching the whole message and
+/// extracting the part with `mail-parser` is kept as a last resort.
+pub async fn fetch_attachment(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    folder: &str,
+    uid: u32,
+    part_id: &str,
+) -> Result<String, MailError> {
+    let path = parse_part_path(part_id)?;
+
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
+        .await
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
+
+    let mut data = None;
+    if caps.binary {
+        data = fetch_binary_section(session, uid, part_id).await?;
+    }
+    if data.is_none() {
+        data = fetch_body_section(session, uid, &path).await?;
+    }
+    let data = match data {
+        Some(data) => data,
+        None => {
+            log::info!("IMAP fetch_attachment: section fetch unavailable for UID {uid} part {part_id}, fetching full message");
+            fetch_attachment_full(session, uid, part_id).await?
+        }
+    };
+
+    Ok(base64::engine::general_purpose::STANDARD.encode(&data))
+}
+
+/// Stream a MIME part of a message to `sink`, decoded, without holding it in
+/// memory.
+///
+/// Uses `BINARY.PEEK[part]` where supported; otherwise the part's
+/// Content-Transfer-Encoding is read from `BODY.PEEK[part.MIME]` and
+/// `BODY.PEEK[part]` is decoded on the fly. If the server has no MIME header
+/// for the part, the full-message path of `fetch_attachment` is used.
+pub(crate) async fn download_attachment(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    folder: &str,
+    uid: u32,
+    part_id: &str,
+    mut sink: DownloadSink,
+) -> Result<DownloadResult, MailError> {
+    let path = parse_part_path(part_id)?;
+
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
+        .await
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
+
+    if caps.binary {
+        let command = format!("UID FETCH {uid} (BINARY.PEEK[{part_id}])");
+        let marker = format!("BINARY[{part_id}]");
+        let (responses, status) =
+            run_raw_command_streaming(session, command.as_bytes(), IMAP_FETCH_TIMEOUT, Some((&marker, &mut sink))).await?;
+        if status.starts_with("OK") {
+            return finish_download(sink, &responses, &marker, uid).await;
+        }
+        log::info!("IMAP BINARY.PEEK[{part_id}] for UID {uid} refused: {status}");
+    }
+
+    match fetch_part_encoding(session, uid, &path).await? {
+        Some(encoding) => {
+            sink.set_encoding(&encoding);
+            let command = format!("UID FETCH {uid} (BODY.PEEK[{part_id}])");
+            let marker = format!("BODY[{part_id}]");
+            let (responses, status) =
+                run_raw_command_streaming(session, command.as_bytes(), IMAP_FETCH_TIMEOUT, Some((&marker, &mut sink))).await?;
+            if !status.starts_with("OK") {
+                return Err(raw_status_failed("UID FETCH attachment", &status, ErrorKind::Other));
+            }
+            finish_download(sink, &responses, &marker, uid).await
+        }
+        None => {
+            log::info!("IMAP download_attachment: no MIME header for UID {uid} part {part_id}, fetching full message");
+            let data = fetch_attachment_full(session, uid, part_id).await?;
+            sink.write(&data).await?;
+            sink.finish().await
+        }
+    }
+}
+
+/// Stream the raw RFC822 source of a message to `sink`.
+pub(crate) async fn download_raw_message(
+    session: &mut ImapSession,
+    folder: &str,
+    uid: u32,
+    mut sink: DownloadSink,
+) -> Result<DownloadResult, MailError> {
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
+        .await
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
+
+    let command = format!("UID FETCH {uid} (BODY.PEEK[])");
+    let (responses, status) =
+        run_raw_command_streaming(session, command.as_bytes(), IMAP_FETCH_TIMEOUT, Some(("BODY[]", &mut sink))).await?;
+    if !status.starts_with("OK") {
+        return Err(raw_status_failed("UID FETCH raw message", &status, ErrorKind::Other));
+    }
+    finish_download(sink, &responses, "BODY[]", uid).await
+}
+
+/// Complete a streamed download once the server answered OK. A response
+/// without the requested section means the message doesn't exist.
+async fn finish_download(
+    sink: DownloadSink,
+    responses: &[RawUntagged],
+    marker: &str,
+    uid: u32,
+) -> Result<DownloadResult, MailError> {
+    if !responses.iter().any(|r| r.text.to_ascii_uppercase().contains(marker)) {
+        return Err(other_error(format!("Message UID {uid} not found")));
+    }
+    sink.finish().await
+}
+
+/// Content-Transfer-Encoding of a part, from `BODY.PEEK[part.MIME]`. `None`
+/// if the server returned no MIME header for it.
+async fn fetch_part_encoding(
     session: &mut ImapSession,
-    folder: &str,
-    uid_set: &str,
-) -> Result<(), String> {
-    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
-        .await
-        .map_err(|_| format!("SELECT {folder} timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("SELECT {folder} failed: {e}"))?;
+    uid: u32,
+    path: &[u32],
+) -> Result<Option<String>, MailError> {
+    use async_imap::imap_proto::{MessageSection, SectionPath};
 
-    tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
-        let store_stream = session
-            .uid_store(uid_set, "+FLAGS (\\Deleted)")
+    let part_id = path.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(".");
+    let query = format!("(UID BODY.PEEK[{part_id}.MIME])");
+    let fetches: Vec<_> = tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
+        let stream = session
+            .uid_fetch(uid.to_string(), &query)
             .await
-            .map_err(|e| format!("UID STORE +Deleted failed: {e}"))?;
-        let _: Vec<_> = store_stream.collect().await;
-        Ok::<_, String>(())
+            .map_err(|e| failed("UID FETCH MIME header", e))?;
+        Ok::<_, MailError>(stream.collect::<Vec<_>>().await)
     })
     .await
-    .map_err(|_| format!("UID STORE +Deleted timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))??;
+    .map_err(|_| timed_out("UID FETCH MIME header", IMAP_CMD_TIMEOUT))?
+    ?
+    .into_iter()
+    .filter_map(|r| r.ok())
+    .collect();
 
-    tokio::time::timeout(IMAP_CMD_TIMEOUT, async {
-        let expunge_stream = session
-            .expunge()
-            .await
-            .map_err(|e| format!("EXPUNGE failed: {e}"))?;
-        let _: Vec<_> = expunge_stream.collect().await;
-        Ok::<_, String>(())
-    })
-    .await
-    .map_err(|_| format!("EXPUNGE timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))??;
+    let mime = fetches
+        .iter()
+        .find(|f| f.uid == Some(uid))
+        .and_then(|f| f.section(&SectionPath::Part(path.to_vec(), Some(MessageSection::Mime))))
+        .filter(|m| !m.is_empty());
+    let Some(mime) = mime else {
+        return Ok(None);
+    };
 
-    Ok(())
+    Ok(Some(
+        MessageParser::default()
+            .parse(mime)
+            .and_then(|m| m.content_transfer_encoding().map(|e| e.to_string()))
+            .unwrap_or_default(),
+    ))
 }
 
-/// Append a raw message to a folder (for saving sent mail or drafts).
-pub async fn append_message(
+/// Parse an IMAP section path like `"1.2"`.
+fn parse_part_path(part_id: &str) -> Result<Vec<u32>, MailError> {
+    part_id
+        .split('.')
+        .map(|n| n.parse().map_err(|_| other_error(format!("Invalid part ID {part_id:?}"))))
+        .collect()
+}
+
+/// `UID FETCH uid (BINARY.PEEK[part])` — the server undoes the
+/// Content-Transfer-Encoding. Returns `None` if the server refuses (e.g.
+/// `[UNKNOWN-CTE]`) so the caller can fall back.
+///
+/// imap-proto has no grammar for BINARY responses, so this bypasses async-imap.
+async fn fetch_binary_section(
     session: &mut ImapSession,
-    folder: &str,
-    flags: Option<&str>,
-    raw_message: &[u8],
-) -> Result<(), String> {
-    tokio::time::timeout(IMAP_FETCH_TIMEOUT, session.append(folder, flags, None, raw_message))
-        .await
-        .map_err(|_| format!("APPEND timed out after {}s — check your server settings or network connection", IMAP_FETCH_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("APPEND failed: {e}"))
+    uid: u32,
+    part_id: &str,
+) -> Result<Option<Vec<u8>>, MailError> {
+    let command = format!("UID FETCH {uid} (BINARY.PEEK[{part_id}])");
+    let (responses, status) = run_raw_command(session, &command, IMAP_FETCH_TIMEOUT).await?;
+    if !status.starts_with("OK") {
+        log::info!("IMAP BINARY.PEEK[{part_id}] for UID {uid} refused: {status}");
+        return Ok(None);
+    }
+
+    let marker = format!("BINARY[{part_id}]");
+    Ok(responses
+        .into_iter()
+        .find(|r| r.text.to_ascii_uppercase().contains(&marker))
+        .and_then(|r| r.literals.into_iter().next()))
 }
 
-/// Get folder status (UIDVALIDITY, UIDNEXT, MESSAGES, UNSEEN).
-pub async fn get_folder_status(
+/// `UID FETCH uid (BODY.PEEK[part.MIME] BODY.PEEK[part])`, decoding the part
+/// according to the Content-Transfer-Encoding in its MIME header. Returns
+/// `None` if the server didn't return both sections.
+async fn fetch_body_section(
     session: &mut ImapSession,
-    folder: &str,
-) -> Result<ImapFolderStatus, String> {
-    let mailbox = tokio::time::timeout(
-        IMAP_CMD_TIMEOUT,
-        session.status(folder, "(UIDVALIDITY UIDNEXT MESSAGES UNSEEN)"),
-    )
-    .await
-    .map_err(|_| format!("STATUS timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-    .map_err(|e| format!("STATUS failed: {e}"))?;
+    uid: u32,
+    path: &[u32],
+) -> Result<Option<Vec<u8>>, MailError> {
+    use async_imap::imap_proto::{MessageSection, SectionPath};
 
-    Ok(ImapFolderStatus {
-        uidvalidity: mailbox.uid_validity.unwrap_or(0),
-        uidnext: mailbox.uid_next.unwrap_or(0),
-        exists: mailbox.exists,
-        unseen: mailbox.unseen.unwrap_or(0),
-        highest_modseq: mailbox.highest_modseq,
+    let part_id = path.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(".");
+    let query = format!("(UID BODY.PEEK[{part_id}.MIME] BODY.PEEK[{part_id}])");
+    let fetches: Vec<_> = tokio::time::timeout(IMAP_FETCH_TIMEOUT, async {
+        let stream = session
+            .uid_fetch(uid.to_string(), &query)
+            .await
+            .map_err(|e| failed("UID FETCH attachment", e))?;
+        Ok::<_, MailError>(stream.collect::<Vec<_>>().await)
     })
+    .await
+    .map_err(|_| timed_out("UID FETCH attachment", IMAP_FETCH_TIMEOUT))?
+    ?
+    .into_iter()
+    .filter_map(|r| r.ok())
+    .collect();
+
+    let Some(fetch) = fetches.iter().find(|f| f.uid == Some(uid)) else {
+        return Ok(None);
+    };
+    let mime = fetch.section(&SectionPath::Part(path.to_vec(), Some(MessageSection::Mime)));
+    let body = fetch.section(&SectionPath::Part(path.to_vec(), None));
+    let (Some(mime), Some(body)) = (mime.filter(|m| !m.is_empty()), body) else {
+        return Ok(None);
+    };
+
+    let encoding = MessageParser::default()
+        .parse(mime)
+        .and_then(|m| m.content_transfer_encoding().map(|e| e.trim().to_ascii_lowercase()))
+        .unwrap_or_default();
+
+    let data = match encoding.as_str() {
+        "base64" => mail_parser::decoders::base64::base64_decode(body)
+            .ok_or_else(|| other_error(format!("Invalid base64 in UID {uid} part {part_id}")))?,
+        "quoted-printable" => mail_parser::decoders::quoted_printable::quoted_printable_decode(body)
+            .ok_or_else(|| other_error(format!("Invalid quoted-printable in UID {uid} part {part_id}")))?,
+        _ => body.to_vec(),
+    };
+    Ok(Some(data))
 }
 
-/// Fetch a specific MIME part (attachment) by UID and part ID.
-/// Returns the decoded binary data as standard base64.
-///
-/// Fetches the full message via `BODY.PEEK[]`, parses it with `mail-parser`
-/// (which handles all content-transfer-encoding decoding), and extracts
-/// the requested part's decoded bytes.
-pub async fn fetch_attachment(
+/// Fallback for `fetch_attachment`: fetch the full message via `BODY.PEEK[]`,
+/// parse it with `mail-parser` (which handles all content-transfer-encoding
+/// decoding), and extract the requested part's decoded bytes.
+async fn fetch_attachment_full(
     session: &mut ImapSession,
-    folder: &str,
     uid: u32,
     part_id: &str,
-) -> Result<String, String> {
-    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
-        .await
-        .map_err(|_| format!("SELECT {folder} timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("SELECT {folder} failed: {e}"))?;
-
+) -> Result<Vec<u8>, MailError> {
     let uid_str = uid.to_string();
     let fetches: Vec<_> = tokio::time::timeout(IMAP_FETCH_TIMEOUT, async {
         let stream = session
             .uid_fetch(&uid_str, "BODY.PEEK[]")
             .await
-            .map_err(|e| format!("UID FETCH attachment failed: {e}"))?;
-        Ok::<_, String>(stream.collect::<Vec<_>>().await)
+            .map_err(|e| failed("UID FETCH attachment", e))?;
+        Ok::<_, MailError>(stream.collect::<Vec<_>>().await)
     })
     .await
-    .map_err(|_| format!("UID FETCH attachment timed out after {}s — check your server settings or network connection", IMAP_FETCH_TIMEOUT.as_secs()))?
+    .map_err(|_| timed_out("UID FETCH attachment", IMAP_FETCH_TIMEOUT))?
     ?
     .into_iter()
     .filter_map(|r| r.ok())
@@ -595,17 +2233,17 @@ pub async fn fetch_attachment(
 
     let fetch = fetches
         .first()
-        .ok_or_else(|| format!("No response for UID {uid}"))?;
+        .ok_or_else(|| other_error(format!("No response for UID {uid}")))?;
 
     let raw = fetch
         .body()
-        .ok_or_else(|| format!("No body for UID {uid}"))?;
+        .ok_or_else(|| other_error(format!("No body for UID {uid}")))?;
 
     // Parse the full message — mail-parser decodes content-transfer-encoding
     let parser = MessageParser::default();
     let message = parser
         .parse(raw)
-        .ok_or_else(|| format!("Failed to parse message UID {uid}"))?;
+        .ok_or_else(|| parse_error(format!("Failed to parse message UID {uid}")))?;
 
     // Build section map and find the part index for the requested section path
     let section_map = build_imap_section_map(&message);
@@ -613,12 +2251,12 @@ pub async fn fetch_attachment(
         .iter()
         .find(|(_, section)| section.as_str() == part_id)
         .map(|(&idx, _)| idx)
-        .ok_or_else(|| format!("Section {part_id} not found in message UID {uid}"))?;
+        .ok_or_else(|| other_error(format!("Section {part_id} not found in message UID {uid}")))?;
 
     let part = message
         .parts
         .get(target_part_idx)
-        .ok_or_else(|| format!("Part index {target_part_idx} out of range for UID {uid}"))?;
+        .ok_or_else(|| other_error(format!("Part index {target_part_idx} out of range for UID {uid}")))?;
 
     // Extract the decoded binary content from the part
     let data = match &part.body {
@@ -632,11 +2270,11 @@ pub async fn fetch_attachment(
             msg.raw_message.as_ref().to_vec()
         }
         mail_parser::PartType::Multipart(_) => {
-            return Err(format!("Part {part_id} is a multipart container, not a leaf part"));
+            return Err(other_error(format!("Part {part_id} is a multipart container, not a leaf part")));
         }
     };
 
-    Ok(base64::engine::general_purpose::STANDARD.encode(&data))
+    Ok(data)
 }
 
 /// Fetch the raw RFC822 source of a single message by UID.
@@ -645,22 +2283,22 @@ pub async fn fetch_raw_message(
     session: &mut ImapSession,
     folder: &str,
     uid: u32,
-) -> Result<String, String> {
+) -> Result<String, MailError> {
     tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
         .await
-        .map_err(|_| format!("SELECT {folder} timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("SELECT {folder} failed: {e}"))?;
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
 
     let uid_str = uid.to_string();
     let fetches: Vec<_> = tokio::time::timeout(IMAP_FETCH_TIMEOUT, async {
         let stream = session
             .uid_fetch(&uid_str, "BODY.PEEK[]")
             .await
-            .map_err(|e| format!("UID FETCH failed: {e}"))?;
-        Ok::<_, String>(stream.collect::<Vec<_>>().await)
+            .map_err(|e| failed("UID FETCH", e))?;
+        Ok::<_, MailError>(stream.collect::<Vec<_>>().await)
     })
     .await
-    .map_err(|_| format!("UID FETCH raw message timed out after {}s — check your server settings or network connection", IMAP_FETCH_TIMEOUT.as_secs()))?
+    .map_err(|_| timed_out("UID FETCH raw message", IMAP_FETCH_TIMEOUT))?
     ?
     .into_iter()
     .filter_map(|r| r.ok())
@@ -668,28 +2306,134 @@ pub async fn fetch_raw_message(
 
     let fetch = fetches
         .first()
-        .ok_or_else(|| format!("Message UID {uid} not found in {folder}"))?;
+        .ok_or_else(|| other_error(format!("Message UID {uid} not found in {folder}")))?;
 
     let raw = fetch
         .body()
-        .ok_or_else(|| format!("No body for UID {uid}"))?;
+        .ok_or_else(|| other_error(format!("No body for UID {uid}")))?;
 
     Ok(String::from_utf8_lossy(raw).to_string())
 }
 
+/// Run CAPABILITY and summarize what the server supports. Called once per
+/// pooled connection, after login, since servers may advertise more then.
+pub async fn get_capabilities(session: &mut ImapSession) -> Result<ImapCapabilities, MailError> {
+    let caps = tokio::time::timeout(IMAP_CMD_TIMEOUT, session.capabilities())
+        .await
+        .map_err(|_| timed_out("CAPABILITY", IMAP_CMD_TIMEOUT))?
+        .map_err(|e| failed("CAPABILITY", e))?;
+
+    let names = caps.iter().map(|c| match c {
+        Capability::Imap4rev1 => "IMAP4rev1".to_string(),
+        Capability::Auth(mechanism) => format!("AUTH={mechanism}"),
+        Capability::Atom(atom) => atom.to_string(),
+    });
+    Ok(parse_capabilities(names))
+}
+
+fn parse_capabilities(names: impl IntoIterator<Item = String>) -> ImapCapabilities {
+    let mut caps = ImapCapabilities::default();
+    for name in names {
+        let upper = name.to_ascii_uppercase();
+        match upper.as_str() {
+            "IDLE" => caps.idle = true,
+            "MOVE" => caps.r#move = true,
+            "UIDPLUS" => caps.uidplus = true,
+            "CONDSTORE" => caps.condstore = true,
+            "QRESYNC" => caps.qresync = true,
+            "SPECIAL-USE" => caps.special_use = true,
+            "CREATE-SPECIAL-USE" => caps.create_special_use = true,
+            "LIST-EXTENDED" => caps.list_extended = true,
+            "LIST-STATUS" => caps.list_status = true,
+            "COMPRESS=DEFLATE" => caps.compress_deflate = true,
+            "ESEARCH" => caps.esearch = true,
+            "SORT" => caps.sort = true,
+            "QUOTA" => caps.quota = true,
+            "BINARY" => caps.binary = true,
+            "LITERAL+" => caps.literal_plus = true,
+            "X-GM-EXT-1" => caps.gmail = true,
+            _ => {
+                if let Some(algorithm) = upper.strip_prefix("THREAD=") {
+                    caps.thread.push(algorithm.to_string());
+                } else if let Some(mechanism) = upper.strip_prefix("AUTH=") {
+                    caps.auth_mechanisms.push(mechanism.to_string());
+                } else if let Some(limit) = upper.strip_prefix("APPENDLIMIT=") {
+                    caps.append_limit = limit.parse().ok();
+                }
+            }
+        }
+        caps.raw.push(name);
+    }
+    // QRESYNC implies CONDSTORE (RFC 7162 §3.2.3).
+    caps.condstore |= caps.qresync;
+    caps
+}
+
+/// Switch the session to COMPRESS DEFLATE (RFC 4978). Everything after
+/// this, including commands sent through the raw path, goes over the
+/// compressed stream.
+///
+/// Takes the session by value since the stream underneath it is replaced;
+/// if the server refuses, the connection is lost and must be reopened.
+pub async fn enable_compression(session: ImapSession) -> Result<ImapSession, MailError> {
+    tokio::time::timeout(
+        IMAP_CMD_TIMEOUT,
+        session.compress(|stream| ImapStream::Deflate(Box::new(stream))),
+    )
+    .await
+    .map_err(|_| timed_out("COMPRESS DEFLATE", IMAP_CMD_TIMEOUT))?
+    .map_err(|e| failed("COMPRESS DEFLATE", e))
+}
+
+/// ENABLE QRESYNC (RFC 7162) on a freshly authenticated session.
+///
+/// Must run before the first SELECT. Returns whether the server accepted it;
+/// servers without QRESYNC are left as they are.
+pub async fn enable_qresync(session: &mut ImapSession, caps: &ImapCapabilities) -> Result<bool, MailError> {
+    if !caps.qresync {
+        return Ok(false);
+    }
+
+    tokio::time::timeout(IMAP_CMD_TIMEOUT, session.run_command_and_check_ok("ENABLE QRESYNC"))
+        .await
+        .map_err(|_| timed_out("ENABLE QRESYNC", IMAP_CMD_TIMEOUT))?
+        .map_err(|e| failed("ENABLE QRESYNC", e))?;
+
+    // Drop the ENABLED response so it isn't mistaken for command output later.
+    while session.unsolicited_responses.try_recv().is_ok() {}
+
+    Ok(true)
+}
+
 /// Check multiple folders for new UIDs in a single IMAP session.
 ///
 /// For each folder: SELECT, compare UIDVALIDITY, UID SEARCH for new messages.
 /// This replaces N separate connections (status + fetch_new_uids per folder)
 /// with a single connection that checks all folders.
+///
+/// When the server supports CONDSTORE and the request carries the folder's
+/// previous HIGHESTMODSEQ, flag changes since then are fetched as well; with
+/// QRESYNC enabled on the session, expunged UIDs are reported via VANISHED
+/// too.
 pub async fn delta_check_folders(
     session: &mut ImapSession,
+    caps: &ImapCapabilities,
     folders: &[DeltaCheckRequest],
-) -> Result<Vec<DeltaCheckResult>, String> {
+) -> Result<Vec<DeltaCheckResult>, MailError> {
     let mut results = Vec::with_capacity(folders.len());
 
+    let condstore = caps.condstore;
+    let qresync_enabled = caps.qresync_enabled;
+
     for req in folders {
-        let mailbox = match tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(&req.folder)).await {
+        let select = async {
+            if condstore {
+                session.select_condstore(&req.folder).await
+            } else {
+                session.select(&req.folder).await
+            }
+        };
+        let mailbox = match tokio::time::timeout(IMAP_CMD_TIMEOUT, select).await {
             Ok(Ok(m)) => m,
             Ok(Err(e)) => {
                 log::warn!("delta_check: SELECT {} failed: {e}", req.folder);
@@ -710,6 +2454,9 @@ pub async fn delta_check_folders(
                 uidvalidity: current_uidvalidity,
                 new_uids: vec![],
                 uidvalidity_changed: true,
+                highest_modseq: mailbox.highest_modseq,
+                flag_changes: vec![],
+                vanished_uids: vec![],
             });
             continue;
         }
@@ -732,17 +2479,103 @@ pub async fn delta_check_folders(
             }
         };
 
+        let mut highest_modseq = mailbox.highest_modseq;
+        let (flag_changes, vanished_uids) = match (req.highest_modseq, mailbox.highest_modseq) {
+            (Some(since), Some(current)) if condstore && current > since => {
+                match fetch_changes_since(session, req, since, qresync_enabled).await {
+                    Ok(changes) => changes,
+                    Err(e) => {
+                        log::warn!("delta_check: {e}");
+                        // Keep the caller's baseline so the changes are fetched next time.
+                        highest_modseq = req.highest_modseq;
+                        (vec![], vec![])
+                    }
+                }
+            }
+            _ => (vec![], vec![]),
+        };
+
         results.push(DeltaCheckResult {
             folder: req.folder.clone(),
             uidvalidity: current_uidvalidity,
             new_uids,
             uidvalidity_changed: false,
+            highest_modseq,
+            flag_changes,
+            vanished_uids,
         });
     }
 
     Ok(results)
 }
 
+/// `UID FETCH 1:* (FLAGS) (CHANGEDSINCE n [VANISHED])` on the selected folder.
+///
+/// Only messages at or below `last_uid` are reported — newer ones are picked
+/// up through `new_uids` anyway.
+async fn fetch_changes_since(
+    session: &mut ImapSession,
+    req: &DeltaCheckRequest,
+    since: u64,
+    qresync_enabled: bool,
+) -> Result<(Vec<FlagChange>, Vec<u32>), MailError> {
+    // Leftovers from SELECT would otherwise be mixed up with our VANISHED data.
+    while session.unsolicited_responses.try_recv().is_ok() {}
+
+    let modifier = if qresync_enabled {
+        format!("(CHANGEDSINCE {since} VANISHED)")
+    } else {
+        format!("(CHANGEDSINCE {since})")
+    };
+    let query = format!("(UID FLAGS) {modifier}");
+
+    let fetches: Vec<_> = tokio::time::timeout(IMAP_FETCH_TIMEOUT, async {
+        let stream = session
+            .uid_fetch("1:*", &query)
+            .await
+            .map_err(|e| failed(format_args!("UID FETCH CHANGEDSINCE {}", req.folder), e))?;
+        let fetches: Vec<_> = stream.collect().await;
+        Ok::<_, MailError>(fetches)
+    })
+    .await
+    .map_err(|_| timed_out(format_args!("UID FETCH CHANGEDSINCE {}", req.folder), IMAP_FETCH_TIMEOUT))??;
+
+    let mut flag_changes = Vec::new();
+    for fetch in fetches.into_iter().flatten() {
+        let Some(uid) = fetch.uid else { continue };
+        if uid > req.last_uid {
+            continue;
+        }
+        let flags: Vec<Flag> = fetch.flags().collect();
+        flag_changes.push(FlagChange {
+            uid,
+            is_read: flags.iter().any(|f| matches!(f, Flag::Seen)),
+            is_starred: flags.iter().any(|f| matches!(f, Flag::Flagged)),
+            is_draft: flags.iter().any(|f| matches!(f, Flag::Draft)),
+            flags: flags.iter().map(flag_name).collect(),
+            modseq: fetch.modseq,
+        });
+    }
+
+    // VANISHED isn't a FETCH response, so async-imap routes it to the
+    // unsolicited channel.
+    let mut vanished_uids = Vec::new();
+    while let Ok(response) = session.unsolicited_responses.try_recv() {
+        if let UnsolicitedResponse::Other(data) = response {
+            if let Response::Vanished { uids, .. } = data.parsed() {
+                vanished_uids.extend(
+                    uids.iter()
+                        .flat_map(|range| *range.start()..=(*range.end()).min(req.last_uid)),
+                );
+            }
+        }
+    }
+    vanished_uids.sort_unstable();
+    vanished_uids.dedup();
+
+    Ok((flag_changes, vanished_uids))
+}
+
 /// Sync a folder in a single IMAP session: SELECT → UID SEARCH ALL → batched UID FETCH.
 ///
 /// This avoids creating multiple TCP connections per folder (one for search,
@@ -750,14 +2583,55 @@ pub async fn delta_check_folders(
 /// many folders.
 pub async fn sync_folder(
     session: &mut ImapSession,
+    caps: &ImapCapabilities,
+    folder: &str,
+    batch_size: u32,
+    headers_only: bool,
+    progress: &mut SyncProgress,
+) -> Result<ImapFolderSyncResult, MailError> {
+    let mut messages = Vec::new();
+    let (uids, folder_status) =
+        sync_folder_batched(session, caps, folder, None, batch_size, headers_only, progress, |batch| {
+            messages.extend(batch);
+            Ok(())
+        })
+        .await?;
+
+    Ok(ImapFolderSyncResult {
+        uids,
+        messages,
+        folder_status,
+    })
+}
+
+/// Batched core of `sync_folder`: SELECT, then fetch `uids` (every UID, via
+/// UID SEARCH ALL, when `None`) in batches, handing each batch of parsed
+/// messages to `on_batch` as soon as it is parsed so that neither side has to
+/// hold the whole folder in memory.
+///
+/// Returns the UIDs that were fetched and the folder status.
+#[allow(clippy::too_many_arguments)]
+pub async fn sync_folder_batched<F>(
+    session: &mut ImapSession,
+    caps: &ImapCapabilities,
     folder: &str,
+    uids: Option<Vec<u32>>,
     batch_size: u32,
-) -> Result<ImapFolderSyncResult, String> {
+    headers_only: bool,
+    progress: &mut SyncProgress,
+    mut on_batch: F,
+) -> Result<(Vec<u32>, ImapFolderStatus), MailError>
+where
+    F: FnMut(Vec<ImapMessage>) -> Result<(), MailError>,
+{
     // SELECT the folder
-    let mailbox = tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder))
-        .await
-        .map_err(|_| format!("SELECT {folder} timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("SELECT {folder} failed: {e}"))?;
+    let select = tokio::select! {
+        r = tokio::time::timeout(IMAP_CMD_TIMEOUT, session.select(folder)) => r,
+        _ = progress.cancelled() => return Err(progress.cancelled_error()),
+    };
+    let mailbox = select
+        .map_err(|_| timed_out(format!("SELECT {folder}"), IMAP_CMD_TIMEOUT))?
+        .map_err(|e| select_failed(folder, e))?;
 
     let folder_status = ImapFolderStatus {
         uidvalidity: mailbox.uid_validity.unwrap_or(0),
@@ -767,14 +2641,23 @@ pub async fn sync_folder(
         highest_modseq: mailbox.highest_modseq,
     };
 
-    // UID SEARCH ALL to get real UIDs
-    let uids_raw = tokio::time::timeout(IMAP_SEARCH_TIMEOUT, session.uid_search("ALL"))
-        .await
-        .map_err(|_| format!("UID SEARCH ALL {folder} timed out after {}s — check your server settings or network connection", IMAP_SEARCH_TIMEOUT.as_secs()))?
-        .map_err(|e| format!("UID SEARCH ALL {folder} failed: {e}"))?;
+    let uids = match uids {
+        Some(uids) => uids,
+        None => {
+            // UID SEARCH ALL to get real UIDs
+            let search = tokio::select! {
+                r = tokio::time::timeout(IMAP_SEARCH_TIMEOUT, session.uid_search("ALL")) => r,
+                _ = progress.cancelled() => return Err(progress.cancelled_error()),
+            };
+            let uids_raw = search
+                .map_err(|_| timed_out(format!("UID SEARCH ALL {folder}"), IMAP_SEARCH_TIMEOUT))?
+                .map_err(|e| failed(format!("UID SEARCH ALL {folder}"), e))?;
 
-    let mut uids: Vec<u32> = uids_raw.into_iter().collect();
-    uids.sort();
+            let mut uids: Vec<u32> = uids_raw.into_iter().collect();
+            uids.sort();
+            uids
+        }
+    };
 
     log::info!(
         "IMAP sync_folder {folder}: {} UIDs found, uidvalidity={}, batch_size={}",
@@ -784,17 +2667,18 @@ pub async fn sync_folder(
     );
 
     if uids.is_empty() {
-        return Ok(ImapFolderSyncResult {
-            uids,
-            messages: vec![],
-            folder_status,
-        });
+        return Ok((uids, folder_status));
     }
 
+    progress.report(0, uids.len());
+
     // Fetch in batches on the SAME session
     let parser = MessageParser::default();
-    let mut all_messages = Vec::new();
-    let bs = batch_size as usize;
+    let bs = batch_size.max(1) as usize;
+    let mut fetched_count = 0;
+    let items = if headers_only { HEADER_FETCH_ITEMS } else { FULL_FETCH_ITEMS };
+
+    let mut processed = 0;
 
     for chunk in uids.chunks(bs) {
         let uid_set: String = chunk
@@ -803,17 +2687,26 @@ pub async fn sync_folder(
             .collect::<Vec<_>>()
             .join(",");
 
-        let fetches = tokio::time::timeout(IMAP_FETCH_TIMEOUT, async {
+        // A cancelled fetch leaves its response half-read; the caller must
+        // discard the session (PooledSession::finish does on Err).
+        let fetch = tokio::time::timeout(IMAP_FETCH_TIMEOUT, async {
             let stream = session
-                .uid_fetch(&uid_set, "UID FLAGS INTERNALDATE BODY.PEEK[]")
+                .uid_fetch(&uid_set, items)
                 .await
-                .map_err(|e| format!("UID FETCH {folder} uids={uid_set} failed: {e}"))?;
-            Ok::<_, String>(stream.collect::<Vec<_>>().await)
-        })
-        .await
-        .map_err(|_| format!("UID FETCH {folder} timed out after {}s — check your server settings or network connection", IMAP_FETCH_TIMEOUT.as_secs()))?;
+                .map_err(|e| failed(format!("UID FETCH {folder} uids={uid_set}"), e))?;
+            Ok::<_, MailError>(stream.collect::<Vec<_>>().await)
+        });
+        let fetches = tokio::select! {
+            r = fetch => r,
+            _ = progress.cancelled() => {
+                log::info!("IMAP sync_folder {folder}: cancelled after {processed}/{} UIDs", uids.len());
+                return Err(progress.cancelled_error());
+            }
+        }
+        .map_err(|_| timed_out(format!("UID FETCH {folder}"), IMAP_FETCH_TIMEOUT))?;
 
         let raw_fetches: Vec<_> = fetches?;
+        let mut batch = Vec::with_capacity(chunk.len());
         for r in raw_fetches {
             match r {
                 Ok(f) => {
@@ -821,38 +2714,49 @@ pub async fn sync_folder(
                         Some(u) => u,
                         None => { log::warn!("IMAP sync_folder {folder}: response missing UID"); continue; }
                     };
+                    if headers_only {
+                        match parse_header_fetch(&parser, &f, uid, folder) {
+                            Ok(msg) => batch.push(msg),
+                            Err(e) => log::warn!("sync_folder: failed to parse headers of UID {uid}: {e}"),
+                        }
+                        continue;
+                    }
                     let raw = match f.body() {
                         Some(b) => b,
                         None => { log::warn!("IMAP sync_folder {folder}: UID {uid} has no body"); continue; }
                     };
                     let raw_size = raw.len() as u32;
-                    let flags: Vec<_> = f.flags().collect();
-                    let is_read = flags.iter().any(|fl| matches!(fl, Flag::Seen));
-                    let is_starred = flags.iter().any(|fl| matches!(fl, Flag::Flagged));
-                    let is_draft = flags.iter().any(|fl| matches!(fl, Flag::Draft));
+                    let flags = MessageFlags::from_flags(f.flags());
                     let internal_date = f.internal_date().map(|dt| dt.timestamp());
 
-                    match parse_message(&parser, raw, uid, folder, raw_size, is_read, is_starred, is_draft, internal_date) {
-                        Ok(msg) => all_messages.push(msg),
+                    match parse_message(&parser, raw, uid, folder, raw_size, flags, internal_date) {
+                        Ok(msg) => batch.push(msg),
                         Err(e) => log::warn!("sync_folder: failed to parse UID {uid}: {e}"),
                     }
                 }
                 Err(e) => log::warn!("IMAP sync_folder fetch stream error in {folder}: {e}"),
             }
         }
+
+        tokio::select! {
+            r = attach_gmail_attributes(session, caps, &mut batch) => r?,
+            _ = progress.cancelled() => return Err(progress.cancelled_error()),
+        }
+
+        fetched_count += batch.len();
+        on_batch(batch)?;
+
+        processed += chunk.len();
+        progress.report(processed, uids.len());
     }
 
-    log::info!("IMAP sync_folder {folder}: fetched {} messages", all_messages.len());
+    log::info!("IMAP sync_folder {folder}: fetched {fetched_count} messages");
 
-    Ok(ImapFolderSyncResult {
-        uids,
-        messages: all_messages,
-        folder_status,
-    })
+    Ok((uids, folder_status))
 }
 
 /// Test IMAP connectivity: connect, login, list, logout.
-pub async fn test_connection(config: &ImapConfig) -> Result<String, String> {
+pub async fn test_connection(config: &ImapConfig) -> Result<String, MailError> {
     let mut session = connect(config).await?;
 
     // Try listing folders to verify access
@@ -860,11 +2764,11 @@ pub async fn test_connection(config: &ImapConfig) -> Result<String, String> {
         let names = session
             .list(Some(""), Some("*"))
             .await
-            .map_err(|e| format!("LIST failed: {e}"))?;
-        Ok::<_, String>(names.collect::<Vec<_>>().await.len())
+            .map_err(|e| failed("LIST", e))?;
+        Ok::<_, MailError>(names.collect::<Vec<_>>().await.len())
     })
     .await
-    .map_err(|_| format!("LIST timed out after {}s — check your server settings or network connection", IMAP_CMD_TIMEOUT.as_secs()))?
+    .map_err(|_| timed_out("LIST", IMAP_CMD_TIMEOUT))?
     ?;
 
     let _ = tokio::time::timeout(IMAP_CMD_TIMEOUT, session.logout()).await;
@@ -875,83 +2779,55 @@ pub async fn test_connection(config: &ImapConfig) -> Result<String, String> {
     ))
 }
 
-/// Raw IMAP fetch: connect via raw TCP/TLS (bypassing async-imap),
-/// authenticate, SELECT folder, UID FETCH with full body, parse responses.
+/// Raw IMAP fetch: SELECT and UID FETCH through `run_raw_command`, parsing
+/// the responses ourselves.
 ///
 /// This is a fallback for servers where async-imap fails to parse responses
 /// (e.g. Mailo with non-standard flags like `Sent` without backslash).
+/// With `headers_only`, only the header block is fetched and the returned
+/// messages have `body_loaded: false`; unlike `fetch_messages` there is no
+/// BODYSTRUCTURE, so attachments aren't listed until the body is loaded.
 pub async fn raw_fetch_messages(
-    config: &ImapConfig,
+    session: &mut ImapSession,
     folder: &str,
     uid_range: &str,
-) -> Result<ImapFetchResult, String> {
-    log::info!("RAW IMAP FETCH: connecting to {}:{} for folder {folder}, UIDs {uid_range}", config.host, config.port);
-
-    // Connect
-    let stream = if config.security == "starttls" {
-        raw_connect_starttls(config).await?
-    } else {
-        connect_stream(config).await?
-    };
-
-    let mut reader = BufReader::new(stream);
-
-    // Read greeting (for non-STARTTLS)
-    if config.security != "starttls" {
-        let mut line = String::new();
-        reader.read_line(&mut line).await.map_err(|e| format!("greeting: {e}"))?;
+    headers_only: bool,
+) -> Result<ImapFetchResult, MailError> {
+    let command = format!("SELECT {}", imap_string(folder, false));
+    let (responses, status) = run_raw_command(session, &command, IMAP_CMD_TIMEOUT).await?;
+    if !status.starts_with("OK") {
+        return Err(raw_status_failed(format_args!("SELECT {folder}"), &status, ErrorKind::FolderNotFound));
     }
 
-    // LOGIN
-    let login_cmd = if config.auth_method == "oauth2" {
-        // XOAUTH2: AUTHENTICATE XOAUTH2 <base64>
-        let xoauth2 = format!("user={}\x01auth=Bearer {}\x01\x01", config.username, config.password);
-        let b64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, xoauth2.as_bytes());
-        format!("a1 AUTHENTICATE XOAUTH2 {b64}\r\n")
-    } else {
-        format!("a1 LOGIN \"{}\" \"{}\"\r\n", config.username, config.password)
+    let mut folder_status = ImapFolderStatus {
+        uidvalidity: 0,
+        uidnext: 0,
+        exists: 0,
+        unseen: 0,
+        highest_modseq: None,
     };
-    raw_send_and_wait(&mut reader, login_cmd.as_bytes(), "a1").await?;
-
-    // SELECT
-    let select_cmd = format!("a2 SELECT \"{folder}\"\r\n");
-    let select_response = raw_send_and_wait(&mut reader, select_cmd.as_bytes(), "a2").await?;
-
-    // Parse SELECT response for UIDVALIDITY, EXISTS, UNSEEN
-    let mut exists = 0u32;
-    let mut uidvalidity = 0u32;
-    let mut unseen = 0u32;
-    for line in select_response.lines() {
+    for line in responses.iter().map(|r| r.text.as_str()) {
         if let Some(n) = parse_untagged_number(line, "EXISTS") {
-            exists = n;
+            folder_status.exists = n;
         }
-        if line.contains("[UIDVALIDITY") {
-            if let Some(v) = extract_bracket_number(line, "UIDVALIDITY") {
-                uidvalidity = v;
-            }
+        if let Some(v) = extract_bracket_number(line, "UIDVALIDITY") {
+            folder_status.uidvalidity = v;
         }
-        if line.contains("[UNSEEN") {
-            if let Some(v) = extract_bracket_number(line, "UNSEEN") {
-                unseen = v;
-            }
+        if let Some(v) = extract_bracket_number(line, "UIDNEXT") {
+            folder_status.uidnext = v;
+        }
+        if let Some(v) = extract_bracket_number(line, "UNSEEN") {
+            folder_status.unseen = v;
         }
     }
 
-    let folder_status = ImapFolderStatus {
-        uidvalidity,
-        uidnext: 0,
-        exists,
-        unseen,
-        highest_modseq: None,
-    };
-
-    // UID FETCH with full body
-    let fetch_cmd = format!("a3 UID FETCH {uid_range} (UID FLAGS INTERNALDATE BODY.PEEK[])\r\n");
-    reader.get_mut().write_all(fetch_cmd.as_bytes()).await
-        .map_err(|e| format!("FETCH write: {e}"))?;
-
-    // Parse FETCH responses with literal handling
-    let raw_messages = raw_parse_fetch_responses(&mut reader, "a3").await?;
+    let items = if headers_only { RAW_HEADER_FETCH_ITEMS } else { FULL_FETCH_ITEMS };
+    let command = format!("UID FETCH {uid_range} ({items})");
+    let (responses, status) = run_raw_command(session, &command, IMAP_FETCH_TIMEOUT).await?;
+    if !status.starts_with("OK") {
+        return Err(raw_status_failed(format_args!("UID FETCH {folder}"), &status, ErrorKind::Other));
+    }
+    let raw_messages: Vec<_> = responses.iter().filter_map(raw_fetched_message).collect();
 
     log::info!("RAW IMAP FETCH {folder}: parsed {} raw messages", raw_messages.len());
 
@@ -965,20 +2841,18 @@ pub async fn raw_fetch_messages(
             &raw_msg.body,
             raw_msg.uid,
             folder,
-            raw_msg.body.len() as u32,
-            raw_msg.is_read,
-            raw_msg.is_starred,
-            raw_msg.is_draft,
+            raw_msg.size.unwrap_or(raw_msg.body.len() as u32),
+            raw_msg.flags.clone(),
             raw_msg.internal_date,
         ) {
-            Ok(msg) => messages.push(msg),
+            Ok(mut msg) => {
+                msg.body_loaded = !headers_only;
+                messages.push(msg);
+            }
             Err(e) => log::warn!("RAW FETCH: failed to parse UID {}: {e}", raw_msg.uid),
         }
     }
 
-    // LOGOUT
-    let _ = reader.get_mut().write_all(b"a4 LOGOUT\r\n").await;
-
     Ok(ImapFetchResult { messages, folder_status })
 }
 
@@ -989,39 +2863,35 @@ pub async fn raw_fetch_diagnostic(
     config: &ImapConfig,
     folder: &str,
     uid_range: &str,
-) -> Result<String, String> {
+) -> Result<String, MailError> {
     // Connect and wrap in our ImapStream
     let mut stream = if config.security == "starttls" {
         raw_connect_starttls(config).await?
     } else {
         connect_stream(config).await?
-    };
+    }
+    .traced(config);
 
     let mut buf = vec![0u8; 16384];
     let mut output = String::new();
 
-    // Read greeting (for non-STARTTLS)
-    if config.security != "starttls" {
-        let n = stream.read(&mut buf).await.map_err(|e| format!("greeting: {e}"))?;
-        output.push_str(&format!("S: {}", String::from_utf8_lossy(&buf[..n])));
-    }
+    // Greeting (for non-STARTTLS) and capabilities, to know how to log in
+    let capabilities = pre_auth_capabilities(&mut stream, config.security != "starttls").await?;
+    output.push_str(&format!("S: * CAPABILITY {}\r\n", capabilities.join(" ")));
 
-    // LOGIN
-    let login_cmd = format!("a1 LOGIN \"{}\" \"{}\"\r\n", config.username, config.password);
-    stream.write_all(login_cmd.as_bytes()).await.map_err(|e| format!("LOGIN: {e}"))?;
-    let n = stream.read(&mut buf).await.map_err(|e| format!("LOGIN read: {e}"))?;
-    output.push_str(&format!("S: {}", String::from_utf8_lossy(&buf[..n])));
+    // LOGIN (the output holds only the server's answer, never credentials)
+    output.push_str(&format!("S: {}", raw_login(&mut stream, config, &capabilities).await?));
 
     // SELECT
-    let select_cmd = format!("a2 SELECT \"{folder}\"\r\n");
-    stream.write_all(select_cmd.as_bytes()).await.map_err(|e| format!("SELECT: {e}"))?;
+    let select_cmd = format!("a2 SELECT {}\r\n", imap_string(folder, false));
+    stream.write_all(select_cmd.as_bytes()).await.map_err(|e| MailError::io(&e, format!("SELECT: {e}")))?;
     tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-    let n = stream.read(&mut buf).await.map_err(|e| format!("SELECT read: {e}"))?;
+    let n = stream.read(&mut buf).await.map_err(|e| MailError::io(&e, format!("SELECT read: {e}")))?;
     output.push_str(&format!("S: {}", String::from_utf8_lossy(&buf[..n])));
 
     // UID FETCH — just get UID and FLAGS first (small response)
     let fetch_cmd = format!("a3 UID FETCH {uid_range} (UID FLAGS)\r\n");
-    stream.write_all(fetch_cmd.as_bytes()).await.map_err(|e| format!("FETCH: {e}"))?;
+    stream.write_all(fetch_cmd.as_bytes()).await.map_err(|e| MailError::io(&e, format!("FETCH: {e}")))?;
 
     let mut fetch_response = String::new();
     loop {
@@ -1038,100 +2908,299 @@ pub async fn raw_fetch_diagnostic(
             Err(_) => { fetch_response.push_str("[timeout]"); break; }
         }
     }
-    output.push_str(&format!("FETCH response:\n{fetch_response}"));
-
-    let _ = stream.write_all(b"a4 LOGOUT\r\n").await;
-
-    log::info!("RAW IMAP DIAGNOSTIC for {folder}:\n{output}");
-
-    Ok(output)
+    output.push_str(&format!("FETCH response:\n{fetch_response}"));
+
+    let _ = stream.write_all(b"a4 LOGOUT\r\n").await;
+
+    log::info!("RAW IMAP DIAGNOSTIC for {folder}:\n{output}");
+
+    Ok(output)
+}
+
+// ---------- Raw TCP helpers ----------
+
+/// Intermediate struct for a raw-parsed IMAP message before mail-parser processing.
+struct RawFetchedMessage {
+    uid: u32,
+    flags: MessageFlags,
+    internal_date: Option<i64>,
+    size: Option<u32>, // RFC822.SIZE, when fetched
+    body: Vec<u8>,     // the whole message, or just its header block
+}
+
+/// Tag used for commands sent with `run_raw_command`; async-imap numbers its
+/// own tags `A0001`, `A0002`, ... so the two never collide.
+const RAW_COMMAND_TAG: &str = "V1";
+
+/// One untagged response read by `run_raw_command`.
+struct RawUntagged {
+    /// Response text, with literal markers (`{n}`/`~{n}`) left in place.
+    text: String,
+    /// Literal payloads in the order they appear.
+    literals: Vec<Vec<u8>>,
+}
+
+impl RawUntagged {
+    /// The response as the server sent it, literals back in place, for
+    /// `response::tokenize`.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.text.len() + self.literals.iter().map(Vec::len).sum::<usize>());
+        let mut literals = self.literals.iter();
+        for line in self.text.split_inclusive('\n') {
+            bytes.extend_from_slice(line.as_bytes());
+            if response::literal_size(line.as_bytes()).is_some() {
+                bytes.extend_from_slice(literals.next().map_or(&[][..], Vec::as_slice));
+            }
+        }
+        bytes
+    }
+}
+
+/// Run a command directly on a session's stream, for responses imap-proto
+/// has no grammar for (an unparsable response would poison the async-imap
+/// session). Returns the untagged responses and the tagged status, e.g.
+/// `"OK Fetch completed"`.
+///
+/// `command` may contain synchronizing literals (`{n}\r\n` followed by the
+/// data); each is sent after the server's `+` continuation. `timeout` applies
+/// to each read, so large responses aren't cut off as long as data keeps
+/// arriving. An `Err` means the stream is in an unknown state and the session
+/// must be discarded.
+///
+/// Nothing past the tagged response is read, so whatever the server sends
+/// next (e.g. an unsolicited EXISTS in the same TLS record) is left for
+/// async-imap.
+async fn run_raw_command(
+    session: &mut ImapSession,
+    command: impl AsRef<[u8]>,
+    timeout: Duration,
+) -> Result<(Vec<RawUntagged>, String), MailError> {
+    run_raw_command_streaming(session, command.as_ref(), timeout, None).await
+}
+
+/// Like `run_raw_command`, but the first literal following `marker` (e.g.
+/// `BODY[1.2]`) is written to `sink` chunk by chunk instead of being held in
+/// memory. It appears as an empty literal in the returned responses.
+async fn run_raw_command_streaming(
+    session: &mut ImapSession,
+    command: &[u8],
+    timeout: Duration,
+    mut stream_to: Option<(&str, &mut DownloadSink)>,
+) -> Result<(Vec<RawUntagged>, String), MailError> {
+    let tagged_prefix = format!("{RAW_COMMAND_TAG} ");
+    let first_line = command.split(|&b| b == b'\r').next().unwrap_or(command);
+    let label = String::from_utf8_lossy(first_line);
+    let timed_out = || timed_out(&label, timeout);
+
+    let full_command = [tagged_prefix.as_bytes(), command, b"\r\n"].concat();
+    let mut segments = split_at_sync_literals(&full_command).into_iter();
+
+    let stream = session.as_mut();
+    if let Some(first) = segments.next() {
+        raw_write(stream, first, timeout).await.map_err(|e| e.unwrap_or_else(timed_out))?;
+    }
+
+    let mut responses = Vec::new();
+    loop {
+        let mut text = Vec::new();
+        let mut literals = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            let n = tokio::time::timeout(timeout, read_line_unbuffered(stream, &mut line))
+                .await
+                .map_err(|_| timed_out())?
+                .map_err(|e| MailError::io(&e, format!("IMAP read failed: {e}")))?;
+            if n == 0 {
+                return Err(closed_error("IMAP connection closed mid-response"));
+            }
+            text.extend_from_slice(&line);
+
+            let Some(size) = response::literal_size(&line) else {
+                break;
+            };
+
+            let streams_here = stream_to.as_ref().is_some_and(|(marker, _)| {
+                String::from_utf8_lossy(&text).to_ascii_uppercase().contains(marker)
+            });
+            if streams_here {
+                let (_, sink) = stream_to.take().expect("checked above");
+                sink.set_total(size as u64);
+                let mut remaining = size;
+                let mut chunk = vec![0u8; 64 * 1024];
+                while remaining > 0 {
+                    let want = remaining.min(chunk.len());
+                    let read = tokio::time::timeout(timeout, stream.read(&mut chunk[..want]))
+                        .await
+                        .map_err(|_| timed_out())?
+                        .map_err(|e| MailError::io(&e, format!("IMAP literal read failed: {e}")))?;
+                    if read == 0 {
+                        return Err(closed_error("IMAP connection closed mid-literal"));
+                    }
+                    sink.write(&chunk[..read]).await?;
+                    remaining -= read;
+                }
+                literals.push(Vec::new());
+            } else {
+                let mut literal = vec![0u8; size];
+                tokio::time::timeout(timeout, stream.read_exact(&mut literal))
+                    .await
+                    .map_err(|_| timed_out())?
+                    .map_err(|e| MailError::io(&e, format!("IMAP literal read failed: {e}")))?;
+                literals.push(literal);
+            }
+        }
+
+        let text = String::from_utf8_lossy(&text).trim_end().to_string();
+        if let Some(status) = text.strip_prefix(&tagged_prefix) {
+            return Ok((responses, status.to_string()));
+        }
+        if text.starts_with('+') {
+            let next = segments
+                .next()
+                .ok_or_else(|| parse_error(format!("{label}: unexpected continuation request: {text}")))?;
+            raw_write(stream, next, timeout).await.map_err(|e| e.unwrap_or_else(timed_out))?;
+            continue;
+        }
+        responses.push(RawUntagged { text, literals });
+    }
+}
+
+/// Read up to and including the next `\n`, one byte at a time: a buffered
+/// reader would take bytes past the line that the session's own reader then
+/// never sees. Over TLS or COMPRESS the layers below buffer, so this isn't a
+/// system call per byte. Returns the number of bytes read, 0 at EOF.
+async fn read_line_unbuffered(stream: &mut ImapStream, line: &mut Vec<u8>) -> std::io::Result<usize> {
+    let start = line.len();
+    let mut byte = [0u8; 1];
+    while stream.read(&mut byte).await? == 1 {
+        line.push(byte[0]);
+        if byte[0] == b'\n' {
+            break;
+        }
+    }
+    Ok(line.len() - start)
 }
 
-// ---------- Raw TCP helpers ----------
+/// Write and flush `data`; `Err(None)` on timeout.
+async fn raw_write(stream: &mut ImapStream, data: &[u8], timeout: Duration) -> Result<(), Option<MailError>> {
+    tokio::time::timeout(timeout, async {
+        stream.write_all(data).await?;
+        stream.flush().await
+    })
+    .await
+    .map_err(|_| None)?
+    .map_err(|e| Some(MailError::io(&e, format!("IMAP write failed: {e}"))))
+}
 
-/// Intermediate struct for a raw-parsed IMAP message before mail-parser processing.
-struct RawFetchedMessage {
-    uid: u32,
-    is_read: bool,
-    is_starred: bool,
-    is_draft: bool,
-    internal_date: Option<i64>,
-    body: Vec<u8>,
+/// Split a command after each synchronizing literal header (`{n}\r\n`): the
+/// literal data may only be sent once the server answers with `+`.
+/// Non-synchronizing literals (`{n+}`, RFC 7888) are sent as they are.
+fn split_at_sync_literals(command: &[u8]) -> Vec<&[u8]> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while let Some(offset) = command[pos..].windows(3).position(|w| w == b"}\r\n") {
+        let close = pos + offset;
+        let data_start = close + 3;
+        let header = command[..close]
+            .iter()
+            .rposition(|&b| b == b'{')
+            .map(|open| &command[open + 1..close]);
+        let (digits, sync) = match header {
+            Some(h) => match h.strip_suffix(b"+") {
+                Some(d) => (d, false),
+                None => (h, true),
+            },
+            None => (&b""[..], false),
+        };
+        match std::str::from_utf8(digits).ok().and_then(|d| d.parse::<usize>().ok()) {
+            Some(size) => {
+                if sync {
+                    segments.push(&command[start..data_start]);
+                    start = data_start;
+                }
+                // Skip the literal data itself, which may contain anything.
+                pos = (data_start + size).min(command.len());
+            }
+            None => pos = data_start,
+        }
+    }
+    segments.push(&command[start..]);
+    segments
 }
 
-/// Connect via STARTTLS for raw TCP operations.
-async fn raw_connect_starttls(config: &ImapConfig) -> Result<ImapStream, String> {
-    let addr = (&*config.host, config.port);
-    let mut tcp = tokio::time::timeout(TCP_CONNECT_TIMEOUT, TcpStream::connect(addr))
-        .await
-        .map_err(|_| format!(
-            "TCP connect to {}:{} timed out after {}s — check your server settings or network connection",
-            config.host, config.port, TCP_CONNECT_TIMEOUT.as_secs()
-        ))?
-        .map_err(|e| format!("TCP: {e}"))?;
-    configure_tcp_socket(&tcp);
-    let mut tmp = vec![0u8; 4096];
-    let _ = tokio::time::timeout(IMAP_CMD_TIMEOUT, tcp.read(&mut tmp)).await; // consume greeting
-    tcp.write_all(b"a0 STARTTLS\r\n").await.map_err(|e| format!("STARTTLS: {e}"))?;
-    let n = tokio::time::timeout(IMAP_CMD_TIMEOUT, tcp.read(&mut tmp))
-        .await
-        .map_err(|_| format!(
-            "STARTTLS response timed out after {}s — check your server settings or network connection",
-            IMAP_CMD_TIMEOUT.as_secs()
-        ))?
-        .map_err(|e| format!("STARTTLS resp: {e}"))?;
-    let resp = String::from_utf8_lossy(&tmp[..n]);
-    if !resp.contains("OK") {
-        return Err(format!("STARTTLS rejected: {resp}"));
-    }
-    let nc = build_tls_connector(config.accept_invalid_certs)?;
-    let tc = tokio_native_tls::TlsConnector::from(nc);
-    let tls = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tc.connect(&config.host, tcp))
-        .await
-        .map_err(|_| format!(
-            "TLS handshake timed out after {}s — check your server settings or network connection",
-            TLS_HANDSHAKE_TIMEOUT.as_secs()
-        ))?
-        .map_err(|e| format!("TLS: {e}"))?;
-    Ok(ImapStream::Tls(tls))
-}
-
-/// Send a command and read all response lines until the tagged response (e.g. "a1 OK ...").
-async fn raw_send_and_wait(
-    reader: &mut tokio::io::BufReader<ImapStream>,
-    cmd: &[u8],
-    tag: &str,
-) -> Result<String, String> {
-    reader.get_mut().write_all(cmd).await
-        .map_err(|e| format!("{tag} write: {e}"))?;
-
-    let mut response = String::new();
-    let tag_ok = format!("{tag} OK");
-    let tag_no = format!("{tag} NO");
-    let tag_bad = format!("{tag} BAD");
+/// Authentication command (tag `a1`) for the raw connections, split where
+/// the server's `+` continuation must be awaited: LOGIN, with credentials
+/// that can't be quoted sent as literals (synchronizing ones without
+/// LITERAL+), or AUTHENTICATE with a single response, sent inline with
+/// SASL-IR.
+fn raw_login_command(config: &ImapConfig, capabilities: &[String]) -> Result<Vec<Vec<u8>>, MailError> {
+    let parsed = parse_capabilities(capabilities.iter().cloned());
+    let login_available = !parsed.raw.iter().any(|c| c.eq_ignore_ascii_case("LOGINDISABLED"));
+    match Mechanism::choose(&config.auth_method, &parsed.auth_mechanisms, login_available).map_err(other_error)? {
+        Mechanism::Login => {
+            let command = format!(
+                "a1 LOGIN {} {}\r\n",
+                imap_string(&config.username, parsed.literal_plus),
+                imap_string(&config.password, parsed.literal_plus)
+            );
+            Ok(split_at_sync_literals(command.as_bytes()).into_iter().map(<[u8]>::to_vec).collect())
+        }
+        Mechanism::ScramSha256 => Err(other_error("SCRAM-SHA-256 isn't supported by the raw IMAP fallback")),
+        mechanism => {
+            let mut sasl = SaslClient::new(mechanism, &config.username, &config.password, &config.host, config.port);
+            let initial = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, sasl.respond(b""));
+            if parsed.raw.iter().any(|c| c.eq_ignore_ascii_case("SASL-IR")) {
+                Ok(vec![format!("a1 AUTHENTICATE {} {initial}\r\n", mechanism.name()).into_bytes()])
+            } else {
+                Ok(vec![
+                    format!("a1 AUTHENTICATE {}\r\n", mechanism.name()).into_bytes(),
+                    format!("{initial}\r\n").into_bytes(),
+                ])
+            }
+        }
+    }
+}
 
+/// Log in on a raw connection with `raw_login_command`, returning the
+/// server's responses up to and including the tagged one. A further
+/// challenge (e.g. XOAUTH2's error details) gets an empty response, so the
+/// server finishes with its tagged NO.
+async fn raw_login(stream: &mut ImapStream, config: &ImapConfig, capabilities: &[String]) -> Result<String, MailError> {
+    let timed_out = || timed_out("LOGIN", IMAP_CMD_TIMEOUT);
+    let mut segments = raw_login_command(config, capabilities)?.into_iter();
+    let first = segments.next().unwrap_or_default();
+    raw_write(stream, &first, IMAP_CMD_TIMEOUT).await.map_err(|e| e.unwrap_or_else(timed_out))?;
+
+    let mut responses = String::new();
     loop {
-        let mut line = String::new();
-        match tokio::time::timeout(
-            std::time::Duration::from_secs(30),
-            reader.read_line(&mut line),
-        ).await {
-            Ok(Ok(0)) => return Err(format!("{tag}: connection closed")),
-            Ok(Ok(_)) => {
-                response.push_str(&line);
-                if line.starts_with(&tag_ok) {
-                    return Ok(response);
-                }
-                if line.starts_with(&tag_no) || line.starts_with(&tag_bad) {
-                    return Err(format!("{tag} failed: {line}"));
-                }
-            }
-            Ok(Err(e)) => return Err(format!("{tag} read: {e}")),
-            Err(_) => return Err(format!("{tag}: timeout")),
+        let mut line = Vec::new();
+        let n = tokio::time::timeout(IMAP_CMD_TIMEOUT, read_line_unbuffered(stream, &mut line))
+            .await
+            .map_err(|_| timed_out())?
+            .map_err(|e| MailError::io(&e, format!("LOGIN read: {e}")))?;
+        if n == 0 {
+            return Err(closed_error("IMAP connection closed during LOGIN"));
+        }
+        let line = String::from_utf8_lossy(&line);
+        if line.starts_with('+') {
+            let next = segments.next().unwrap_or_else(|| b"\r\n".to_vec());
+            raw_write(stream, &next, IMAP_CMD_TIMEOUT).await.map_err(|e| e.unwrap_or_else(timed_out))?;
+            continue;
+        }
+        responses.push_str(&line);
+        if line.starts_with("a1 ") {
+            return Ok(responses);
         }
     }
 }
 
+/// Connect via STARTTLS for raw TCP operations.
+async fn raw_connect_starttls(config: &ImapConfig) -> Result<ImapStream, MailError> {
+    let tcp = starttls_tcp(config).await?;
+    Ok(ImapStream::Tls(tls_handshake(config, tcp).await?))
+}
+
 /// Parse untagged responses like "* 3 EXISTS" → 3
 fn parse_untagged_number(line: &str, keyword: &str) -> Option<u32> {
     // Format: "* <number> <KEYWORD>"
@@ -1155,126 +3224,62 @@ fn extract_bracket_number(line: &str, keyword: &str) -> Option<u32> {
     None
 }
 
-/// Parse IMAP FETCH responses with literal support ({size}\r\n...data...).
-///
-/// IMAP FETCH response format:
-/// ```text
-/// * 1 FETCH (UID 1 FLAGS (\Seen) INTERNALDATE "16-Feb-2026 12:00:00 +0000" BODY[] {1234}
-/// <1234 bytes of raw email data>
-/// )
-/// a3 OK UID FETCH done
-/// ```
-async fn raw_parse_fetch_responses(
-    reader: &mut tokio::io::BufReader<ImapStream>,
-    tag: &str,
-) -> Result<Vec<RawFetchedMessage>, String> {
-    let mut messages: Vec<RawFetchedMessage> = Vec::new();
-    let tag_ok = format!("{tag} OK");
-    let tag_no = format!("{tag} NO");
-    let tag_bad = format!("{tag} BAD");
-
-    loop {
-        let mut line = String::new();
-        match tokio::time::timeout(
-            std::time::Duration::from_secs(60),
-            reader.read_line(&mut line),
-        ).await {
-            Ok(Ok(0)) => return Err("Connection closed during FETCH".to_string()),
-            Ok(Ok(_)) => {
-                // Check for tagged response (end of FETCH)
-                if line.starts_with(&tag_ok) {
-                    break;
-                }
-                if line.starts_with(&tag_no) || line.starts_with(&tag_bad) {
-                    return Err(format!("FETCH failed: {line}"));
-                }
-
-                // Check for untagged FETCH response: "* <seq> FETCH (...)"
-                if !line.starts_with("* ") || !line.contains("FETCH") {
-                    continue;
-                }
-
-                // Parse UID from the response line
-                let uid = extract_fetch_uid(&line).unwrap_or(0);
-                if uid == 0 {
-                    log::warn!("RAW FETCH: could not parse UID from: {}", line.trim());
-                    // Still need to consume any literal
-                    if let Some(literal_size) = extract_literal_size(&line) {
-                        let mut discard = vec![0u8; literal_size];
-                        reader.read_exact(&mut discard).await
-                            .map_err(|e| format!("discard literal: {e}"))?;
-                    }
-                    continue;
-                }
-
-                // Parse flags
-                let flags_str = extract_flags_from_fetch(&line);
-                let is_read = flags_str.contains("\\Seen");
-                let is_starred = flags_str.contains("\\Flagged");
-                let is_draft = flags_str.contains("\\Draft");
-
-                // Parse INTERNALDATE
-                let internal_date = extract_internal_date(&line);
-
-                // Check for literal: {size}
-                if let Some(literal_size) = extract_literal_size(&line) {
-                    // Read exactly `literal_size` bytes
-                    let mut body = vec![0u8; literal_size];
-                    reader.read_exact(&mut body).await
-                        .map_err(|e| format!("read literal for UID {uid}: {e}"))?;
-
-                    // Read the closing ")\r\n" after the literal
-                    let mut closing = String::new();
-                    let _ = reader.read_line(&mut closing).await;
-
-                    messages.push(RawFetchedMessage {
-                        uid,
-                        is_read,
-                        is_starred,
-                        is_draft,
-                        internal_date,
-                        body,
-                    });
-                }
-            }
-            Ok(Err(e)) => return Err(format!("FETCH read: {e}")),
-            Err(_) => return Err("FETCH timeout".to_string()),
+/// The message in a FETCH response read by `run_raw_command`. The response
+/// is tokenized, so quoted strings, nested lists such as BODYSTRUCTURE and
+/// literals anywhere in it are handled. Messages without a body are skipped.
+fn raw_fetched_message(response: &RawUntagged) -> Option<RawFetchedMessage> {
+    let bytes = response.to_bytes();
+    let values = match response::tokenize(&bytes) {
+        Ok(values) => values,
+        Err(e) => {
+            log::warn!("RAW FETCH: skipping unparsable response: {e}");
+            return None;
         }
+    };
+    let [star, _, keyword, response::Value::List(attrs)] = values.as_slice() else { return None };
+    if star.as_atom() != Some("*") || !keyword.as_atom().is_some_and(|k| k.eq_ignore_ascii_case("FETCH")) {
+        return None;
     }
 
-    Ok(messages)
-}
-
-/// Extract UID from a FETCH response line like "* 1 FETCH (UID 123 FLAGS ...)"
-fn extract_fetch_uid(line: &str) -> Option<u32> {
-    // Look for "UID " followed by a number
-    let uid_idx = line.find("UID ")?;
-    let after_uid = &line[uid_idx + 4..];
-    let end = after_uid.find(|c: char| !c.is_ascii_digit()).unwrap_or(after_uid.len());
-    after_uid[..end].parse().ok()
+    let message = parse_raw_fetch(attrs);
+    if message.is_none() {
+        let start: String = response.text.chars().take(200).collect();
+        log::warn!("RAW FETCH: response without UID or body: {}", start.trim());
+    }
+    message
 }
 
-/// Extract flags string from FETCH response like "FLAGS (\Seen \Flagged)"
-fn extract_flags_from_fetch(line: &str) -> String {
-    if let Some(flags_start) = line.find("FLAGS (") {
-        let after = &line[flags_start + 7..];
-        if let Some(end) = after.find(')') {
-            return after[..end].to_string();
+/// Build a message from the attribute list of a tokenized FETCH response.
+fn parse_raw_fetch(attrs: &[response::Value]) -> Option<RawFetchedMessage> {
+    let mut uid = None;
+    let mut flags = MessageFlags::default();
+    let mut internal_date = None;
+    let mut size = None;
+    let mut body = None;
+
+    for pair in attrs.chunks(2) {
+        let [key, value] = pair else { break };
+        let Some(key) = key.as_atom() else { continue };
+        match key.to_ascii_uppercase().as_str() {
+            "UID" => uid = value.as_number(),
+            "FLAGS" => {
+                let names = value.as_list().unwrap_or_default().iter().filter_map(|f| f.as_atom());
+                flags = MessageFlags::from_flags(names.map(Flag::from));
+            }
+            "INTERNALDATE" => internal_date = value.as_text().and_then(|d| parse_imap_date(&d)),
+            "RFC822.SIZE" => size = value.as_number(),
+            "BODY[]" | "RFC822" | "BODY[HEADER]" => body = value.as_bytes().map(<[u8]>::to_vec),
+            _ => {}
         }
     }
-    String::new()
-}
 
-/// Extract INTERNALDATE from FETCH response.
-/// Format: INTERNALDATE "16-Feb-2026 12:00:00 +0000"
-/// Returns None if not present — mail-parser will use the Date header instead.
-fn extract_internal_date(line: &str) -> Option<i64> {
-    let idx = line.find("INTERNALDATE \"")?;
-    let after = &line[idx + 14..];
-    let end = after.find('"')?;
-    let date_str = &after[..end];
-    // Parse "DD-Mon-YYYY HH:MM:SS +ZZZZ" manually
-    parse_imap_date(date_str)
+    Some(RawFetchedMessage {
+        uid: uid?,
+        flags,
+        internal_date,
+        size,
+        body: body?,
+    })
 }
 
 /// Parse IMAP date format "16-Feb-2026 12:00:00 +0000" to Unix timestamp.
@@ -1333,155 +3338,248 @@ fn is_leap_year(y: i64) -> bool {
     (y % 4 == 0 && y % 100 != 0) || (y % 400 == 0)
 }
 
-/// Extract literal size from a line ending with {1234}\r\n
-fn extract_literal_size(line: &str) -> Option<usize> {
-    let trimmed = line.trim_end();
-    if !trimmed.ends_with('}') {
-        return None;
-    }
-    let brace_start = trimmed.rfind('{')?;
-    trimmed[brace_start + 1..trimmed.len() - 1].parse().ok()
-}
-
 // ---------- Internal helpers ----------
 
 /// Establish TCP + TLS or plain stream for "tls" and "none" security modes.
-async fn connect_stream(config: &ImapConfig) -> Result<ImapStream, String> {
-    let addr = (&*config.host, config.port);
-
+async fn connect_stream(config: &ImapConfig) -> Result<ImapStream, MailError> {
     match config.security.as_str() {
         "tls" => {
-            let native_connector = build_tls_connector(config.accept_invalid_certs)?;
-            let tls_connector = tokio_native_tls::TlsConnector::from(native_connector);
-            let tcp = tokio::time::timeout(TCP_CONNECT_TIMEOUT, TcpStream::connect(addr))
-                .await
-                .map_err(|_| format!(
-                    "TCP connect to {}:{} timed out after {}s — check your server settings or network connection",
-                    config.host, config.port, TCP_CONNECT_TIMEOUT.as_secs()
-                ))?
-                .map_err(|e| format!("TCP connect to {}:{} failed: {e}", config.host, config.port))?;
-            configure_tcp_socket(&tcp);
-            let tls = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_connector.connect(&config.host, tcp))
-                .await
-                .map_err(|_| format!(
-                    "TLS handshake with {} timed out after {}s — check your server settings or network connection",
-                    config.host, TLS_HANDSHAKE_TIMEOUT.as_secs()
-                ))?
-                .map_err(|e| format!("TLS handshake with {} failed: {e}", config.host))?;
-            Ok(ImapStream::Tls(tls))
-        }
-        "none" => {
-            let tcp = tokio::time::timeout(TCP_CONNECT_TIMEOUT, TcpStream::connect(addr))
-                .await
-                .map_err(|_| format!(
-                    "TCP connect to {}:{} timed out after {}s — check your server settings or network connection",
-                    config.host, config.port, TCP_CONNECT_TIMEOUT.as_secs()
-                ))?
-                .map_err(|e| format!("TCP connect to {}:{} failed: {e}", config.host, config.port))?;
-            configure_tcp_socket(&tcp);
-            Ok(ImapStream::Plain(tcp))
+            let tcp = tcp_connect(config).await?;
+            Ok(ImapStream::Tls(tls_handshake(config, tcp).await?))
         }
-        other => Err(format!(
+        "none" => Ok(ImapStream::Plain(tcp_connect(config).await?)),
+        other => Err(other_error(format!(
             "Unknown security mode: {other}. Use \"tls\", \"starttls\", or \"none\"."
-        )),
+        ))),
     }
 }
 
+async fn tcp_connect(config: &ImapConfig) -> Result<TcpStream, MailError> {
+    let connect = proxy::connect(config.proxy.as_ref(), &config.host, config.port);
+    let tcp = tokio::time::timeout(TCP_CONNECT_TIMEOUT, connect)
+        .await
+        .map_err(|_| {
+            MailError::timeout(
+                "connect",
+                format!(
+                    "TCP connect to {}:{} timed out after {}s — check your server settings or network connection",
+                    config.host,
+                    config.port,
+                    TCP_CONNECT_TIMEOUT.as_secs()
+                ),
+            )
+        })??;
+    configure_tcp_socket(&tcp);
+    Ok(tcp)
+}
+
 /// Handle STARTTLS connection: connect plain, upgrade to TLS, then authenticate.
 ///
 /// STARTTLS is special because we must issue the STARTTLS command on the plain
 /// connection, upgrade the underlying TCP stream to TLS, and then create a new
 /// Client on the TLS stream for authentication.
-async fn connect_starttls(config: &ImapConfig) -> Result<ImapSession, String> {
-    let addr = (&*config.host, config.port);
-    let mut tcp = tokio::time::timeout(TCP_CONNECT_TIMEOUT, TcpStream::connect(addr))
+async fn connect_starttls(config: &ImapConfig) -> Result<(ImapSession, Vec<String>), MailError> {
+    let tcp = starttls_tcp(config).await?;
+    let tls = tls_handshake(config, tcp).await?;
+
+    // Create a new IMAP client on the TLS stream and authenticate. The trace
+    // starts here: the exchange before STARTTLS holds nothing of interest.
+    let mut stream = ImapStream::Tls(tls).traced(config);
+    // Capabilities from before STARTTLS can't be trusted (RFC 3501 §6.2.1).
+    let capabilities = pre_auth_capabilities(&mut stream, false).await?;
+    let client = Client::new(stream);
+    let session = tokio::time::timeout(AUTH_TIMEOUT, authenticate(client, config, &capabilities))
         .await
-        .map_err(|_| format!(
-            "TCP connect to {}:{} timed out after {}s — check your server settings or network connection",
-            config.host, config.port, TCP_CONNECT_TIMEOUT.as_secs()
-        ))?
-        .map_err(|e| format!("TCP connect to {}:{} failed: {e}", config.host, config.port))?;
-    configure_tcp_socket(&tcp);
+        .map_err(|_| auth_timed_out())??;
+    Ok((session, parse_capabilities(capabilities).auth_mechanisms))
+}
+
+/// Connect plain, read the greeting and send STARTTLS, leaving the connection
+/// ready for the TLS handshake.
+async fn starttls_tcp(config: &ImapConfig) -> Result<TcpStream, MailError> {
+    let mut tcp = tcp_connect(config).await?;
 
     // Read the server greeting
     let mut buf = vec![0u8; 4096];
     let n = tokio::time::timeout(IMAP_CMD_TIMEOUT, tcp.read(&mut buf))
         .await
-        .map_err(|_| format!(
-            "Reading server greeting timed out after {}s — check your server settings or network connection",
-            IMAP_CMD_TIMEOUT.as_secs()
-        ))?
-        .map_err(|e| format!("Failed to read server greeting: {e}"))?;
+        .map_err(|_| {
+            MailError::timeout(
+                "connect",
+                format!(
+                    "Reading server greeting timed out after {}s — check your server settings or network connection",
+                    IMAP_CMD_TIMEOUT.as_secs()
+                ),
+            )
+        })?
+        .map_err(|e| MailError::io(&e, format!("Failed to read server greeting: {e}")))?;
     let greeting = String::from_utf8_lossy(&buf[..n]);
     if !greeting.contains("OK") {
-        return Err(format!("Unexpected server greeting: {greeting}"));
+        return Err(greeting_refused(&greeting, format!("Unexpected server greeting: {greeting}")));
     }
 
     // Send STARTTLS command
     tcp.write_all(b"a001 STARTTLS\r\n")
         .await
-        .map_err(|e| format!("Failed to send STARTTLS: {e}"))?;
+        .map_err(|e| MailError::io(&e, format!("Failed to send STARTTLS: {e}")))?;
 
     // Read STARTTLS response
     let n = tokio::time::timeout(IMAP_CMD_TIMEOUT, tcp.read(&mut buf))
         .await
-        .map_err(|_| format!(
-            "STARTTLS response timed out after {}s — check your server settings or network connection",
-            IMAP_CMD_TIMEOUT.as_secs()
-        ))?
-        .map_err(|e| format!("Failed to read STARTTLS response: {e}"))?;
+        .map_err(|_| {
+            MailError::timeout(
+                "tls",
+                format!(
+                    "STARTTLS response timed out after {}s — check your server settings or network connection",
+                    IMAP_CMD_TIMEOUT.as_secs()
+                ),
+            )
+        })?
+        .map_err(|e| MailError::io(&e, format!("Failed to read STARTTLS response: {e}")))?;
     let response = String::from_utf8_lossy(&buf[..n]);
     if !response.contains("OK") {
-        return Err(format!("STARTTLS rejected: {response}"));
+        let message = format!("STARTTLS rejected: {response}");
+        return Err(MailError::imap_response(response.trim_end(), ErrorKind::TlsError, message));
     }
+    Ok(tcp)
+}
 
-    // Upgrade to TLS
-    let native_connector = build_tls_connector(config.accept_invalid_certs)?;
-    let tls_connector = tokio_native_tls::TlsConnector::from(native_connector);
-    let tls = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_connector.connect(&config.host, tcp))
-        .await
-        .map_err(|_| format!(
-            "TLS upgrade after STARTTLS timed out after {}s — check your server settings or network connection",
-            TLS_HANDSHAKE_TIMEOUT.as_secs()
-        ))?
-        .map_err(|e| format!("TLS upgrade after STARTTLS failed: {e}"))?;
-
-    // Create a new IMAP client on the TLS stream and authenticate
-    let client = Client::new(ImapStream::Tls(tls));
-    tokio::time::timeout(AUTH_TIMEOUT, authenticate(client, config))
+/// Capabilities before login, to choose an authentication mechanism. Reads
+/// the greeting first unless it was consumed before STARTTLS; a CAPABILITY
+/// code in the greeting saves the extra round-trip.
+async fn pre_auth_capabilities(stream: &mut ImapStream, read_greeting: bool) -> Result<Vec<String>, MailError> {
+    let mut reader = BufReader::new(stream);
+    if read_greeting {
+        let greeting = response::read_response(&mut reader, IMAP_CMD_TIMEOUT)
+            .await?
+            .ok_or_else(|| closed_error("IMAP connection closed before the server greeting"))?;
+        let greeting = String::from_utf8_lossy(&greeting);
+        if strip_keyword(&greeting, "BYE").is_some() {
+            return Err(greeting_refused(&greeting, format!("Server refused the connection: {}", greeting.trim_end())));
+        }
+        if let Some(capabilities) = greeting_capabilities(&greeting) {
+            return Ok(capabilities);
+        }
+    }
+
+    let timed_out = || timed_out("CAPABILITY", IMAP_CMD_TIMEOUT);
+    raw_write(reader.get_mut(), b"a002 CAPABILITY\r\n", IMAP_CMD_TIMEOUT)
         .await
-        .map_err(|_| format!(
-            "IMAP authentication timed out after {}s — check your server settings or network connection",
-            AUTH_TIMEOUT.as_secs()
-        ))?
+        .map_err(|e| e.unwrap_or_else(timed_out))?;
+    let mut capabilities = Vec::new();
+    loop {
+        let line = response::read_response(&mut reader, IMAP_CMD_TIMEOUT)
+            .await?
+            .ok_or_else(|| closed_error("IMAP connection closed during CAPABILITY"))?;
+        let line = String::from_utf8_lossy(&line);
+        if let Some(names) = strip_keyword(&line, "CAPABILITY") {
+            capabilities.extend(names.split_whitespace().map(str::to_string));
+        } else if let Some(status) = line.strip_prefix("a002 ") {
+            if !status.get(..2).is_some_and(|s| s.eq_ignore_ascii_case("OK")) {
+                return Err(raw_status_failed("CAPABILITY", status.trim_end(), ErrorKind::Other));
+            }
+            return Ok(capabilities);
+        }
+    }
+}
+
+/// Capabilities from a `[CAPABILITY ...]` code in the server greeting.
+fn greeting_capabilities(greeting: &str) -> Option<Vec<String>> {
+    let start = greeting.to_ascii_uppercase().find("[CAPABILITY ")? + "[CAPABILITY ".len();
+    let end = start + greeting[start..].find(']')?;
+    Some(greeting[start..end].split_whitespace().map(str::to_string).collect())
 }
 
-/// Authenticate with the IMAP server (LOGIN or XOAUTH2).
+/// Authenticate with the IMAP server, using the mechanism `auth_method` picks
+/// from the pre-login `capabilities` (see `Mechanism::choose`). A rejected
+/// SCRAM attempt is retried once on the same connection (see
+/// `Mechanism::password_fallback`).
 async fn authenticate(
     client: Client<ImapStream>,
     config: &ImapConfig,
-) -> Result<ImapSession, String> {
-    match config.auth_method.as_str() {
-        "oauth2" => {
-            let auth = XOAuth2::new(&config.username, &config.password);
-            client
-                .authenticate("XOAUTH2", auth)
-                .await
-                .map_err(|(e, _)| format!("XOAUTH2 authentication failed: {e}"))
+    capabilities: &[String],
+) -> Result<ImapSession, MailError> {
+    let parsed = parse_capabilities(capabilities.iter().cloned());
+    let login_available = !parsed.raw.iter().any(|c| c.eq_ignore_ascii_case("LOGINDISABLED"));
+    let mechanism =
+        Mechanism::choose(&config.auth_method, &parsed.auth_mechanisms, login_available).map_err(other_error)?;
+
+    match authenticate_with(client, config, mechanism).await {
+        Ok(session) => Ok(session),
+        Err((e, Some(client))) => {
+            let Some(fallback) = mechanism.password_fallback(&config.auth_method, &parsed.auth_mechanisms, login_available) else {
+                return Err(e);
+            };
+            log::info!("IMAP {}: {e}; retrying with {}", config.host, fallback.name());
+            authenticate_with(client, config, fallback).await.map_err(|(e, _)| e)
         }
-        _ => client
+        Err((e, None)) => Err(e),
+    }
+}
+
+/// One authentication attempt. When the server rejected the credentials and
+/// the exchange went as expected otherwise, the client comes back for
+/// another attempt.
+async fn authenticate_with(
+    client: Client<ImapStream>,
+    config: &ImapConfig,
+    mechanism: Mechanism,
+) -> Result<ImapSession, (MailError, Option<Client<ImapStream>>)> {
+    if mechanism == Mechanism::Login {
+        return client
             .login(&config.username, &config.password)
             .await
-            .map_err(|(e, _)| format!("Login failed: {e}")),
+            .map_err(|(e, _)| (command_failed("Login", e, ErrorKind::AuthFailed), None));
+    }
+
+    let sasl = Arc::new(Mutex::new(SaslClient::new(
+        mechanism,
+        &config.username,
+        &config.password,
+        &config.host,
+        config.port,
+    )));
+    let result = client
+        .authenticate(mechanism.name(), SaslAuthenticator(sasl.clone()))
+        .await;
+    let sasl = sasl.lock().map_err(|_| (other_error("SASL state poisoned"), None))?;
+    let command = format!("{} authentication", mechanism.name());
+    match result {
+        Ok(session) => {
+            sasl.finish().map_err(|e| {
+                (MailError::new(ErrorKind::AuthFailed, format!("{command} failed: {e}")), None)
+            })?;
+            Ok(session)
+        }
+        Err((e, client)) => {
+            let rejected = matches!(e, async_imap::error::Error::No(_)) && sasl.error().is_none();
+            let error = match sasl.error() {
+                Some(detail) => MailError::new(ErrorKind::AuthFailed, format!("{command} failed: {e} ({detail})")),
+                None => command_failed(&command, e, ErrorKind::AuthFailed),
+            };
+            Err((error, rejected.then_some(client)))
+        }
     }
 }
 
-/// Detect special-use attribute from IMAP folder attributes and name heuristics.
-fn detect_special_use(name: &async_imap::types::Name) -> Option<String> {
-    use async_imap::types::NameAttribute;
+/// IMAP wire form of a flag, e.g. `\Seen` or a keyword like `$Forwarded`.
+fn flag_name(flag: &Flag) -> String {
+    match flag {
+        Flag::Seen => "\\Seen".to_string(),
+        Flag::Answered => "\\Answered".to_string(),
+        Flag::Flagged => "\\Flagged".to_string(),
+        Flag::Deleted => "\\Deleted".to_string(),
+        Flag::Draft => "\\Draft".to_string(),
+        Flag::Recent => "\\Recent".to_string(),
+        Flag::MayCreate => "\\*".to_string(),
+        Flag::Custom(name) => name.to_string(),
+    }
+}
 
+/// Detect special-use attribute from IMAP folder attributes and name heuristics.
+fn detect_special_use(attributes: &[NameAttribute], name: &str) -> Option<String> {
     // Check RFC 6154 attributes first
-    for attr in name.attributes() {
+    for attr in attributes {
         let special = match attr {
             NameAttribute::Sent => Some("\\Sent"),
             NameAttribute::Trash => Some("\\Trash"),
@@ -1490,6 +3588,8 @@ fn detect_special_use(name: &async_imap::types::Name) -> Option<String> {
             NameAttribute::Archive => Some("\\Archive"),
             NameAttribute::All => Some("\\All"),
             NameAttribute::Flagged => Some("\\Flagged"),
+            // Gmail's own attribute for [Gmail]/Important
+            NameAttribute::Extension(ext) if ext.eq_ignore_ascii_case("\\Important") => Some("\\Important"),
             _ => None,
         };
         if let Some(s) = special {
@@ -1498,19 +3598,24 @@ fn detect_special_use(name: &async_imap::types::Name) -> Option<String> {
     }
 
     // Heuristic fallback based on common folder names
-    let lower = name.name().to_lowercase();
+    let lower = name.to_lowercase();
     match lower.as_str() {
         "inbox" => Some("\\Inbox".to_string()),
-        "sent" | "sent messages" | "sent items" | "[gmail]/sent mail" => {
+        "sent" | "sent messages" | "sent items" | "[gmail]/sent mail" | "[google mail]/sent mail" => {
             Some("\\Sent".to_string())
         }
         "trash" | "deleted" | "deleted items" | "deleted messages" | "bin" | "corbeille"
-        | "unsolbox" | "[gmail]/trash" => {
+        | "unsolbox" | "[gmail]/trash" | "[google mail]/trash" => {
             Some("\\Trash".to_string())
         }
-        "drafts" | "draft" | "draftbox" | "brouillons" | "[gmail]/drafts" => Some("\\Drafts".to_string()),
-        "junk" | "spam" | "junk e-mail" | "[gmail]/spam" => Some("\\Junk".to_string()),
-        "archive" | "archives" | "[gmail]/all mail" => Some("\\Archive".to_string()),
+        "drafts" | "draft" | "draftbox" | "brouillons" | "[gmail]/drafts" | "[google mail]/drafts" => Some("\\Drafts".to_string()),
+        "junk" | "spam" | "junk e-mail" | "[gmail]/spam" | "[google mail]/spam" => Some("\\Junk".to_string()),
+        "archive" | "archives" => Some("\\Archive".to_string()),
+        // Every Gmail message lives in All Mail whatever its labels, so it
+        // must not pass for an ordinary archive folder.
+        "[gmail]/all mail" | "[google mail]/all mail" => Some("\\All".to_string()),
+        "[gmail]/starred" | "[google mail]/starred" => Some("\\Flagged".to_string()),
+        "[gmail]/important" | "[google mail]/important" => Some("\\Important".to_string()),
         _ => None,
     }
 }
@@ -1525,12 +3630,10 @@ fn parse_message(
     uid: u32,
     folder: &str,
     raw_size: u32,
-    is_read: bool,
-    is_starred: bool,
-    is_draft: bool,
+    flags: MessageFlags,
     internal_date: Option<i64>,
-) -> Result<ImapMessage, String> {
-    let message = parser.parse(raw).ok_or("Failed to parse MIME message")?;
+) -> Result<ImapMessage, MailError> {
+    let message = parser.parse(raw).ok_or_else(|| parse_error("Failed to parse MIME message"))?;
 
     let message_id = message.message_id().map(|s| s.to_string());
     let subject = message.subject().map(|s| s.to_string());
@@ -1547,18 +3650,7 @@ fn parse_message(
         _ => None,
     };
 
-    // References (space-separated message IDs)
-    let references = match message.references() {
-        mail_parser::HeaderValue::Text(t) => Some(t.to_string()),
-        mail_parser::HeaderValue::TextList(list) => {
-            if list.is_empty() {
-                None
-            } else {
-                Some(list.iter().map(|s| s.as_ref()).collect::<Vec<_>>().join(" "))
-            }
-        }
-        _ => None,
-    };
+    let references = extract_references(&message);
 
     // Addresses
     let (from_address, from_name) = extract_first_address(message.from());
@@ -1664,9 +3756,13 @@ fn parse_message(
         reply_to,
         subject,
         date,
-        is_read,
-        is_starred,
-        is_draft,
+        is_read: flags.is_read,
+        is_starred: flags.is_starred,
+        is_draft: flags.is_draft,
+        keywords: flags.keywords,
+        gmail_labels: Vec::new(),
+        gmail_thread_id: None,
+        gmail_message_id: None,
         body_html,
         body_text,
         snippet,
@@ -1675,9 +3771,220 @@ fn parse_message(
         list_unsubscribe_post,
         auth_results,
         attachments,
+        body_loaded: true,
+    })
+}
+
+/// Build a header-only `ImapMessage` from an ENVELOPE/BODYSTRUCTURE fetch
+/// (see `HEADER_FETCH_ITEMS`).
+fn parse_header_fetch(
+    parser: &MessageParser,
+    fetch: &async_imap::types::Fetch,
+    uid: u32,
+    folder: &str,
+) -> Result<ImapMessage, MailError> {
+    let envelope = fetch.envelope().ok_or_else(|| parse_error("FETCH response has no ENVELOPE"))?;
+
+    let flags = MessageFlags::from_flags(fetch.flags());
+
+    let date = envelope
+        .date
+        .as_deref()
+        .and_then(|d| mail_parser::DateTime::parse_rfc822(&String::from_utf8_lossy(d)))
+        .map(|d| d.to_timestamp())
+        .or_else(|| fetch.internal_date().map(|dt| dt.timestamp()))
+        .unwrap_or(0);
+
+    let from = envelope.from.as_deref().and_then(|a| a.first());
+    let from_address = from.and_then(envelope_email);
+    let from_name = from
+        .and_then(|a| a.name.as_deref())
+        .map(decode_envelope_text)
+        .filter(|n| !n.is_empty());
+
+    // The remaining headers aren't part of ENVELOPE and were fetched by name.
+    let extra = fetch.header().and_then(|h| parser.parse(h));
+    let header = |name: &str| {
+        extra
+            .as_ref()
+            .and_then(|m| extract_header_text(m.header(mail_parser::HeaderName::Other(name.into()))))
+    };
+
+    let mut attachments = Vec::new();
+    if let Some(structure) = fetch.bodystructure() {
+        collect_structure_attachments(structure, "", &mut attachments);
+    }
+
+    Ok(ImapMessage {
+        uid,
+        folder: folder.to_string(),
+        message_id: envelope.message_id.as_deref().and_then(envelope_message_id),
+        in_reply_to: envelope.in_reply_to.as_deref().and_then(envelope_message_id),
+        references: extra.as_ref().and_then(extract_references),
+        from_address,
+        from_name,
+        to_addresses: format_envelope_addresses(envelope.to.as_deref()),
+        cc_addresses: format_envelope_addresses(envelope.cc.as_deref()),
+        bcc_addresses: format_envelope_addresses(envelope.bcc.as_deref()),
+        reply_to: format_envelope_addresses(envelope.reply_to.as_deref()),
+        subject: envelope.subject.as_deref().map(decode_envelope_text),
+        date,
+        is_read: flags.is_read,
+        is_starred: flags.is_starred,
+        is_draft: flags.is_draft,
+        keywords: flags.keywords,
+        gmail_labels: Vec::new(),
+        gmail_thread_id: None,
+        gmail_message_id: None,
+        body_html: None,
+        body_text: None,
+        snippet: None,
+        raw_size: fetch.size.unwrap_or(0),
+        list_unsubscribe: extra
+            .as_ref()
+            .and_then(|m| extract_header_text(m.header(mail_parser::HeaderName::ListUnsubscribe))),
+        list_unsubscribe_post: header("List-Unsubscribe-Post"),
+        auth_results: header("Authentication-Results"),
+        attachments,
+        body_loaded: false,
     })
 }
 
+/// Walk a BODYSTRUCTURE and collect the parts that aren't displayable text
+/// bodies, numbering them the way IMAP sections are numbered.
+fn collect_structure_attachments(
+    structure: &async_imap::imap_proto::BodyStructure,
+    section: &str,
+    out: &mut Vec<ImapAttachment>,
+) {
+    use async_imap::imap_proto::{BodyStructure, ContentEncoding};
+
+    let (common, other) = match structure {
+        BodyStructure::Multipart { bodies, .. } => {
+            for (i, body) in bodies.iter().enumerate() {
+                let child = if section.is_empty() {
+                    format!("{}", i + 1)
+                } else {
+                    format!("{section}.{}", i + 1)
+                };
+                collect_structure_attachments(body, &child, out);
+            }
+            return;
+        }
+        BodyStructure::Basic { common, other, .. }
+        | BodyStructure::Text { common, other, .. }
+        | BodyStructure::Message { common, other, .. } => (common, other),
+    };
+
+    let param = |params: &async_imap::imap_proto::BodyParams, key: &str| {
+        params.as_ref().and_then(|p| {
+            p.iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| decode_envelope_text(v.as_bytes()))
+        })
+    };
+
+    let disposition = common.disposition.as_ref();
+    let is_attachment = disposition.is_some_and(|d| d.ty.eq_ignore_ascii_case("attachment"));
+    let filename = disposition
+        .and_then(|d| param(&d.params, "filename"))
+        .or_else(|| param(&common.ty.params, "name"));
+
+    let is_text_body = matches!(structure, BodyStructure::Text { .. })
+        && (common.ty.subtype.eq_ignore_ascii_case("plain") || common.ty.subtype.eq_ignore_ascii_case("html"))
+        && !is_attachment
+        && filename.is_none();
+    if is_text_body {
+        return;
+    }
+
+    // BODYSTRUCTURE reports the encoded size; approximate the decoded one.
+    let size = match other.transfer_encoding {
+        ContentEncoding::Base64 => other.octets / 4 * 3,
+        _ => other.octets,
+    };
+
+    out.push(ImapAttachment {
+        part_id: if section.is_empty() { "1".to_string() } else { section.to_string() },
+        filename: filename.unwrap_or_else(|| "attachment".to_string()),
+        mime_type: format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase(),
+        size,
+        content_id: other
+            .id
+            .as_ref()
+            .map(|id| id.trim().trim_start_matches('<').trim_end_matches('>').to_string()),
+        is_inline: disposition.is_some_and(|d| d.ty.eq_ignore_ascii_case("inline")),
+    });
+}
+
+/// Decode an ENVELOPE or BODYSTRUCTURE string, which may contain RFC 2047
+/// encoded words.
+fn decode_envelope_text(raw: &[u8]) -> String {
+    let text = String::from_utf8_lossy(raw);
+    if !text.contains("=?") {
+        return text.into_owned();
+    }
+    // Let mail-parser do the decoding by parsing it as a Subject header.
+    let header = format!("Subject: {text}\r\n\r\n");
+    MessageParser::default()
+        .parse(header.as_bytes())
+        .and_then(|m| m.subject().map(|s| s.to_string()))
+        .unwrap_or_else(|| text.into_owned())
+}
+
+/// `mailbox@host` of an ENVELOPE address; `None` for group markers.
+fn envelope_email(addr: &async_imap::imap_proto::Address) -> Option<String> {
+    let mailbox = String::from_utf8_lossy(addr.mailbox.as_deref()?);
+    let host = String::from_utf8_lossy(addr.host.as_deref()?);
+    Some(format!("{mailbox}@{host}"))
+}
+
+/// Same format as `format_address_list`, from ENVELOPE addresses.
+fn format_envelope_addresses(addrs: Option<&[async_imap::imap_proto::Address]>) -> Option<String> {
+    let parts: Vec<String> = addrs?
+        .iter()
+        .filter_map(|a| {
+            let email = envelope_email(a)?;
+            match a.name.as_deref().map(decode_envelope_text) {
+                Some(name) if !name.is_empty() => Some(format!("{name} <{email}>")),
+                _ => Some(email),
+            }
+        })
+        .collect();
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(", "))
+    }
+}
+
+/// First message ID of an ENVELOPE Message-ID/In-Reply-To, without angle
+/// brackets (matching what mail-parser returns for full messages).
+fn envelope_message_id(raw: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(raw);
+    let id = match (text.find('<'), text.find('>')) {
+        (Some(start), Some(end)) if start < end => &text[start + 1..end],
+        _ => text.trim(),
+    };
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+/// References header as space-separated message IDs.
+fn extract_references(message: &mail_parser::Message) -> Option<String> {
+    match message.references() {
+        mail_parser::HeaderValue::Text(t) => Some(t.to_string()),
+        mail_parser::HeaderValue::TextList(list) => {
+            if list.is_empty() {
+                None
+            } else {
+                Some(list.iter().map(|s| s.as_ref()).collect::<Vec<_>>().join(" "))
+            }
+        }
+        _ => None,
+    }
+}
+
 /// Build a mapping from mail-parser part index → IMAP MIME section path string.
 ///
 /// IMAP section numbering: children of a multipart container are numbered 1, 2, 3, ...
@@ -1778,3 +4085,289 @@ fn format_address_list(addr: Option<&mail_parser::Address>) -> Option<String> {
         Some(parts.join(", "))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_criteria() {
+        let query = ImapSearchQuery {
+            from: Some("alice@example.com".into()),
+            subject: Some("say \"hi\"".into()),
+            since: Some(1_707_264_000), // 2024-02-07
+            is_read: Some(false),
+            larger: Some(1024),
+            keywords: vec!["$Important".into()],
+            ..Default::default()
+        };
+        let (criteria, utf8) = build_search_criteria(&query, false).unwrap();
+        assert_eq!(
+            criteria,
+            r#"FROM "alice@example.com" SUBJECT "say \"hi\"" SINCE 7-Feb-2024 UNSEEN LARGER 1024 KEYWORD $Important"#
+        );
+        assert!(!utf8);
+
+        let (criteria, _) = build_search_criteria(&ImapSearchQuery::default(), false).unwrap();
+        assert_eq!(criteria, "ALL");
+    }
+
+    #[test]
+    fn test_search_criteria_utf8_literal() {
+        let query = ImapSearchQuery {
+            body: Some("café".into()),
+            ..Default::default()
+        };
+        assert_eq!(build_search_criteria(&query, false).unwrap(), ("BODY {5}\r\ncafé".to_string(), true));
+        assert_eq!(build_search_criteria(&query, true).unwrap(), ("BODY {5+}\r\ncafé".to_string(), true));
+
+        let bad = ImapSearchQuery {
+            keywords: vec!["two words".into()],
+            ..Default::default()
+        };
+        assert!(build_search_criteria(&bad, false).is_err());
+    }
+
+    #[test]
+    fn test_gmail_search_and_labels() {
+        let query = ImapSearchQuery {
+            gmail_raw: Some("has:attachment older_than:1y".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_search_criteria(&query, false).unwrap(),
+            (r#"X-GM-RAW "has:attachment older_than:1y""#.to_string(), false)
+        );
+
+        assert_eq!(encode_gmail_label("\\Important").unwrap(), "\\Important");
+        assert_eq!(encode_gmail_label("Work/Receipts").unwrap(), r#""Work/Receipts""#);
+        assert!(encode_gmail_label("").is_err());
+        assert!(encode_gmail_label("\\Not valid").is_err());
+    }
+
+    #[test]
+    fn test_sort_program() {
+        assert_eq!(build_sort_program(&[]).unwrap(), "REVERSE ARRIVAL");
+        let keys = [
+            ImapSortKey { key: "date".into(), reverse: true },
+            ImapSortKey { key: "SUBJECT".into(), reverse: false },
+        ];
+        assert_eq!(build_sort_program(&keys).unwrap(), "REVERSE DATE SUBJECT");
+        assert!(build_sort_program(&[ImapSortKey { key: "THREAD".into(), reverse: false }]).is_err());
+    }
+
+    #[test]
+    fn test_parse_thread_list() {
+        fn uids(node: &ImapThreadNode) -> String {
+            let uid = node.uid.map_or("-".to_string(), |u| u.to_string());
+            match node.children.as_slice() {
+                [] => uid,
+                children => format!("{uid}[{}]", children.iter().map(uids).collect::<Vec<_>>().join(",")),
+            }
+        }
+
+        let threads = parse_thread_list("(2)(3 6 (4 23)(44 7 96))((5)(8))").unwrap();
+        let rendered: Vec<_> = threads.iter().map(uids).collect();
+        assert_eq!(rendered, ["2", "3[6[4[23],44[7[96]]]]", "-[5,8]"]);
+
+        assert_eq!(parse_thread_list("").unwrap().len(), 0);
+        assert!(parse_thread_list("(1 (2) 3)").is_err());
+        assert!(parse_thread_list("(1").is_err());
+    }
+
+    #[test]
+    fn test_parse_raw_fetch() {
+        let wire = b"* 3 FETCH (FLAGS (\\Seen Sent) INTERNALDATE \"16-Feb-2026 12:00:00 +0000\" \
+X-NOTE \"not UID 9)\" UID 42 BODY[] {5}\r\nHello)\r\n";
+        let values = response::tokenize(wire).unwrap();
+        let message = parse_raw_fetch(values[3].as_list().unwrap()).unwrap();
+        assert_eq!(message.uid, 42);
+        assert!(message.flags.is_read);
+        assert_eq!(message.flags.keywords, ["Sent"]);
+        assert_eq!(message.internal_date, Some(1_771_243_200));
+        assert_eq!(message.body, b"Hello");
+
+        let values = response::tokenize(b"* 4 FETCH (UID 44 RFC822.SIZE 2048 BODY[HEADER] {6}\r\nA: b\r\n)").unwrap();
+        let message = parse_raw_fetch(values[3].as_list().unwrap()).unwrap();
+        assert_eq!(message.size, Some(2048));
+        assert_eq!(message.body, b"A: b\r\n");
+
+        let values = response::tokenize(b"* 4 FETCH (UID 43 FLAGS ())").unwrap();
+        assert!(parse_raw_fetch(values[3].as_list().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_child_path() {
+        assert_eq!(child_path(None, "Archive", Some("/")).unwrap(), "Archive");
+        assert_eq!(child_path(Some("INBOX"), "Receipts", Some(".")).unwrap(), "INBOX.Receipts");
+        assert!(child_path(Some("INBOX"), "a.b", Some(".")).is_err());
+        assert!(child_path(Some("INBOX"), "Receipts", None).is_err());
+        assert!(child_path(None, "", Some("/")).is_err());
+    }
+
+    #[test]
+    fn test_normalize_flag() {
+        assert_eq!(normalize_flag("Seen").unwrap(), "\\Seen");
+        assert_eq!(normalize_flag("\\flagged").unwrap(), "\\Flagged");
+        assert_eq!(normalize_flag("$Forwarded").unwrap(), "$Forwarded");
+        assert_eq!(normalize_flag("Work").unwrap(), "Work");
+        assert!(normalize_flag("\\Recent").is_err());
+        assert!(normalize_flag("two words").is_err());
+    }
+
+    #[test]
+    fn test_flag_permitted() {
+        let fixed = vec!["\\Seen".to_string(), "$Junk".to_string()];
+        assert!(flag_permitted("\\Seen", Some(&fixed)));
+        assert!(flag_permitted("$junk", Some(&fixed)));
+        assert!(!flag_permitted("Work", Some(&fixed)));
+
+        let open = vec!["\\Seen".to_string(), "\\*".to_string()];
+        assert!(flag_permitted("Work", Some(&open)));
+        assert!(!flag_permitted("\\Deleted", Some(&open)));
+
+        assert!(!flag_permitted("\\Seen", Some(&[])));
+        assert!(flag_permitted("Work", None));
+    }
+
+    #[test]
+    fn test_parse_permanent_flags() {
+        assert_eq!(
+            parse_permanent_flags("OK [PERMANENTFLAGS (\\Seen \\Deleted \\*)] Limited").unwrap(),
+            ["\\Seen", "\\Deleted", "\\*"]
+        );
+        assert_eq!(parse_permanent_flags("OK [PERMANENTFLAGS ()] No permanent flags").unwrap(), Vec::<String>::new());
+        assert!(parse_permanent_flags("OK [UIDVALIDITY 3857529045] UIDs valid").is_none());
+    }
+
+    #[test]
+    fn test_parse_capabilities() {
+        let names = ["IMAP4rev1", "AUTH=PLAIN", "AUTH=XOAUTH2", "move", "QRESYNC", "THREAD=REFERENCES", "APPENDLIMIT=35651584"];
+        let caps = parse_capabilities(names.iter().map(|n| n.to_string()));
+        assert!(caps.r#move);
+        assert!(caps.qresync && caps.condstore);
+        assert!(!caps.idle);
+        assert_eq!(caps.thread, vec!["REFERENCES"]);
+        assert_eq!(caps.auth_mechanisms, vec!["PLAIN", "XOAUTH2"]);
+        assert_eq!(caps.append_limit, Some(35_651_584));
+        assert_eq!(caps.raw.len(), names.len());
+    }
+
+    #[test]
+    fn test_greeting_capabilities() {
+        let greeting = "* OK [CAPABILITY IMAP4rev1 SASL-IR AUTH=PLAIN LOGINDISABLED] Dovecot ready.\r\n";
+        assert_eq!(
+            greeting_capabilities(greeting).unwrap(),
+            ["IMAP4rev1", "SASL-IR", "AUTH=PLAIN", "LOGINDISABLED"]
+        );
+        assert!(greeting_capabilities("* OK IMAP4rev1 Service Ready\r\n").is_none());
+    }
+
+    #[test]
+    fn test_expunged_uids() {
+        // Targets at sequence numbers 2, 4 and 5; a foreign message at 3.
+        let targets = [(2, 20), (4, 40), (5, 50)];
+        // Expunging 2 shifts 4 and 5 down to 3 and 4; then 3 is UID 40.
+        assert_eq!(expunged_uids(&targets, &[2, 3]), vec![20, 40]);
+        assert_eq!(expunged_uids(&targets, &[3, 3]), vec![40]);
+        assert!(expunged_uids(&targets, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_response_code() {
+        assert_eq!(parse_response_code("OK [COPYUID 38505 304,319:320 3956:3958] Done", "COPYUID"), Some("38505 304,319:320 3956:3958"));
+        assert_eq!(parse_response_code("OK [APPENDUID 38505 3955] APPEND completed", "appenduid"), Some("38505 3955"));
+        assert_eq!(parse_response_code("OK [READ-WRITE] Done", "COPYUID"), None);
+        assert_eq!(parse_response_code("OK Done", "COPYUID"), None);
+    }
+
+    #[test]
+    fn test_split_at_sync_literals() {
+        let command = b"V1 APPEND INBOX {3}\r\nabc\r\n";
+        assert_eq!(split_at_sync_literals(command), vec![&b"V1 APPEND INBOX {3}\r\n"[..], &b"abc\r\n"[..]]);
+        let command = b"V1 APPEND INBOX {3+}\r\nabc\r\n";
+        assert_eq!(split_at_sync_literals(command), vec![&command[..]]);
+    }
+
+    #[test]
+    fn test_raw_login_command() {
+        let config: ImapConfig = serde_json::from_value(serde_json::json!({
+            "host": "imap.example.com", "port": 993, "security": "tls",
+            "username": "user", "password": "pässword", "auth_method": "password",
+        }))
+        .unwrap();
+        let caps = |names: &[&str]| names.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+
+        let segments = raw_login_command(&config, &caps(&["IMAP4rev1"])).unwrap();
+        assert_eq!(segments, vec![b"a1 LOGIN \"user\" {9}\r\n".to_vec(), "pässword\r\n".as_bytes().to_vec()]);
+        let segments = raw_login_command(&config, &caps(&["IMAP4rev1", "LITERAL+"])).unwrap();
+        assert_eq!(segments, vec!["a1 LOGIN \"user\" {9+}\r\npässword\r\n".as_bytes().to_vec()]);
+
+        let config = ImapConfig { auth_method: "oauth2".to_string(), ..config };
+        let segments = raw_login_command(&config, &caps(&["AUTH=XOAUTH2"])).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0], b"a1 AUTHENTICATE XOAUTH2\r\n");
+        let segments = raw_login_command(&config, &caps(&["AUTH=XOAUTH2", "SASL-IR"])).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0].starts_with(b"a1 AUTHENTICATE XOAUTH2 "));
+    }
+
+    #[test]
+    fn test_take_within_budget() {
+        let sizes = std::collections::HashMap::from([(1, 400), (2, 300), (3, 2_000), (4, 100)]);
+        assert_eq!(take_within_budget(&[1, 2, 3, 4], &sizes, 1_000), (vec![1, 2, 4], vec![3], 800));
+        // An oversized message on its own still goes through.
+        assert_eq!(take_within_budget(&[3, 1], &sizes, 1_000), (vec![3], vec![1], 2_000));
+        // Unknown UIDs are dropped.
+        assert_eq!(take_within_budget(&[9, 4], &sizes, 1_000), (vec![4], vec![], 100));
+    }
+
+    #[test]
+    fn test_expand_uid_set() {
+        assert_eq!(expand_uid_set("4:7,12").unwrap(), vec![4, 5, 6, 7, 12]);
+        assert!(expand_uid_set("4:*").is_err());
+    }
+
+    #[test]
+    fn test_raw_fetched_message() {
+        let response = RawUntagged {
+            text: "* 1 FETCH (UID 7 FLAGS (\\Seen Sent) RFC822.SIZE 5 BODY[] {5}\r\n)".to_string(),
+            literals: vec![b"Hi!\r\n".to_vec()],
+        };
+        assert_eq!(response.to_bytes(), b"* 1 FETCH (UID 7 FLAGS (\\Seen Sent) RFC822.SIZE 5 BODY[] {5}\r\nHi!\r\n)");
+        let message = raw_fetched_message(&response).unwrap();
+        assert_eq!(message.uid, 7);
+        assert_eq!(message.size, Some(5));
+        assert_eq!(message.body, b"Hi!\r\n");
+
+        let exists = RawUntagged { text: "* 3 EXISTS".to_string(), literals: Vec::new() };
+        assert!(raw_fetched_message(&exists).is_none());
+    }
+
+    #[test]
+    fn test_command_errors() {
+        use async_imap::error::Error;
+
+        let e = failed("UID FETCH Certificates", Error::ConnectionLost);
+        assert_eq!(e.kind, ErrorKind::Network);
+
+        let no = |info: &str| Error::No(format!("code: None, info: Some({info:?})"));
+        let e = select_failed("Quota reports", no("Unknown Mailbox"));
+        assert_eq!(e.kind, ErrorKind::FolderNotFound);
+        assert_eq!(e.server_response.as_deref(), Some("Unknown Mailbox"));
+        let e = failed("APPEND to Quota reports", no("Mailbox is locked"));
+        assert_eq!(e.kind, ErrorKind::Other);
+        let e = failed("APPEND to Sent", no("[OVERQUOTA] Quota exceeded"));
+        assert_eq!(e.kind, ErrorKind::QuotaExceeded);
+        let e = command_failed("Login", no("Invalid credentials"), ErrorKind::AuthFailed);
+        assert_eq!(e.kind, ErrorKind::AuthFailed);
+
+        let e = raw_status_failed("SELECT Archive", "NO [NONEXISTENT] Unknown Mailbox", ErrorKind::FolderNotFound);
+        assert_eq!(e.kind, ErrorKind::FolderNotFound);
+        let e = raw_status_failed("SELECT Archive", "BAD Command syntax error", ErrorKind::FolderNotFound);
+        assert_eq!(e.kind, ErrorKind::Other);
+
+        let e = timed_out("UID FETCH Quota reports", IMAP_FETCH_TIMEOUT);
+        assert_eq!(e.kind, ErrorKind::Timeout { stage: "UID FETCH".to_string() });
+    }
+}
@@ -0,0 +1,362 @@
+use std::path::{Component, Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::io::AsyncWriteExt;
+
+use super::types::{DownloadProgressEvent, DownloadResult};
+use crate::error::{ErrorKind, MailError};
+
+const DOWNLOAD_PROGRESS_EVENT: &str = "imap-download-progress";
+/// Emit at most one progress event per this many bytes received.
+const PROGRESS_STEP: u64 = 256 * 1024;
+/// Subdirectory of the app-data dir used for `cache_key` downloads.
+const CACHE_DIR: &str = "attachments";
+
+/// Resolve where a download goes: an explicit `destination` path (e.g. from a
+/// save dialog), which must be in the downloads or app-data folder, or
+/// `cache_key` under the app-data attachment cache.
+pub fn resolve_target(
+    app: &AppHandle,
+    destination: Option<String>,
+    cache_key: Option<String>,
+) -> Result<PathBuf, MailError> {
+    let app_data = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| MailError::new(ErrorKind::FileError, format!("Failed to resolve app data dir: {e}")))?;
+
+    match (destination, cache_key) {
+        (Some(destination), _) => {
+            let mut roots = vec![app_data];
+            roots.extend(app.path().download_dir().ok());
+            confine_destination(Path::new(&destination), &roots)
+        }
+        (None, Some(key)) => {
+            let valid = !key.is_empty()
+                && key != "."
+                && key != ".."
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+            if !valid {
+                return Err(MailError::new(ErrorKind::Other, format!("Invalid cache key {key:?}")));
+            }
+            Ok(app_data.join(CACHE_DIR).join(key))
+        }
+        (None, None) => Err(MailError::new(ErrorKind::Other, "Either a destination or a cache key is required")),
+    }
+}
+
+/// Check that `destination` names a file inside one of `roots` once `..` and
+/// symlinks are taken into account, and return it with its folder
+/// canonicalised. The folder must exist, and the file itself must not be a
+/// symlink.
+fn confine_destination(destination: &Path, roots: &[PathBuf]) -> Result<PathBuf, MailError> {
+    let outside = || {
+        MailError::new(
+            ErrorKind::PermissionDenied,
+            format!("{} is outside the folders downloads can be saved to", destination.display()),
+        )
+    };
+
+    if !destination.is_absolute() || destination.components().any(|c| c == Component::ParentDir) {
+        return Err(outside());
+    }
+    let (Some(parent), Some(name)) = (destination.parent(), destination.file_name()) else {
+        return Err(outside());
+    };
+
+    let parent = std::fs::canonicalize(parent)
+        .map_err(|e| MailError::file(&e, format!("Failed to open {}: {e}", parent.display())))?;
+    let inside = roots
+        .iter()
+        .filter_map(|root| std::fs::canonicalize(root).ok())
+        .any(|root| parent.starts_with(root));
+    if !inside {
+        return Err(outside());
+    }
+
+    let path = parent.join(name);
+    if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink()) {
+        return Err(outside());
+    }
+    Ok(path)
+}
+
+/// Content-Transfer-Encoding decoder that works on arbitrary chunks of the
+/// encoded data, carrying incomplete input over to the next chunk.
+pub(crate) enum TransferDecoder {
+    Identity,
+    Base64 { carry: Vec<u8> },
+    QuotedPrintable { carry: Vec<u8> },
+}
+
+impl TransferDecoder {
+    pub fn for_encoding(encoding: &str) -> Self {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "base64" => Self::Base64 { carry: Vec::new() },
+            "quoted-printable" => Self::QuotedPrintable { carry: Vec::new() },
+            _ => Self::Identity,
+        }
+    }
+
+    pub fn decode(&mut self, chunk: &[u8]) -> Result<Vec<u8>, MailError> {
+        match self {
+            Self::Identity => Ok(chunk.to_vec()),
+            Self::Base64 { carry } => {
+                carry.extend(chunk.iter().filter(|b| !b.is_ascii_whitespace()));
+                // Decode whole 4-character groups only.
+                let complete = carry.len() - carry.len() % 4;
+                let rest = carry.split_off(complete);
+                let decoded = mail_parser::decoders::base64::base64_decode(carry)
+                    .ok_or_else(|| invalid_encoding("base64"))?;
+                *carry = rest;
+                Ok(decoded)
+            }
+            Self::QuotedPrintable { carry } => {
+                carry.extend_from_slice(chunk);
+                // Decode whole lines only, so `=XX` escapes and soft line
+                // breaks are never split.
+                let Some(end) = carry.iter().rposition(|&b| b == b'\n') else {
+                    return Ok(Vec::new());
+                };
+                let rest = carry.split_off(end + 1);
+                let decoded = mail_parser::decoders::quoted_printable::quoted_printable_decode(carry)
+                    .ok_or_else(|| invalid_encoding("quoted-printable"))?;
+                *carry = rest;
+                Ok(decoded)
+            }
+        }
+    }
+
+    /// Decode whatever input is left at the end of the data.
+    pub fn finish(&mut self) -> Result<Vec<u8>, MailError> {
+        match self {
+            Self::Identity => Ok(Vec::new()),
+            Self::Base64 { carry } if carry.is_empty() => Ok(Vec::new()),
+            Self::Base64 { carry } => mail_parser::decoders::base64::base64_decode(&std::mem::take(carry))
+                .ok_or_else(|| invalid_encoding("base64")),
+            Self::QuotedPrintable { carry } if carry.is_empty() => Ok(Vec::new()),
+            Self::QuotedPrintable { carry } => {
+                mail_parser::decoders::quoted_printable::quoted_printable_decode(&std::mem::take(carry))
+                    .ok_or_else(|| invalid_encoding("quoted-printable"))
+            }
+        }
+    }
+}
+
+fn invalid_encoding(encoding: &str) -> MailError {
+    MailError::new(ErrorKind::ProtocolParse, format!("Invalid {encoding} data"))
+}
+
+/// Destination of a streamed download: decodes, hashes and writes chunks to a
+/// temporary file next to the target, which is renamed into place by
+/// `finish`. Dropping an unfinished sink removes the temporary file.
+pub(crate) struct DownloadSink {
+    app: AppHandle,
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: Option<tokio::fs::File>,
+    decoder: TransferDecoder,
+    hasher: Sha256,
+    size: u64,
+    received: u64,
+    reported: u64,
+    total: Option<u64>,
+    completed: bool,
+}
+
+impl DownloadSink {
+    pub async fn create(app: &AppHandle, path: PathBuf) -> Result<Self, MailError> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| MailError::file(&e, format!("Failed to create {}: {e}", parent.display())))?;
+        }
+
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".part");
+        let temp_path = path.with_file_name(temp_name);
+        let file = tokio::fs::File::create(&temp_path)
+            .await
+            .map_err(|e| MailError::file(&e, format!("Failed to create {}: {e}", temp_path.display())))?;
+
+        Ok(Self {
+            app: app.clone(),
+            path,
+            temp_path,
+            file: Some(file),
+            decoder: TransferDecoder::Identity,
+            hasher: Sha256::new(),
+            size: 0,
+            received: 0,
+            reported: 0,
+            total: None,
+            completed: false,
+        })
+    }
+
+    /// Content-Transfer-Encoding of the data that will be written.
+    pub fn set_encoding(&mut self, encoding: &str) {
+        self.decoder = TransferDecoder::for_encoding(encoding);
+    }
+
+    /// Expected number of bytes on the wire, for progress reporting.
+    pub fn set_total(&mut self, total: u64) {
+        self.total = Some(total);
+        self.emit_progress();
+    }
+
+    /// Write a chunk of (still encoded) data as received from the server.
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), MailError> {
+        let decoded = self.decoder.decode(chunk)?;
+        self.write_decoded(&decoded).await?;
+
+        self.received += chunk.len() as u64;
+        if self.received - self.reported >= PROGRESS_STEP {
+            self.emit_progress();
+        }
+        Ok(())
+    }
+
+    /// Flush the decoder, move the file into place and return its details.
+    pub async fn finish(mut self) -> Result<DownloadResult, MailError> {
+        let tail = self.decoder.finish()?;
+        self.write_decoded(&tail).await?;
+
+        let mut file = self.file.take().ok_or_else(already_finished)?;
+        file.flush()
+            .await
+            .map_err(|e| MailError::file(&e, format!("Failed to write {}: {e}", self.temp_path.display())))?;
+        drop(file);
+
+        tokio::fs::rename(&self.temp_path, &self.path)
+            .await
+            .map_err(|e| MailError::file(&e, format!("Failed to move download to {}: {e}", self.path.display())))?;
+        self.completed = true;
+
+        self.emit_progress();
+
+        Ok(DownloadResult {
+            path: self.path.to_string_lossy().to_string(),
+            sha256: format!("{:x}", std::mem::take(&mut self.hasher).finalize()),
+            size: self.size,
+        })
+    }
+
+    async fn write_decoded(&mut self, data: &[u8]) -> Result<(), MailError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let file = self.file.as_mut().ok_or_else(already_finished)?;
+        file.write_all(data)
+            .await
+            .map_err(|e| MailError::file(&e, format!("Failed to write {}: {e}", self.temp_path.display())))?;
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    fn emit_progress(&mut self) {
+        self.reported = self.received;
+        let event = DownloadProgressEvent {
+            path: self.path.to_string_lossy().to_string(),
+            downloaded: self.received,
+            total: self.total,
+        };
+        if let Err(e) = self.app.emit(DOWNLOAD_PROGRESS_EVENT, event) {
+            log::warn!("Failed to emit {DOWNLOAD_PROGRESS_EVENT}: {e}");
+        }
+    }
+}
+
+fn already_finished() -> MailError {
+    MailError::new(ErrorKind::Other, "Download already finished")
+}
+
+impl Drop for DownloadSink {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.file.take();
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_in_chunks(encoding: &str, data: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut decoder = TransferDecoder::for_encoding(encoding);
+        let mut out = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            out.extend(decoder.decode(chunk).unwrap());
+        }
+        out.extend(decoder.finish().unwrap());
+        out
+    }
+
+    #[test]
+    fn test_base64_split_across_chunks() {
+        let encoded = b"SGVsbG8sIHdv\r\ncmxkIQ==\r\n";
+        for chunk_size in 1..encoded.len() {
+            assert_eq!(decode_in_chunks("base64", encoded, chunk_size), b"Hello, world!");
+        }
+    }
+
+    #[test]
+    fn test_quoted_printable_split_across_chunks() {
+        let encoded = b"caf=C3=A9 au =\r\nlait\r\nsecond line";
+        for chunk_size in 1..encoded.len() {
+            assert_eq!(
+                decode_in_chunks("Quoted-Printable", encoded, chunk_size),
+                "café au lait\r\nsecond line".as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_identity_passthrough() {
+        assert_eq!(decode_in_chunks("7bit", b"plain text", 3), b"plain text");
+    }
+
+    #[test]
+    fn test_destination_confined_to_roots() {
+        let base = std::env::temp_dir().join(format!("velo-download-test-{}", std::process::id()));
+        let downloads = base.join("Downloads");
+        let elsewhere = base.join("elsewhere");
+        std::fs::create_dir_all(downloads.join("sub")).unwrap();
+        std::fs::create_dir_all(&elsewhere).unwrap();
+        let roots = [downloads.clone()];
+
+        let ok = confine_destination(&downloads.join("sub").join("a.pdf"), &roots).unwrap();
+        assert!(ok.ends_with("sub/a.pdf"));
+
+        for escape in [
+            elsewhere.join("a.pdf"),
+            downloads.join("..").join("elsewhere").join("a.pdf"),
+            PathBuf::from("relative.pdf"),
+        ] {
+            let e = confine_destination(&escape, &roots).unwrap_err();
+            assert_eq!(e.kind, ErrorKind::PermissionDenied, "{}", escape.display());
+        }
+
+        let e = confine_destination(&downloads.join("missing").join("a.pdf"), &roots).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::FileError);
+
+        #[cfg(unix)]
+        {
+            // A symlinked folder or file pointing out of the root.
+            std::os::unix::fs::symlink(&elsewhere, downloads.join("link")).unwrap();
+            std::os::unix::fs::symlink(elsewhere.join("a.pdf"), downloads.join("b.pdf")).unwrap();
+            for escape in [downloads.join("link").join("a.pdf"), downloads.join("b.pdf")] {
+                let e = confine_destination(&escape, &roots).unwrap_err();
+                assert_eq!(e.kind, ErrorKind::PermissionDenied, "{}", escape.display());
+            }
+        }
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
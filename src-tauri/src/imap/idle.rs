@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_imap::extensions::idle::IdleResponse;
+use async_imap::imap_proto::{AttributeValue, MailboxDatum, Response};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::watch;
+
+use super::client::{self, ImapSession};
+use super::pool::ImapPool;
+use super::types::{IdleStoppedEvent, ImapConfig, ImapFolderStatus, MailboxChangedEvent};
+use crate::error::{ErrorKind, MailError};
+
+const MAILBOX_CHANGED_EVENT: &str = "imap-mailbox-changed";
+const IDLE_STOPPED_EVENT: &str = "imap-idle-stopped";
+
+// ---------- Timing ----------
+
+/// RFC 2177 §3: servers may drop a client idling for 30 minutes, so IDLE is
+/// re-issued before that.
+const IDLE_REARM_INTERVAL: Duration = Duration::from_secs(29 * 60);
+/// STATUS poll interval for folders watched without IDLE.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// Folders per account watched with IDLE, each on its own connection;
+/// further folders are polled. These connections are opened outside the pool
+/// and don't count against its per-server cap, so an account can hold this
+/// many on top of the pool's. Keep the sum under per-user limits such as
+/// Dovecot's `mail_max_userip_connections` (10 by default).
+const MAX_IDLE_PER_ACCOUNT: usize = 4;
+const IDLE_CMD_TIMEOUT: Duration = Duration::from_secs(30);
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(5);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Long-lived IDLE watchers, one per account and folder, kept in Tauri
+/// managed state.
+///
+/// Up to `MAX_IDLE_PER_ACCOUNT` watchers per account hold their own
+/// connection (an IDLEing session can't run other commands, so it is not
+/// taken from the pool); the rest, and every watcher on a server without
+/// IDLE, poll STATUS through the pool instead. Either way they emit
+/// `imap-mailbox-changed` events as the server reports changes.
+#[derive(Default)]
+pub struct IdleManager {
+    watchers: Mutex<Watchers>,
+}
+
+type Watchers = HashMap<(String, String), WatcherHandle>;
+
+struct WatcherHandle {
+    stop: watch::Sender<bool>,
+    idle: bool, // holds an IDLE connection rather than polling
+    config: ImapConfig,
+}
+
+impl IdleManager {
+    /// Start watching `folders` for an account. A folder that is already
+    /// watched is restarted with the new config (e.g. a refreshed OAuth token).
+    pub fn start(&self, app: &AppHandle, account_id: &str, config: &ImapConfig, folders: &[String]) {
+        let Ok(mut watchers) = self.watchers.lock() else {
+            return;
+        };
+
+        for folder in folders {
+            spawn_watcher(&mut watchers, app, account_id, folder, config);
+        }
+    }
+
+    /// Restart an account's watchers with a new config, e.g. after its TLS or
+    /// proxy settings change, keeping the folders they watch.
+    pub fn reconfigure(&self, app: &AppHandle, account_id: &str, config: &ImapConfig) {
+        let Ok(mut watchers) = self.watchers.lock() else {
+            return;
+        };
+
+        let folders: Vec<String> = watchers
+            .keys()
+            .filter(|(account, _)| account == account_id)
+            .map(|(_, folder)| folder.clone())
+            .collect();
+        for folder in &folders {
+            spawn_watcher(&mut watchers, app, account_id, folder, config);
+        }
+    }
+
+    /// Restart every watcher with its own config, so the connections are
+    /// opened again after the global proxy changes.
+    pub fn reconnect_all(&self, app: &AppHandle) {
+        let Ok(mut watchers) = self.watchers.lock() else {
+            return;
+        };
+
+        let all: Vec<(String, String, ImapConfig)> = watchers
+            .iter()
+            .map(|((account, folder), watcher)| (account.clone(), folder.clone(), watcher.config.clone()))
+            .collect();
+        for (account_id, folder, config) in &all {
+            spawn_watcher(&mut watchers, app, account_id, folder, config);
+        }
+    }
+
+    /// Drop the entry of a watcher that stopped on its own, unless the folder
+    /// has been restarted with a new watcher since.
+    fn forget(&self, account_id: &str, folder: &str, stop: &watch::Receiver<bool>) {
+        let Ok(mut watchers) = self.watchers.lock() else {
+            return;
+        };
+
+        let key = (account_id.to_string(), folder.to_string());
+        if watchers.get(&key).is_some_and(|watcher| watcher.stop.subscribe().same_channel(stop)) {
+            watchers.remove(&key);
+        }
+    }
+
+    /// Stop the watcher for one folder, or all of an account's watchers when
+    /// `folder` is `None`.
+    pub fn stop(&self, account_id: &str, folder: Option<&str>) {
+        let Ok(mut watchers) = self.watchers.lock() else {
+            return;
+        };
+
+        watchers.retain(|(account, watched), watcher| {
+            let matches = account == account_id && folder.map_or(true, |f| f == watched);
+            if matches {
+                let _ = watcher.stop.send(true);
+            }
+            !matches
+        });
+    }
+}
+
+/// Start a watcher for `folder`, stopping the one it replaces.
+fn spawn_watcher(watchers: &mut Watchers, app: &AppHandle, account_id: &str, folder: &str, config: &ImapConfig) {
+    let key = (account_id.to_string(), folder.to_string());
+    if let Some(previous) = watchers.remove(&key) {
+        let _ = previous.stop.send(true);
+    }
+
+    let idle_in_use = watchers
+        .iter()
+        .filter(|((account, _), watcher)| account == account_id && watcher.idle)
+        .count();
+    let idle = idle_in_use < MAX_IDLE_PER_ACCOUNT;
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    watchers.insert(
+        key,
+        WatcherHandle {
+            stop: stop_tx,
+            idle,
+            config: config.clone(),
+        },
+    );
+
+    let watcher = Watcher {
+        app: app.clone(),
+        account_id: account_id.to_string(),
+        folder: folder.to_string(),
+        config: config.clone(),
+        idle,
+        stop: stop_rx,
+    };
+    tauri::async_runtime::spawn(watcher.run());
+}
+
+struct Watcher {
+    app: AppHandle,
+    account_id: String,
+    folder: String,
+    config: ImapConfig,
+    idle: bool,
+    stop: watch::Receiver<bool>,
+}
+
+impl Watcher {
+    /// Watch until stopped, reconnecting with exponential backoff whenever the
+    /// connection drops. Errors reconnecting can't fix, such as rejected
+    /// credentials or certificate, stop the watcher and emit
+    /// `imap-idle-stopped` instead.
+    async fn run(mut self) {
+        if !self.idle {
+            log::info!("IMAP polling {} {} every {}s", self.account_id, self.folder, POLL_INTERVAL.as_secs());
+            self.status_poll_loop().await;
+            log::info!("IMAP IDLE watcher for {} {} stopped", self.account_id, self.folder);
+            return;
+        }
+
+        let mut delay = RECONNECT_MIN_DELAY;
+        let mut reconnecting = false;
+
+        while !*self.stop.borrow() {
+            match self.watch_once(reconnecting, &mut delay).await {
+                Ok(()) => break,
+                Err(e) if matches!(e.kind, ErrorKind::AuthFailed | ErrorKind::TlsError) => {
+                    log::warn!("IMAP IDLE {} {}: {e} — giving up", self.account_id, self.folder);
+                    self.give_up(e);
+                    break;
+                }
+                Err(e) => log::warn!(
+                    "IMAP IDLE {} {}: {e} — reconnecting in {}s",
+                    self.account_id,
+                    self.folder,
+                    delay.as_secs()
+                ),
+            }
+
+            if self.sleep(delay).await {
+                break;
+            }
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            reconnecting = true;
+        }
+
+        log::info!("IMAP IDLE watcher for {} {} stopped", self.account_id, self.folder);
+    }
+
+    /// Connect, SELECT the folder and watch it until stopped (`Ok`) or the
+    /// connection fails (`Err`).
+    async fn watch_once(&mut self, reconnecting: bool, delay: &mut Duration) -> Result<(), MailError> {
+        let mut session = client::connect(&self.config).await?;
+
+        let supports_idle = client::get_capabilities(&mut session).await?.idle;
+
+        let mailbox = tokio::time::timeout(IDLE_CMD_TIMEOUT, session.select(&self.folder))
+            .await
+            .map_err(|_| client::timed_out(format!("SELECT {}", self.folder), IDLE_CMD_TIMEOUT))?
+            .map_err(|e| client::select_failed(&self.folder, e))?;
+
+        *delay = RECONNECT_MIN_DELAY;
+
+        // Anything could have changed while we were disconnected.
+        if reconnecting {
+            self.emit(MailboxChangedEvent {
+                exists: Some(mailbox.exists),
+                ..self.event("resync")
+            });
+        }
+
+        if supports_idle {
+            log::info!("IMAP IDLE watching {} {}", self.account_id, self.folder);
+            self.idle_loop(session).await
+        } else {
+            log::info!(
+                "IMAP server for {} lacks IDLE — polling {} every {}s",
+                self.account_id,
+                self.folder,
+                POLL_INTERVAL.as_secs()
+            );
+            // Don't hold a connection just to poll.
+            let _ = tokio::time::timeout(IDLE_CMD_TIMEOUT, session.logout()).await;
+            self.status_poll_loop().await;
+            Ok(())
+        }
+    }
+
+    async fn idle_loop(&mut self, mut session: ImapSession) -> Result<(), MailError> {
+        loop {
+            let mut handle = session.idle();
+            tokio::time::timeout(IDLE_CMD_TIMEOUT, handle.init())
+                .await
+                .map_err(|_| client::timed_out("IDLE", IDLE_CMD_TIMEOUT))?
+                .map_err(|e| client::failed("IDLE", e))?;
+            let armed_at = Instant::now();
+
+            let stopped = loop {
+                let remaining = IDLE_REARM_INTERVAL.saturating_sub(armed_at.elapsed());
+                if remaining.is_zero() {
+                    break false;
+                }
+
+                // `_interrupt` must outlive the wait: dropping it ends the IDLE.
+                let (wait, _interrupt) = handle.wait_with_timeout(remaining);
+                let response = tokio::select! {
+                    r = wait => Some(r.map_err(|e| client::failed("IDLE wait", e))?),
+                    _ = self.stop.changed() => None,
+                };
+
+                match response {
+                    None => break true,
+                    Some(IdleResponse::NewData(data)) => self.emit_response(data.parsed()),
+                    Some(IdleResponse::Timeout) | Some(IdleResponse::ManualInterrupt) => {
+                        break false
+                    }
+                }
+            };
+
+            session = tokio::time::timeout(IDLE_CMD_TIMEOUT, handle.done())
+                .await
+                .map_err(|_| client::timed_out("IDLE DONE", IDLE_CMD_TIMEOUT))?
+                .map_err(|e| client::failed("IDLE DONE", e))?;
+
+            if stopped {
+                let _ = tokio::time::timeout(IDLE_CMD_TIMEOUT, session.logout()).await;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Poll the folder's STATUS through the pool until stopped, for servers
+    /// without IDLE and folders beyond `MAX_IDLE_PER_ACCOUNT`. No connection
+    /// is held between polls.
+    async fn status_poll_loop(&mut self) {
+        let mut last: Option<ImapFolderStatus> = None;
+        loop {
+            match self.poll_status().await {
+                Ok(status) => {
+                    if let Some(previous) = &last {
+                        if status.exists != previous.exists {
+                            self.emit(MailboxChangedEvent {
+                                exists: Some(status.exists),
+                                ..self.event("exists")
+                            });
+                        } else if status.uidnext != previous.uidnext
+                            || status.uidvalidity != previous.uidvalidity
+                            || status.unseen != previous.unseen
+                        {
+                            self.emit(MailboxChangedEvent {
+                                exists: Some(status.exists),
+                                ..self.event("resync")
+                            });
+                        }
+                    }
+                    last = Some(status);
+                }
+                Err(e) => log::warn!("IMAP poll {} {}: {e}", self.account_id, self.folder),
+            }
+
+            if self.sleep(POLL_INTERVAL).await {
+                return;
+            }
+        }
+    }
+
+    async fn poll_status(&self) -> Result<ImapFolderStatus, MailError> {
+        let pool = self.app.state::<ImapPool>();
+        let mut session = pool.get(&self.config).await?;
+        let result = client::get_folder_status(&mut session, &self.folder).await;
+        session.finish(result)
+    }
+
+    /// Sleep for `duration`, returning `true` early if the watcher was stopped.
+    async fn sleep(&mut self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => *self.stop.borrow(),
+            _ = self.stop.changed() => true,
+        }
+    }
+
+    fn emit_response(&self, response: &Response<'_>) {
+        match response {
+            Response::MailboxData(MailboxDatum::Exists(n)) => self.emit(MailboxChangedEvent {
+                exists: Some(*n),
+                ..self.event("exists")
+            }),
+            Response::Expunge(seq) => self.emit(MailboxChangedEvent {
+                seq: Some(*seq),
+                ..self.event("expunge")
+            }),
+            Response::Fetch(seq, attrs) => {
+                let mut event = MailboxChangedEvent {
+                    seq: Some(*seq),
+                    ..self.event("fetch")
+                };
+                for attr in attrs {
+                    match attr {
+                        AttributeValue::Uid(uid) => event.uid = Some(*uid),
+                        AttributeValue::Flags(flags) => {
+                            event.flags = Some(flags.iter().map(|f| f.to_string()).collect())
+                        }
+                        _ => {}
+                    }
+                }
+                self.emit(event);
+            }
+            other => log::debug!("IMAP IDLE {}: ignoring {other:?}", self.folder),
+        }
+    }
+
+    fn event(&self, change: &str) -> MailboxChangedEvent {
+        MailboxChangedEvent {
+            account_id: self.account_id.clone(),
+            folder: self.folder.clone(),
+            change: change.to_string(),
+            exists: None,
+            seq: None,
+            uid: None,
+            flags: None,
+        }
+    }
+
+    /// Stop for good after `error`: the watcher is dropped from the manager
+    /// and the frontend told why, so it can ask for new credentials.
+    fn give_up(&self, error: MailError) {
+        self.app.state::<IdleManager>().forget(&self.account_id, &self.folder, &self.stop);
+        let event = IdleStoppedEvent {
+            account_id: self.account_id.clone(),
+            folder: self.folder.clone(),
+            error,
+        };
+        if let Err(e) = self.app.emit(IDLE_STOPPED_EVENT, event) {
+            log::warn!("Failed to emit {IDLE_STOPPED_EVENT}: {e}");
+        }
+    }
+
+    fn emit(&self, event: MailboxChangedEvent) {
+        if let Err(e) = self.app.emit(MAILBOX_CHANGED_EVENT, event) {
+            log::warn!("Failed to emit {MAILBOX_CHANGED_EVENT}: {e}");
+        }
+    }
+}
@@ -1,2 +1,7 @@
 pub mod client;
+pub mod download;
+pub mod idle;
+pub mod pool;
+pub mod response;
+pub mod sync;
 pub mod types;
@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::client::{self, ImapSession};
+use crate::error::{ErrorKind, MailError};
+use super::types::{ImapCapabilities, ImapConfig};
+
+// ---------- Pool limits ----------
+
+/// Maximum simultaneous connections (in use + idle) to a single server.
+/// Fastmail, Dovecot and Gmail all throttle logins well before this matters.
+const MAX_CONNECTIONS_PER_SERVER: usize = 4;
+/// Idle sessions older than this are logged out instead of reused.
+/// Servers may autologout after 30 minutes (RFC 3501 §5.4), so stay well below.
+const MAX_IDLE_AGE: Duration = Duration::from_secs(10 * 60);
+/// Sessions idle for longer than this are health-checked with NOOP before reuse.
+const HEALTH_CHECK_AFTER: Duration = Duration::from_secs(15);
+const NOOP_TIMEOUT: Duration = Duration::from_secs(10);
+const LOGOUT_TIMEOUT: Duration = Duration::from_secs(5);
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An authenticated session parked in the pool, together with the server
+/// slot it occupies.
+struct IdleSession {
+    session: ImapSession,
+    permit: OwnedSemaphorePermit,
+    server: String,
+    since: Instant,
+    capabilities: Arc<ImapCapabilities>,
+    account_id: Option<String>, // `ImapConfig::account_id` it was checked out with
+}
+
+/// What the pool knows about one server.
+struct Server {
+    /// The connection cap.
+    slots: Arc<Semaphore>,
+    /// async-imap can't parse this server's FETCH responses (see
+    /// `client::Fetched::Unparsable`).
+    raw_fetch: bool,
+    /// COMPRESS DEFLATE failed, so new sessions go without it.
+    compress_refused: bool,
+}
+
+#[derive(Default)]
+struct PoolInner {
+    /// Idle sessions keyed by account (see `account_key`).
+    idle: Mutex<HashMap<String, Vec<IdleSession>>>,
+    /// Per-server state keyed by `host:port`.
+    servers: Mutex<HashMap<String, Server>>,
+    /// Bumped whenever sessions are closed because settings changed. A
+    /// session checked out before that is closed when it comes back instead
+    /// of being parked with the old settings.
+    generation: AtomicU64,
+}
+
+/// Pool of authenticated IMAP sessions, kept in Tauri managed state.
+///
+/// Commands check out a session per call instead of connecting and logging
+/// out every time, so bursts of flag/move operations reuse one login.
+#[derive(Default)]
+pub struct ImapPool {
+    inner: Arc<PoolInner>,
+}
+
+impl ImapPool {
+    /// Check out an authenticated session for the account described by `config`.
+    ///
+    /// Reuses an idle session when one is available (NOOP-checking it if it has
+    /// been idle for a while), otherwise opens a new connection once a slot on
+    /// the server is free.
+    pub async fn get(&self, config: &ImapConfig) -> Result<PooledSession, MailError> {
+        let key = account_key(config);
+
+        while let Some(idle) = self.take_idle(&key) {
+            let age = idle.since.elapsed();
+            let IdleSession {
+                mut session,
+                permit,
+                server,
+                capabilities,
+                ..
+            } = idle;
+
+            if age > MAX_IDLE_AGE {
+                log::debug!("IMAP pool: retiring session for {key} idle {}s", age.as_secs());
+                let _ = tokio::time::timeout(LOGOUT_TIMEOUT, session.logout()).await;
+                continue;
+            }
+
+            if age > HEALTH_CHECK_AFTER {
+                match tokio::time::timeout(NOOP_TIMEOUT, session.noop()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        log::info!("IMAP pool: NOOP failed for {key}, reconnecting: {e}");
+                        continue;
+                    }
+                    Err(_) => {
+                        log::info!("IMAP pool: NOOP timed out for {key}, reconnecting");
+                        continue;
+                    }
+                }
+            }
+
+            // Drop unilateral responses left over from earlier commands so
+            // callers only see what their own commands produced.
+            while session.unsolicited_responses.try_recv().is_ok() {}
+
+            return Ok(PooledSession::new(session, permit, key, server, capabilities, config, &self.inner));
+        }
+
+        let server = server_key(config);
+        let permit = self.acquire_slot(&server).await?;
+        let compress = !self.server_state(&server, |s| s.compress_refused);
+        let (session, capabilities) = self.open(config, &key, &server, compress).await?;
+
+        Ok(PooledSession::new(session, permit, key, server, Arc::new(capabilities), config, &self.inner))
+    }
+
+    /// Connect, log in and set up a new session. With `compress`, COMPRESS
+    /// DEFLATE is negotiated if the server offers it.
+    async fn open(
+        &self,
+        config: &ImapConfig,
+        key: &str,
+        server: &str,
+        compress: bool,
+    ) -> Result<(ImapSession, ImapCapabilities), MailError> {
+        let (mut session, pre_auth_mechanisms) = client::connect_with_auth_mechanisms(config).await?;
+        // The session is usable without extensions, so failing to detect or
+        // enable them only costs the commands that rely on them.
+        let mut capabilities = match client::get_capabilities(&mut session).await {
+            Ok(capabilities) => capabilities,
+            Err(e) => {
+                log::warn!("IMAP pool: CAPABILITY failed for {key}, assuming no extensions: {e}");
+                ImapCapabilities::default()
+            }
+        };
+        for mechanism in pre_auth_mechanisms {
+            if !capabilities.auth_mechanisms.contains(&mechanism) {
+                capabilities.auth_mechanisms.push(mechanism);
+            }
+        }
+        // ENABLE is only allowed before the first SELECT, so it has to happen
+        // here rather than in the commands that rely on it.
+        capabilities.qresync_enabled = match client::enable_qresync(&mut session, &capabilities).await {
+            Ok(enabled) => enabled,
+            Err(e) => {
+                log::warn!("IMAP pool: ENABLE QRESYNC failed for {key}: {e}");
+                false
+            }
+        };
+        // A compressed stream would leave the protocol trace unreadable, so
+        // traced sessions go without.
+        if compress && capabilities.compress_deflate && !session.as_mut().is_traced() {
+            match client::enable_compression(session).await {
+                Ok(compressed) => {
+                    session = compressed;
+                    capabilities.compress_enabled = true;
+                }
+                Err(e) => {
+                    // The failed attempt costs the connection, so open
+                    // another one without it.
+                    log::warn!("IMAP pool: COMPRESS DEFLATE failed for {key}, reconnecting without it: {e}");
+                    if let Ok(mut servers) = self.inner.servers.lock() {
+                        if let Some(server) = servers.get_mut(server) {
+                            server.compress_refused = true;
+                        }
+                    }
+                    return Box::pin(self.open(config, key, server, false)).await;
+                }
+            }
+        }
+        log::debug!(
+            "IMAP pool: opened new session for {key} (QRESYNC: {}, COMPRESS: {})",
+            capabilities.qresync_enabled,
+            capabilities.compress_enabled
+        );
+        Ok((session, capabilities))
+    }
+
+    /// Read what the pool knows about `server`.
+    fn server_state(&self, server: &str, read: impl FnOnce(&Server) -> bool) -> bool {
+        self.inner
+            .servers
+            .lock()
+            .ok()
+            .and_then(|servers| servers.get(server).map(read))
+            .unwrap_or(false)
+    }
+
+    /// Close all idle sessions for an account, e.g. after its credentials change
+    /// or it is removed.
+    pub async fn close_account(&self, config: &ImapConfig) {
+        self.inner.generation.fetch_add(1, Ordering::SeqCst);
+        let key = account_key(config);
+        let sessions = self
+            .inner
+            .idle
+            .lock()
+            .map(|mut idle| idle.remove(&key).unwrap_or_default())
+            .unwrap_or_default();
+
+        for mut idle in sessions {
+            let _ = tokio::time::timeout(LOGOUT_TIMEOUT, idle.session.logout()).await;
+        }
+    }
+
+    /// Close the idle sessions of the account with this ID (see
+    /// `ImapConfig::account_id`), e.g. so a protocol trace starts from a fresh
+    /// login.
+    pub async fn close_account_id(&self, account_id: &str) {
+        self.inner.generation.fetch_add(1, Ordering::SeqCst);
+        let sessions: Vec<IdleSession> = self
+            .inner
+            .idle
+            .lock()
+            .map(|mut idle| {
+                let mut closed = Vec::new();
+                for sessions in idle.values_mut() {
+                    let (matching, kept): (Vec<_>, Vec<_>) = std::mem::take(sessions)
+                        .into_iter()
+                        .partition(|s| s.account_id.as_deref() == Some(account_id));
+                    *sessions = kept;
+                    closed.extend(matching);
+                }
+                idle.retain(|_, sessions| !sessions.is_empty());
+                closed
+            })
+            .unwrap_or_default();
+
+        for mut idle in sessions {
+            let _ = tokio::time::timeout(LOGOUT_TIMEOUT, idle.session.logout()).await;
+        }
+    }
+
+    /// Close every idle session, e.g. after the global proxy changes.
+    pub async fn close_all(&self) {
+        self.inner.generation.fetch_add(1, Ordering::SeqCst);
+        let sessions: Vec<IdleSession> = self
+            .inner
+            .idle
+            .lock()
+            .map(|mut idle| idle.drain().flat_map(|(_, sessions)| sessions).collect())
+            .unwrap_or_default();
+
+        for mut idle in sessions {
+            let _ = tokio::time::timeout(LOGOUT_TIMEOUT, idle.session.logout()).await;
+        }
+    }
+
+    fn take_idle(&self, key: &str) -> Option<IdleSession> {
+        let mut idle = self.inner.idle.lock().ok()?;
+        let sessions = idle.get_mut(key)?;
+        // Most recently returned first — it is the least likely to be stale.
+        let session = sessions.pop();
+        if sessions.is_empty() {
+            idle.remove(key);
+        }
+        session
+    }
+
+    /// Wait for a free connection slot on `server`.
+    ///
+    /// If every slot is held by an idle session (possibly of another account on
+    /// the same server), the oldest one is closed to make room.
+    async fn acquire_slot(&self, server: &str) -> Result<OwnedSemaphorePermit, MailError> {
+        let semaphore = {
+            let mut servers = self
+                .inner
+                .servers
+                .lock()
+                .map_err(|_| MailError::new(ErrorKind::Other, "IMAP pool lock poisoned"))?;
+            servers
+                .entry(server.to_string())
+                .or_insert_with(|| Server {
+                    slots: Arc::new(Semaphore::new(MAX_CONNECTIONS_PER_SERVER)),
+                    raw_fetch: false,
+                    compress_refused: false,
+                })
+                .slots
+                .clone()
+        };
+
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        if let Some(mut evicted) = self.evict_oldest_idle(server) {
+            let _ = tokio::time::timeout(LOGOUT_TIMEOUT, evicted.session.logout()).await;
+            drop(evicted);
+        }
+
+        tokio::time::timeout(ACQUIRE_TIMEOUT, semaphore.acquire_owned())
+            .await
+            .map_err(|_| {
+                MailError::new(
+                    ErrorKind::ServerBusy,
+                    format!(
+                        "Timed out after {}s waiting for a free IMAP connection to {server} ({MAX_CONNECTIONS_PER_SERVER} already in use)",
+                        ACQUIRE_TIMEOUT.as_secs()
+                    ),
+                )
+            })?
+            .map_err(|e| MailError::new(ErrorKind::Other, format!("IMAP pool closed: {e}")))
+    }
+
+    fn evict_oldest_idle(&self, server: &str) -> Option<IdleSession> {
+        let mut idle = self.inner.idle.lock().ok()?;
+        let (key, index) = idle
+            .iter()
+            .flat_map(|(key, sessions)| {
+                sessions
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, s)| s.server == server)
+                    .map(move |(i, s)| (key.clone(), i, s.since))
+            })
+            .min_by_key(|(_, _, since)| *since)
+            .map(|(key, i, _)| (key, i))?;
+
+        let sessions = idle.get_mut(&key)?;
+        let evicted = sessions.remove(index);
+        if sessions.is_empty() {
+            idle.remove(&key);
+        }
+        Some(evicted)
+    }
+}
+
+/// A session checked out of the pool. Derefs to the underlying async-imap
+/// session and goes back to the pool when dropped, unless discarded.
+pub(crate) struct PooledSession {
+    session: Option<ImapSession>,
+    permit: Option<OwnedSemaphorePermit>,
+    key: String,
+    server: String,
+    capabilities: Arc<ImapCapabilities>,
+    account_id: Option<String>,
+    pool: Arc<PoolInner>,
+    generation: u64, // `PoolInner::generation` at checkout
+    reusable: bool,
+}
+
+impl PooledSession {
+    fn new(
+        session: ImapSession,
+        permit: OwnedSemaphorePermit,
+        key: String,
+        server: String,
+        capabilities: Arc<ImapCapabilities>,
+        config: &ImapConfig,
+        pool: &Arc<PoolInner>,
+    ) -> Self {
+        Self {
+            session: Some(session),
+            permit: Some(permit),
+            key,
+            server,
+            capabilities,
+            account_id: config.account_id.clone(),
+            pool: pool.clone(),
+            generation: pool.generation.load(Ordering::SeqCst),
+            reusable: true,
+        }
+    }
+
+    /// What the server advertised when this session was opened. Cheap to
+    /// clone, so it can be passed alongside `&mut` access to the session.
+    pub fn capabilities(&self) -> Arc<ImapCapabilities> {
+        self.capabilities.clone()
+    }
+
+    /// Whether async-imap failed to parse this server's FETCH responses
+    /// before, so fetches should go straight to the raw path.
+    pub fn raw_fetch(&self) -> bool {
+        self.pool
+            .servers
+            .lock()
+            .ok()
+            .and_then(|servers| servers.get(&self.server).map(|s| s.raw_fetch))
+            .unwrap_or(false)
+    }
+
+    /// Remember that async-imap can't parse this server's FETCH responses.
+    pub fn set_raw_fetch(&self) {
+        if let Some(server) = self.pool.servers.lock().ok().as_mut().and_then(|s| s.get_mut(&self.server)) {
+            server.raw_fetch = true;
+        }
+    }
+
+    /// Close the connection on drop instead of returning it to the pool.
+    pub fn discard(&mut self) {
+        self.reusable = false;
+    }
+
+    /// Release the session after a command, passing its result through.
+    ///
+    /// A failed command may have left a half-read response on the wire (e.g.
+    /// after a timeout), so the connection is discarded rather than reused.
+    pub fn finish<T>(mut self, result: Result<T, MailError>) -> Result<T, MailError> {
+        if result.is_err() {
+            self.discard();
+        }
+        result
+    }
+}
+
+impl Deref for PooledSession {
+    type Target = ImapSession;
+
+    fn deref(&self) -> &ImapSession {
+        self.session.as_ref().expect("pooled session used after release")
+    }
+}
+
+impl DerefMut for PooledSession {
+    fn deref_mut(&mut self) -> &mut ImapSession {
+        self.session.as_mut().expect("pooled session used after release")
+    }
+}
+
+impl Drop for PooledSession {
+    fn drop(&mut self) {
+        let (Some(session), Some(permit)) = (self.session.take(), self.permit.take()) else {
+            return;
+        };
+        if !self.reusable {
+            log::debug!("IMAP pool: closing discarded session for {}", self.key);
+            return;
+        }
+        // Closing sessions doesn't reach checked-out ones, which may have
+        // logged in with settings that have since changed. Closing all of
+        // them on return is simpler than tracking whose settings changed.
+        if self.pool.generation.load(Ordering::SeqCst) != self.generation {
+            log::debug!("IMAP pool: closing session for {} checked out before a settings change", self.key);
+            return;
+        }
+        if let Ok(mut idle) = self.pool.idle.lock() {
+            idle.entry(self.key.clone()).or_default().push(IdleSession {
+                session,
+                permit,
+                server: self.server.clone(),
+                since: Instant::now(),
+                capabilities: self.capabilities.clone(),
+                account_id: self.account_id.clone(),
+            });
+        }
+    }
+}
+
+/// Pool key identifying one account's sessions.
+fn account_key(config: &ImapConfig) -> String {
+    format!(
+        "{}@{}/{}",
+        config.username,
+        server_key(config),
+        config.security
+    )
+}
+
+/// Key used for the per-server connection cap.
+fn server_key(config: &ImapConfig) -> String {
+    format!("{}:{}", config.host.to_lowercase(), config.port)
+}
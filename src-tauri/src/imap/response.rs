@@ -0,0 +1,300 @@
+//! A small IMAP response reader and tokenizer for the raw fallback path,
+//! used where async-imap can't parse a server's responses.
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use crate::error::{ErrorKind, MailError};
+
+/// One value of a response: an atom (including numbers, `NIL`, flags and
+/// section specs like `BODY[HEADER.FIELDS (FROM)]`), a string (quoted or
+/// literal), or a parenthesized list.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Atom(String),
+    String(Vec<u8>),
+    List(Vec<Value>),
+}
+
+impl Value {
+    pub fn as_atom(&self) -> Option<&str> {
+        match self {
+            Value::Atom(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<u32> {
+        self.as_atom()?.parse().ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// String contents; `NIL` and lists have none.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<Cow<'_, str>> {
+        self.as_bytes().map(String::from_utf8_lossy)
+    }
+}
+
+/// Size of the literal announced at the end of a response line (`{n}`,
+/// `{n+}` or literal8 `~{n}`), if any.
+pub fn literal_size(line: &[u8]) -> Option<usize> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let inner = line.strip_suffix(b"}")?;
+    let start = inner.iter().rposition(|&b| b == b'{')?;
+    let digits = &inner[start + 1..];
+    let digits = digits.strip_suffix(b"+").unwrap_or(digits);
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Read one complete response: a line plus every literal it announces and
+/// the lines that follow them, exactly as sent. `Ok(None)` at end of stream.
+/// `timeout` applies to each read.
+pub async fn read_response<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    timeout: Duration,
+) -> Result<Option<Vec<u8>>, MailError> {
+    let timed_out = || {
+        MailError::timeout(
+            "read",
+            format!("IMAP read timed out after {}s — check your server settings or network connection", timeout.as_secs()),
+        )
+    };
+
+    let mut response = Vec::new();
+    loop {
+        let start = response.len();
+        let n = tokio::time::timeout(timeout, reader.read_until(b'\n', &mut response))
+            .await
+            .map_err(|_| timed_out())?
+            .map_err(|e| MailError::io(&e, format!("IMAP read failed: {e}")))?;
+        if n == 0 {
+            return if response.is_empty() {
+                Ok(None)
+            } else {
+                Err(MailError::new(ErrorKind::Network, "IMAP connection closed mid-response"))
+            };
+        }
+
+        let Some(size) = literal_size(&response[start..]) else {
+            return Ok(Some(response));
+        };
+        let literal_start = response.len();
+        response.resize(literal_start + size, 0);
+        tokio::time::timeout(timeout, reader.read_exact(&mut response[literal_start..]))
+            .await
+            .map_err(|_| timed_out())?
+            .map_err(|e| MailError::io(&e, format!("IMAP literal read failed: {e}")))?;
+    }
+}
+
+/// Tokenize a complete response as returned by `read_response`. The trailing
+/// CRLF is optional.
+///
+/// Only meant for data responses (FETCH, LIST, ...): the free text after
+/// `OK`/`NO`/`BAD` isn't made of tokens and may not parse.
+pub fn tokenize(response: &[u8]) -> Result<Vec<Value>, String> {
+    let mut tokenizer = Tokenizer { input: response, pos: 0 };
+    let values = tokenizer.values(false)?;
+    match tokenizer.input.get(tokenizer.pos..) {
+        Some(b"" | b"\r\n" | b"\n") => Ok(values),
+        _ => Err(tokenizer.error("unexpected data")),
+    }
+}
+
+struct Tokenizer<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Tokenizer<'_> {
+    fn error(&self, what: &str) -> String {
+        format!("Malformed IMAP response: {what} at byte {}", self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    /// Values up to the end of the line, or up to the closing `)` of a list.
+    fn values(&mut self, in_list: bool) -> Result<Vec<Value>, String> {
+        let mut values = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b' ') => self.pos += 1,
+                Some(b'(') => {
+                    self.pos += 1;
+                    values.push(Value::List(self.values(true)?));
+                }
+                Some(b')') if in_list => {
+                    self.pos += 1;
+                    return Ok(values);
+                }
+                Some(b'\r' | b'\n') | None if !in_list => return Ok(values),
+                Some(b')') => return Err(self.error("unbalanced ')'")),
+                Some(b'\r' | b'\n') | None => return Err(self.error("unterminated list")),
+                Some(b'"') => values.push(Value::String(self.quoted()?)),
+                Some(b'{') => values.push(Value::String(self.literal()?)),
+                Some(b'~') if self.input.get(self.pos + 1) == Some(&b'{') => {
+                    self.pos += 1;
+                    values.push(Value::String(self.literal()?));
+                }
+                Some(_) => values.push(self.atom()?),
+            }
+        }
+    }
+
+    fn quoted(&mut self) -> Result<Vec<u8>, String> {
+        self.pos += 1;
+        let mut value = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(value);
+                }
+                Some(b'\\') => {
+                    let escaped = self
+                        .input
+                        .get(self.pos + 1)
+                        .copied()
+                        .filter(|c| matches!(c, b'"' | b'\\'))
+                        .ok_or_else(|| self.error("bad escape in quoted string"))?;
+                    value.push(escaped);
+                    self.pos += 2;
+                }
+                Some(b'\r' | b'\n') | None => return Err(self.error("unterminated quoted string")),
+                Some(c) => {
+                    value.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn literal(&mut self) -> Result<Vec<u8>, String> {
+        let rest = &self.input[self.pos..];
+        let header_len = rest
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .map(|i| i + 2)
+            .ok_or_else(|| self.error("literal without CRLF"))?;
+        let size = literal_size(&rest[..header_len]).ok_or_else(|| self.error("bad literal header"))?;
+        let start = self.pos + header_len;
+        let data = self
+            .input
+            .get(start..start + size)
+            .ok_or_else(|| self.error("truncated literal"))?;
+        self.pos = start + size;
+        Ok(data.to_vec())
+    }
+
+    /// An atom, keeping `[...]` sections whole even when they contain
+    /// spaces or parentheses.
+    fn atom(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        let mut depth = 0usize;
+        while let Some(c) = self.peek() {
+            match c {
+                b'[' => depth += 1,
+                b']' if depth > 0 => depth -= 1,
+                b'\r' | b'\n' => break,
+                b' ' | b'(' | b')' | b'"' | b'{' if depth == 0 => break,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        if depth > 0 {
+            return Err(self.error("unterminated ["));
+        }
+        Ok(Value::Atom(String::from_utf8_lossy(&self.input[start..self.pos]).into_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom(s: &str) -> Value {
+        Value::Atom(s.to_string())
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_tokenize_fetch() {
+        let response = b"* 12 FETCH (FLAGS (\\Seen $Label1) UID 7 INTERNALDATE \"16-Feb-2026 12:00:00 +0000\" \
+BODY[HEADER.FIELDS (FROM TO)] {4}\r\nab\r\n BODY[] {3}\r\nx)y)\r\n";
+        let values = tokenize(response).unwrap();
+        assert_eq!(values[..3], [atom("*"), atom("12"), atom("FETCH")]);
+        assert_eq!(
+            values[3],
+            Value::List(vec![
+                atom("FLAGS"),
+                Value::List(vec![atom("\\Seen"), atom("$Label1")]),
+                atom("UID"),
+                atom("7"),
+                atom("INTERNALDATE"),
+                string("16-Feb-2026 12:00:00 +0000"),
+                atom("BODY[HEADER.FIELDS (FROM TO)]"),
+                string("ab\r\n"),
+                atom("BODY[]"),
+                string("x)y"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_tokenize_strings() {
+        let values = tokenize(b"* LIST (\\HasNoChildren) \"/\" \"say \\\"UID 5\\\"\" NIL ~{2}\r\n\0\x01").unwrap();
+        assert_eq!(values[4], string("say \"UID 5\""));
+        assert_eq!(values[5], atom("NIL"));
+        assert_eq!(values[6], Value::String(vec![0, 1]));
+
+        assert!(tokenize(b"* 1 FETCH (UID 1").is_err());
+        assert!(tokenize(b"* 1 FETCH (BODY[] {10}\r\nshort)").is_err());
+        assert!(tokenize(b"* 1 FETCH (X \"open)\r\n").is_err());
+    }
+
+    #[test]
+    fn test_literal_size() {
+        assert_eq!(literal_size(b"* 1 FETCH (BODY[] {1234}\r\n"), Some(1234));
+        assert_eq!(literal_size(b"A1 APPEND INBOX {5+}\r\n"), Some(5));
+        assert_eq!(literal_size(b"* 1 FETCH (BINARY[1] ~{8}\r\n"), Some(8));
+        assert_eq!(literal_size(b"* OK {not a literal}\r\n"), None);
+        assert_eq!(literal_size(b"* 1 FETCH (UID 1)\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_response_multiple_literals() {
+        let wire: &[u8] = b"* 1 FETCH (BODY[1] {3}\r\nabc BODY[2] {2}\r\n\r\n)\r\na3 OK done\r\n";
+        let mut reader = tokio::io::BufReader::new(wire);
+        let timeout = Duration::from_secs(1);
+
+        let first = read_response(&mut reader, timeout).await.unwrap().unwrap();
+        assert_eq!(first, b"* 1 FETCH (BODY[1] {3}\r\nabc BODY[2] {2}\r\n\r\n)\r\n");
+        let second = read_response(&mut reader, timeout).await.unwrap().unwrap();
+        assert_eq!(second, b"a3 OK done\r\n");
+        assert!(read_response(&mut reader, timeout).await.unwrap().is_none());
+    }
+}
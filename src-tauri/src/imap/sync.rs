@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tauri::{AppHandle, Emitter};
+use tokio::sync::watch;
+
+use super::types::SyncProgressEvent;
+use crate::error::{ErrorKind, MailError};
+
+const SYNC_PROGRESS_EVENT: &str = "imap-sync-progress";
+
+type Syncs = Arc<Mutex<HashMap<String, watch::Sender<bool>>>>;
+
+/// Running folder syncs by frontend-supplied sync ID, kept in Tauri managed
+/// state so they can be cancelled from another command.
+#[derive(Default)]
+pub struct SyncRegistry {
+    syncs: Syncs,
+}
+
+impl SyncRegistry {
+    /// Register a sync. Without a `sync_id` the sync still reports progress
+    /// but can't be cancelled.
+    pub fn start(&self, app: &AppHandle, sync_id: Option<String>, folder: &str) -> SyncProgress {
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+
+        let registered = sync_id.as_ref().and_then(|id| {
+            let mut syncs = self.syncs.lock().ok()?;
+            if let Some(previous) = syncs.insert(id.clone(), cancel_tx.clone()) {
+                log::warn!("IMAP sync {id} started twice; cancelling the earlier one");
+                let _ = previous.send(true);
+            }
+            Some((self.syncs.clone(), cancel_tx))
+        });
+
+        SyncProgress {
+            app: app.clone(),
+            sync_id,
+            folder: folder.to_string(),
+            cancel: cancel_rx,
+            registered,
+        }
+    }
+
+    /// Cancel one sync, or every running sync when `sync_id` is `None`.
+    pub fn cancel(&self, sync_id: Option<&str>) {
+        let Ok(syncs) = self.syncs.lock() else {
+            return;
+        };
+        for (id, cancel) in syncs.iter() {
+            if sync_id.map_or(true, |s| s == id) {
+                log::info!("IMAP sync {id}: cancel requested");
+                let _ = cancel.send(true);
+            }
+        }
+    }
+}
+
+/// Progress reporting and cancellation for one running sync. Unregisters
+/// itself from the `SyncRegistry` when dropped.
+pub struct SyncProgress {
+    app: AppHandle,
+    sync_id: Option<String>,
+    folder: String,
+    cancel: watch::Receiver<bool>,
+    registered: Option<(Syncs, watch::Sender<bool>)>,
+}
+
+impl SyncProgress {
+    /// Emit an `imap-sync-progress` event.
+    pub fn report(&self, fetched: usize, total: usize) {
+        let event = SyncProgressEvent {
+            sync_id: self.sync_id.clone(),
+            folder: self.folder.clone(),
+            fetched: fetched as u32,
+            total: total as u32,
+        };
+        if let Err(e) = self.app.emit(SYNC_PROGRESS_EVENT, event) {
+            log::warn!("Failed to emit {SYNC_PROGRESS_EVENT}: {e}");
+        }
+    }
+
+    /// Resolves once the sync has been cancelled; never resolves otherwise.
+    pub async fn cancelled(&mut self) {
+        loop {
+            if *self.cancel.borrow() {
+                return;
+            }
+            if self.cancel.changed().await.is_err() {
+                // Nothing can cancel us any more.
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// The error a cancelled sync returns.
+    pub fn cancelled_error(&self) -> MailError {
+        MailError::new(ErrorKind::Cancelled, format!("Sync of {} was cancelled", self.folder))
+    }
+}
+
+impl Drop for SyncProgress {
+    fn drop(&mut self) {
+        let (Some(id), Some((syncs, cancel))) = (&self.sync_id, &self.registered) else {
+            return;
+        };
+        if let Ok(mut syncs) = syncs.lock() {
+            // A later sync may have reused the ID; leave its entry alone.
+            if syncs.get(id).is_some_and(|current| current.same_channel(cancel)) {
+                syncs.remove(id);
+            }
+        }
+    }
+}
@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
 
+use crate::error::MailError;
+use crate::proxy::ProxyConfig;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImapConfig {
     pub host: String,
@@ -7,9 +10,51 @@ pub struct ImapConfig {
     pub security: String, // "tls", "starttls", "none"
     pub username: String,
     pub password: String, // plaintext password or OAuth2 access token
-    pub auth_method: String, // "password" or "oauth2"
+    pub auth_method: String, // "password", "oauth2", or a mechanism to force, e.g. "scram-sha-256" (see sasl::Mechanism::choose)
     #[serde(default)]
     pub accept_invalid_certs: bool,
+    #[serde(default)]
+    pub pinned_cert_sha256: Option<String>, // accept only this certificate; overrides accept_invalid_certs
+    #[serde(default)]
+    pub ca_certificates_pem: Option<String>, // CAs to trust besides the system's, e.g. a corporate root
+    #[serde(default)]
+    pub client_identity_pkcs12: Option<String>, // base64 PKCS#12 client certificate and key, for mutual TLS
+    #[serde(default)]
+    pub client_identity_password: Option<String>,
+    #[serde(default)]
+    pub min_tls_version: Option<String>, // "1.0", "1.1", "1.2" or "1.3"; defaults to 1.2
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>, // overrides the global proxy; kind "none" connects directly
+    #[serde(default)]
+    pub account_id: Option<String>, // identifies the account's protocol trace, if one is running
+}
+
+/// Extensions and limits advertised by the server after login, cached per
+/// pooled session.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImapCapabilities {
+    pub idle: bool,
+    pub r#move: bool,
+    pub uidplus: bool,
+    pub condstore: bool,
+    pub qresync: bool,
+    pub qresync_enabled: bool, // ENABLE QRESYNC succeeded on this session
+    pub special_use: bool,
+    pub create_special_use: bool,
+    pub list_extended: bool,
+    pub list_status: bool,
+    pub compress_deflate: bool,
+    pub compress_enabled: bool, // COMPRESS DEFLATE is active on this session
+    pub esearch: bool,
+    pub sort: bool,
+    pub thread: Vec<String>, // THREAD algorithms, e.g. "REFERENCES"
+    pub quota: bool,
+    pub binary: bool,
+    pub literal_plus: bool,
+    pub gmail: bool, // X-GM-EXT-1
+    pub append_limit: Option<u64>,
+    pub auth_mechanisms: Vec<String>, // e.g. "PLAIN", "XOAUTH2", including those offered before login
+    pub raw: Vec<String>,             // every capability as advertised
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,6 +66,12 @@ pub struct ImapFolder {
     pub special_use: Option<String>, // "\Sent", "\Trash", "\Drafts", "\Junk", "\Archive", "\All"
     pub exists: u32,
     pub unseen: u32,
+    pub uidnext: Option<u32>,
+    pub uidvalidity: Option<u32>,
+    pub highest_modseq: Option<u64>, // only with CONDSTORE
+    pub no_select: bool,             // \Noselect: a hierarchy node that holds no messages
+    pub has_children: bool,
+    pub subscribed: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -41,6 +92,10 @@ pub struct ImapMessage {
     pub is_read: bool,
     pub is_starred: bool,
     pub is_draft: bool,
+    pub keywords: Vec<String>, // non-system flags such as $Forwarded, $Junk or user labels
+    pub gmail_labels: Vec<String>,        // X-GM-LABELS, e.g. "\Inbox", "\Important", "Receipts"
+    pub gmail_thread_id: Option<String>,  // X-GM-THRID; u64 as a string, too large for a JS number
+    pub gmail_message_id: Option<String>, // X-GM-MSGID; same across every folder the message is in
     pub body_html: Option<String>,
     pub body_text: Option<String>,
     pub snippet: Option<String>,
@@ -49,6 +104,7 @@ pub struct ImapMessage {
     pub list_unsubscribe_post: Option<String>,
     pub auth_results: Option<String>,
     pub attachments: Vec<ImapAttachment>,
+    pub body_loaded: bool, // false for header-only fetches; bodies come later via fetch_message_body(ies)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -83,11 +139,95 @@ pub struct ImapFolderSyncResult {
     pub folder_status: ImapFolderStatus,
 }
 
+/// Final result of a streamed sync; the messages themselves went through the
+/// channel as `ImapMessageBatch`es.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImapFolderSyncSummary {
+    pub uids: Vec<u32>,
+    pub folder_status: ImapFolderStatus,
+}
+
+/// One batch of parsed messages sent through a sync channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImapMessageBatch {
+    pub folder: String,
+    pub messages: Vec<ImapMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImapBodyFetchResult {
+    pub messages: Vec<ImapMessage>,
+    pub remaining_uids: Vec<u32>, // UIDs left over once the size budget was used up
+}
+
+/// Where moved or copied messages ended up, from the COPYUID response code
+/// (UIDPLUS). Empty when the server doesn't report it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImapCopyResult {
+    pub dest_uidvalidity: Option<u32>,
+    pub uid_map: Vec<UidMapping>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UidMapping {
+    pub source_uid: u32,
+    pub dest_uid: u32,
+}
+
+/// UID of an appended message, from the APPENDUID response code (UIDPLUS).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImapAppendResult {
+    pub uidvalidity: Option<u32>,
+    pub uid: Option<u32>,
+}
+
+/// Structured server-side search. Every criterion that is set must match;
+/// an empty query matches all messages.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImapSearchQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub subject: Option<String>,
+    pub body: Option<String>,
+    pub since: Option<i64>,  // unix seconds; matches INTERNALDATE on or after that day
+    pub before: Option<i64>, // unix seconds; matches INTERNALDATE before that day
+    pub is_read: Option<bool>,
+    pub is_starred: Option<bool>,
+    pub is_answered: Option<bool>,
+    pub is_draft: Option<bool>,
+    pub larger: Option<u32>,  // RFC822.SIZE in bytes
+    pub smaller: Option<u32>, // RFC822.SIZE in bytes
+    pub keywords: Vec<String>,
+    pub not_keywords: Vec<String>,
+    pub gmail_raw: Option<String>, // X-GM-RAW: Gmail search syntax, e.g. "has:attachment in:anywhere"
+}
+
+/// One SORT key (RFC 5256): `ARRIVAL`, `DATE`, `FROM`, `TO`, `CC`, `SUBJECT`,
+/// `SIZE`, or `DISPLAYFROM`/`DISPLAYTO` where SORT=DISPLAY is advertised.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImapSortKey {
+    pub key: String,
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+/// A node of a server-built thread tree (RFC 5256 THREAD). `uid` is `None`
+/// for a parent the server knows of but that isn't in the folder; it only
+/// groups its children.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImapThreadNode {
+    pub uid: Option<u32>,
+    pub children: Vec<ImapThreadNode>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeltaCheckRequest {
     pub folder: String,
     pub last_uid: u32,
     pub uidvalidity: u32,
+    #[serde(default)]
+    pub highest_modseq: Option<u64>, // HIGHESTMODSEQ from the last sync; enables flag/expunge deltas
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -96,4 +236,63 @@ pub struct DeltaCheckResult {
     pub uidvalidity: u32,
     pub new_uids: Vec<u32>,
     pub uidvalidity_changed: bool,
+    pub highest_modseq: Option<u64>,
+    pub flag_changes: Vec<FlagChange>, // CONDSTORE: messages whose flags changed since highest_modseq
+    pub vanished_uids: Vec<u32>,       // QRESYNC: UIDs expunged since highest_modseq
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlagChange {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub is_read: bool,
+    pub is_starred: bool,
+    pub is_draft: bool,
+    pub modseq: Option<u64>,
+}
+
+/// Payload of the `imap-sync-progress` event, emitted after each batch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncProgressEvent {
+    pub sync_id: Option<String>,
+    pub folder: String,
+    pub fetched: u32, // UIDs processed so far
+    pub total: u32,   // UIDs in the folder
+}
+
+/// A message or attachment streamed to disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadResult {
+    pub path: String,
+    pub sha256: String, // hex digest of the decoded file contents
+    pub size: u64,      // decoded size in bytes
+}
+
+/// Payload of the `imap-download-progress` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadProgressEvent {
+    pub path: String,
+    pub downloaded: u64,    // bytes received from the server so far (still encoded)
+    pub total: Option<u64>, // expected bytes on the wire, once the server announced it
+}
+
+/// Payload of the `imap-mailbox-changed` event emitted by IDLE watchers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxChangedEvent {
+    pub account_id: String,
+    pub folder: String,
+    pub change: String, // "exists", "expunge", "fetch", or "resync" after a reconnect or a polled change
+    pub exists: Option<u32>,         // new message count ("exists", "resync")
+    pub seq: Option<u32>,            // message sequence number ("expunge", "fetch")
+    pub uid: Option<u32>,            // UID, when the server includes it ("fetch")
+    pub flags: Option<Vec<String>>,  // current flags ("fetch")
+}
+
+/// Payload of the `imap-idle-stopped` event: a watcher gave up because
+/// reconnecting can't fix `error` (rejected credentials or certificate).
+#[derive(Debug, Clone, Serialize)]
+pub struct IdleStoppedEvent {
+    pub account_id: String,
+    pub folder: String,
+    pub error: MailError,
 }
@@ -7,9 +7,14 @@ use tauri::{Emitter, Manager};
 use tauri_plugin_autostart::MacosLauncher;
 
 mod commands;
+mod error;
 mod imap;
 mod oauth;
+mod proxy;
+mod sasl;
 mod smtp;
+mod tls;
+mod trace;
 
 #[tauri::command]
 fn close_splashscreen(app: tauri::AppHandle) {
@@ -86,6 +91,9 @@ pub fn run() {
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_process::init())
         .plugin(tauri_plugin_os::init())
+        .manage(imap::pool::ImapPool::default())
+        .manage(imap::idle::IdleManager::default())
+        .manage(imap::sync::SyncRegistry::default())
         .invoke_handler(tauri::generate_handler![
             oauth::start_oauth_server,
             oauth::oauth_exchange_token,
@@ -94,23 +102,48 @@ pub fn run() {
             close_splashscreen,
             open_devtools,
             commands::imap_test_connection,
+            commands::imap_fetch_certificate,
             commands::imap_list_folders,
+            commands::imap_get_capabilities,
+            commands::imap_create_folder,
+            commands::imap_rename_folder,
+            commands::imap_delete_folder,
+            commands::imap_subscribe,
+            commands::imap_unsubscribe,
             commands::imap_fetch_messages,
             commands::imap_fetch_new_uids,
             commands::imap_search_all_uids,
+            commands::imap_search,
+            commands::imap_sort,
+            commands::imap_thread,
             commands::imap_fetch_message_body,
+            commands::imap_fetch_message_bodies,
             commands::imap_fetch_raw_message,
             commands::imap_set_flags,
+            commands::imap_set_gmail_labels,
             commands::imap_move_messages,
             commands::imap_delete_messages,
             commands::imap_get_folder_status,
             commands::imap_fetch_attachment,
+            commands::imap_download_attachment,
+            commands::imap_download_raw_message,
             commands::imap_append_message,
             commands::imap_sync_folder,
+            commands::imap_sync_folder_stream,
+            commands::imap_cancel_sync,
             commands::imap_raw_fetch_diagnostic,
             commands::imap_delta_check,
+            commands::imap_close_connections,
+            commands::imap_idle_start,
+            commands::imap_idle_stop,
             commands::smtp_send_email,
             commands::smtp_test_connection,
+            commands::smtp_fetch_certificate,
+            commands::proxy_set_global,
+            commands::protocol_trace_start,
+            commands::protocol_trace_stop,
+            commands::protocol_trace_export,
+            commands::protocol_trace_clear,
         ])
         .setup(|app| {
             {
@@ -254,8 +287,14 @@ pub fn run() {
                 }
             }
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            // Stop running folder syncs so quitting doesn't wait out a long fetch
+            if let tauri::RunEvent::ExitRequested { .. } = event {
+                app.state::<imap::sync::SyncRegistry>().cancel(None);
+            }
+        });
 
     log::info!("Tauri application exited normally");
 }
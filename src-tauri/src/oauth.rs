@@ -4,6 +4,9 @@ use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 
+use crate::error::{ErrorKind, MailError};
+use crate::proxy::{self, ProxyConfig};
+
 #[derive(Serialize)]
 pub struct OAuthResult {
     pub code: String,
@@ -160,7 +163,9 @@ pub struct TokenExchangeResult {
 }
 
 /// Exchange an OAuth authorization code for tokens via Rust HTTP client (avoids CORS).
+/// `proxy` is the account's own proxy setting; the global one applies otherwise.
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn oauth_exchange_token(
     token_url: String,
     code: String,
@@ -169,7 +174,8 @@ pub async fn oauth_exchange_token(
     code_verifier: Option<String>,
     client_secret: Option<String>,
     scope: Option<String>,
-) -> Result<TokenExchangeResult, String> {
+    proxy: Option<ProxyConfig>,
+) -> Result<TokenExchangeResult, MailError> {
     let mut params = vec![
         ("code", code),
         ("client_id", client_id),
@@ -188,29 +194,31 @@ pub async fn oauth_exchange_token(
         params.push(("scope", s));
     }
 
-    let client = reqwest::Client::new();
+    let client = proxy::http_client(proxy.as_ref()).map_err(|e| MailError::new(ErrorKind::Other, e))?;
     let response = client
         .post(&token_url)
         .form(&params)
         .send()
         .await
-        .map_err(|e| format!("Token exchange request failed: {}", e))?;
+        .map_err(|e| request_error("Token exchange", e))?;
 
-    if !response.status().is_success() {
+    let status = response.status();
+    if !status.is_success() {
         let error = response
             .text()
             .await
             .unwrap_or_else(|_| "Unknown error".to_string());
-        return Err(format!("Token exchange failed: {}", error));
+        return Err(token_error("Token exchange", status, error));
     }
 
     response
         .json::<TokenExchangeResult>()
         .await
-        .map_err(|e| format!("Failed to parse token response: {}", e))
+        .map_err(|e| MailError::new(ErrorKind::ProtocolParse, format!("Failed to parse token response: {}", e)))
 }
 
 /// Refresh an OAuth token via Rust HTTP client (avoids CORS).
+/// `proxy` is the account's own proxy setting; the global one applies otherwise.
 #[tauri::command]
 pub async fn oauth_refresh_token(
     token_url: String,
@@ -218,7 +226,8 @@ pub async fn oauth_refresh_token(
     client_id: String,
     client_secret: Option<String>,
     scope: Option<String>,
-) -> Result<TokenExchangeResult, String> {
+    proxy: Option<ProxyConfig>,
+) -> Result<TokenExchangeResult, MailError> {
     let mut params = vec![
         ("refresh_token", refresh_token),
         ("client_id", client_id),
@@ -233,24 +242,46 @@ pub async fn oauth_refresh_token(
         params.push(("scope", s));
     }
 
-    let client = reqwest::Client::new();
+    let client = proxy::http_client(proxy.as_ref()).map_err(|e| MailError::new(ErrorKind::Other, e))?;
     let response = client
         .post(&token_url)
         .form(&params)
         .send()
         .await
-        .map_err(|e| format!("Token refresh request failed: {}", e))?;
+        .map_err(|e| request_error("Token refresh", e))?;
 
-    if !response.status().is_success() {
+    let status = response.status();
+    if !status.is_success() {
         let error = response
             .text()
             .await
             .unwrap_or_else(|_| "Unknown error".to_string());
-        return Err(format!("Token refresh failed: {}", error));
+        return Err(token_error("Token refresh", status, error));
     }
 
     response
         .json::<TokenExchangeResult>()
         .await
-        .map_err(|e| format!("Failed to parse token response: {}", e))
+        .map_err(|e| MailError::new(ErrorKind::ProtocolParse, format!("Failed to parse token response: {}", e)))
+}
+
+fn request_error(what: &str, e: reqwest::Error) -> MailError {
+    let kind = if e.is_timeout() {
+        ErrorKind::Timeout { stage: "token".to_string() }
+    } else {
+        ErrorKind::Network
+    };
+    MailError::new(kind, format!("{what} request failed: {e}"))
+}
+
+/// The token endpoint's error reply. A 400 or 401 (`invalid_grant`,
+/// `invalid_client`) means the grant is no longer accepted and the user has
+/// to sign in again.
+fn token_error(what: &str, status: reqwest::StatusCode, body: String) -> MailError {
+    let kind = match status.as_u16() {
+        400 | 401 => ErrorKind::AuthFailed,
+        429 | 500..=599 => ErrorKind::ServerBusy,
+        _ => ErrorKind::Other,
+    };
+    MailError::new(kind, format!("{what} failed: {body}")).with_server_response(body)
 }
@@ -0,0 +1,399 @@
+//! Outbound proxies for IMAP, SMTP and OAuth traffic: SOCKS5 (RFC 1928, with
+//! RFC 1929 username/password authentication) and HTTP CONNECT.
+//!
+//! A proxy is set globally or per account. An account's own setting wins,
+//! and `"none"` there connects directly even when a global proxy is set.
+//! Servers on the loopback interface (local bridges) are always reached
+//! directly.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::error::{ErrorKind, MailError};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    pub kind: String, // "socks5" (names resolved locally), "socks5h" (resolved by the proxy), "http" (CONNECT) or "none"
+    #[serde(default)]
+    pub host: String,
+    #[serde(default)]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl ProxyConfig {
+    fn validate(&self) -> Result<(), String> {
+        match self.kind.as_str() {
+            "none" => Ok(()),
+            "socks5" | "socks5h" | "http" if self.host.is_empty() || self.port == 0 => {
+                Err(format!("The {} proxy needs a host and port", self.kind))
+            }
+            "socks5" | "socks5h" | "http" => Ok(()),
+            other => Err(format!(
+                "Unknown proxy kind: {other}. Use \"socks5\", \"socks5h\", \"http\" or \"none\"."
+            )),
+        }
+    }
+
+    fn credentials(&self) -> Option<(&str, &str)> {
+        let username = self.username.as_deref().filter(|u| !u.is_empty())?;
+        Some((username, self.password.as_deref().unwrap_or_default()))
+    }
+
+    fn address(&self) -> String {
+        authority(&self.host, self.port)
+    }
+}
+
+fn global() -> &'static Mutex<Option<ProxyConfig>> {
+    static GLOBAL: OnceLock<Mutex<Option<ProxyConfig>>> = OnceLock::new();
+    GLOBAL.get_or_init(|| Mutex::new(None))
+}
+
+/// Set or clear the proxy used by accounts without one of their own, and by
+/// OAuth requests.
+pub fn set_global(proxy: Option<ProxyConfig>) -> Result<(), String> {
+    if let Some(proxy) = &proxy {
+        proxy.validate()?;
+    }
+    *global().lock().map_err(|e| format!("Proxy lock poisoned: {e}"))? = proxy;
+    Ok(())
+}
+
+/// The proxy in effect given the account's own setting, if any. `None` to
+/// connect directly.
+fn resolve(account: Option<&ProxyConfig>) -> Result<Option<ProxyConfig>, String> {
+    let proxy = match account {
+        Some(proxy) => Some(proxy.clone()),
+        None => global().lock().map_err(|e| format!("Proxy lock poisoned: {e}"))?.clone(),
+    };
+    match proxy {
+        Some(proxy) if proxy.kind != "none" => {
+            proxy.validate()?;
+            Ok(Some(proxy))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// `host:port`, bracketing IPv6 addresses.
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost") || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Open a TCP connection to `host:port`, through the proxy in effect.
+pub async fn connect(account: Option<&ProxyConfig>, host: &str, port: u16) -> Result<TcpStream, MailError> {
+    let proxy = match resolve(account).map_err(|e| MailError::new(ErrorKind::Other, e))? {
+        Some(proxy) if !is_loopback(host) => proxy,
+        _ => {
+            return TcpStream::connect((host, port))
+                .await
+                .map_err(|e| MailError::io(&e, format!("TCP connect to {host}:{port} failed: {e}")));
+        }
+    };
+
+    let mut tcp = TcpStream::connect((proxy.host.as_str(), proxy.port))
+        .await
+        .map_err(|e| MailError::io(&e, format!("Connecting to the {} proxy at {} failed: {e}", proxy.kind, proxy.address())))?;
+    match proxy.kind.as_str() {
+        "socks5" => {
+            let addr = tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|e| MailError::new(ErrorKind::Network, format!("Failed to resolve {host}: {e}")))?
+                .next()
+                .ok_or_else(|| MailError::new(ErrorKind::Network, format!("Failed to resolve {host}: no addresses")))?;
+            socks5_handshake(&mut tcp, &proxy, Target::Addr(addr)).await?;
+        }
+        "socks5h" => socks5_handshake(&mut tcp, &proxy, Target::Name(host, port)).await?,
+        _ => http_connect(&mut tcp, &proxy, host, port).await?,
+    }
+    Ok(tcp)
+}
+
+/// An HTTP client for OAuth requests, through the proxy in effect. Without
+/// one, reqwest's defaults apply (including the `HTTPS_PROXY` variables),
+/// unless the account is explicitly set to "none".
+pub fn http_client(account: Option<&ProxyConfig>) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = resolve(account)? {
+        let mut reqwest_proxy = reqwest::Proxy::all(format!("{}://{}", proxy.kind, proxy.address()))
+            .map_err(|e| format!("Invalid proxy {}: {e}", proxy.address()))?;
+        if let Some((username, password)) = proxy.credentials() {
+            reqwest_proxy = reqwest_proxy.basic_auth(username, password);
+        }
+        builder = builder.proxy(reqwest_proxy);
+    } else if account.is_some_and(|proxy| proxy.kind == "none") {
+        builder = builder.no_proxy();
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {e}"))
+}
+
+// ---------- SOCKS5 ----------
+
+enum Target<'a> {
+    Addr(SocketAddr),
+    Name(&'a str, u16),
+}
+
+async fn socks5_handshake<S>(stream: &mut S, proxy: &ProxyConfig, target: Target<'_>) -> Result<(), MailError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let io_error = |e: std::io::Error| MailError::io(&e, format!("SOCKS5 proxy connection failed: {e}"));
+    let other = |message: String| MailError::new(ErrorKind::Other, message);
+
+    // Method negotiation: no authentication, or username/password (0x02).
+    let credentials = proxy.credentials();
+    let greeting: &[u8] = if credentials.is_some() { &[5, 2, 0x00, 0x02] } else { &[5, 1, 0x00] };
+    stream.write_all(greeting).await.map_err(io_error)?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await.map_err(io_error)?;
+    if choice[0] != 5 {
+        return Err(other(format!("{} is not a SOCKS5 proxy", proxy.address())));
+    }
+    match (choice[1], credentials) {
+        (0x00, _) => {}
+        (0x02, Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(other("SOCKS5 proxy username and password are limited to 255 bytes".to_string()));
+            }
+            let mut request = vec![1, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await.map_err(io_error)?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await.map_err(io_error)?;
+            if status[1] != 0 {
+                return Err(other("The SOCKS5 proxy rejected the username or password".to_string()));
+            }
+        }
+        (_, None) => return Err(other("The SOCKS5 proxy requires a username and password".to_string())),
+        (_, Some(_)) => return Err(other("The SOCKS5 proxy accepts none of our authentication methods".to_string())),
+    }
+
+    // CONNECT
+    let mut request = vec![5, 1, 0];
+    let (target_name, port) = match target {
+        Target::Addr(SocketAddr::V4(addr)) => {
+            request.push(1);
+            request.extend_from_slice(&addr.ip().octets());
+            (addr.to_string(), addr.port())
+        }
+        Target::Addr(SocketAddr::V6(addr)) => {
+            request.push(4);
+            request.extend_from_slice(&addr.ip().octets());
+            (addr.to_string(), addr.port())
+        }
+        Target::Name(host, port) => {
+            if host.len() > 255 {
+                return Err(other(format!("Host name too long for the SOCKS5 proxy: {host}")));
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+            (authority(host, port), port)
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await.map_err(io_error)?;
+
+    // Reply: VER REP RSV ATYP BND.ADDR BND.PORT
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.map_err(io_error)?;
+    if reply[1] != 0 {
+        let (reason, kind) = match reply[1] {
+            1 => ("general failure", ErrorKind::Network),
+            2 => ("not allowed by the proxy's rules", ErrorKind::Other),
+            3 => ("network unreachable", ErrorKind::Network),
+            4 => ("host unreachable", ErrorKind::Network),
+            5 => ("connection refused", ErrorKind::ConnectionRefused),
+            6 => ("TTL expired", ErrorKind::Network),
+            7 => ("command not supported", ErrorKind::Other),
+            8 => ("address type not supported", ErrorKind::Other),
+            _ => ("unknown error", ErrorKind::Other),
+        };
+        return Err(MailError::new(kind, format!("The SOCKS5 proxy couldn't connect to {target_name}: {reason}")));
+    }
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await.map_err(io_error)? as usize,
+        atyp => return Err(other(format!("SOCKS5 proxy sent an unknown address type {atyp}"))),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await.map_err(io_error)?;
+    Ok(())
+}
+
+// ---------- HTTP CONNECT ----------
+
+/// Longest proxy response head we accept.
+const MAX_CONNECT_RESPONSE: usize = 16 * 1024;
+
+async fn http_connect<S>(stream: &mut S, proxy: &ProxyConfig, host: &str, port: u16) -> Result<(), MailError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let io_error = |e: std::io::Error| MailError::io(&e, format!("HTTP proxy connection failed: {e}"));
+    let other = |message: String| MailError::new(ErrorKind::Other, message);
+    let target = authority(host, port);
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((username, password)) = proxy.credentials() {
+        let token = base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.map_err(io_error)?;
+
+    // A byte at a time: whatever follows the head belongs to the tunnel.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_CONNECT_RESPONSE {
+            return Err(other("HTTP proxy response too long".to_string()));
+        }
+        head.push(stream.read_u8().await.map_err(io_error)?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.get(2..5))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| other(format!("Unexpected HTTP proxy response: {status_line}")))?;
+    match status {
+        200..=299 => Ok(()),
+        407 if proxy.credentials().is_some() => Err(other("The HTTP proxy rejected the username or password".to_string())),
+        407 => Err(other("The HTTP proxy requires a username and password".to_string())),
+        // Bad gateway, gateway timeout: the proxy couldn't reach the server.
+        502 | 504 => Err(MailError::new(
+            ErrorKind::Network,
+            format!("The HTTP proxy couldn't connect to {target}: {status_line}"),
+        )),
+        _ => Err(other(format!("The HTTP proxy refused the connection to {target}: {status_line}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_proxy(kind: &str, username: Option<&str>) -> ProxyConfig {
+        ProxyConfig {
+            kind: kind.to_string(),
+            host: "proxy.example.com".to_string(),
+            port: 1080,
+            username: username.map(str::to_string),
+            password: username.map(|_| "secret".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks5_handshake() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let proxy = test_proxy("socks5h", Some("user"));
+        let handshake = socks5_handshake(&mut client, &proxy, Target::Name("imap.example.com", 993));
+        let server_side = async {
+            let mut buf = [0u8; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 2, 0, 2]);
+            server.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0u8; 13];
+            server.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x06secret");
+            server.write_all(&[1, 0]).await.unwrap();
+            let mut connect = vec![0u8; 5 + 16 + 2];
+            server.read_exact(&mut connect).await.unwrap();
+            assert_eq!(&connect[..5], [5, 1, 0, 3, 16]);
+            assert_eq!(&connect[5..21], b"imap.example.com");
+            assert_eq!(&connect[21..], 993u16.to_be_bytes());
+            server.write_all(&[5, 0, 0, 1, 10, 0, 0, 1, 0x03, 0xE1]).await.unwrap();
+        };
+        let (result, _) = tokio::join!(handshake, server_side);
+        result.unwrap();
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let addr: SocketAddr = "192.0.2.7:465".parse().unwrap();
+        let proxy = test_proxy("socks5", None);
+        let handshake = socks5_handshake(&mut client, &proxy, Target::Addr(addr));
+        let server_side = async {
+            let mut greeting = [0u8; 3];
+            server.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            server.write_all(&[5, 0]).await.unwrap();
+            let mut connect = [0u8; 10];
+            server.read_exact(&mut connect).await.unwrap();
+            assert_eq!(connect, [5, 1, 0, 1, 192, 0, 2, 7, 0x01, 0xD1]);
+            server.write_all(&[5, 5, 0, 1]).await.unwrap();
+        };
+        let (result, _) = tokio::join!(handshake, server_side);
+        let error = result.unwrap_err();
+        assert_eq!(error.message, "The SOCKS5 proxy couldn't connect to 192.0.2.7:465: connection refused");
+        assert_eq!(error.kind, ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let proxy = test_proxy("http", Some("user"));
+        let connect = http_connect(&mut client, &proxy, "smtp.example.com", 587);
+        let server_side = async {
+            let mut request = vec![0u8; 256];
+            let n = server.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).into_owned();
+            server.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n220 greeting").await.unwrap();
+            request
+        };
+        let (result, request) = tokio::join!(connect, server_side);
+        result.unwrap();
+        assert_eq!(
+            request,
+            "CONNECT smtp.example.com:587 HTTP/1.1\r\nHost: smtp.example.com:587\r\n\
+             Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\r\n"
+        );
+        // The greeting is left for the tunnelled protocol.
+        let mut rest = [0u8; 12];
+        client.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"220 greeting");
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let connect = http_connect(&mut client, &proxy, "::1", 993);
+        let server_side = async {
+            let mut request = vec![0u8; 256];
+            let _ = server.read(&mut request).await.unwrap();
+            server.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await.unwrap();
+        };
+        let (result, _) = tokio::join!(connect, server_side);
+        let error = result.unwrap_err();
+        assert_eq!(error.message, "The HTTP proxy rejected the username or password");
+        assert_eq!(error.kind, ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_resolve_proxy() {
+        let direct = ProxyConfig { kind: "none".to_string(), host: String::new(), port: 0, username: None, password: None };
+        assert_eq!(resolve(Some(&direct)).unwrap(), None);
+        assert_eq!(resolve(Some(&test_proxy("socks5h", None))).unwrap(), Some(test_proxy("socks5h", None)));
+        assert!(resolve(Some(&test_proxy("socks4", None))).is_err());
+        assert!(is_loopback("127.0.0.1") && is_loopback("LOCALHOST") && is_loopback("::1"));
+        assert!(!is_loopback("imap.example.com"));
+    }
+}
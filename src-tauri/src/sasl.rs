@@ -0,0 +1,413 @@
+//! SASL mechanisms shared by IMAP `AUTHENTICATE` and SMTP `AUTH`: PLAIN,
+//! LOGIN, XOAUTH2, OAUTHBEARER (RFC 7628) and SCRAM-SHA-256 (RFC 7677).
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Highest SCRAM iteration count accepted from a server. RFC 7677 asks for at
+/// least 4096; a million already takes about a second, and an unbounded count
+/// would let a server stall the client.
+const MAX_SCRAM_ITERATIONS: u32 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mechanism {
+    /// IMAP `LOGIN` command, or SMTP `AUTH LOGIN`.
+    Login,
+    Plain,
+    ScramSha256,
+    XOAuth2,
+    OAuthBearer,
+}
+
+impl Mechanism {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mechanism::Login => "LOGIN",
+            Mechanism::Plain => "PLAIN",
+            Mechanism::ScramSha256 => "SCRAM-SHA-256",
+            Mechanism::XOAuth2 => "XOAUTH2",
+            Mechanism::OAuthBearer => "OAUTHBEARER",
+        }
+    }
+
+    /// Pick the mechanism for an account's `auth_method`.
+    ///
+    /// `"password"` takes the strongest advertised password mechanism
+    /// (SCRAM-SHA-256, then PLAIN, then LOGIN) and `"oauth2"` prefers
+    /// OAUTHBEARER over XOAUTH2; XOAUTH2 is tried even when not advertised,
+    /// as some providers leave it out. A mechanism name forces that one.
+    /// `advertised` holds the server's SASL mechanism names and
+    /// `login_available` whether LOGIN may be used (no IMAP `LOGINDISABLED`).
+    /// If the server rejects SCRAM-SHA-256, see `password_fallback`.
+    pub fn choose(auth_method: &str, advertised: &[String], login_available: bool) -> Result<Mechanism, String> {
+        let offers = |m: Mechanism| advertised.iter().any(|a| a.eq_ignore_ascii_case(m.name()));
+        match auth_method.to_ascii_lowercase().as_str() {
+            "password" => [Mechanism::ScramSha256, Mechanism::Plain]
+                .into_iter()
+                .find(|&m| offers(m))
+                .or(login_available.then_some(Mechanism::Login))
+                .ok_or_else(|| {
+                    format!(
+                        "The server offers no supported password authentication (advertised: {})",
+                        if advertised.is_empty() { "none".to_string() } else { advertised.join(", ") }
+                    )
+                }),
+            "oauth2" => Ok(if offers(Mechanism::OAuthBearer) {
+                Mechanism::OAuthBearer
+            } else {
+                Mechanism::XOAuth2
+            }),
+            "login" => Ok(Mechanism::Login),
+            "plain" => Ok(Mechanism::Plain),
+            "scram-sha-256" => Ok(Mechanism::ScramSha256),
+            "xoauth2" => Ok(Mechanism::XOAuth2),
+            "oauthbearer" => Ok(Mechanism::OAuthBearer),
+            other => Err(format!(
+                "Unknown auth method: {other}. Use \"password\", \"oauth2\", \"login\", \"plain\", \
+                 \"scram-sha-256\", \"xoauth2\" or \"oauthbearer\"."
+            )),
+        }
+    }
+
+    /// What to retry with after the server rejected the credentials sent with
+    /// `self`. Some Dovecot and Cyrus setups advertise SCRAM-SHA-256 but store
+    /// password hashes it can't be checked against, so for `"password"`
+    /// accounts a rejected SCRAM attempt is retried with PLAIN or LOGIN.
+    pub fn password_fallback(self, auth_method: &str, advertised: &[String], login_available: bool) -> Option<Mechanism> {
+        if self != Mechanism::ScramSha256 || !auth_method.eq_ignore_ascii_case("password") {
+            return None;
+        }
+        if advertised.iter().any(|a| a.eq_ignore_ascii_case(Mechanism::Plain.name())) {
+            Some(Mechanism::Plain)
+        } else {
+            login_available.then_some(Mechanism::Login)
+        }
+    }
+}
+
+/// Whether an `auth_method` authenticates with an OAuth2 access token rather
+/// than a password.
+pub fn uses_oauth_token(auth_method: &str) -> bool {
+    matches!(auth_method.to_ascii_lowercase().as_str(), "oauth2" | "xoauth2" | "oauthbearer")
+}
+
+/// Client side of one SASL exchange. Each server challenge (decoded) goes
+/// through `respond`; the first call returns the initial response whatever
+/// the challenge, since neither IMAP nor SMTP is sent one up front here.
+pub struct SaslClient {
+    mechanism: Mechanism,
+    username: String,
+    secret: String, // password or access token
+    host: String,
+    port: u16,
+    step: u8,
+    scram: Option<Scram>,
+    server_verified: bool,
+    error: Option<String>,
+}
+
+impl SaslClient {
+    pub fn new(mechanism: Mechanism, username: &str, secret: &str, host: &str, port: u16) -> Self {
+        Self {
+            mechanism,
+            username: username.to_string(),
+            secret: secret.to_string(),
+            host: host.to_string(),
+            port,
+            step: 0,
+            scram: None,
+            server_verified: false,
+            error: None,
+        }
+    }
+
+    /// The response to the next challenge. On a malformed challenge the
+    /// returned response is meant to fail the exchange, and `error` explains.
+    pub fn respond(&mut self, challenge: &[u8]) -> Vec<u8> {
+        let step = self.step;
+        self.step = self.step.saturating_add(1);
+        match (self.mechanism, step) {
+            (Mechanism::Plain, 0) => format!("\0{}\0{}", self.username, self.secret).into_bytes(),
+            (Mechanism::Login, 0) => self.username.clone().into_bytes(),
+            (Mechanism::Login, 1) => self.secret.clone().into_bytes(),
+            (Mechanism::XOAuth2, 0) => {
+                format!("user={}\x01auth=Bearer {}\x01\x01", self.username, self.secret).into_bytes()
+            }
+            (Mechanism::OAuthBearer, 0) => format!(
+                "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+                sasl_name(&self.username),
+                self.host,
+                self.port,
+                self.secret
+            )
+            .into_bytes(),
+            // A challenge after the token carries the error status (JSON);
+            // XOAUTH2 answers it with an empty response, OAUTHBEARER with
+            // ^A, and the server then fails the exchange.
+            (Mechanism::XOAuth2 | Mechanism::OAuthBearer, _) => {
+                self.error = Some(format!(
+                    "{} rejected the token: {}",
+                    self.mechanism.name(),
+                    String::from_utf8_lossy(challenge)
+                ));
+                if self.mechanism == Mechanism::OAuthBearer {
+                    b"\x01".to_vec()
+                } else {
+                    Vec::new()
+                }
+            }
+            (Mechanism::ScramSha256, 0) => {
+                let scram = Scram::new(&self.username, &client_nonce());
+                let first = scram.client_first();
+                self.scram = Some(scram);
+                first.into_bytes()
+            }
+            (Mechanism::ScramSha256, 1) => {
+                let result = self
+                    .scram
+                    .as_mut()
+                    .ok_or_else(|| "SCRAM exchange out of order".to_string())
+                    .and_then(|scram| scram.client_final(&self.secret, challenge));
+                match result {
+                    Ok(response) => response.into_bytes(),
+                    Err(e) => {
+                        self.error = Some(e);
+                        b"*".to_vec()
+                    }
+                }
+            }
+            (Mechanism::ScramSha256, 2) => {
+                match self.scram.as_ref().map(|scram| scram.verify_server_final(challenge)) {
+                    Some(Ok(())) => self.server_verified = true,
+                    Some(Err(e)) => self.error = Some(e),
+                    None => self.error = Some("SCRAM exchange out of order".to_string()),
+                }
+                Vec::new()
+            }
+            _ => {
+                self.error = Some(format!("Unexpected {} challenge from the server", self.mechanism.name()));
+                Vec::new()
+            }
+        }
+    }
+
+    /// What went wrong on our side of the exchange, if anything.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Check a completed exchange: SCRAM must have seen the server prove it
+    /// knows the password, or the server may be an impostor.
+    pub fn finish(&self) -> Result<(), String> {
+        if let Some(e) = &self.error {
+            return Err(e.clone());
+        }
+        if self.mechanism == Mechanism::ScramSha256 && !self.server_verified {
+            return Err("SCRAM-SHA-256: the server did not send its signature".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// `saslname` escaping (RFC 5802): `=` and `,` are reserved.
+fn sasl_name(name: &str) -> String {
+    name.replace('=', "=3D").replace(',', "=2C")
+}
+
+fn client_nonce() -> String {
+    let mut bytes = [0u8; 24];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        // No OS randomness: fall back to something unique, which is all a
+        // SCRAM nonce needs.
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        bytes[..16].copy_from_slice(&now.to_le_bytes());
+    }
+    base64::engine::general_purpose::STANDARD_NO_PAD.encode(bytes)
+}
+
+/// SCRAM-SHA-256 state between the client's first and final messages.
+struct Scram {
+    client_first_bare: String,
+    nonce: String,
+    server_signature: Option<Vec<u8>>,
+}
+
+impl Scram {
+    fn new(username: &str, nonce: &str) -> Self {
+        Self {
+            client_first_bare: format!("n={},r={nonce}", sasl_name(username)),
+            nonce: nonce.to_string(),
+            server_signature: None,
+        }
+    }
+
+    fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first_bare)
+    }
+
+    fn client_final(&mut self, password: &str, server_first: &[u8]) -> Result<String, String> {
+        let server_first = std::str::from_utf8(server_first).map_err(|_| "SCRAM: server-first message is not UTF-8".to_string())?;
+        let attribute = |name: char| {
+            server_first
+                .split(',')
+                .find_map(|part| part.strip_prefix(name).and_then(|p| p.strip_prefix('=')))
+        };
+        if let Some(e) = attribute('e') {
+            return Err(format!("SCRAM: server error: {e}"));
+        }
+        let nonce = attribute('r').ok_or("SCRAM: no nonce in server-first message")?;
+        if !nonce.starts_with(&self.nonce) {
+            return Err("SCRAM: server nonce doesn't extend ours".to_string());
+        }
+        let salt = attribute('s')
+            .and_then(|s| base64::engine::general_purpose::STANDARD.decode(s).ok())
+            .ok_or("SCRAM: bad salt in server-first message")?;
+        let iterations: u32 = attribute('i')
+            .and_then(|i| i.parse().ok())
+            .filter(|&i| i > 0)
+            .ok_or("SCRAM: bad iteration count in server-first message")?;
+        if iterations > MAX_SCRAM_ITERATIONS {
+            return Err(format!("SCRAM: the server asks for {iterations} iterations, more than the {MAX_SCRAM_ITERATIONS} allowed"));
+        }
+
+        let salted = run_blocking(|| pbkdf2_sha256(password.as_bytes(), &salt, iterations));
+        let client_key = hmac_sha256(&salted, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        let without_proof = format!("c=biws,r={nonce}");
+        let auth_message = format!("{},{server_first},{without_proof}", self.client_first_bare);
+        let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key.iter().zip(&client_signature).map(|(k, s)| k ^ s).collect();
+
+        let server_key = hmac_sha256(&salted, b"Server Key");
+        self.server_signature = Some(hmac_sha256(&server_key, auth_message.as_bytes()));
+
+        Ok(format!(
+            "{without_proof},p={}",
+            base64::engine::general_purpose::STANDARD.encode(proof)
+        ))
+    }
+
+    fn verify_server_final(&self, server_final: &[u8]) -> Result<(), String> {
+        let server_final = String::from_utf8_lossy(server_final);
+        if let Some(e) = server_final.strip_prefix("e=") {
+            return Err(format!("SCRAM: server error: {e}"));
+        }
+        let signature = server_final
+            .strip_prefix("v=")
+            .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v.trim()).ok())
+            .ok_or("SCRAM: bad server-final message")?;
+        if Some(signature) != self.server_signature {
+            return Err("SCRAM: the server's signature doesn't match — it may not be the server it claims to be".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Run CPU-heavy work without stalling the async runtime. async-imap calls
+/// `Authenticator::process` synchronously, so the work can't go through
+/// `spawn_blocking`; `block_in_place` hands this worker's other tasks to
+/// another thread instead. Outside a multi-threaded runtime it runs inline.
+fn run_blocking<T>(f: impl FnOnce() -> T) -> T {
+    use tokio::runtime::{Handle, RuntimeFlavor};
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+/// PBKDF2-HMAC-SHA-256 with a single output block, which is all SCRAM needs.
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let keyed = HmacSha256::new_from_slice(password).expect("HMAC accepts keys of any length");
+    let mut mac = keyed.clone();
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut u = mac.finalize().into_bytes();
+    let mut result = u;
+    for _ in 1..iterations {
+        let mut mac = keyed.clone();
+        mac.update(&u);
+        u = mac.finalize().into_bytes();
+        for (r, b) in result.iter_mut().zip(u.iter()) {
+            *r ^= b;
+        }
+    }
+    result.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scram_sha256_rfc7677() {
+        let mut scram = Scram::new("user", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(scram.client_first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let client_final = scram
+            .client_final(
+                "pencil",
+                b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            )
+            .unwrap();
+        assert_eq!(
+            client_final,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert!(scram.verify_server_final(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").is_ok());
+        assert!(scram.verify_server_final(b"v=AAAATRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").is_err());
+        assert!(scram.client_final("pencil", b"r=someoneelse,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096").is_err());
+        assert!(scram
+            .client_final("pencil", b"r=rOprNGfwEbeRWgbNEkqO%hvY,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4000000000")
+            .unwrap_err()
+            .contains("iterations"));
+    }
+
+    #[test]
+    fn test_choose_mechanism() {
+        let advertised = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let all = advertised(&["PLAIN", "SCRAM-SHA-256", "OAUTHBEARER", "XOAUTH2"]);
+        assert_eq!(Mechanism::choose("password", &all, true), Ok(Mechanism::ScramSha256));
+        assert_eq!(Mechanism::choose("password", &advertised(&["plain"]), false), Ok(Mechanism::Plain));
+        assert_eq!(Mechanism::choose("password", &[], true), Ok(Mechanism::Login));
+        assert!(Mechanism::choose("password", &advertised(&["GSSAPI"]), false).is_err());
+        assert_eq!(Mechanism::choose("oauth2", &all, true), Ok(Mechanism::OAuthBearer));
+        assert_eq!(Mechanism::choose("oauth2", &[], true), Ok(Mechanism::XOAuth2));
+        assert_eq!(Mechanism::choose("plain", &[], true), Ok(Mechanism::Plain));
+        assert!(Mechanism::choose("kerberos", &all, true).is_err());
+    }
+
+    #[test]
+    fn test_password_fallback() {
+        let advertised = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let scram_plain = advertised(&["SCRAM-SHA-256", "PLAIN"]);
+        let scram = Mechanism::ScramSha256;
+        assert_eq!(scram.password_fallback("password", &scram_plain, true), Some(Mechanism::Plain));
+        assert_eq!(scram.password_fallback("password", &advertised(&["SCRAM-SHA-256"]), true), Some(Mechanism::Login));
+        assert_eq!(scram.password_fallback("password", &advertised(&["SCRAM-SHA-256"]), false), None);
+        // A forced mechanism is never swapped, nor is anything but SCRAM.
+        assert_eq!(scram.password_fallback("scram-sha-256", &scram_plain, true), None);
+        assert_eq!(Mechanism::Plain.password_fallback("password", &scram_plain, true), None);
+    }
+
+    #[test]
+    fn test_oauthbearer_response() {
+        let mut client = SaslClient::new(Mechanism::OAuthBearer, "a,b@example.com", "tok", "imap.example.com", 993);
+        assert_eq!(
+            client.respond(b""),
+            b"n,a=a=2Cb@example.com,\x01host=imap.example.com\x01port=993\x01auth=Bearer tok\x01\x01"
+        );
+        assert_eq!(client.respond(br#"{"status":"invalid_token"}"#), b"\x01");
+        assert!(client.finish().unwrap_err().contains("invalid_token"));
+    }
+}
@@ -1,13 +1,64 @@
-use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
-use lettre::{
-    transport::smtp::{
-        authentication::{Credentials, Mechanism},
-        client::{Tls, TlsParametersBuilder},
-    },
-    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use lettre::transport::smtp::{
+    client::{AsyncSmtpConnection, AsyncTokioStream},
+    commands::Ehlo,
+    extension::ClientId,
 };
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_native_tls::TlsStream;
 
 use super::types::{SmtpConfig, SmtpSendResult};
+use crate::error::{ErrorKind, MailError};
+use crate::proxy;
+use crate::sasl::{Mechanism, SaslClient};
+use crate::tls::{self, ServerCertificate};
+use crate::trace::{self, TracedStream};
+
+/// Connect, TLS and authentication, together.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Sending the message, which may be large.
+const SEND_TIMEOUT: Duration = Duration::from_secs(300);
+/// AUTH challenges answered before giving up on a misbehaving server.
+const MAX_AUTH_CHALLENGES: usize = 10;
+
+/// A lettre error, typed by the server's reply code where there is one.
+fn smtp_error(context: &str, e: lettre::transport::smtp::Error) -> MailError {
+    let message = format!("{context}: {e}");
+    if let Some(code) = e.status() {
+        let response = match std::error::Error::source(&e) {
+            Some(text) => format!("{code} {text}"),
+            None => code.to_string(),
+        };
+        return MailError::smtp_reply(code.into(), response, message);
+    }
+    let kind = if e.is_tls() {
+        ErrorKind::TlsError
+    } else if e.is_response() {
+        ErrorKind::ProtocolParse
+    } else if e.is_client() {
+        ErrorKind::Other
+    } else {
+        ErrorKind::Network
+    };
+    MailError::new(kind, message)
+}
+
+/// An unexpected reply we read ourselves, e.g. to STARTTLS.
+fn reply_error(reply: &str, message: String) -> MailError {
+    match reply.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+        Some(code @ 400..=599) => MailError::smtp_reply(code, reply.trim_end(), message),
+        _ => MailError::new(ErrorKind::ProtocolParse, message).with_server_response(reply.trim_end()),
+    }
+}
 
 /// Decode a base64url-encoded string (Gmail format) to raw bytes.
 fn decode_base64url(input: &str) -> Result<Vec<u8>, String> {
@@ -16,69 +67,351 @@ fn decode_base64url(input: &str) -> Result<Vec<u8>, String> {
         .map_err(|e| format!("Base64 decode error: {}", e))
 }
 
-/// Build an async SMTP transport from the given config.
-fn build_transport(
-    config: &SmtpConfig,
-) -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
-    let credentials = Credentials::new(config.username.clone(), config.password.clone());
+// ---------- Connection ----------
+//
+// Sessions run on lettre's `AsyncSmtpConnection` over a stream of our own
+// rather than on `AsyncSmtpTransport`. TLS is negotiated here, underneath the
+// protocol trace, so a trace shows the SMTP dialogue rather than TLS records,
+// and authentication goes through our SASL mechanisms, as lettre only knows
+// PLAIN, LOGIN and XOAUTH2.
 
-    // For OAuth2, force XOAUTH2 mechanism; for password, use default mechanisms
-    let auth_mechanisms = if config.auth_method == "oauth2" {
-        vec![Mechanism::Xoauth2]
-    } else {
-        vec![Mechanism::Plain, Mechanism::Login]
-    };
+/// Wrapper to unify TLS / plain sockets. `Traced` wraps one of the others
+/// while the account's protocol trace is running.
+#[derive(Debug)]
+enum SmtpSocket {
+    Tls(TlsStream<TcpStream>),
+    Plain(TcpStream),
+    Traced(Box<TracedStream<SmtpSocket>>),
+}
 
-    let transport = match config.security.as_str() {
-        "tls" => {
-            // Implicit TLS (typically port 465)
-            let mut builder = AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)
-                .map_err(|e| format!("SMTP relay error: {}", e))?
-                .port(config.port)
-                .credentials(credentials)
-                .authentication(auth_mechanisms);
-
-            if config.accept_invalid_certs {
-                let tls_params = TlsParametersBuilder::new(config.host.clone())
-                    .dangerous_accept_invalid_certs(true)
-                    .dangerous_accept_invalid_hostnames(true)
-                    .build()
-                    .map_err(|e| format!("SMTP TLS params error: {}", e))?;
-                builder = builder.tls(Tls::Required(tls_params));
+impl SmtpSocket {
+    /// Wrap the socket for the account's protocol trace, if one is running.
+    fn traced(self, config: &SmtpConfig) -> Self {
+        let server = format!("{}:{} ({})", config.host, config.port, config.security);
+        match trace::recorder(config.account_id.as_deref(), trace::Protocol::Smtp, &server) {
+            Some(recorder) => SmtpSocket::Traced(Box::new(TracedStream::new(self, recorder))),
+            None => self,
+        }
+    }
+
+    /// Upgrade a plain socket to TLS, keeping its trace.
+    async fn upgrade(self, config: &SmtpConfig) -> Result<Self, MailError> {
+        match self {
+            SmtpSocket::Plain(tcp) => Ok(SmtpSocket::Tls(tls_connect(config, tcp).await?)),
+            SmtpSocket::Traced(traced) => {
+                let (inner, recorder) = traced.into_parts();
+                let tls = Box::pin(inner.upgrade(config)).await?;
+                recorder.note("TLS established");
+                Ok(SmtpSocket::Traced(Box::new(TracedStream::new(tls, recorder))))
             }
+            SmtpSocket::Tls(_) => Err(MailError::new(ErrorKind::Other, "SMTP connection is already encrypted")),
+        }
+    }
+}
 
-            builder.build()
+impl AsyncRead for SmtpSocket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            SmtpSocket::Tls(s) => Pin::new(s).poll_read(cx, buf),
+            SmtpSocket::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            SmtpSocket::Traced(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
         }
-        "starttls" => {
-            // STARTTLS (typically port 587)
-            let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
-                .map_err(|e| format!("SMTP STARTTLS error: {}", e))?
-                .port(config.port)
-                .credentials(credentials)
-                .authentication(auth_mechanisms);
-
-            if config.accept_invalid_certs {
-                let tls_params = TlsParametersBuilder::new(config.host.clone())
-                    .dangerous_accept_invalid_certs(true)
-                    .dangerous_accept_invalid_hostnames(true)
-                    .build()
-                    .map_err(|e| format!("SMTP TLS params error: {}", e))?;
-                builder = builder.tls(Tls::Required(tls_params));
+    }
+}
+
+impl AsyncWrite for SmtpSocket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            SmtpSocket::Tls(s) => Pin::new(s).poll_write(cx, buf),
+            SmtpSocket::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            SmtpSocket::Traced(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            SmtpSocket::Tls(s) => Pin::new(s).poll_flush(cx),
+            SmtpSocket::Plain(s) => Pin::new(s).poll_flush(cx),
+            SmtpSocket::Traced(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            SmtpSocket::Tls(s) => Pin::new(s).poll_shutdown(cx),
+            SmtpSocket::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            SmtpSocket::Traced(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The stream handed to lettre. After our own STARTTLS, `replay` holds the
+/// server greeting so that lettre's handshake (greeting, then EHLO) runs as
+/// on a fresh connection; RFC 3207 requires a new EHLO after STARTTLS anyway.
+#[derive(Debug)]
+struct SmtpStream {
+    replay: Vec<u8>,
+    socket: SmtpSocket,
+    peer: SocketAddr,
+}
+
+impl AsyncRead for SmtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.replay.is_empty() {
+            let n = this.replay.len().min(buf.remaining());
+            buf.put_slice(&this.replay[..n]);
+            this.replay.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.socket).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SmtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().socket).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().socket).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().socket).poll_shutdown(cx)
+    }
+}
+
+impl AsyncTokioStream for SmtpStream {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+/// How to check the server's certificate: the pinned one only, if the
+/// account has a pin, otherwise against the system's and the account's CAs
+/// unless the account accepts invalid certificates (for local mail bridges
+/// like ProtonMail Bridge with self-signed certs).
+fn tls_options(config: &SmtpConfig) -> tls::TlsOptions<'_> {
+    tls::TlsOptions {
+        accept_invalid_certs: config.accept_invalid_certs,
+        pinned_sha256: config.pinned_cert_sha256.as_deref(),
+        ca_certificates_pem: config.ca_certificates_pem.as_deref(),
+        client_identity_pkcs12: config.client_identity_pkcs12.as_deref(),
+        client_identity_password: config.client_identity_password.as_deref(),
+        min_tls_version: config.min_tls_version.as_deref(),
+    }
+}
+
+async fn tls_connect(config: &SmtpConfig, tcp: TcpStream) -> Result<TlsStream<TcpStream>, MailError> {
+    tls::handshake(&config.host, tcp, tls_options(config)).await
+}
+
+/// Read one reply, all lines of it.
+async fn read_reply<S: AsyncRead + Unpin>(reader: &mut BufReader<S>) -> Result<String, MailError> {
+    let mut reply = String::new();
+    loop {
+        let mut line = String::new();
+        let n = reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| MailError::io(&e, format!("SMTP read failed: {e}")))?;
+        if n == 0 {
+            return Err(MailError::new(ErrorKind::Network, "SMTP connection closed by server"));
+        }
+        reply.push_str(&line);
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(reply);
+        }
+    }
+}
+
+/// Greeting, EHLO and STARTTLS on the plain connection. Returns the greeting
+/// for `SmtpStream::replay`.
+async fn starttls_handshake<S>(socket: &mut S, hello: &ClientId) -> Result<Vec<u8>, MailError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let write_failed = |e: std::io::Error| MailError::io(&e, format!("SMTP write failed: {e}"));
+    let mut reader = BufReader::new(socket);
+    let greeting = read_reply(&mut reader).await?;
+    if !greeting.starts_with("220") {
+        return Err(reply_error(&greeting, format!("Unexpected SMTP greeting: {}", greeting.trim_end())));
+    }
+    for (command, expected) in [(Ehlo::new(hello.clone()).to_string(), "250"), ("STARTTLS\r\n".to_string(), "220")] {
+        let io = reader.get_mut();
+        io.write_all(command.as_bytes()).await.map_err(write_failed)?;
+        io.flush().await.map_err(write_failed)?;
+        let reply = read_reply(&mut reader).await?;
+        if !reply.starts_with(expected) {
+            let message = format!(
+                "SMTP {} rejected: {}",
+                command.split_whitespace().next().unwrap_or_default(),
+                reply.trim_end()
+            );
+            return Err(reply_error(&reply, message));
+        }
+    }
+    Ok(greeting.into_bytes())
+}
+
+/// The certificate the server presents, for the user to review and pin.
+pub async fn fetch_certificate(config: &SmtpConfig) -> Result<ServerCertificate, MailError> {
+    let plain = || async {
+        let tcp = tcp_connect(config).await?;
+        match config.security.as_str() {
+            "tls" => Ok(tcp),
+            "starttls" => {
+                let mut tcp = tcp;
+                starttls_handshake(&mut tcp, &ClientId::default()).await?;
+                Ok(tcp)
             }
+            _ => Err(MailError::new(
+                ErrorKind::Other,
+                "The connection isn't encrypted, so there is no certificate to check",
+            )),
+        }
+    };
+    tokio::time::timeout(CONNECT_TIMEOUT, tls::fetch_certificate(&config.host, tls_options(config), plain))
+        .await
+        .map_err(|_| connect_timed_out(config))?
+}
+
+fn connect_timed_out(config: &SmtpConfig) -> MailError {
+    MailError::timeout(
+        "connect",
+        format!(
+            "SMTP connection to {}:{} timed out after {}s — check your server settings or network connection",
+            config.host, config.port, CONNECT_TIMEOUT.as_secs()
+        ),
+    )
+}
 
-            builder.build()
+/// Open and authenticate a session.
+async fn connect(config: &SmtpConfig) -> Result<AsyncSmtpConnection, MailError> {
+    tokio::time::timeout(CONNECT_TIMEOUT, connect_inner(config))
+        .await
+        .map_err(|_| connect_timed_out(config))?
+}
+
+async fn connect_inner(config: &SmtpConfig) -> Result<AsyncSmtpConnection, MailError> {
+    let hello = ClientId::default();
+    let tcp = tcp_connect(config).await?;
+    let peer = tcp
+        .peer_addr()
+        .map_err(|e| MailError::io(&e, format!("TCP connect to {}:{} failed: {e}", config.host, config.port)))?;
+    let mut socket = SmtpSocket::Plain(tcp).traced(config);
+
+    let replay = match config.security.as_str() {
+        // Implicit TLS (typically port 465)
+        "tls" => {
+            socket = socket.upgrade(config).await?;
+            Vec::new()
+        }
+        // STARTTLS (typically port 587)
+        "starttls" => {
+            let greeting = starttls_handshake(&mut socket, &hello).await?;
+            socket = socket.upgrade(config).await?;
+            greeting
         }
-        _ => {
-            // Plain / no encryption (typically port 25) — not recommended
-            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
-                .port(config.port)
-                .credentials(credentials)
-                .authentication(auth_mechanisms)
-                .build()
+        // Plain / no encryption (typically port 25) — not recommended
+        _ => Vec::new(),
+    };
+
+    let stream = SmtpStream { replay, socket, peer };
+    let mut connection = AsyncSmtpConnection::connect_with_transport(Box::new(stream), &hello)
+        .await
+        .map_err(|e| smtp_error("SMTP connection error", e))?;
+    authenticate(&mut connection, config, &hello).await?;
+    Ok(connection)
+}
+
+async fn tcp_connect(config: &SmtpConfig) -> Result<TcpStream, MailError> {
+    proxy::connect(config.proxy.as_ref(), &config.host, config.port).await
+}
+
+/// Authenticate with the mechanism `auth_method` picks from the server's AUTH
+/// list (see `Mechanism::choose`). A rejected SCRAM attempt is retried once
+/// (see `Mechanism::password_fallback`).
+async fn authenticate(connection: &mut AsyncSmtpConnection, config: &SmtpConfig, hello: &ClientId) -> Result<(), MailError> {
+    // lettre drops the mechanisms it doesn't know from its EHLO results, so
+    // ask again for the full list.
+    let ehlo = connection
+        .command(Ehlo::new(hello.clone()))
+        .await
+        .map_err(|e| smtp_error("SMTP EHLO failed", e))?;
+    let advertised = auth_mechanisms(ehlo.message());
+    let login_available = advertised.iter().any(|m| m == "LOGIN");
+    let mechanism = Mechanism::choose(&config.auth_method, &advertised, login_available)
+        .map_err(|e| MailError::new(ErrorKind::Other, e))?;
+
+    match authenticate_with(connection, config, mechanism).await {
+        Err((e, true)) => {
+            let Some(fallback) = mechanism.password_fallback(&config.auth_method, &advertised, login_available) else {
+                return Err(e);
+            };
+            log::info!("SMTP {}: {e}; retrying with {}", config.host, fallback.name());
+            authenticate_with(connection, config, fallback).await.map_err(|(e, _)| e)
         }
+        result => result.map_err(|(e, _)| e),
+    }
+}
+
+/// One AUTH exchange. The flag in the error is set when the server rejected
+/// the credentials (5xx) and the exchange went as expected otherwise, so
+/// another attempt can follow on the same connection.
+async fn authenticate_with(
+    connection: &mut AsyncSmtpConnection,
+    config: &SmtpConfig,
+    mechanism: Mechanism,
+) -> Result<(), (MailError, bool)> {
+    let context = format!("SMTP {} authentication failed", mechanism.name());
+    let failed = |sasl: &SaslClient, e: String| {
+        let message = match sasl.error() {
+            Some(detail) => format!("{context}: {e} ({detail})"),
+            None => format!("{context}: {e}"),
+        };
+        MailError::new(ErrorKind::AuthFailed, message)
+    };
+    let rejected = |sasl: &SaslClient, e: lettre::transport::smtp::Error| {
+        let retry = e.is_permanent() && sasl.error().is_none();
+        let error = match sasl.error() {
+            Some(_) => failed(sasl, e.to_string()),
+            None => smtp_error(&context, e),
+        };
+        (error, retry)
     };
+    let mut sasl = SaslClient::new(mechanism, &config.username, &config.password, &config.host, config.port);
+    let mut response = connection
+        .command(format!("AUTH {}\r\n", mechanism.name()))
+        .await
+        .map_err(|e| rejected(&sasl, e))?;
+    for _ in 0..MAX_AUTH_CHALLENGES {
+        if !response.has_code(334) {
+            return sasl.finish().map_err(|e| (failed(&sasl, e), false));
+        }
+        let challenge = STANDARD
+            .decode(response.first_line().unwrap_or_default().trim())
+            .map_err(|e| (failed(&sasl, format!("bad challenge: {e}")), false))?;
+        let reply = STANDARD.encode(sasl.respond(&challenge));
+        response = connection
+            .command(format!("{reply}\r\n"))
+            .await
+            .map_err(|e| rejected(&sasl, e))?;
+    }
+    Err((failed(&sasl, "too many challenges".to_string()), false))
+}
 
-    Ok(transport)
+/// SASL mechanisms from the lines of an EHLO reply, e.g. `AUTH PLAIN LOGIN`
+/// (or the pre-standard `AUTH=PLAIN LOGIN`).
+fn auth_mechanisms<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<String> {
+    lines
+        .filter_map(|line| {
+            let keyword = line.get(..5)?;
+            (keyword.eq_ignore_ascii_case("AUTH ") || keyword.eq_ignore_ascii_case("AUTH=")).then(|| &line[5..])
+        })
+        .flat_map(|mechanisms| mechanisms.split_whitespace().map(|m| m.to_ascii_uppercase()))
+        .collect()
 }
 
 /// Extract an SMTP envelope (sender + recipients) from raw RFC 2822 bytes.
@@ -150,37 +483,44 @@ fn extract_envelope(raw: &[u8]) -> Result<lettre::address::Envelope, String> {
 pub async fn send_raw_email(
     config: &SmtpConfig,
     raw_email_base64url: &str,
-) -> Result<SmtpSendResult, String> {
-    let raw_bytes = decode_base64url(raw_email_base64url)?;
-    let envelope = extract_envelope(&raw_bytes)?;
-    let transport = build_transport(config)?;
+) -> Result<SmtpSendResult, MailError> {
+    let raw_bytes = decode_base64url(raw_email_base64url).map_err(|e| MailError::new(ErrorKind::Other, e))?;
+    let envelope = extract_envelope(&raw_bytes).map_err(|e| MailError::new(ErrorKind::Other, e))?;
 
-    transport
-        .send_raw(&envelope, &raw_bytes)
+    let mut connection = connect(config).await?;
+
+    let result = tokio::time::timeout(SEND_TIMEOUT, connection.send(&envelope, &raw_bytes))
         .await
+        .map_err(|_| {
+            MailError::timeout(
+                "send",
+                format!(
+                    "SMTP send timed out after {}s — check your server settings or network connection",
+                    SEND_TIMEOUT.as_secs()
+                ),
+            )
+        })?
         .map(|_response| SmtpSendResult {
             success: true,
             message: "Email sent successfully".to_string(),
         })
-        .map_err(|e| format!("SMTP send error: {}", e))
+        .map_err(|e| smtp_error("SMTP send error", e));
+    let _ = connection.quit().await;
+    result
 }
 
 /// Test SMTP connectivity by connecting, authenticating, and disconnecting.
-pub async fn test_connection(config: &SmtpConfig) -> Result<SmtpSendResult, String> {
-    let transport = build_transport(config)?;
+pub async fn test_connection(config: &SmtpConfig) -> Result<SmtpSendResult, MailError> {
+    let mut connection = connect(config).await.map_err(|e| MailError {
+        message: format!("SMTP test error: {}", e.message),
+        ..e
+    })?;
+    let _ = connection.quit().await;
 
-    transport
-        .test_connection()
-        .await
-        .map(|success| SmtpSendResult {
-            success,
-            message: if success {
-                "Connection successful".to_string()
-            } else {
-                "Connection failed".to_string()
-            },
-        })
-        .map_err(|e| format!("SMTP test error: {}", e))
+    Ok(SmtpSendResult {
+        success: true,
+        message: "Connection successful".to_string(),
+    })
 }
 
 #[cfg(test)]
@@ -227,6 +567,16 @@ mod tests {
         assert!(result.unwrap_err().contains("No recipients found"));
     }
 
+    #[test]
+    fn test_auth_mechanisms() {
+        let ehlo = ["smtp.example.com", "PIPELINING", "AUTH PLAIN login SCRAM-SHA-256", "AUTH=OAUTHBEARER", "8BITMIME"];
+        assert_eq!(
+            auth_mechanisms(ehlo.into_iter()),
+            ["PLAIN", "LOGIN", "SCRAM-SHA-256", "OAUTHBEARER"]
+        );
+        assert!(auth_mechanisms(["smtp.example.com", "AUTHX"].into_iter()).is_empty());
+    }
+
     #[test]
     fn test_extract_envelope_with_bcc() {
         let raw = b"From: alice@example.com\r\nTo: bob@example.com\r\nBcc: secret@example.com\r\nSubject: Test\r\n\r\nBody";
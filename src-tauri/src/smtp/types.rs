@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::proxy::ProxyConfig;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SmtpConfig {
     pub host: String,
@@ -7,9 +9,23 @@ pub struct SmtpConfig {
     pub security: String,    // "tls", "starttls", "none"
     pub username: String,
     pub password: String,    // plaintext password or OAuth2 access token
-    pub auth_method: String, // "password" or "oauth2"
+    pub auth_method: String, // "password", "oauth2", or a mechanism to force, e.g. "scram-sha-256" (see sasl::Mechanism::choose)
     #[serde(default)]
     pub accept_invalid_certs: bool,
+    #[serde(default)]
+    pub pinned_cert_sha256: Option<String>, // accept only this certificate; overrides accept_invalid_certs
+    #[serde(default)]
+    pub ca_certificates_pem: Option<String>, // CAs to trust besides the system's, e.g. a corporate root
+    #[serde(default)]
+    pub client_identity_pkcs12: Option<String>, // base64 PKCS#12 client certificate and key, for mutual TLS
+    #[serde(default)]
+    pub client_identity_password: Option<String>,
+    #[serde(default)]
+    pub min_tls_version: Option<String>, // "1.0", "1.1", "1.2" or "1.3"; defaults to 1.2
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>, // overrides the global proxy; kind "none" connects directly
+    #[serde(default)]
+    pub account_id: Option<String>, // identifies the account's protocol trace, if one is running
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,460 @@
+//! TLS handshakes shared by the IMAP and SMTP clients: extra trusted CAs,
+//! client certificates, a minimum protocol version, and trust-on-first-use
+//! certificate pinning for servers the system doesn't trust (self-signed
+//! certificates, local bridges such as Proton Mail Bridge).
+//!
+//! A pinned connection skips the usual chain and hostname checks but accepts
+//! exactly one certificate, identified by the SHA-256 digest of its DER
+//! encoding, and fails before anything is sent if the server presents another.
+
+use std::future::Future;
+use std::net::IpAddr;
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_native_tls::TlsStream;
+
+use crate::error::{ErrorKind, MailError};
+
+/// How to verify the server's certificate, and how to identify ourselves.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TlsOptions<'a> {
+    /// Accept any certificate. A pin, when set, takes precedence.
+    pub accept_invalid_certs: bool,
+    /// SHA-256 fingerprint of the only certificate to accept.
+    pub pinned_sha256: Option<&'a str>,
+    /// PEM certificates to trust besides the system's, e.g. a corporate CA.
+    pub ca_certificates_pem: Option<&'a str>,
+    /// Base64 PKCS#12 archive holding the client certificate and key, for
+    /// servers that require mutual TLS.
+    pub client_identity_pkcs12: Option<&'a str>,
+    pub client_identity_password: Option<&'a str>,
+    /// "1.0", "1.1", "1.2" or "1.3"; native-tls defaults to 1.2.
+    pub min_tls_version: Option<&'a str>,
+}
+
+/// The certificate a server presents, for the user to review before pinning
+/// it. native-tls only exposes the server's own certificate, not the
+/// intermediates sent with it; the pin covers that certificate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerCertificate {
+    pub sha256: String, // "AB:CD:...", the value to pin
+    pub subject: Option<String>,
+    pub issuer: Option<String>,
+    pub not_before: Option<i64>, // unix seconds
+    pub not_after: Option<i64>,  // unix seconds
+    pub alt_names: Vec<String>,  // subjectAltName DNS names and IP addresses
+    pub self_signed: bool,
+    pub trusted: bool,                // accepted for this host without a pin, by the system's or the account's CAs
+    pub verify_error: Option<String>, // why it wasn't
+    pub pem: String,
+}
+
+/// TLS handshake over an established connection, verified as `options` says.
+/// Every failure, including invalid settings, is a `TlsError`.
+pub async fn handshake<S>(host: &str, stream: S, options: TlsOptions<'_>) -> Result<TlsStream<S>, MailError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let tls_error = |message: String| MailError::new(ErrorKind::TlsError, message);
+    let pin = options.pinned_sha256.map(normalize_fingerprint).transpose().map_err(tls_error)?;
+    let tls = tokio_native_tls::TlsConnector::from(connector(&options, pin.is_none()).map_err(tls_error)?)
+        .connect(host, stream)
+        .await
+        .map_err(|e| tls_error(format!("TLS handshake with {host} failed: {e}")))?;
+
+    if let Some(pin) = pin {
+        let actual = fingerprint(&peer_certificate(&tls).map_err(tls_error)?);
+        if normalize_fingerprint(&actual).map_err(tls_error)? != pin {
+            return Err(tls_error(format!(
+                "The TLS certificate of {host} has changed since it was pinned (pinned SHA-256 {}, \
+                 presented {actual}). If the server's certificate was replaced, review the new one \
+                 and pin it; otherwise the connection may be intercepted.",
+                format_fingerprint(&pin)
+            )));
+        }
+    }
+    Ok(tls)
+}
+
+/// The certificate the server presents, checked against the system's and
+/// `options`' CAs; any pin in `options` is ignored. `connect` opens a
+/// connection ready for the handshake; it's called a second time when the
+/// certificate isn't trusted, to fetch it anyway.
+pub async fn fetch_certificate<S, F, Fut>(
+    host: &str,
+    options: TlsOptions<'_>,
+    connect: F,
+) -> Result<ServerCertificate, MailError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<S, MailError>>,
+{
+    let verified = TlsOptions { accept_invalid_certs: false, pinned_sha256: None, ..options };
+    let (tls, verify_error) = match handshake(host, connect().await?, verified).await {
+        Ok(tls) => (tls, None),
+        Err(e) => {
+            let unverified = TlsOptions { accept_invalid_certs: true, ..verified };
+            (handshake(host, connect().await?, unverified).await?, Some(e.message))
+        }
+    };
+    let der = peer_certificate(&tls).map_err(|e| MailError::new(ErrorKind::TlsError, e))?;
+    Ok(describe(&der, verify_error))
+}
+
+/// The native-tls connector for `options`. Without `verify`, any certificate
+/// is accepted and it's up to the caller to check it.
+fn connector(options: &TlsOptions<'_>, verify: bool) -> Result<native_tls::TlsConnector, String> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if !verify || options.accept_invalid_certs {
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+    if let Some(pem) = options.ca_certificates_pem.filter(|pem| !pem.trim().is_empty()) {
+        let certificates = native_tls::Certificate::stack_from_pem(pem.as_bytes())
+            .map_err(|e| format!("Invalid CA certificates: {e}"))?;
+        if certificates.is_empty() {
+            return Err("Invalid CA certificates: no PEM certificate found".to_string());
+        }
+        for certificate in certificates {
+            builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(archive) = options.client_identity_pkcs12.filter(|a| !a.trim().is_empty()) {
+        let der = base64::engine::general_purpose::STANDARD
+            .decode(archive.trim())
+            .map_err(|e| format!("Invalid client certificate: {e}"))?;
+        let identity = native_tls::Identity::from_pkcs12(&der, options.client_identity_password.unwrap_or_default())
+            .map_err(|e| format!("Invalid client certificate (wrong password?): {e}"))?;
+        builder.identity(identity);
+    }
+    if let Some(version) = options.min_tls_version.filter(|v| !v.trim().is_empty()) {
+        builder.min_protocol_version(Some(parse_tls_version(version)?));
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Failed to create TLS connector: {e}"))
+}
+
+fn parse_tls_version(version: &str) -> Result<native_tls::Protocol, String> {
+    match version.trim().to_ascii_lowercase().trim_start_matches("tls").trim_start_matches('v') {
+        "1.0" | "1" => Ok(native_tls::Protocol::Tlsv10),
+        "1.1" => Ok(native_tls::Protocol::Tlsv11),
+        "1.2" => Ok(native_tls::Protocol::Tlsv12),
+        "1.3" => Ok(native_tls::Protocol::Tlsv13),
+        _ => Err(format!(
+            "Unknown minimum TLS version: {version}. Use \"1.0\", \"1.1\", \"1.2\" or \"1.3\"."
+        )),
+    }
+}
+
+fn peer_certificate<S>(tls: &TlsStream<S>) -> Result<Vec<u8>, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tls.get_ref()
+        .peer_certificate()
+        .map_err(|e| format!("Failed to read the server certificate: {e}"))?
+        .ok_or("The server presented no certificate")?
+        .to_der()
+        .map_err(|e| format!("Failed to read the server certificate: {e}"))
+}
+
+fn describe(der: &[u8], verify_error: Option<String>) -> ServerCertificate {
+    let fields = parse_certificate(der);
+    let body = base64::engine::general_purpose::STANDARD.encode(der);
+    let lines: Vec<&str> = body
+        .as_bytes()
+        .chunks(64)
+        .map(|line| std::str::from_utf8(line).unwrap_or_default())
+        .collect();
+    ServerCertificate {
+        sha256: fingerprint(der),
+        subject: fields.as_ref().map(|f| f.subject.clone()),
+        issuer: fields.as_ref().map(|f| f.issuer.clone()),
+        not_before: fields.as_ref().and_then(|f| f.not_before),
+        not_after: fields.as_ref().and_then(|f| f.not_after),
+        alt_names: fields.as_ref().map(|f| f.alt_names.clone()).unwrap_or_default(),
+        self_signed: fields.as_ref().is_some_and(|f| f.self_signed),
+        trusted: verify_error.is_none(),
+        verify_error,
+        pem: format!("-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n", lines.join("\n")),
+    }
+}
+
+// ---------- Fingerprints ----------
+
+/// SHA-256 of a DER certificate as colon-separated uppercase hex.
+fn fingerprint(der: &[u8]) -> String {
+    format_fingerprint(&hex(&Sha256::digest(der)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+fn format_fingerprint(hex: &str) -> String {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| std::str::from_utf8(pair).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// A fingerprint as 64 uppercase hex digits, accepting colons, spaces and
+/// either case.
+fn normalize_fingerprint(pin: &str) -> Result<String, String> {
+    let digits: String = pin
+        .chars()
+        .filter(|c| !matches!(c, ':' | ' '))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if digits.len() != 64 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid pinned certificate fingerprint: {pin} (expected a SHA-256 digest)"));
+    }
+    Ok(digits)
+}
+
+// ---------- Certificate fields ----------
+//
+// Just enough DER to show what is being pinned: names, validity and
+// subjectAltName. Anything unexpected leaves the fields out.
+
+struct CertificateFields {
+    subject: String,
+    issuer: String,
+    not_before: Option<i64>,
+    not_after: Option<i64>,
+    alt_names: Vec<String>,
+    self_signed: bool,
+}
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_VERSION: u8 = 0xA0; // [0] EXPLICIT
+const TAG_EXTENSIONS: u8 = 0xA3; // [3] EXPLICIT
+const TAG_SAN_DNS: u8 = 0x82; // dNSName [2]
+const TAG_SAN_IP: u8 = 0x87; // iPAddress [7]
+
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1D, 0x11]; // 2.5.29.17
+
+/// Take one element off the front of `input`: its tag and contents.
+fn der_next<'a>(input: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7F) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0usize, |len, &b| len << 8 | b as usize);
+        rest = &rest[n..];
+        len
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    *input = rest;
+    Some((tag, contents))
+}
+
+fn der_expect<'a>(input: &mut &'a [u8], tag: u8) -> Option<&'a [u8]> {
+    let (actual, contents) = der_next(input)?;
+    (actual == tag).then_some(contents)
+}
+
+fn parse_certificate(der: &[u8]) -> Option<CertificateFields> {
+    let mut input = der;
+    let mut certificate = der_expect(&mut input, TAG_SEQUENCE)?;
+    let mut tbs = der_expect(&mut certificate, TAG_SEQUENCE)?;
+    if tbs.first() == Some(&TAG_VERSION) {
+        der_next(&mut tbs)?;
+    }
+    der_next(&mut tbs)?; // serialNumber
+    der_next(&mut tbs)?; // signature algorithm
+    let issuer = der_expect(&mut tbs, TAG_SEQUENCE)?;
+    let mut validity = der_expect(&mut tbs, TAG_SEQUENCE)?;
+    let not_before = der_next(&mut validity).and_then(|(tag, time)| parse_time(tag, time));
+    let not_after = der_next(&mut validity).and_then(|(tag, time)| parse_time(tag, time));
+    let subject = der_expect(&mut tbs, TAG_SEQUENCE)?;
+    der_next(&mut tbs)?; // subjectPublicKeyInfo
+
+    let mut alt_names = Vec::new();
+    while let Some((tag, contents)) = der_next(&mut tbs) {
+        if tag == TAG_EXTENSIONS {
+            alt_names = subject_alt_names(contents).unwrap_or_default();
+        }
+    }
+    Some(CertificateFields {
+        subject: format_name(subject),
+        issuer: format_name(issuer),
+        not_before,
+        not_after,
+        alt_names,
+        self_signed: subject == issuer,
+    })
+}
+
+/// A distinguished name as `C=US, O=Example, CN=mail.example.com`, in the
+/// order encoded. Attributes without a short name are left out.
+fn format_name(mut name: &[u8]) -> String {
+    let mut parts = Vec::new();
+    while let Some(mut set) = der_expect(&mut name, TAG_SET) {
+        while let Some(mut attribute) = der_expect(&mut set, TAG_SEQUENCE) {
+            let Some(oid) = der_expect(&mut attribute, TAG_OID) else { continue };
+            let label = match oid {
+                [0x55, 0x04, 0x03] => "CN",
+                [0x55, 0x04, 0x06] => "C",
+                [0x55, 0x04, 0x07] => "L",
+                [0x55, 0x04, 0x08] => "ST",
+                [0x55, 0x04, 0x0A] => "O",
+                [0x55, 0x04, 0x0B] => "OU",
+                _ => continue,
+            };
+            if let Some((_, value)) = der_next(&mut attribute) {
+                parts.push(format!("{label}={}", String::from_utf8_lossy(value)));
+            }
+        }
+    }
+    parts.join(", ")
+}
+
+fn subject_alt_names(extensions: &[u8]) -> Option<Vec<String>> {
+    let mut input = extensions;
+    let mut extensions = der_expect(&mut input, TAG_SEQUENCE)?;
+    while let Some(mut extension) = der_expect(&mut extensions, TAG_SEQUENCE) {
+        if der_expect(&mut extension, TAG_OID)? != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+        if extension.first() == Some(&TAG_BOOLEAN) {
+            der_next(&mut extension)?; // critical
+        }
+        let mut value = der_expect(&mut extension, TAG_OCTET_STRING)?;
+        let mut general_names = der_expect(&mut value, TAG_SEQUENCE)?;
+        let mut names = Vec::new();
+        while let Some((tag, name)) = der_next(&mut general_names) {
+            match tag {
+                TAG_SAN_DNS => names.push(String::from_utf8_lossy(name).into_owned()),
+                TAG_SAN_IP => {
+                    let ip = match name.len() {
+                        4 => <[u8; 4]>::try_from(name).ok().map(IpAddr::from),
+                        16 => <[u8; 16]>::try_from(name).ok().map(IpAddr::from),
+                        _ => None,
+                    };
+                    names.extend(ip.map(|ip| ip.to_string()));
+                }
+                _ => {}
+            }
+        }
+        return Some(names);
+    }
+    Some(Vec::new())
+}
+
+/// UTCTime (`YYMMDDHHMMSSZ`) or GeneralizedTime (`YYYYMMDDHHMMSSZ`) to unix
+/// seconds.
+fn parse_time(tag: u8, value: &[u8]) -> Option<i64> {
+    let text = std::str::from_utf8(value).ok()?.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        TAG_UTC_TIME => {
+            let yy: i64 = text.get(..2)?.parse().ok()?;
+            (if yy >= 50 { 1900 + yy } else { 2000 + yy }, text.get(2..)?)
+        }
+        TAG_GENERALIZED_TIME => (text.get(..4)?.parse().ok()?, text.get(4..)?),
+        _ => return None,
+    };
+    if rest.len() != 10 || !rest.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let field = |i: usize| rest[i..i + 2].parse::<i64>().unwrap_or_default();
+    let (month, day) = (field(0), field(2));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * 86400 + field(4) * 3600 + field(6) * 60 + field(8))
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed P-256 certificate for mail.example.test.
+    const TEST_CERT: &str = "\
+MIICCDCCAa6gAwIBAgIUOBsch/359z/Q7/mzS8+D2QbzOQcwCgYIKoZIzj0EAwIw\
+PTELMAkGA1UEBhMCVVMxEjAQBgNVBAoMCVZlbG8gVGVzdDEaMBgGA1UEAwwRbWFp\
+bC5leGFtcGxlLnRlc3QwHhcNMjYwMTAxMDAwMDAwWhcNMzYwMTAxMDAwMDAwWjA9\
+MQswCQYDVQQGEwJVUzESMBAGA1UECgwJVmVsbyBUZXN0MRowGAYDVQQDDBFtYWls\
+LmV4YW1wbGUudGVzdDBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABEyCsjmE9D0M\
+D1WcyzlLsYHDAYpoRINvFZXTcQMj10lt039i3LetaNCJcK0WZP2ocx5fU6ScJBq6\
+UkXHIqe1G3CjgYswgYgwHQYDVR0OBBYEFCNkedVWbOUbiK6biOyFvLdTP9OvMB8G\
+A1UdIwQYMBaAFCNkedVWbOUbiK6biOyFvLdTP9OvMA8GA1UdEwEB/wQFMAMBAf8w\
+NQYDVR0RBC4wLIIRbWFpbC5leGFtcGxlLnRlc3SCEWltYXAuZXhhbXBsZS50ZXN0\
+hwR/AAABMAoGCCqGSM49BAMCA0gAMEUCIQCDAjn5rz3+bL5PKuJz4vvxsoWmWgpJ\
+HiiUMFnc4XOZTAIgStalciIXzP9F7WTQwYZ3yGLFl8JIT6znKWZRGJ2jsxg=";
+
+    #[test]
+    fn test_describe_certificate() {
+        let der = base64::engine::general_purpose::STANDARD.decode(TEST_CERT).unwrap();
+        let cert = describe(&der, Some("self-signed".to_string()));
+        assert_eq!(
+            cert.sha256,
+            "07:09:18:D0:BC:78:CF:2C:DC:BB:21:A4:33:F2:A8:27:E4:FB:27:FF:69:A8:F6:C7:C3:37:0D:7F:D9:51:17:48"
+        );
+        assert_eq!(cert.subject.as_deref(), Some("C=US, O=Velo Test, CN=mail.example.test"));
+        assert_eq!(cert.issuer, cert.subject);
+        assert!(cert.self_signed);
+        assert_eq!(cert.not_before, Some(1767225600));
+        assert_eq!(cert.not_after, Some(2082758400));
+        assert_eq!(cert.alt_names, ["mail.example.test", "imap.example.test", "127.0.0.1"]);
+        assert!(!cert.trusted);
+        assert!(cert.pem.starts_with("-----BEGIN CERTIFICATE-----\nMIICCDCC"));
+    }
+
+    #[test]
+    fn test_normalize_fingerprint() {
+        let hex = "070918d0bc78cf2cdcbb21a433f2a827e4fb27ff69a8f6c7c3370d7fd9511748";
+        let formatted = "07:09:18:D0:BC:78:CF:2C:DC:BB:21:A4:33:F2:A8:27:E4:FB:27:FF:69:A8:F6:C7:C3:37:0D:7F:D9:51:17:48";
+        assert_eq!(normalize_fingerprint(hex).unwrap(), normalize_fingerprint(formatted).unwrap());
+        assert_eq!(format_fingerprint(&normalize_fingerprint(hex).unwrap()), formatted);
+        assert!(normalize_fingerprint("07:09:18").is_err());
+        assert!(normalize_fingerprint(&hex.replace('0', "g")).is_err());
+    }
+
+    #[test]
+    fn test_parse_tls_version() {
+        assert!(matches!(parse_tls_version("1.2"), Ok(native_tls::Protocol::Tlsv12)));
+        assert!(matches!(parse_tls_version("TLSv1.3"), Ok(native_tls::Protocol::Tlsv13)));
+        assert!(matches!(parse_tls_version("1.0"), Ok(native_tls::Protocol::Tlsv10)));
+        assert!(parse_tls_version("1.4").is_err());
+        assert!(parse_tls_version("").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time(TAG_UTC_TIME, b"700101000000Z"), Some(0));
+        assert_eq!(parse_time(TAG_UTC_TIME, b"491231235959Z"), Some(2524607999));
+        assert_eq!(parse_time(TAG_GENERALIZED_TIME, b"20240229120000Z"), Some(1709208000));
+        assert_eq!(parse_time(TAG_UTC_TIME, b"2401011200Z"), None);
+    }
+}
@@ -0,0 +1,533 @@
+//! Opt-in, per-account wire traces of IMAP and SMTP sessions that users can
+//! attach to bug reports.
+//!
+//! Nothing secret reaches the buffer: LOGIN arguments, every line of an
+//! AUTHENTICATE/AUTH exchange and bearer tokens are replaced before a line is
+//! recorded, and literals and message data are reduced to their size.
+
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Entries kept per account unless the caller asks for another size; the
+/// oldest are dropped first.
+const DEFAULT_MAX_ENTRIES: usize = 5000;
+/// Longer lines are cut, e.g. a FETCH response with a huge ENVELOPE.
+const MAX_LINE_BYTES: usize = 1000;
+const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Imap,
+    Smtp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Client,
+    Server,
+}
+
+struct Entry {
+    elapsed_ms: u128,
+    connection: u64,
+    protocol: Protocol,
+    direction: Direction,
+    text: String,
+}
+
+/// The ring buffer of one account's trace.
+struct Trace {
+    started: Instant,
+    started_unix: u64,
+    max_entries: usize,
+    active: AtomicBool,
+    next_connection: AtomicU64,
+    dropped: AtomicU64,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl Trace {
+    fn push(&self, connection: u64, protocol: Protocol, direction: Direction, text: String) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        if entries.len() >= self.max_entries {
+            entries.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        entries.push_back(Entry {
+            elapsed_ms: self.started.elapsed().as_millis(),
+            connection,
+            protocol,
+            direction,
+            text,
+        });
+    }
+}
+
+/// Traces by account ID. Process-wide rather than Tauri state because
+/// connections are opened from the pool, IDLE watchers and one-off commands
+/// alike, none of which carry an `AppHandle`.
+fn traces() -> &'static Mutex<HashMap<String, Arc<Trace>>> {
+    static TRACES: OnceLock<Mutex<HashMap<String, Arc<Trace>>>> = OnceLock::new();
+    TRACES.get_or_init(Default::default)
+}
+
+/// Start recording connections for an account, discarding any earlier trace.
+/// Connections that are already open (e.g. pooled IMAP sessions) aren't
+/// recorded.
+pub fn start(account_id: &str, max_entries: Option<usize>) -> Result<(), String> {
+    let trace = Trace {
+        started: Instant::now(),
+        started_unix: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        max_entries: max_entries.unwrap_or(DEFAULT_MAX_ENTRIES).max(1),
+        active: AtomicBool::new(true),
+        next_connection: AtomicU64::new(1),
+        dropped: AtomicU64::new(0),
+        entries: Mutex::new(VecDeque::new()),
+    };
+    let mut traces = traces().lock().map_err(|_| "Trace registry poisoned".to_string())?;
+    if let Some(previous) = traces.insert(account_id.to_string(), Arc::new(trace)) {
+        previous.active.store(false, Ordering::Relaxed);
+    }
+    log::info!("Protocol trace started for account {account_id}");
+    Ok(())
+}
+
+/// Stop recording, keeping what was recorded for `export`.
+pub fn stop(account_id: &str) {
+    if let Some(trace) = traces().lock().ok().and_then(|t| t.get(account_id).cloned()) {
+        trace.active.store(false, Ordering::Relaxed);
+        log::info!("Protocol trace stopped for account {account_id}");
+    }
+}
+
+/// Stop recording and discard the trace.
+pub fn clear(account_id: &str) {
+    if let Some(trace) = traces().lock().ok().and_then(|mut t| t.remove(account_id)) {
+        trace.active.store(false, Ordering::Relaxed);
+    }
+}
+
+/// The recorded trace as plain text, one line per entry.
+pub fn export(account_id: &str) -> Result<String, String> {
+    let trace = traces()
+        .lock()
+        .ok()
+        .and_then(|t| t.get(account_id).cloned())
+        .ok_or_else(|| format!("No protocol trace recorded for account {account_id}"))?;
+    let entries = trace.entries.lock().map_err(|_| "Trace buffer poisoned".to_string())?;
+
+    let mut out = format!(
+        "# Velo protocol trace, started at unix time {}; credentials and message contents removed\n",
+        trace.started_unix
+    );
+    let dropped = trace.dropped.load(Ordering::Relaxed);
+    if dropped > 0 {
+        out.push_str(&format!("# {dropped} earlier entries dropped\n"));
+    }
+    for entry in entries.iter() {
+        let protocol = match entry.protocol {
+            Protocol::Imap => "imap",
+            Protocol::Smtp => "smtp",
+        };
+        let direction = match entry.direction {
+            Direction::Client => "C",
+            Direction::Server => "S",
+        };
+        out.push_str(&format!(
+            "{:>9}.{:03} {protocol}#{} {direction}: {}\n",
+            entry.elapsed_ms / 1000,
+            entry.elapsed_ms % 1000,
+            entry.connection,
+            entry.text
+        ));
+    }
+    Ok(out)
+}
+
+/// A recorder for a new connection if the account is being traced.
+pub fn recorder(account_id: Option<&str>, protocol: Protocol, server: &str) -> Option<Recorder> {
+    let trace = traces().lock().ok()?.get(account_id?).cloned()?;
+    if !trace.active.load(Ordering::Relaxed) {
+        return None;
+    }
+    let connection = trace.next_connection.fetch_add(1, Ordering::Relaxed);
+    trace.push(connection, protocol, Direction::Client, format!("-- connecting to {server}"));
+    Some(Recorder {
+        trace,
+        connection,
+        protocol,
+        client: Side::default(),
+        server: Side::default(),
+        state: State::default(),
+    })
+}
+
+/// Line assembly for one direction of a connection.
+#[derive(Default)]
+struct Side {
+    line: Vec<u8>,
+    /// Bytes left in the literal being skipped.
+    literal_left: usize,
+    literal_size: usize,
+}
+
+/// Protocol state that decides what gets redacted.
+#[derive(Default)]
+struct State {
+    /// Inside a LOGIN command whose arguments are sent as literals.
+    login: bool,
+    /// Inside an AUTHENTICATE (IMAP) or AUTH (SMTP) exchange.
+    auth: bool,
+    /// SMTP: DATA sent, waiting for 354.
+    data_pending: bool,
+    /// SMTP: sending message data, `data_bytes` so far.
+    data: bool,
+    data_bytes: usize,
+}
+
+/// Records one connection into its account's trace.
+pub struct Recorder {
+    trace: Arc<Trace>,
+    connection: u64,
+    protocol: Protocol,
+    client: Side,
+    server: Side,
+    state: State,
+}
+
+impl Recorder {
+    /// Record bytes sent or received, as they come.
+    pub fn record(&mut self, direction: Direction, mut bytes: &[u8]) {
+        if !self.trace.active.load(Ordering::Relaxed) {
+            return;
+        }
+        while !bytes.is_empty() {
+            let side = match direction {
+                Direction::Client => &mut self.client,
+                Direction::Server => &mut self.server,
+            };
+            if side.literal_left > 0 {
+                let take = side.literal_left.min(bytes.len());
+                side.literal_left -= take;
+                bytes = &bytes[take..];
+                if side.literal_left == 0 {
+                    let size = side.literal_size;
+                    let text = if self.state.login || self.state.auth {
+                        REDACTED.to_string()
+                    } else {
+                        format!("[{size}-byte literal]")
+                    };
+                    self.push(direction, text);
+                }
+                continue;
+            }
+            let (chunk, complete) = match bytes.iter().position(|&b| b == b'\n') {
+                Some(end) => (&bytes[..=end], true),
+                None => (bytes, false),
+            };
+            side.line.extend_from_slice(chunk);
+            bytes = &bytes[chunk.len()..];
+            if complete {
+                let line = std::mem::take(&mut side.line);
+                self.line(direction, &line);
+            }
+        }
+    }
+
+    fn push(&self, direction: Direction, text: String) {
+        self.trace.push(self.connection, self.protocol, direction, text);
+    }
+
+    fn line(&mut self, direction: Direction, line: &[u8]) {
+        let text = match self.protocol {
+            Protocol::Imap => self.imap_line(direction, line),
+            Protocol::Smtp => self.smtp_line(direction, line),
+        };
+        if let Some(text) = text {
+            self.push(direction, truncate(redact_bearer(&text)));
+        }
+    }
+
+    fn imap_line(&mut self, direction: Direction, line: &[u8]) -> Option<String> {
+        let text = String::from_utf8_lossy(line).trim_end().to_string();
+        let literal = crate::imap::response::literal_size(line);
+        let side = match direction {
+            Direction::Client => &mut self.client,
+            Direction::Server => &mut self.server,
+        };
+        if let Some(size) = literal {
+            side.literal_left = size;
+            side.literal_size = size;
+        }
+
+        match direction {
+            Direction::Client => {
+                if self.state.auth || self.state.login {
+                    // A continuation of LOGIN after a literal, or a SASL
+                    // response.
+                    self.state.login &= literal.is_some();
+                    return Some(REDACTED.to_string());
+                }
+                let mut words = text.splitn(3, ' ');
+                let tag = words.next().unwrap_or_default();
+                let command = words.next().unwrap_or_default().to_ascii_uppercase();
+                let args = words.next();
+                match command.as_str() {
+                    "LOGIN" => {
+                        self.state.login = literal.is_some();
+                        Some(format!("{tag} LOGIN {REDACTED}"))
+                    }
+                    "AUTHENTICATE" => {
+                        self.state.auth = true;
+                        let mut args = args.unwrap_or_default().splitn(2, ' ');
+                        let mechanism = args.next().unwrap_or_default();
+                        Some(match args.next() {
+                            Some(_) => format!("{tag} AUTHENTICATE {mechanism} {REDACTED}"),
+                            None => format!("{tag} AUTHENTICATE {mechanism}"),
+                        })
+                    }
+                    _ => Some(text),
+                }
+            }
+            Direction::Server => {
+                if self.state.auth && !text.starts_with('+') && !text.starts_with('*') {
+                    self.state.auth = false;
+                }
+                Some(text)
+            }
+        }
+    }
+
+    fn smtp_line(&mut self, direction: Direction, line: &[u8]) -> Option<String> {
+        let text = String::from_utf8_lossy(line).trim_end().to_string();
+        match direction {
+            Direction::Client => {
+                if self.state.data {
+                    if text == "." {
+                        self.state.data = false;
+                        let bytes = std::mem::take(&mut self.state.data_bytes);
+                        self.push(direction, format!("[{bytes} bytes of message data]"));
+                        return Some(text);
+                    }
+                    self.state.data_bytes += line.len();
+                    return None;
+                }
+                if self.state.auth {
+                    return Some(REDACTED.to_string());
+                }
+                let upper = text.to_ascii_uppercase();
+                if upper == "DATA" {
+                    self.state.data_pending = true;
+                } else if let Some(args) = upper.strip_prefix("AUTH ") {
+                    self.state.auth = true;
+                    return Some(match args.split_once(' ') {
+                        Some((mechanism, _)) => format!("AUTH {mechanism} {REDACTED}"),
+                        None => text,
+                    });
+                }
+                Some(text)
+            }
+            Direction::Server => {
+                // Only the last line of a multiline reply ("250 OK" after
+                // "250-...") ends a step.
+                let last = text.as_bytes().get(3) != Some(&b'-');
+                if last && self.state.auth && !text.starts_with("334") {
+                    self.state.auth = false;
+                }
+                if last && self.state.data_pending {
+                    self.state.data_pending = false;
+                    self.state.data = text.starts_with("354");
+                }
+                Some(text)
+            }
+        }
+    }
+
+    /// Note an event that isn't on the wire, e.g. a TLS upgrade.
+    pub fn note(&self, text: &str) {
+        if self.trace.active.load(Ordering::Relaxed) {
+            self.push(Direction::Client, format!("-- {text}"));
+        }
+    }
+}
+
+/// Replace the token after `Bearer`, wherever it turns up.
+fn redact_bearer(text: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let Some(start) = lower.find("bearer ") else {
+        return text.to_string();
+    };
+    let token_start = start + "bearer ".len();
+    let token_end = text[token_start..]
+        .find(|c: char| c.is_whitespace() || c == '\x01' || c == '"')
+        .map_or(text.len(), |i| token_start + i);
+    format!(
+        "{}{REDACTED}{}",
+        &text[..token_start],
+        redact_bearer(&text[token_end..])
+    )
+}
+
+fn truncate(text: String) -> String {
+    if text.len() <= MAX_LINE_BYTES {
+        return text;
+    }
+    let mut end = MAX_LINE_BYTES;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... [{} more bytes]", &text[..end], text.len() - end)
+}
+
+/// A stream that records everything read and written through it.
+pub struct TracedStream<S> {
+    inner: S,
+    recorder: Recorder,
+}
+
+impl<S> TracedStream<S> {
+    pub fn new(inner: S, recorder: Recorder) -> Self {
+        Self { inner, recorder }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Unwrap, e.g. to upgrade the inner stream to TLS and wrap it again.
+    pub fn into_parts(self) -> (S, Recorder) {
+        (self.inner, self.recorder)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TracedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            this.recorder.record(Direction::Server, &buf.filled()[before..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TracedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.recorder.record(Direction::Client, &buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: std::fmt::Debug> std::fmt::Debug for TracedStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TracedStream({:?}, connection {})", self.inner, self.recorder.connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(account: &str, protocol: Protocol, wire: &[(Direction, &[u8])]) -> String {
+        start(account, None).unwrap();
+        let mut recorder = recorder(Some(account), protocol, "mail.example.com:993").unwrap();
+        for (direction, bytes) in wire {
+            recorder.record(*direction, bytes);
+        }
+        let out = export(account).unwrap();
+        clear(account);
+        out
+    }
+
+    #[test]
+    fn test_imap_redaction() {
+        use Direction::*;
+        let out = record(
+            "test-imap",
+            Protocol::Imap,
+            &[
+                (Server, b"* OK ready\r\n"),
+                (Client, b"a1 LOGIN {4}\r\n"),
+                (Client, b"user {6}\r\nsecret\r\n"),
+                (Server, b"a1 OK logged in\r\n"),
+                (Client, b"a2 AUTHENTICATE XOAUTH2 dXNlcj1hAWF1dGg9QmVhcmVy\r\n"),
+                (Client, b"\r\n"),
+                (Server, b"a2 NO failed\r\n"),
+                (Client, b"a3 UID FETCH 1 BODY.PEEK[]\r\n"),
+                (Server, b"* 1 FETCH (UID 1 BODY[] {11}\r\nhello\r\nbody"),
+                (Server, b")\r\na3 OK done\r\n"),
+            ],
+        );
+        assert!(!out.contains("user"));
+        assert!(!out.contains("secret"));
+        assert!(!out.contains("dXNlcj1h"));
+        assert!(out.contains("C: a1 LOGIN [redacted]"));
+        assert!(out.contains("C: a2 AUTHENTICATE XOAUTH2 [redacted]"));
+        assert!(out.contains("S: a2 NO failed"));
+        assert!(out.contains("S: * 1 FETCH (UID 1 BODY[] {11}"));
+        assert!(out.contains("S: [11-byte literal]"));
+        assert!(!out.contains("hello"));
+        assert!(out.contains("S: a3 OK done"));
+    }
+
+    #[test]
+    fn test_smtp_redaction() {
+        use Direction::*;
+        let out = record(
+            "test-smtp",
+            Protocol::Smtp,
+            &[
+                (Server, b"220 smtp.example.com ESMTP\r\n"),
+                (Client, b"AUTH LOGIN\r\n"),
+                (Server, b"334 VXNlcm5hbWU6\r\n"),
+                (Client, b"dXNlcg==\r\n"),
+                (Server, b"334 UGFzc3dvcmQ6\r\n"),
+                (Client, b"c2VjcmV0\r\n"),
+                (Server, b"235 2.7.0 Accepted\r\n"),
+                (Client, b"DATA\r\n"),
+                (Server, b"354 Go ahead\r\n"),
+                (Client, b"Subject: hi\r\n\r\nprivate\r\n.\r\n"),
+                (Server, b"250 OK\r\n"),
+            ],
+        );
+        assert!(!out.contains("dXNlcg=="));
+        assert!(!out.contains("c2VjcmV0"));
+        assert!(!out.contains("private"));
+        assert!(out.contains("C: AUTH LOGIN"));
+        assert!(out.contains("C: [24 bytes of message data]"));
+        assert!(out.contains("S: 250 OK"));
+    }
+
+    #[test]
+    fn test_redact_bearer() {
+        assert_eq!(
+            redact_bearer("user=a\x01auth=Bearer ya29.token\x01\x01"),
+            "user=a\x01auth=Bearer [redacted]\x01\x01"
+        );
+        assert_eq!(redact_bearer("* OK no tokens here"), "* OK no tokens here");
+    }
+}
@@ -12,7 +12,7 @@ import { useAccountStore } from "./stores/accountStore";
 import { useKeyboardShortcuts } from "./hooks/useKeyboardShortcuts";
 import { runMigrations } from "./services/db/migrations";
 import { getAllAccounts } from "./services/db/accounts";
-import { getSetting } from "./services/db/settings";
+import { getSecureSetting, getSetting } from "./services/db/settings";
 import {
   startBackgroundSync,
   stopBackgroundSync,
@@ -60,6 +60,7 @@ import {
 import { fetchSendAsAliases } from "./services/gmail/sendAs";
 import { getGmailClient } from "./services/gmail/tokenManager";
 import { invoke } from "@tauri-apps/api/core";
+import { proxySetGlobal } from "./services/imap/tauriCommands";
 import { DndProvider } from "./components/dnd/DndProvider";
 import { TitleBar } from "./components/layout/TitleBar";
 import { useShortcutStore } from "./stores/shortcutStore";
@@ -286,6 +287,17 @@ export default function App() {
         // Load custom keyboard shortcuts
         await useShortcutStore.getState().loadKeyMap();
 
+        // Route IMAP, SMTP and OAuth traffic through the saved proxy, if any,
+        // before anything connects
+        const savedProxy = await getSecureSetting("proxy");
+        if (savedProxy) {
+          try {
+            await proxySetGlobal(JSON.parse(savedProxy));
+          } catch (err) {
+            console.warn("Ignoring invalid proxy setting:", err);
+          }
+        }
+
         const dbAccounts = await getAllAccounts();
         const mapped = dbAccounts.map((a) => ({
           id: a.id,
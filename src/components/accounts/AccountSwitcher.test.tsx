@@ -1,5 +1,10 @@
-import { describe, it, expect, beforeEach } from "vitest";
+import { describe, it, expect, beforeEach, vi } from "vitest";
 import { render, screen, fireEvent } from "@testing-library/react";
+
+vi.mock("@/services/gmail/syncManager", () => ({
+  prioritizeAccountSync: vi.fn(),
+}));
+
 import { AccountSwitcher } from "./AccountSwitcher";
 import { useAccountStore } from "@/stores/accountStore";
 
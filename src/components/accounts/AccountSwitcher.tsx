@@ -2,6 +2,7 @@ import { useState, useRef, useCallback } from "react";
 import { useAccountStore, type Account } from "@/stores/accountStore";
 import { ChevronDown, Check, Plus, UserPlus, Calendar } from "lucide-react";
 import { useClickOutside } from "@/hooks/useClickOutside";
+import { prioritizeAccountSync } from "@/services/gmail/syncManager";
 
 interface AccountSwitcherProps {
   collapsed: boolean;
@@ -22,10 +23,11 @@ export function AccountSwitcher({
 
   const handleSwitch = useCallback(
     (id: string) => {
+      if (id !== activeAccountId) prioritizeAccountSync(id);
       setActiveAccount(id);
       setOpen(false);
     },
-    [setActiveAccount],
+    [activeAccountId, setActiveAccount],
   );
 
   const handleAdd = useCallback(() => {
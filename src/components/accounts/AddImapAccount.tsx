@@ -23,6 +23,7 @@ import {
 } from "@/services/imap/autoDiscovery";
 import { getOAuthProvider } from "@/services/oauth/providers";
 import { startProviderOAuthFlow } from "@/services/oauth/oauthFlow";
+import { toMailError } from "@/services/imap/mailError";
 
 interface AddImapAccountProps {
   onClose: () => void;
@@ -300,7 +301,8 @@ export function AddImapAccount({
       );
       setImapTest({ state: "success", message: result });
     } catch (err) {
-      const message = err instanceof Error ? err.message : String(err);
+      const error = toMailError(err);
+      const message = error instanceof Error ? error.message : String(error);
       setImapTest({ state: "error", message });
     }
   };
@@ -332,7 +334,8 @@ export function AddImapAccount({
         message: result.message,
       });
     } catch (err) {
-      const message = err instanceof Error ? err.message : String(err);
+      const error = toMailError(err);
+      const message = error instanceof Error ? error.message : String(error);
       setSmtpTest({ state: "error", message });
     }
   };
@@ -27,7 +27,12 @@ vi.mock("@/utils/crypto", () => ({
   isEncrypted: vi.fn((val: string) => val.startsWith("enc:")),
 }));
 
+vi.mock("../imap/imapConnections", () => ({
+  closeImapConnections: vi.fn(() => Promise.resolve()),
+}));
+
 import { selectFirstBy } from "./connection";
+import { closeImapConnections } from "../imap/imapConnections";
 
 const mockSelectFirstBy = vi.mocked(selectFirstBy);
 
@@ -256,6 +261,19 @@ describe("accounts", () => {
       expect(sql).toContain("DELETE FROM accounts");
       expect(params).toEqual(["acc-1"]);
     });
+
+    it("closes the account's IMAP connections and IDLE watchers first", async () => {
+      const account = createMockImapAccount();
+      mockSelectFirstBy.mockResolvedValueOnce(account);
+      mockExecute.mockResolvedValue(undefined);
+
+      await deleteAccount(account.id);
+
+      expect(closeImapConnections).toHaveBeenCalledWith(
+        expect.objectContaining({ id: account.id }),
+        { stopIdle: true },
+      );
+    });
   });
 
   describe("updateAccountTokens", () => {
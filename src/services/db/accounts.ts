@@ -1,5 +1,6 @@
 import { getDb, selectFirstBy } from "./connection";
 import { encryptValue, decryptValue, isEncrypted } from "@/utils/crypto";
+import { closeImapConnections } from "../imap/imapConnections";
 
 export interface DbAccount {
   id: string;
@@ -34,6 +35,13 @@ export interface DbAccount {
   caldav_home_url: string | null;
   calendar_provider: string | null;
   accept_invalid_certs: number;
+  imap_cert_sha256: string | null;
+  smtp_cert_sha256: string | null;
+  tls_ca_pem: string | null;
+  tls_client_pkcs12: string | null; // base64 PKCS#12 archive
+  tls_client_password: string | null;
+  tls_min_version: string | null;
+  proxy_json: string | null; // ProxyConfig as JSON, encrypted (it may hold a password)
 }
 
 async function decryptAccountTokens(account: DbAccount): Promise<DbAccount> {
@@ -72,6 +80,20 @@ async function decryptAccountTokens(account: DbAccount): Promise<DbAccount> {
       console.warn("Failed to decrypt CalDAV password, using raw value:", err);
     }
   }
+  if (account.tls_client_password && isEncrypted(account.tls_client_password)) {
+    try {
+      account.tls_client_password = await decryptValue(account.tls_client_password);
+    } catch (err) {
+      console.warn("Failed to decrypt client certificate password, using raw value:", err);
+    }
+  }
+  if (account.proxy_json && isEncrypted(account.proxy_json)) {
+    try {
+      account.proxy_json = await decryptValue(account.proxy_json);
+    } catch (err) {
+      console.warn("Failed to decrypt proxy settings, using raw value:", err);
+    }
+  }
   return account;
 }
 
@@ -175,7 +197,76 @@ export async function updateAccountAllTokens(
   );
 }
 
+/** Pooled sessions keep the settings they logged in with; drop them. */
+async function closeConnectionsAfterUpdate(id: string): Promise<void> {
+  const account = await getAccount(id);
+  if (account) await closeImapConnections(account);
+}
+
+/**
+ * Pin the IMAP or SMTP server certificate by its SHA-256 fingerprint, or
+ * remove the pin with null. A pinned account accepts no other certificate.
+ */
+export async function updateAccountPinnedCert(
+  id: string,
+  protocol: "imap" | "smtp",
+  sha256: string | null,
+): Promise<void> {
+  const db = await getDb();
+  const column = protocol === "imap" ? "imap_cert_sha256" : "smtp_cert_sha256";
+  await db.execute(
+    `UPDATE accounts SET ${column} = $1, updated_at = unixepoch() WHERE id = $2`,
+    [sha256, id],
+  );
+  await closeConnectionsAfterUpdate(id);
+}
+
+/**
+ * Set the account's extra trusted CAs (PEM), client certificate (base64
+ * PKCS#12 and its password) and minimum TLS version, for both IMAP and SMTP.
+ * Null clears a setting.
+ */
+export async function updateAccountTlsSettings(
+  id: string,
+  settings: {
+    caPem: string | null;
+    clientPkcs12: string | null;
+    clientPassword: string | null;
+    minVersion: string | null;
+  },
+): Promise<void> {
+  const db = await getDb();
+  const encPassword = settings.clientPassword
+    ? await encryptValue(settings.clientPassword)
+    : null;
+  await db.execute(
+    `UPDATE accounts SET tls_ca_pem = $1, tls_client_pkcs12 = $2, tls_client_password = $3,
+       tls_min_version = $4, updated_at = unixepoch() WHERE id = $5`,
+    [settings.caPem, settings.clientPkcs12, encPassword, settings.minVersion, id],
+  );
+  await closeConnectionsAfterUpdate(id);
+}
+
+/**
+ * Set the account's own proxy (JSON of a ProxyConfig), overriding the global
+ * one, or null to use the global proxy.
+ */
+export async function updateAccountProxy(
+  id: string,
+  proxyJson: string | null,
+): Promise<void> {
+  const db = await getDb();
+  const encProxy = proxyJson ? await encryptValue(proxyJson) : null;
+  await db.execute(
+    "UPDATE accounts SET proxy_json = $1, updated_at = unixepoch() WHERE id = $2",
+    [encProxy, id],
+  );
+  await closeConnectionsAfterUpdate(id);
+}
+
 export async function deleteAccount(id: string): Promise<void> {
+  const account = await getAccount(id);
+  if (account) await closeImapConnections(account, { stopIdle: true });
   const db = await getDb();
   await db.execute("DELETE FROM accounts WHERE id = $1", [id]);
 }
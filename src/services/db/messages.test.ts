@@ -9,7 +9,11 @@ vi.mock("@/services/db/connection", async (importOriginal) => {
 });
 
 import { getDb } from "@/services/db/connection";
-import { deleteAllMessagesForAccount, updateMessageThreadIds } from "./messages";
+import {
+  deleteAllMessagesForAccount,
+  getMessagesByImapUids,
+  updateMessageThreadIds,
+} from "./messages";
 import { createMockDb } from "@/test/mocks";
 
 const mockDb = createMockDb();
@@ -89,4 +93,22 @@ describe("messages service", () => {
       expect(secondCall[1]).toHaveLength(3); // threadId + accountId + 1 ID
     });
   });
+
+  describe("getMessagesByImapUids", () => {
+    it("selects messages by folder and UID", async () => {
+      await getMessagesByImapUids("acc-1", "INBOX", [10, 11]);
+
+      expect(mockDb.select).toHaveBeenCalledWith(
+        "SELECT * FROM messages WHERE account_id = $1 AND imap_folder = $2 AND imap_uid IN ($3, $4)",
+        ["acc-1", "INBOX", 10, 11],
+      );
+    });
+
+    it("chunks large UID lists", async () => {
+      const uids = Array.from({ length: 1200 }, (_, i) => i + 1);
+      await getMessagesByImapUids("acc-1", "INBOX", uids);
+
+      expect(mockDb.select).toHaveBeenCalledTimes(3);
+    });
+  });
 });
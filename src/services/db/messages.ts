@@ -127,6 +127,74 @@ export async function deleteMessage(
   );
 }
 
+/** Stored messages for the given UIDs of an IMAP folder. */
+export async function getMessagesByImapUids(
+  accountId: string,
+  folder: string,
+  uids: number[],
+): Promise<DbMessage[]> {
+  const db = await getDb();
+  const messages: DbMessage[] = [];
+  // SQLite variable limit is 999; process in chunks
+  for (let i = 0; i < uids.length; i += 500) {
+    const chunk = uids.slice(i, i + 500);
+    const placeholders = chunk.map((_, idx) => `$${idx + 3}`).join(", ");
+    messages.push(
+      ...(await db.select<DbMessage[]>(
+        `SELECT * FROM messages WHERE account_id = $1 AND imap_folder = $2 AND imap_uid IN (${placeholders})`,
+        [accountId, folder, ...chunk],
+      )),
+    );
+  }
+  return messages;
+}
+
+/**
+ * IMAP messages stored without a body (e.g. by a header-only sync), newest
+ * first, as folder and UID.
+ */
+export async function getImapMessagesWithoutBody(
+  accountId: string,
+  limit: number,
+): Promise<{ imap_folder: string; imap_uid: number }[]> {
+  const db = await getDb();
+  return db.select<{ imap_folder: string; imap_uid: number }[]>(
+    `SELECT imap_folder, imap_uid FROM messages
+     WHERE account_id = $1 AND body_cached = 0 AND imap_folder IS NOT NULL AND imap_uid IS NOT NULL
+     ORDER BY date DESC LIMIT $2`,
+    [accountId, limit],
+  );
+}
+
+/** Store a message body downloaded after its headers. */
+export async function updateMessageBody(
+  accountId: string,
+  messageId: string,
+  bodyHtml: string | null,
+  bodyText: string | null,
+  snippet: string | null,
+): Promise<void> {
+  const db = await getDb();
+  await db.execute(
+    `UPDATE messages SET body_html = $1, body_text = $2, snippet = COALESCE($3, snippet), body_cached = 1
+     WHERE account_id = $4 AND id = $5`,
+    [bodyHtml, bodyText, snippet, accountId, messageId],
+  );
+}
+
+export async function updateMessageFlags(
+  accountId: string,
+  messageId: string,
+  isRead: boolean,
+  isStarred: boolean,
+): Promise<void> {
+  const db = await getDb();
+  await db.execute(
+    "UPDATE messages SET is_read = $1, is_starred = $2 WHERE account_id = $3 AND id = $4",
+    [isRead ? 1 : 0, isStarred ? 1 : 0, accountId, messageId],
+  );
+}
+
 export async function updateMessageThreadIds(
   accountId: string,
   messageIds: string[],
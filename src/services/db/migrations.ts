@@ -775,6 +775,29 @@ const MIGRATIONS = [
     description: "Accept self-signed certificates for IMAP/SMTP",
     sql: `ALTER TABLE accounts ADD COLUMN accept_invalid_certs INTEGER DEFAULT 0;`,
   },
+  {
+    version: 24,
+    description: "Pinned IMAP/SMTP server certificate fingerprints",
+    sql: `
+      ALTER TABLE accounts ADD COLUMN imap_cert_sha256 TEXT;
+      ALTER TABLE accounts ADD COLUMN smtp_cert_sha256 TEXT;
+    `,
+  },
+  {
+    version: 25,
+    description: "Custom CA, client certificate and minimum TLS version for IMAP/SMTP",
+    sql: `
+      ALTER TABLE accounts ADD COLUMN tls_ca_pem TEXT;
+      ALTER TABLE accounts ADD COLUMN tls_client_pkcs12 TEXT;
+      ALTER TABLE accounts ADD COLUMN tls_client_password TEXT;
+      ALTER TABLE accounts ADD COLUMN tls_min_version TEXT;
+    `,
+  },
+  {
+    version: 26,
+    description: "Per-account outbound proxy",
+    sql: `ALTER TABLE accounts ADD COLUMN proxy_json TEXT;`,
+  },
 ];
 
 /**
@@ -119,6 +119,19 @@ export async function upsertThread(thread: {
   );
 }
 
+/** Update a thread's summary flags after its messages changed on the server. */
+export async function updateThreadFlags(
+  accountId: string,
+  threadId: string,
+  flags: { messageCount: number; isRead: boolean; isStarred: boolean },
+): Promise<void> {
+  const db = await getDb();
+  await db.execute(
+    "UPDATE threads SET message_count = $1, is_read = $2, is_starred = $3 WHERE account_id = $4 AND id = $5",
+    [flags.messageCount, flags.isRead ? 1 : 0, flags.isStarred ? 1 : 0, accountId, threadId],
+  );
+}
+
 export async function setThreadLabels(
   accountId: string,
   threadId: string,
@@ -216,7 +216,7 @@ describe("ImapSmtpProvider", () => {
   describe("archive", () => {
     it("moves messages to Archive folder", async () => {
       vi.mocked(findSpecialFolder).mockResolvedValue("Archive");
-      vi.mocked(imapMoveMessages).mockResolvedValue(undefined);
+      vi.mocked(imapMoveMessages).mockResolvedValue({ dest_uidvalidity: null, uid_map: [] });
 
       await provider.archive("thread-1", [
         "imap-acc-1-INBOX-100",
@@ -234,7 +234,7 @@ describe("ImapSmtpProvider", () => {
 
     it("skips messages already in Archive", async () => {
       vi.mocked(findSpecialFolder).mockResolvedValue("Archive");
-      vi.mocked(imapMoveMessages).mockResolvedValue(undefined);
+      vi.mocked(imapMoveMessages).mockResolvedValue({ dest_uidvalidity: null, uid_map: [] });
 
       await provider.archive("thread-1", ["imap-acc-1-Archive-100"]);
 
@@ -243,7 +243,7 @@ describe("ImapSmtpProvider", () => {
 
     it("falls back to 'Archive' when special folder not found", async () => {
       vi.mocked(findSpecialFolder).mockResolvedValue(null);
-      vi.mocked(imapMoveMessages).mockResolvedValue(undefined);
+      vi.mocked(imapMoveMessages).mockResolvedValue({ dest_uidvalidity: null, uid_map: [] });
 
       await provider.archive("thread-1", ["imap-acc-1-INBOX-100"]);
 
@@ -259,7 +259,7 @@ describe("ImapSmtpProvider", () => {
   describe("trash", () => {
     it("moves messages to Trash folder", async () => {
       vi.mocked(findSpecialFolder).mockResolvedValue("Deleted Items");
-      vi.mocked(imapMoveMessages).mockResolvedValue(undefined);
+      vi.mocked(imapMoveMessages).mockResolvedValue({ dest_uidvalidity: null, uid_map: [] });
 
       await provider.trash("thread-1", ["imap-acc-1-INBOX-100"]);
 
@@ -275,7 +275,7 @@ describe("ImapSmtpProvider", () => {
 
   describe("permanentDelete", () => {
     it("calls imapDeleteMessages for each folder group", async () => {
-      vi.mocked(imapDeleteMessages).mockResolvedValue(undefined);
+      vi.mocked(imapDeleteMessages).mockResolvedValue([]);
 
       await provider.permanentDelete("thread-1", [
         "imap-acc-1-INBOX-100",
@@ -345,7 +345,7 @@ describe("ImapSmtpProvider", () => {
   describe("spam", () => {
     it("moves to Junk when isSpam=true", async () => {
       vi.mocked(findSpecialFolder).mockResolvedValue("Junk E-Mail");
-      vi.mocked(imapMoveMessages).mockResolvedValue(undefined);
+      vi.mocked(imapMoveMessages).mockResolvedValue({ dest_uidvalidity: null, uid_map: [] });
 
       await provider.spam("thread-1", ["imap-acc-1-INBOX-100"], true);
 
@@ -359,7 +359,7 @@ describe("ImapSmtpProvider", () => {
 
     it("moves to INBOX when isSpam=false", async () => {
       vi.mocked(findSpecialFolder).mockResolvedValue("Junk");
-      vi.mocked(imapMoveMessages).mockResolvedValue(undefined);
+      vi.mocked(imapMoveMessages).mockResolvedValue({ dest_uidvalidity: null, uid_map: [] });
 
       await provider.spam("thread-1", ["imap-acc-1-Junk-100"], false);
 
@@ -374,7 +374,7 @@ describe("ImapSmtpProvider", () => {
 
   describe("moveToFolder", () => {
     it("moves messages to specified folder", async () => {
-      vi.mocked(imapMoveMessages).mockResolvedValue(undefined);
+      vi.mocked(imapMoveMessages).mockResolvedValue({ dest_uidvalidity: null, uid_map: [] });
 
       await provider.moveToFolder("thread-1", ["imap-acc-1-INBOX-100"], "Work");
 
@@ -387,7 +387,7 @@ describe("ImapSmtpProvider", () => {
     });
 
     it("skips messages already in target folder", async () => {
-      vi.mocked(imapMoveMessages).mockResolvedValue(undefined);
+      vi.mocked(imapMoveMessages).mockResolvedValue({ dest_uidvalidity: null, uid_map: [] });
 
       await provider.moveToFolder(
         "thread-1",
@@ -428,7 +428,7 @@ describe("ImapSmtpProvider", () => {
         message: "OK",
       });
       vi.mocked(findSpecialFolder).mockResolvedValue("Sent Items");
-      vi.mocked(imapAppendMessage).mockResolvedValue(undefined);
+      vi.mocked(imapAppendMessage).mockResolvedValue({ uidvalidity: null, uid: null });
 
       const result = await provider.sendMessage(rawBase64Url);
 
@@ -465,7 +465,7 @@ describe("ImapSmtpProvider", () => {
         message: "OK",
       });
       vi.mocked(findSpecialFolder).mockResolvedValue("Sent");
-      vi.mocked(imapAppendMessage).mockResolvedValue(undefined);
+      vi.mocked(imapAppendMessage).mockResolvedValue({ uidvalidity: null, uid: null });
       vi.mocked(getThreadLabelIds).mockResolvedValue(["INBOX"]);
 
       const result = await provider.sendMessage(rawBase64Url, "existing-thread-1");
@@ -520,7 +520,7 @@ describe("ImapSmtpProvider", () => {
   describe("createDraft", () => {
     it("appends to Drafts folder with Draft flag", async () => {
       vi.mocked(findSpecialFolder).mockResolvedValue("INBOX.Drafts");
-      vi.mocked(imapAppendMessage).mockResolvedValue(undefined);
+      vi.mocked(imapAppendMessage).mockResolvedValue({ uidvalidity: null, uid: null });
 
       const result = await provider.createDraft("base64data");
 
@@ -535,7 +535,7 @@ describe("ImapSmtpProvider", () => {
 
     it("falls back to 'Drafts' when special folder not found", async () => {
       vi.mocked(findSpecialFolder).mockResolvedValue(null);
-      vi.mocked(imapAppendMessage).mockResolvedValue(undefined);
+      vi.mocked(imapAppendMessage).mockResolvedValue({ uidvalidity: null, uid: null });
 
       await provider.createDraft("base64data");
 
@@ -551,8 +551,8 @@ describe("ImapSmtpProvider", () => {
   describe("updateDraft", () => {
     it("deletes old draft and creates new one", async () => {
       vi.mocked(findSpecialFolder).mockResolvedValue("Drafts");
-      vi.mocked(imapDeleteMessages).mockResolvedValue(undefined);
-      vi.mocked(imapAppendMessage).mockResolvedValue(undefined);
+      vi.mocked(imapDeleteMessages).mockResolvedValue([]);
+      vi.mocked(imapAppendMessage).mockResolvedValue({ uidvalidity: null, uid: null });
 
       const result = await provider.updateDraft(
         "imap-acc-1-Drafts-500",
@@ -578,7 +578,7 @@ describe("ImapSmtpProvider", () => {
 
   describe("deleteDraft", () => {
     it("deletes draft by parsed message ID", async () => {
-      vi.mocked(imapDeleteMessages).mockResolvedValue(undefined);
+      vi.mocked(imapDeleteMessages).mockResolvedValue([]);
 
       await provider.deleteDraft("imap-acc-1-Drafts-500");
 
@@ -686,7 +686,7 @@ describe("ImapSmtpProvider", () => {
 
   describe("groupByFolder (via actions)", () => {
     it("groups messages from different folders", async () => {
-      vi.mocked(imapDeleteMessages).mockResolvedValue(undefined);
+      vi.mocked(imapDeleteMessages).mockResolvedValue([]);
 
       await provider.permanentDelete("thread-1", [
         "imap-acc-1-INBOX-100",
@@ -708,7 +708,7 @@ describe("ImapSmtpProvider", () => {
     });
 
     it("handles folder names with hyphens", async () => {
-      vi.mocked(imapDeleteMessages).mockResolvedValue(undefined);
+      vi.mocked(imapDeleteMessages).mockResolvedValue([]);
 
       await provider.permanentDelete("thread-1", [
         "imap-acc-1-INBOX.Sub-Folder-100",
@@ -722,7 +722,7 @@ describe("ImapSmtpProvider", () => {
     });
 
     it("skips invalid message IDs", async () => {
-      vi.mocked(imapDeleteMessages).mockResolvedValue(undefined);
+      vi.mocked(imapDeleteMessages).mockResolvedValue([]);
       const spy = vi.spyOn(console, "warn").mockImplementation(() => {});
 
       await provider.permanentDelete("thread-1", ["invalid-id"]);
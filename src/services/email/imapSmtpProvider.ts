@@ -108,6 +108,7 @@ export class ImapSmtpProvider implements EmailProvider {
 
   private _imapConfig: ImapConfig | null = null;
   private _smtpConfig: SmtpConfig | null = null;
+  private _configVersion: number | null = null; // account updated_at the configs were built from
 
   constructor(accountId: string) {
     this.accountId = accountId;
@@ -121,6 +122,14 @@ export class ImapSmtpProvider implements EmailProvider {
     return account;
   }
 
+  /** Drop cached configs once the account row has changed, e.g. its TLS or proxy settings. */
+  private checkConfigVersion(account: DbAccount): void {
+    if (this._configVersion !== account.updated_at) {
+      this.clearConfigCache();
+      this._configVersion = account.updated_at;
+    }
+  }
+
   private async getImapConfig(): Promise<ImapConfig> {
     const account = await this.getAccount();
     if (account.auth_method === "oauth2") {
@@ -128,6 +137,7 @@ export class ImapSmtpProvider implements EmailProvider {
       const token = await ensureFreshToken(account);
       return buildImapConfig(account, token);
     }
+    this.checkConfigVersion(account);
     if (!this._imapConfig) {
       this._imapConfig = buildImapConfig(account);
     }
@@ -140,6 +150,7 @@ export class ImapSmtpProvider implements EmailProvider {
       const token = await ensureFreshToken(account);
       return buildSmtpConfig(account, token);
     }
+    this.checkConfigVersion(account);
     if (!this._smtpConfig) {
       this._smtpConfig = buildSmtpConfig(account);
     }
@@ -28,6 +28,16 @@ vi.mock("../imap/imapSync", () => ({
 }));
 vi.mock("../db/folderSyncState", () => ({
   clearAllFolderSyncStates: vi.fn(),
+  getAllFolderSyncStates: vi.fn(),
+}));
+vi.mock("../imap/imapConfigBuilder", () => ({
+  buildImapConfig: vi.fn(() => ({ password: "secret" })),
+}));
+vi.mock("../imap/tauriCommands", () => ({
+  imapIdleStart: vi.fn(),
+}));
+vi.mock("@tauri-apps/api/event", () => ({
+  listen: vi.fn().mockResolvedValue(() => {}),
 }));
 vi.mock("../oauth/oauthTokenManager", () => ({
   ensureFreshToken: vi.fn(),
@@ -43,11 +53,17 @@ import {
 import { getAccount } from "../db/accounts";
 import { getGmailClient } from "./tokenManager";
 import { initialSync, deltaSync } from "./sync";
+import { imapDeltaSync } from "../imap/imapSync";
+import { getAllFolderSyncStates } from "../db/folderSyncState";
+import { imapIdleStart } from "../imap/tauriCommands";
 
 const mockGetAccount = vi.mocked(getAccount);
 const mockGetGmailClient = vi.mocked(getGmailClient);
 const mockInitialSync = vi.mocked(initialSync);
 const mockDeltaSync = vi.mocked(deltaSync);
+const mockImapDeltaSync = vi.mocked(imapDeltaSync);
+const mockGetAllFolderSyncStates = vi.mocked(getAllFolderSyncStates);
+const mockImapIdleStart = vi.mocked(imapIdleStart);
 
 const wait = (ms: number) => new Promise((r) => setTimeout(r, ms));
 
@@ -230,6 +246,29 @@ describe("syncManager", () => {
     });
   });
 
+  describe("IMAP IDLE watchers", () => {
+    it("starts watchers on synced folders, INBOX first, once per credential", async () => {
+      mockGetAccount.mockResolvedValue({
+        ...makeGmailAccount("imap1", "imap-synced-1"),
+        provider: "imap" as const,
+        auth_method: "password",
+      } as unknown as Awaited<ReturnType<typeof getAccount>>);
+      mockImapDeltaSync.mockResolvedValue({ messages: [{}] } as unknown as Awaited<ReturnType<typeof imapDeltaSync>>);
+      mockGetAllFolderSyncStates.mockResolvedValue([
+        { folder_path: "Sent" },
+        { folder_path: "INBOX" },
+      ] as unknown as Awaited<ReturnType<typeof getAllFolderSyncStates>>);
+
+      await syncAccount("imap1");
+      await wait(10);
+      await syncAccount("imap1");
+      await wait(10);
+
+      expect(mockImapIdleStart).toHaveBeenCalledTimes(1);
+      expect(mockImapIdleStart).toHaveBeenCalledWith("imap1", { password: "secret" }, ["INBOX", "Sent"]);
+    });
+  });
+
   describe("triggerSync", () => {
     it("syncs all provided accounts", async () => {
       const a1 = makeGmailAccount("a1", "100");
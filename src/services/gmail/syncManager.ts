@@ -1,11 +1,15 @@
+import { listen } from "@tauri-apps/api/event";
 import { getGmailClient } from "./tokenManager";
 import { initialSync, deltaSync, type SyncProgress } from "./sync";
 import { getAccount, clearAccountHistoryId } from "../db/accounts";
 import { getSetting } from "../db/settings";
 import { getThreadCountForAccount, deleteAllThreadsForAccount } from "../db/threads";
 import { deleteAllMessagesForAccount } from "../db/messages";
-import { imapInitialSync, imapDeltaSync } from "../imap/imapSync";
-import { clearAllFolderSyncStates } from "../db/folderSyncState";
+import { imapInitialSync, imapDeltaSync, cancelImapSync } from "../imap/imapSync";
+import { MailError } from "../imap/mailError";
+import { clearAllFolderSyncStates, getAllFolderSyncStates } from "../db/folderSyncState";
+import { buildImapConfig } from "../imap/imapConfigBuilder";
+import { imapIdleStart, type IdleStoppedEvent, type MailboxChangedEvent } from "../imap/tauriCommands";
 import { ensureFreshToken } from "../oauth/oauthTokenManager";
 import { hasCalendarSupport, getCalendarProvider } from "../calendar/providerFactory";
 import { getVisibleCalendars, upsertCalendar, updateCalendarSyncToken } from "../db/calendars";
@@ -16,6 +20,16 @@ const SYNC_INTERVAL_MS = 60_000; // 60 seconds — delta syncs are lightweight (
 let syncTimer: ReturnType<typeof setInterval> | null = null;
 let syncPromise: Promise<void> | null = null;
 let pendingAccountIds: string[] | null = null;
+/** The IMAP account being synced right now, if any. */
+let syncingImapAccountId: string | null = null;
+
+/** Wait after an IDLE change before syncing, so a burst of changes is one sync. */
+const IDLE_SYNC_DELAY_MS = 2_000;
+
+/** Credentials and folders each IMAP account's IDLE watchers were started with. */
+const idleWatchers = new Map<string, string>();
+const idleSyncTimers = new Map<string, ReturnType<typeof setTimeout>>();
+let idleListeners: Promise<void> | null = null;
 
 export type SyncStatusCallback = (
   accountId: string,
@@ -122,6 +136,58 @@ async function syncImapAccount(accountId: string): Promise<void> {
   }
 }
 
+/**
+ * Start IDLE watchers on the account's synced folders, INBOX first so it gets
+ * one of the IDLE connections. They are only restarted when the credentials
+ * (e.g. a refreshed OAuth token) or the folders change.
+ */
+async function startIdleWatchers(accountId: string): Promise<void> {
+  const account = await getAccount(accountId);
+  if (!account) return;
+
+  const config =
+    account.auth_method === "oauth2"
+      ? buildImapConfig(account, await ensureFreshToken(account))
+      : buildImapConfig(account);
+  const folders = (await getAllFolderSyncStates(accountId))
+    .map((s) => s.folder_path)
+    .sort((a, b) => Number(b.toUpperCase() === "INBOX") - Number(a.toUpperCase() === "INBOX"));
+  if (folders.length === 0) return;
+
+  const started = [config.password, ...folders].join("\n");
+  if (idleWatchers.get(accountId) === started) return;
+
+  await listenForIdleEvents();
+  await imapIdleStart(accountId, config, folders);
+  idleWatchers.set(accountId, started);
+}
+
+/**
+ * Sync an account shortly after its IDLE watchers report a change, and
+ * surface watchers that gave up on a rejected login or certificate.
+ */
+function listenForIdleEvents(): Promise<void> {
+  idleListeners ??= (async () => {
+    await listen<MailboxChangedEvent>("imap-mailbox-changed", ({ payload }) => {
+      const { account_id: accountId } = payload;
+      if (idleSyncTimers.has(accountId)) return;
+      idleSyncTimers.set(accountId, setTimeout(() => {
+        idleSyncTimers.delete(accountId);
+        runSync([accountId]);
+      }, IDLE_SYNC_DELAY_MS));
+    });
+    await listen<IdleStoppedEvent>("imap-idle-stopped", ({ payload }) => {
+      console.warn(`[syncManager] IDLE stopped for ${payload.account_id} ${payload.folder}:`, payload.error.message);
+      idleWatchers.delete(payload.account_id);
+      statusCallback?.(payload.account_id, "error", undefined, payload.error.message);
+    });
+  })().catch((err) => {
+    idleListeners = null;
+    throw err;
+  });
+  return idleListeners;
+}
+
 /**
  * Sync calendars for a single account via the CalendarProvider abstraction.
  * Discovers calendars, syncs events for each visible calendar, stores results in DB.
@@ -220,7 +286,16 @@ async function syncAccountInternal(accountId: string): Promise<void> {
     }
 
     if (account.provider === "imap") {
-      await syncImapAccount(accountId);
+      syncingImapAccountId = accountId;
+      try {
+        await syncImapAccount(accountId);
+      } finally {
+        syncingImapAccountId = null;
+      }
+      // New mail is pushed by IDLE between the periodic syncs
+      startIdleWatchers(accountId).catch((err) => {
+        console.warn(`[syncManager] Failed to start IDLE watchers for ${accountId}:`, err);
+      });
     } else {
       await syncGmailAccount(accountId);
     }
@@ -235,6 +310,11 @@ async function syncAccountInternal(accountId: string): Promise<void> {
       console.warn(`[syncManager] Calendar sync error for ${accountId}:`, err);
     });
   } catch (err) {
+    if (err instanceof MailError && err.kind === "Cancelled") {
+      console.log(`[syncManager] Sync of account ${accountId} was cancelled`);
+      statusCallback?.(accountId, "done");
+      return;
+    }
     const message = err instanceof Error ? err.message : "Unknown error";
     console.error(`[syncManager] Sync failed for account ${accountId}:`, message);
     statusCallback?.(accountId, "error", undefined, message);
@@ -277,6 +357,21 @@ export async function syncAccount(accountId: string): Promise<void> {
   return runSync([accountId]);
 }
 
+/**
+ * Switch syncing to the account the user switched to: a running IMAP sync of
+ * another account (e.g. a long initial sync) is cancelled so this one doesn't
+ * wait behind it. The cancelled account resumes on the next periodic sync.
+ */
+export async function prioritizeAccountSync(accountId: string): Promise<void> {
+  const running = syncingImapAccountId;
+  if (running && running !== accountId) {
+    await cancelImapSync(running).catch((err) => {
+      console.warn(`[syncManager] Failed to cancel sync of ${running}:`, err);
+    });
+  }
+  await runSync([accountId]);
+}
+
 /**
  * Start the background sync timer for all accounts.
  * When `skipImmediateSync` is true the first periodic sync is deferred to the
@@ -1,5 +1,10 @@
 import { describe, it, expect } from "vitest";
-import { mapFolderToLabel, getLabelsForMessage, getSyncableFolders } from "./folderMapper";
+import {
+  mapFolderToLabel,
+  getLabelsForMessage,
+  getSyncableFolders,
+  mapGmailLabel,
+} from "./folderMapper";
 import { createMockImapFolder } from "@/test/mocks";
 
 describe("mapFolderToLabel", () => {
@@ -118,6 +123,19 @@ describe("getLabelsForMessage", () => {
   });
 });
 
+describe("mapGmailLabel", () => {
+  it("maps Gmail system labels", () => {
+    expect(mapGmailLabel("\\Inbox")).toBe("INBOX");
+    expect(mapGmailLabel("\\Important")).toBe("IMPORTANT");
+    expect(mapGmailLabel("\\Muted")).toBeNull();
+  });
+
+  it("maps user labels to the same ID as their folder", () => {
+    const folder = createMockImapFolder({ path: "Work/Receipts", name: "Receipts" });
+    expect(mapGmailLabel("Work/Receipts")).toBe(mapFolderToLabel(folder).labelId);
+  });
+});
+
 describe("getSyncableFolders", () => {
   it("filters out [Gmail] parent folder", () => {
     const folders: ImapFolder[] = [
@@ -148,4 +166,25 @@ describe("getSyncableFolders", () => {
     const result = getSyncableFolders(folders);
     expect(result).toHaveLength(3);
   });
+
+  it("syncs only All Mail, Trash and Spam on Gmail", () => {
+    const folders: ImapFolder[] = [
+      createMockImapFolder({ path: "INBOX", name: "INBOX" }),
+      createMockImapFolder({ path: "[Gmail]/All Mail", name: "All Mail", special_use: "\\All" }),
+      createMockImapFolder({ path: "[Gmail]/Sent Mail", name: "Sent Mail", special_use: "\\Sent" }),
+      createMockImapFolder({ path: "[Gmail]/Trash", name: "Trash", special_use: "\\Trash" }),
+      createMockImapFolder({ path: "[Gmail]/Spam", name: "Spam", special_use: "\\Junk" }),
+      createMockImapFolder({ path: "Receipts", name: "Receipts" }),
+    ];
+    const result = getSyncableFolders(folders, true);
+    expect(result.map((f) => f.path)).toEqual(["[Gmail]/All Mail", "[Gmail]/Trash", "[Gmail]/Spam"]);
+  });
+
+  it("syncs every folder on Gmail when All Mail is hidden", () => {
+    const folders: ImapFolder[] = [
+      createMockImapFolder({ path: "INBOX", name: "INBOX" }),
+      createMockImapFolder({ path: "Receipts", name: "Receipts" }),
+    ];
+    expect(getSyncableFolders(folders, true)).toHaveLength(2);
+  });
 });
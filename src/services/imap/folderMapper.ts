@@ -52,6 +52,19 @@ const FOLDER_NAME_MAP: Record<string, string> = {
   "[gmail]/important": "\\Important",
 };
 
+/**
+ * Gmail system labels as they appear in X-GM-LABELS.
+ */
+const GMAIL_SYSTEM_LABELS: Record<string, string> = {
+  "\\Inbox": "INBOX",
+  "\\Sent": "SENT",
+  "\\Draft": "DRAFT",
+  "\\Starred": "STARRED",
+  "\\Important": "IMPORTANT",
+  "\\Spam": "SPAM",
+  "\\Trash": "TRASH",
+};
+
 export interface FolderLabelMapping {
   labelId: string;
   labelName: string;
@@ -120,6 +133,16 @@ export function getLabelsForMessage(
   return labels;
 }
 
+/**
+ * Map a Gmail X-GM-LABELS entry to a label ID. User labels get the same ID
+ * as the folder Gmail exposes them as, so a message reached through either
+ * ends up with one label. Unknown system labels map to null.
+ */
+export function mapGmailLabel(label: string): string | null {
+  if (label.startsWith("\\")) return GMAIL_SYSTEM_LABELS[label] ?? null;
+  return `folder-${label}`;
+}
+
 /**
  * Sync IMAP folders to the labels table in the DB.
  * Creates/updates label entries for each folder.
@@ -149,12 +172,19 @@ export async function syncFoldersToLabels(
   });
 }
 
+/** Gmail folders that hold messages missing from All Mail. */
+const GMAIL_SYNCED_LABELS = new Set(["all-mail", "TRASH", "SPAM"]);
+
 /**
  * Determine which folders should be synced during initial sync.
  * Excludes special folders like [Gmail] parent folder.
+ *
+ * With `gmail` (the server advertises X-GM-EXT-1), every label is also a
+ * folder holding copies of the same messages, so only All Mail, Trash and
+ * Spam are synced; labels then come from each message's X-GM-LABELS.
  */
-export function getSyncableFolders(folders: ImapFolder[]): ImapFolder[] {
-  return folders.filter((f) => {
+export function getSyncableFolders(folders: ImapFolder[], gmail = false): ImapFolder[] {
+  const syncable = folders.filter((f) => {
     const lowerPath = f.path.toLowerCase();
     // Skip the Gmail parent container folder
     if (lowerPath === "[gmail]" || lowerPath === "[google mail]") return false;
@@ -162,4 +192,9 @@ export function getSyncableFolders(folders: ImapFolder[]): ImapFolder[] {
     if (lowerPath.startsWith("[nostromo]")) return false;
     return true;
   });
+
+  if (!gmail || !syncable.some((f) => mapFolderToLabel(f).labelId === "all-mail")) {
+    return syncable;
+  }
+  return syncable.filter((f) => GMAIL_SYNCED_LABELS.has(mapFolderToLabel(f).labelId));
 }
@@ -15,6 +15,7 @@ describe("buildImapConfig", () => {
       password: "secret123",
       auth_method: "password",
       accept_invalid_certs: false,
+      account_id: "acc-1",
     });
   });
 
@@ -67,6 +68,13 @@ describe("buildImapConfig", () => {
     expect(config.password).toBe("secret123");
   });
 
+  it("passes a forced password mechanism through", () => {
+    const account = createMockDbAccount({ auth_method: "scram-sha-256" });
+    const config = buildImapConfig(account, "should-not-use");
+    expect(config.auth_method).toBe("scram-sha-256");
+    expect(config.password).toBe("secret123");
+  });
+
   it("throws when imap_host is missing", () => {
     const account = createMockDbAccount({ imap_host: null });
     expect(() => buildImapConfig(account)).toThrow("no IMAP host configured");
@@ -92,6 +100,7 @@ describe("buildSmtpConfig", () => {
       password: "secret123",
       auth_method: "password",
       accept_invalid_certs: false,
+      account_id: "acc-1",
     });
   });
 
@@ -163,3 +172,51 @@ describe("accept_invalid_certs", () => {
     expect(smtpConfig.accept_invalid_certs).toBe(true);
   });
 });
+
+describe("TLS settings", () => {
+  it("passes the CA, client certificate and minimum version to both configs", () => {
+    const account = createMockDbAccount({
+      tls_ca_pem: "-----BEGIN CERTIFICATE-----",
+      tls_client_pkcs12: "MIIK",
+      tls_client_password: "p12-secret",
+      tls_min_version: "1.3",
+    });
+    for (const config of [buildImapConfig(account), buildSmtpConfig(account)]) {
+      expect(config.ca_certificates_pem).toBe("-----BEGIN CERTIFICATE-----");
+      expect(config.client_identity_pkcs12).toBe("MIIK");
+      expect(config.client_identity_password).toBe("p12-secret");
+      expect(config.min_tls_version).toBe("1.3");
+    }
+  });
+});
+
+describe("proxy", () => {
+  it("passes the account's proxy to both configs", () => {
+    const proxy = { kind: "socks5h", host: "127.0.0.1", port: 9050 };
+    const account = createMockDbAccount({ proxy_json: JSON.stringify(proxy) });
+    expect(buildImapConfig(account).proxy).toEqual(proxy);
+    expect(buildSmtpConfig(account).proxy).toEqual(proxy);
+  });
+
+  it("ignores a malformed proxy setting", () => {
+    const account = createMockDbAccount({ proxy_json: "{not json" });
+    expect(buildImapConfig(account).proxy).toBeUndefined();
+  });
+});
+
+describe("pinned certificates", () => {
+  it("passes each protocol's pin through", () => {
+    const account = createMockDbAccount({
+      imap_cert_sha256: "AA:BB",
+      smtp_cert_sha256: "CC:DD",
+    });
+    expect(buildImapConfig(account).pinned_cert_sha256).toBe("AA:BB");
+    expect(buildSmtpConfig(account).pinned_cert_sha256).toBe("CC:DD");
+  });
+
+  it("leaves the pin unset when the account has none", () => {
+    const account = createMockDbAccount();
+    expect(buildImapConfig(account).pinned_cert_sha256).toBeUndefined();
+    expect(buildSmtpConfig(account).pinned_cert_sha256).toBeUndefined();
+  });
+});
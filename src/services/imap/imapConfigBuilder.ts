@@ -1,5 +1,5 @@
 import type { DbAccount } from "../db/accounts";
-import type { ImapConfig, SmtpConfig } from "./tauriCommands";
+import type { AuthMethod, ImapConfig, ProxyConfig, SmtpConfig } from "./tauriCommands";
 
 /**
  * Map the DB-stored security value to the config type.
@@ -15,13 +15,45 @@ function mapSecurity(security: string | null): "tls" | "starttls" | "none" {
 }
 
 /**
- * Map the DB auth_method value to config type.
+ * Map the DB auth_method value to config type. A specific password mechanism
+ * is passed through so it can be forced for servers that refuse LOGIN.
  */
-function mapAuthMethod(method: string | null): "password" | "oauth2" {
+function mapAuthMethod(method: string | null): AuthMethod {
   if (method === "oauth2") return "oauth2";
+  if (method === "login" || method === "plain" || method === "scram-sha-256") {
+    return method;
+  }
   return "password";
 }
 
+/**
+ * The account's custom CA, client certificate, minimum TLS version and
+ * proxy, shared by IMAP and SMTP. Unset settings are left out.
+ */
+function connectionSettings(account: DbAccount) {
+  return {
+    ca_certificates_pem: account.tls_ca_pem ?? undefined,
+    client_identity_pkcs12: account.tls_client_pkcs12 ?? undefined,
+    client_identity_password: account.tls_client_password ?? undefined,
+    min_tls_version: account.tls_min_version ?? undefined,
+    proxy: parseProxy(account),
+  };
+}
+
+/**
+ * The account's own proxy. A malformed setting falls back to the global
+ * proxy rather than failing every connection.
+ */
+export function parseProxy(account: DbAccount): ProxyConfig | undefined {
+  if (!account.proxy_json) return undefined;
+  try {
+    return JSON.parse(account.proxy_json) as ProxyConfig;
+  } catch {
+    console.warn(`Ignoring malformed proxy setting for account ${account.id}`);
+    return undefined;
+  }
+}
+
 /**
  * Build an ImapConfig from a DbAccount's IMAP fields.
  * Assumes the account's imap_password has already been decrypted.
@@ -51,6 +83,9 @@ export function buildImapConfig(
     password,
     auth_method: authMethod,
     accept_invalid_certs: !!account.accept_invalid_certs,
+    pinned_cert_sha256: account.imap_cert_sha256 ?? undefined,
+    ...connectionSettings(account),
+    account_id: account.id,
   };
 }
 
@@ -83,5 +118,8 @@ export function buildSmtpConfig(
     password,
     auth_method: authMethod,
     accept_invalid_certs: !!account.accept_invalid_certs,
+    pinned_cert_sha256: account.smtp_cert_sha256 ?? undefined,
+    ...connectionSettings(account),
+    account_id: account.id,
   };
 }
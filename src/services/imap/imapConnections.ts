@@ -0,0 +1,27 @@
+import type { DbAccount } from "../db/accounts";
+import { ensureFreshToken } from "../oauth/oauthTokenManager";
+import { buildImapConfig } from "./imapConfigBuilder";
+import { imapCloseConnections, imapIdleStop } from "./tauriCommands";
+
+/**
+ * Log out the account's pooled IMAP sessions, so the next command logs in
+ * again with its current credentials, TLS and proxy settings, and restart
+ * its IDLE watchers with them. With `stopIdle`, its IDLE watchers are
+ * stopped instead (e.g. when it is removed).
+ */
+export async function closeImapConnections(
+  account: DbAccount,
+  { stopIdle = false }: { stopIdle?: boolean } = {},
+): Promise<void> {
+  if (account.provider !== "imap" || !account.imap_host) return;
+  try {
+    if (stopIdle) await imapIdleStop(account.id);
+    const token =
+      account.auth_method === "oauth2" && !stopIdle
+        ? await ensureFreshToken(account).catch(() => undefined)
+        : undefined;
+    await imapCloseConnections(buildImapConfig(account, token));
+  } catch (err) {
+    console.warn(`Failed to close IMAP connections for ${account.email}:`, err);
+  }
+}
@@ -8,7 +8,13 @@ vi.mock("./tauriCommands", () => ({
   imapFetchNewUids: vi.fn(),
   imapSearchAllUids: vi.fn(),
   imapSyncFolder: vi.fn(),
+  imapFetchMessageBodies: vi.fn(),
+  imapCancelSync: vi.fn(),
   imapDeltaCheck: vi.fn(),
+  imapGetCapabilities: vi.fn(() => Promise.resolve({ gmail: false })),
+}));
+vi.mock("@tauri-apps/api/event", () => ({
+  listen: vi.fn(() => Promise.resolve(() => {})),
 }));
 vi.mock("./imapConfigBuilder", () => ({
   buildImapConfig: vi.fn(() => ({
@@ -40,10 +46,19 @@ vi.mock("./folderMapper", () => ({
 vi.mock("../db/messages", () => ({
   upsertMessage: vi.fn(),
   updateMessageThreadIds: vi.fn(),
+  deleteMessage: vi.fn(),
+  getMessagesForThread: vi.fn(),
+  getMessagesByImapUids: vi.fn(),
+  updateMessageFlags: vi.fn(),
+  getImapMessagesWithoutBody: vi.fn(() => []),
+  updateMessageBody: vi.fn(),
 }));
 vi.mock("../db/threads", () => ({
   upsertThread: vi.fn(),
   setThreadLabels: vi.fn(),
+  getThreadLabelIds: vi.fn(),
+  updateThreadFlags: vi.fn(),
+  deleteThread: vi.fn(),
 }));
 vi.mock("../db/attachments", () => ({
   upsertAttachment: vi.fn(),
@@ -67,9 +82,15 @@ import {
   createMockImapFolder,
   createMockImapFolderSyncResult,
 } from "@/test/mocks";
-import { imapListFolders, imapSyncFolder } from "./tauriCommands";
+import { imapListFolders, imapSyncFolder, imapFetchMessageBodies } from "./tauriCommands";
+import { MailError } from "./mailError";
 import { getAccount } from "../db/accounts";
-import { upsertMessage, updateMessageThreadIds } from "../db/messages";
+import {
+  upsertMessage,
+  updateMessageThreadIds,
+  getImapMessagesWithoutBody,
+  updateMessageBody,
+} from "../db/messages";
 import { upsertThread } from "../db/threads";
 import { upsertAttachment } from "../db/attachments";
 
@@ -100,6 +121,15 @@ describe("imapMessageToParsedMessage", () => {
     expect(parsed.id).toBe("imap-acc-2-Sent-99");
   });
 
+  it("keys Gmail messages on X-GM-MSGID so folder copies share an ID", () => {
+    const inAllMail = createMockImapMessage({ uid: 7, folder: "[Gmail]/All Mail", gmail_message_id: "1278455344230334865" });
+    const inInbox = createMockImapMessage({ uid: 3, folder: "INBOX", gmail_message_id: "1278455344230334865" });
+    const { parsed: a } = imapMessageToParsedMessage(inAllMail, "acc-2", "all-mail");
+    const { parsed: b } = imapMessageToParsedMessage(inInbox, "acc-2", "INBOX");
+    expect(a.id).toBe("imap-acc-2-gm-1278455344230334865");
+    expect(b.id).toBe(a.id);
+  });
+
   it("includes UNREAD label for unread messages", () => {
     const msg = createMockImapMessage({ is_read: false });
     const { parsed } = imapMessageToParsedMessage(msg, "acc-1", "INBOX");
@@ -427,7 +457,33 @@ describe("imapInitialSync", () => {
       expect.objectContaining({ host: "imap.example.com" }),
       "INBOX",
       50, // BATCH_SIZE
+      true, // headers only; bodies follow in the budgeted pass
+      "imap-sync-acc-1",
+    );
+  });
+
+  it("downloads bodies of header-only messages newest first after the sync", async () => {
+    const msg = createMockImapMessage({ uid: 1, message_id: "<m1@test>", date: Math.floor(Date.now() / 1000) });
+    setupFolderWithMessages("INBOX", [msg]);
+    vi.mocked(getImapMessagesWithoutBody).mockResolvedValueOnce([
+      { imap_folder: "INBOX", imap_uid: 7 },
+      { imap_folder: "INBOX", imap_uid: 3 },
+    ]);
+    vi.mocked(imapFetchMessageBodies).mockResolvedValueOnce({
+      messages: [createMockImapMessage({ uid: 7, body_html: "<p>hi</p>", body_text: "hi", raw_size: 100 })],
+      remaining_uids: [3],
+    });
+
+    await imapInitialSync("acc-1");
+
+    expect(imapFetchMessageBodies).toHaveBeenCalledWith(
+      expect.objectContaining({ host: "imap.example.com" }),
+      "INBOX",
+      [7, 3],
+      50 * 1024 * 1024,
     );
+    expect(updateMessageBody).toHaveBeenCalledTimes(1);
+    expect(updateMessageBody).toHaveBeenCalledWith("acc-1", "imap-acc-1-INBOX-7", "<p>hi</p>", "hi", expect.anything());
   });
 
   it("circuit breaker skips remaining folders after 5 consecutive connection failures", async () => {
@@ -487,4 +543,21 @@ describe("imapInitialSync", () => {
     // All folders should be attempted since these aren't connection errors
     expect(mockImapSyncFolder).toHaveBeenCalledTimes(6);
   });
+
+  it("stops at a cancelled folder without syncing the rest", async () => {
+    const folders = Array.from({ length: 3 }, (_, i) =>
+      createMockImapFolder({ path: `folder-${i}`, raw_path: `folder-${i}`, exists: 10 }),
+    );
+    mockImapListFolders.mockResolvedValue(folders);
+    mockImapSyncFolder.mockRejectedValue(
+      new MailError({ kind: "Cancelled", message: "Sync of folder-0 was cancelled", server_response: null, retryable: false }),
+    );
+
+    const syncPromise = imapInitialSync("acc-1");
+    const rejection = expect(syncPromise).rejects.toMatchObject({ kind: "Cancelled" });
+    await vi.runAllTimersAsync();
+    await rejection;
+
+    expect(mockImapSyncFolder).toHaveBeenCalledTimes(1);
+  });
 });
@@ -1,23 +1,51 @@
-import type { ImapConfig, ImapMessage, DeltaCheckRequest, DeltaCheckResult } from "./tauriCommands";
+import { listen } from "@tauri-apps/api/event";
+import type {
+  ImapConfig,
+  ImapMessage,
+  ImapFolderSyncResult,
+  DeltaCheckRequest,
+  DeltaCheckResult,
+  SyncProgressEvent,
+} from "./tauriCommands";
 import {
   imapListFolders,
   imapGetFolderStatus,
   imapFetchMessages,
   imapFetchNewUids,
   imapSyncFolder,
+  imapFetchMessageBodies,
+  imapCancelSync,
   imapDeltaCheck,
+  imapGetCapabilities,
 } from "./tauriCommands";
 import { buildImapConfig } from "./imapConfigBuilder";
+import { MailError } from "./mailError";
 import {
   mapFolderToLabel,
   getLabelsForMessage,
   syncFoldersToLabels,
   getSyncableFolders,
+  mapGmailLabel,
 } from "./folderMapper";
 import type { ParsedMessage, ParsedAttachment } from "../gmail/messageParser";
 import type { SyncResult } from "../email/types";
-import { upsertMessage, updateMessageThreadIds } from "../db/messages";
-import { upsertThread, setThreadLabels } from "../db/threads";
+import {
+  upsertMessage,
+  updateMessageThreadIds,
+  deleteMessage,
+  getMessagesForThread,
+  getMessagesByImapUids,
+  updateMessageFlags,
+  getImapMessagesWithoutBody,
+  updateMessageBody,
+} from "../db/messages";
+import {
+  upsertThread,
+  setThreadLabels,
+  getThreadLabelIds,
+  updateThreadFlags,
+  deleteThread,
+} from "../db/threads";
 import { upsertAttachment } from "../db/attachments";
 import { getAccount, updateAccountSyncState } from "../db/accounts";
 import {
@@ -36,6 +64,10 @@ import { getPendingOpsForResource } from "../db/pendingOperations";
 // ---------------------------------------------------------------------------
 
 const BATCH_SIZE = 50;
+/** Message bodies downloaded per sync for messages stored header-only. */
+const BODY_BUDGET_BYTES = 50 * 1024 * 1024;
+/** Header-only messages considered per body pass, newest first. */
+const BODY_PASS_MAX_MESSAGES = 2_000;
 
 // ---------------------------------------------------------------------------
 // Circuit breaker for connection storms
@@ -51,6 +83,7 @@ const CIRCUIT_BREAKER_MAX_FAILURES = 5;
 const INTER_FOLDER_DELAY_MS = 1_000;
 
 function isConnectionError(err: unknown): boolean {
+  if (err instanceof MailError) return err.retryable;
   const msg = String(err).toLowerCase();
   return msg.includes("timed out") || msg.includes("connection") || msg.includes("tcp");
 }
@@ -59,6 +92,69 @@ function delay(ms: number): Promise<void> {
   return new Promise((resolve) => setTimeout(resolve, ms));
 }
 
+// ---------------------------------------------------------------------------
+// Cancellation
+// ---------------------------------------------------------------------------
+
+/** Accounts whose running sync was cancelled, checked between folders. */
+const cancelledAccounts = new Set<string>();
+
+/** Folder syncs run one at a time per account, so they share one sync id. */
+function syncIdFor(accountId: string): string {
+  return `imap-sync-${accountId}`;
+}
+
+/**
+ * Stop the account's running initial or delta sync: the folder being fetched
+ * is cancelled in the backend and the remaining folders are skipped. The sync
+ * rejects with a MailError of kind "Cancelled".
+ */
+export async function cancelImapSync(accountId: string): Promise<void> {
+  cancelledAccounts.add(accountId);
+  await imapCancelSync(syncIdFor(accountId));
+}
+
+function isCancelled(err: unknown): boolean {
+  return err instanceof MailError && err.kind === "Cancelled";
+}
+
+function throwIfCancelled(accountId: string): void {
+  if (!cancelledAccounts.has(accountId)) return;
+  throw new MailError({
+    kind: "Cancelled",
+    message: `Sync of account ${accountId} was cancelled`,
+    server_response: null,
+    retryable: false,
+  });
+}
+
+/**
+ * imapSyncFolder under the account's sync id, so cancelImapSync can stop it.
+ * `onFetched` receives the folder's `imap-sync-progress` events.
+ */
+async function syncFolder(
+  config: ImapConfig,
+  accountId: string,
+  folder: string,
+  headersOnly: boolean,
+  onFetched?: (fetched: number, total: number) => void,
+): Promise<ImapFolderSyncResult> {
+  throwIfCancelled(accountId);
+  const syncId = syncIdFor(accountId);
+  const unlisten = onFetched
+    ? await listen<SyncProgressEvent>("imap-sync-progress", ({ payload }) => {
+        if (payload.sync_id === syncId && payload.folder === folder) {
+          onFetched(payload.fetched, payload.total);
+        }
+      })
+    : undefined;
+  try {
+    return await imapSyncFolder(config, folder, BATCH_SIZE, headersOnly, syncId);
+  } finally {
+    unlisten?.();
+  }
+}
+
 // ---------------------------------------------------------------------------
 // Progress reporting
 // ---------------------------------------------------------------------------
@@ -92,7 +188,11 @@ export function imapMessageToParsedMessage(
   accountId: string,
   folderLabelId: string,
 ): { parsed: ParsedMessage; threadable: ThreadableMessage } {
-  const messageId = `imap-${accountId}-${msg.folder}-${msg.uid}`;
+  // On Gmail a message keeps its X-GM-MSGID in every folder, so copies
+  // reached through different folders are stored once.
+  const messageId = msg.gmail_message_id
+    ? `imap-${accountId}-gm-${msg.gmail_message_id}`
+    : `imap-${accountId}-${msg.folder}-${msg.uid}`;
   const rfc2822MessageId =
     msg.message_id ?? syntheticMessageId(accountId, msg.folder, msg.uid);
 
@@ -103,6 +203,10 @@ export function imapMessageToParsedMessage(
     msg.is_starred,
     msg.is_draft,
   );
+  for (const label of msg.gmail_labels) {
+    const labelId = mapGmailLabel(label);
+    if (labelId && !labelIds.includes(labelId)) labelIds.push(labelId);
+  }
 
   const snippet = msg.snippet ?? (msg.body_text ? msg.body_text.slice(0, 200) : "");
 
@@ -288,6 +392,69 @@ async function storeThreadsAndMessages(
   return storedMessages;
 }
 
+// ---------------------------------------------------------------------------
+// Apply flag changes and expunges reported by a delta check
+// ---------------------------------------------------------------------------
+
+/**
+ * Apply CONDSTORE flag changes and QRESYNC expunges to the stored copies of a
+ * folder's messages, then refresh the affected threads. Threads with pending
+ * local changes are left alone, as in storeThreadsAndMessages.
+ */
+async function applyServerChanges(
+  accountId: string,
+  folder: string,
+  deltaResult: DeltaCheckResult,
+): Promise<void> {
+  const changesByUid = new Map(deltaResult.flag_changes.map((c) => [c.uid, c]));
+  const vanished = new Set(deltaResult.vanished_uids);
+  const stored = await getMessagesByImapUids(accountId, folder, [
+    ...changesByUid.keys(),
+    ...vanished,
+  ]);
+
+  const changedThreads = new Set<string>();
+  for (const msg of stored) {
+    const pendingOps = await getPendingOpsForResource(accountId, msg.thread_id);
+    if (pendingOps.length > 0) continue;
+
+    const change = changesByUid.get(msg.imap_uid!);
+    if (vanished.has(msg.imap_uid!)) {
+      await deleteMessage(accountId, msg.id);
+    } else if (change) {
+      await updateMessageFlags(accountId, msg.id, change.is_read, change.is_starred);
+    }
+    changedThreads.add(msg.thread_id);
+  }
+
+  for (const threadId of changedThreads) {
+    const messages = await getMessagesForThread(accountId, threadId);
+    if (messages.length === 0) {
+      await deleteThread(accountId, threadId);
+      continue;
+    }
+
+    const isRead = messages.every((m) => m.is_read === 1);
+    const isStarred = messages.some((m) => m.is_starred === 1);
+    await updateThreadFlags(accountId, threadId, {
+      messageCount: messages.length,
+      isRead,
+      isStarred,
+    });
+
+    const labelIds = new Set(await getThreadLabelIds(accountId, threadId));
+    if (isRead) labelIds.delete("UNREAD");
+    else labelIds.add("UNREAD");
+    if (isStarred) labelIds.add("STARRED");
+    else labelIds.delete("STARRED");
+    await setThreadLabels(accountId, threadId, [...labelIds]);
+  }
+
+  console.log(
+    `[imapSync] Folder ${folder}: ${deltaResult.flag_changes.length} flag changes, ${deltaResult.vanished_uids.length} expunged`,
+  );
+}
+
 // ---------------------------------------------------------------------------
 // Fetch messages from a folder in batches
 // ---------------------------------------------------------------------------
@@ -322,13 +489,56 @@ async function fetchMessagesInBatches(
   return { messages: allMessages, lastUid, uidvalidity };
 }
 
+// ---------------------------------------------------------------------------
+// Background body download
+// ---------------------------------------------------------------------------
+
+/**
+ * Download bodies of messages stored header-only, newest first, until
+ * BODY_BUDGET_BYTES is used up. The rest are picked up by later syncs.
+ */
+async function fetchMissingBodies(config: ImapConfig, accountId: string): Promise<void> {
+  const missing = await getImapMessagesWithoutBody(accountId, BODY_PASS_MAX_MESSAGES);
+  if (missing.length === 0) return;
+
+  // Folders in order of their newest missing body
+  const uidsByFolder = new Map<string, number[]>();
+  for (const { imap_folder, imap_uid } of missing) {
+    const uids = uidsByFolder.get(imap_folder);
+    if (uids) uids.push(imap_uid);
+    else uidsByFolder.set(imap_folder, [imap_uid]);
+  }
+
+  let budget = BODY_BUDGET_BYTES;
+  let loaded = 0;
+  for (const [folder, uids] of uidsByFolder) {
+    throwIfCancelled(accountId);
+    try {
+      const result = await imapFetchMessageBodies(config, folder, uids, budget);
+      for (const msg of result.messages) {
+        const { parsed } = imapMessageToParsedMessage(msg, accountId, folder);
+        await updateMessageBody(accountId, parsed.id, parsed.bodyHtml, parsed.bodyText, parsed.snippet || null);
+        budget -= msg.raw_size;
+        loaded++;
+      }
+      if (result.remaining_uids.length > 0 || budget <= 0) break;
+    } catch (err) {
+      if (isCancelled(err)) throw err;
+      console.warn(`[imapSync] Failed to download bodies in ${folder}:`, err);
+    }
+  }
+
+  console.log(`[imapSync] Downloaded ${loaded} message bodies, ${missing.length - loaded} left for later syncs`);
+}
+
 // ---------------------------------------------------------------------------
 // Initial sync
 // ---------------------------------------------------------------------------
 
 /**
  * Perform initial sync for an IMAP account.
- * Fetches messages from all folders for the past N days.
+ * Fetches headers and structure from all folders for the past N days, then
+ * downloads as many bodies as BODY_BUDGET_BYTES allows, newest first.
  */
 export async function imapInitialSync(
   accountId: string,
@@ -341,12 +551,15 @@ export async function imapInitialSync(
   }
 
   const config = buildImapConfig(account);
+  cancelledAccounts.delete(accountId);
 
   // Phase 1: List and sync folders
   onProgress?.({ phase: "folders", current: 0, total: 1 });
   const allFolders = await imapListFolders(config);
-  const syncableFolders = getSyncableFolders(allFolders);
-  await syncFoldersToLabels(accountId, syncableFolders);
+  const { gmail } = await imapGetCapabilities(config);
+  const syncableFolders = getSyncableFolders(allFolders, gmail);
+  // Label folders that aren't synced on Gmail still become labels.
+  await syncFoldersToLabels(accountId, getSyncableFolders(allFolders));
   console.log(`[imapSync] Initial sync for account ${accountId}: ${syncableFolders.length} syncable folders`);
   onProgress?.({ phase: "folders", current: 1, total: 1 });
 
@@ -420,7 +633,14 @@ export async function imapInitialSync(
 
     try {
       // Use single-connection sync: UID SEARCH ALL + batched UID FETCH in one session
-      const syncResult = await imapSyncFolder(config, folder.raw_path, BATCH_SIZE);
+      const syncResult = await syncFolder(config, accountId, folder.raw_path, true, (fetched) => {
+        onProgress?.({
+          phase: "messages",
+          current: fetchedTotal + fetched,
+          total: totalEstimate,
+          folder: folder.path,
+        });
+      });
       const uidsToFetch = syncResult.uids;
 
       // Reset circuit breaker on success
@@ -571,10 +791,11 @@ export async function imapInitialSync(
         folder_path: folder.raw_path,
         uidvalidity,
         last_uid: lastUid,
-        modseq: null,
+        modseq: syncResult.folder_status.highest_modseq,
         last_sync_at: Math.floor(Date.now() / 1000),
       });
     } catch (err) {
+      if (isCancelled(err)) throw err;
       console.error(`[imapSync] Failed to sync folder ${folder.path}:`, err);
       if (isConnectionError(err)) {
         consecutiveFailures++;
@@ -666,6 +887,9 @@ export async function imapInitialSync(
     );
   }
 
+  // Phase 5: bodies, newest first; later delta syncs download the rest
+  await fetchMissingBodies(config, accountId);
+
   onProgress?.({
     phase: "done",
     current: storedCount,
@@ -690,14 +914,16 @@ export async function imapDeltaSync(accountId: string): Promise<SyncResult> {
   }
 
   const config = buildImapConfig(account);
+  cancelledAccounts.delete(accountId);
 
   // Get all folders we've synced before
   const syncStates = await getAllFolderSyncStates(accountId);
 
   // Also check for any new folders
   const allFolders = await imapListFolders(config);
-  const syncableFolders = getSyncableFolders(allFolders);
-  await syncFoldersToLabels(accountId, syncableFolders);
+  const { gmail } = await imapGetCapabilities(config);
+  const syncableFolders = getSyncableFolders(allFolders, gmail);
+  await syncFoldersToLabels(accountId, getSyncableFolders(allFolders));
 
   const syncStateMap = new Map(syncStates.map((s) => [s.folder_path, s]));
 
@@ -725,7 +951,7 @@ export async function imapDeltaSync(accountId: string): Promise<SyncResult> {
 
     const folderMapping = mapFolderToLabel(folder);
     try {
-      const syncResult = await imapSyncFolder(config, folder.raw_path, BATCH_SIZE);
+      const syncResult = await syncFolder(config, accountId, folder.raw_path, false);
       consecutiveFailures = 0;
 
       if (syncResult.uids.length === 0) continue;
@@ -748,10 +974,11 @@ export async function imapDeltaSync(accountId: string): Promise<SyncResult> {
         folder_path: folder.raw_path,
         uidvalidity: syncResult.folder_status.uidvalidity,
         last_uid: lastUid,
-        modseq: null,
+        modseq: syncResult.folder_status.highest_modseq,
         last_sync_at: Math.floor(Date.now() / 1000),
       });
     } catch (err) {
+      if (isCancelled(err)) throw err;
       console.error(`Delta sync failed for new folder ${folder.path}:`, err);
       if (isConnectionError(err)) {
         consecutiveFailures++;
@@ -768,6 +995,7 @@ export async function imapDeltaSync(accountId: string): Promise<SyncResult> {
         folder: folder.raw_path,
         last_uid: savedState.last_uid,
         uidvalidity: savedState.uidvalidity ?? 0,
+        highest_modseq: savedState.modseq,
       };
     });
 
@@ -794,6 +1022,9 @@ export async function imapDeltaSync(accountId: string): Promise<SyncResult> {
               uidvalidity: currentStatus.uidvalidity,
               new_uids: [],
               uidvalidity_changed: true,
+              highest_modseq: currentStatus.highest_modseq,
+              flag_changes: [],
+              vanished_uids: [],
             });
           } else {
             const newUids = await imapFetchNewUids(config, folder.raw_path, savedState.last_uid);
@@ -802,6 +1033,10 @@ export async function imapDeltaSync(accountId: string): Promise<SyncResult> {
               uidvalidity: currentStatus.uidvalidity,
               new_uids: newUids,
               uidvalidity_changed: false,
+              // No flag changes were fetched, so keep the saved baseline.
+              highest_modseq: savedState.modseq,
+              flag_changes: [],
+              vanished_uids: [],
             });
           }
         } catch (folderErr) {
@@ -811,6 +1046,7 @@ export async function imapDeltaSync(accountId: string): Promise<SyncResult> {
     }
 
     for (const folder of existingFolders) {
+      throwIfCancelled(accountId);
       const folderMapping = mapFolderToLabel(folder);
       const savedState = syncStateMap.get(folder.raw_path)!;
       const deltaResult = deltaResultMap.get(folder.raw_path);
@@ -825,7 +1061,7 @@ export async function imapDeltaSync(accountId: string): Promise<SyncResult> {
               `(was ${savedState.uidvalidity}, now ${deltaResult.uidvalidity}). ` +
               `Doing full resync of this folder.`,
           );
-          const syncResult = await imapSyncFolder(config, folder.raw_path, BATCH_SIZE);
+          const syncResult = await syncFolder(config, accountId, folder.raw_path, false);
           if (syncResult.uids.length === 0) continue;
 
           let lastUid = 0;
@@ -846,46 +1082,62 @@ export async function imapDeltaSync(accountId: string): Promise<SyncResult> {
             folder_path: folder.raw_path,
             uidvalidity: syncResult.folder_status.uidvalidity,
             last_uid: lastUid,
-            modseq: null,
+            modseq: syncResult.folder_status.highest_modseq,
             last_sync_at: Math.floor(Date.now() / 1000),
           });
           continue;
         }
 
-        // Normal delta: fetch the new UIDs returned by delta check
-        if (deltaResult.new_uids.length === 0) continue;
+        // Flag changes and expunges since the saved HIGHESTMODSEQ (CONDSTORE/QRESYNC)
+        if (deltaResult.flag_changes.length > 0 || deltaResult.vanished_uids.length > 0) {
+          await applyServerChanges(accountId, folder.raw_path, deltaResult);
+        }
 
-        const { messages, lastUid, uidvalidity } = await fetchMessagesInBatches(
-          config,
-          folder.raw_path,
-          deltaResult.new_uids,
-        );
+        const modseqChanged = deltaResult.highest_modseq !== savedState.modseq;
+        if (deltaResult.new_uids.length === 0 && !modseqChanged) continue;
 
-        for (const msg of messages) {
-          const { parsed, threadable } = imapMessageToParsedMessage(
-            msg,
-            accountId,
-            folderMapping.labelId,
+        // Normal delta: fetch the new UIDs returned by delta check
+        let lastUid = savedState.last_uid;
+        let uidvalidity = deltaResult.uidvalidity;
+        if (deltaResult.new_uids.length > 0) {
+          const fetched = await fetchMessagesInBatches(
+            config,
+            folder.raw_path,
+            deltaResult.new_uids,
           );
-          allParsed.set(parsed.id, parsed);
-          allThreadable.push(threadable);
-          allImapMsgs.set(parsed.id, msg);
+
+          for (const msg of fetched.messages) {
+            const { parsed, threadable } = imapMessageToParsedMessage(
+              msg,
+              accountId,
+              folderMapping.labelId,
+            );
+            allParsed.set(parsed.id, parsed);
+            allThreadable.push(threadable);
+            allImapMsgs.set(parsed.id, msg);
+          }
+          lastUid = Math.max(lastUid, fetched.lastUid);
+          uidvalidity = fetched.uidvalidity;
         }
 
         await upsertFolderSyncState({
           account_id: accountId,
           folder_path: folder.raw_path,
           uidvalidity,
-          last_uid: Math.max(savedState.last_uid, lastUid),
-          modseq: null,
+          last_uid: lastUid,
+          modseq: deltaResult.highest_modseq,
           last_sync_at: Math.floor(Date.now() / 1000),
         });
       } catch (err) {
+        if (isCancelled(err)) throw err;
         console.error(`Delta sync failed for folder ${folder.path}:`, err);
       }
     }
   }
 
+  // Continue downloading bodies left over from a header-only initial sync
+  await fetchMissingBodies(config, accountId);
+
   if (allThreadable.length === 0) {
     return { messages: [] };
   }
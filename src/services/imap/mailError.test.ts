@@ -0,0 +1,40 @@
+import { describe, it, expect } from "vitest";
+import { MailError, toMailError } from "./mailError";
+
+describe("toMailError", () => {
+  it("wraps a backend error payload", () => {
+    const err = toMailError({
+      kind: "Timeout",
+      stage: "SELECT",
+      message: "SELECT INBOX timed out after 30s",
+      server_response: null,
+      retryable: true,
+    });
+
+    expect(err).toBeInstanceOf(MailError);
+    const mailError = err as MailError;
+    expect(mailError.kind).toBe("Timeout");
+    expect(mailError.stage).toBe("SELECT");
+    expect(mailError.message).toBe("SELECT INBOX timed out after 30s");
+    expect(mailError.retryable).toBe(true);
+  });
+
+  it("keeps the server response", () => {
+    const err = toMailError({
+      kind: "AuthFailed",
+      message: "Login failed",
+      server_response: "[AUTHENTICATIONFAILED] Invalid credentials",
+      retryable: false,
+    }) as MailError;
+
+    expect(err.serverResponse).toBe("[AUTHENTICATIONFAILED] Invalid credentials");
+    expect(err.stage).toBeNull();
+  });
+
+  it("passes other errors through unchanged", () => {
+    const error = new Error("boom");
+    expect(toMailError(error)).toBe(error);
+    expect(toMailError("Proxy lock poisoned")).toBe("Proxy lock poisoned");
+    expect(toMailError(null)).toBeNull();
+  });
+});
@@ -0,0 +1,59 @@
+export type MailErrorKind =
+  | "AuthFailed"
+  | "Timeout"
+  | "TlsError"
+  | "ConnectionRefused"
+  | "Network"
+  | "FolderNotFound"
+  | "QuotaExceeded"
+  | "ServerBusy"
+  | "ProtocolParse"
+  | "Cancelled"
+  | "DiskFull" // downloads: no space left on the disk
+  | "PermissionDenied" // downloads: can't write there, or outside the downloads and app data folders
+  | "FileError"
+  | "Other";
+
+/** How an IMAP, SMTP or OAuth command failed, as the backend reports it. */
+export interface MailErrorPayload {
+  kind: MailErrorKind;
+  stage?: string; // Timeout only: "connect", "tls", "auth", "send", "token" or the IMAP command, e.g. "SELECT"
+  message: string;
+  server_response: string | null; // the server's reply, e.g. "[AUTHENTICATIONFAILED] Invalid credentials"
+  retryable: boolean; // worth trying again later without user action
+}
+
+/**
+ * A rejected IMAP, SMTP or OAuth command. `message` is the backend's text,
+ * so code that only displays errors doesn't need to know about this class.
+ */
+export class MailError extends Error {
+  readonly kind: MailErrorKind;
+  readonly stage: string | null;
+  readonly serverResponse: string | null;
+  readonly retryable: boolean;
+
+  constructor(payload: MailErrorPayload) {
+    super(payload.message);
+    this.name = "MailError";
+    this.kind = payload.kind;
+    this.stage = payload.stage ?? null;
+    this.serverResponse = payload.server_response;
+    this.retryable = payload.retryable;
+  }
+}
+
+function isMailErrorPayload(value: unknown): value is MailErrorPayload {
+  return (
+    typeof value === "object" &&
+    value !== null &&
+    typeof (value as MailErrorPayload).kind === "string" &&
+    typeof (value as MailErrorPayload).message === "string" &&
+    typeof (value as MailErrorPayload).retryable === "boolean"
+  );
+}
+
+/** Turn an error payload rejected by `invoke` into a MailError; anything else is returned as is. */
+export function toMailError(err: unknown): unknown {
+  return isMailErrorPayload(err) && !(err instanceof MailError) ? new MailError(err) : err;
+}
@@ -1,15 +1,85 @@
-import { invoke } from '@tauri-apps/api/core';
+import { Channel, invoke } from '@tauri-apps/api/core';
+import { toMailError, type MailErrorPayload } from './mailError';
+
+export { MailError, type MailErrorKind, type MailErrorPayload } from './mailError';
+
+/** `invoke` for the IMAP, SMTP, proxy and trace commands, which reject with a MailError. */
+async function invokeMail<T>(command: string, args?: Record<string, unknown>): Promise<T> {
+  try {
+    return await invoke<T>(command, args);
+  } catch (err) {
+    throw toMailError(err);
+  }
+}
 
 // ---------- IMAP types ----------
 
+/**
+ * "password" and "oauth2" pick the strongest mechanism the server offers
+ * (SCRAM-SHA-256, PLAIN or LOGIN; OAUTHBEARER or XOAUTH2); the others force one.
+ */
+export type AuthMethod =
+  | 'password'
+  | 'oauth2'
+  | 'login'
+  | 'plain'
+  | 'scram-sha-256'
+  | 'xoauth2'
+  | 'oauthbearer';
+
+/**
+ * Outbound proxy. "socks5h" resolves server names through the proxy (e.g.
+ * Tor); "none" connects an account directly despite a global proxy.
+ */
+export interface ProxyConfig {
+  kind: 'socks5' | 'socks5h' | 'http' | 'none';
+  host: string;
+  port: number;
+  username?: string;
+  password?: string;
+}
+
 export interface ImapConfig {
   host: string;
   port: number;
   security: 'tls' | 'starttls' | 'none';
   username: string;
   password: string; // plaintext password or OAuth2 access token
-  auth_method: 'password' | 'oauth2';
+  auth_method: AuthMethod;
   accept_invalid_certs?: boolean;
+  pinned_cert_sha256?: string; // accept only this certificate; overrides accept_invalid_certs
+  ca_certificates_pem?: string; // CAs to trust besides the system's, e.g. a corporate root
+  client_identity_pkcs12?: string; // base64 PKCS#12 client certificate and key, for mutual TLS
+  client_identity_password?: string;
+  min_tls_version?: string; // "1.0", "1.1", "1.2" or "1.3"; defaults to 1.2
+  proxy?: ProxyConfig; // overrides the global proxy
+  account_id?: string; // lets the backend record the account's protocol trace
+}
+
+/** Extensions and limits advertised by the server. */
+export interface ImapCapabilities {
+  idle: boolean;
+  move: boolean;
+  uidplus: boolean;
+  condstore: boolean;
+  qresync: boolean;
+  qresync_enabled: boolean;
+  special_use: boolean;
+  create_special_use: boolean;
+  list_extended: boolean;
+  list_status: boolean;
+  compress_deflate: boolean;
+  compress_enabled: boolean; // COMPRESS DEFLATE is active on the pooled session
+  esearch: boolean;
+  sort: boolean;
+  thread: string[];
+  quota: boolean;
+  binary: boolean;
+  literal_plus: boolean;
+  gmail: boolean;
+  append_limit: number | null;
+  auth_mechanisms: string[];
+  raw: string[];
 }
 
 export interface ImapFolder {
@@ -20,6 +90,12 @@ export interface ImapFolder {
   special_use: string | null;
   exists: number;
   unseen: number;
+  uidnext: number | null;
+  uidvalidity: number | null;
+  highest_modseq: number | null;
+  no_select: boolean;     // \Noselect: a hierarchy node that holds no messages
+  has_children: boolean;
+  subscribed: boolean;
 }
 
 export interface ImapMessage {
@@ -39,6 +115,11 @@ export interface ImapMessage {
   is_read: boolean;
   is_starred: boolean;
   is_draft: boolean;
+  /** Non-system flags such as "$Forwarded", "$Junk" or user labels. */
+  keywords: string[];
+  gmail_labels: string[];          // X-GM-LABELS on Gmail, e.g. "\Inbox", "Receipts"
+  gmail_thread_id: string | null;  // X-GM-THRID (64-bit, hence a string)
+  gmail_message_id: string | null; // X-GM-MSGID, shared by every folder copy
   body_html: string | null;
   body_text: string | null;
   snippet: string | null;
@@ -47,6 +128,8 @@ export interface ImapMessage {
   list_unsubscribe_post: string | null;
   auth_results: string | null;
   attachments: ImapAttachment[];
+  /** false for header-only fetches; load the body with imapFetchMessageBody(ies). */
+  body_loaded: boolean;
 }
 
 export interface ImapAttachment {
@@ -71,6 +154,12 @@ export interface ImapFetchResult {
   folder_status: ImapFolderStatus;
 }
 
+export interface ImapBodyFetchResult {
+  messages: ImapMessage[];
+  /** UIDs left over once the size budget was used up. */
+  remaining_uids: number[];
+}
+
 // ---------- Folder sync result (single-connection search + fetch) ----------
 
 export interface ImapFolderSyncResult {
@@ -79,12 +168,68 @@ export interface ImapFolderSyncResult {
   folder_status: ImapFolderStatus;
 }
 
+/** Final result of imapSyncFolderStream; the messages arrive through `onBatch`. */
+export interface ImapFolderSyncSummary {
+  uids: number[];
+  folder_status: ImapFolderStatus;
+}
+
+export interface ImapMessageBatch {
+  folder: string;
+  messages: ImapMessage[];
+}
+
 // ---------- Delta check types ----------
 
+/** New UIDs of moved messages (COPYUID); empty when the server doesn't report them. */
+export interface ImapCopyResult {
+  dest_uidvalidity: number | null;
+  uid_map: { source_uid: number; dest_uid: number }[];
+}
+
+/** UID of an appended message (APPENDUID); null when the server doesn't report it. */
+export interface ImapAppendResult {
+  uidvalidity: number | null;
+  uid: number | null;
+}
+
+/** Structured server-side search; every field set must match. Dates are unix seconds. */
+export interface ImapSearchQuery {
+  from?: string;
+  to?: string;
+  subject?: string;
+  body?: string;
+  since?: number;
+  before?: number;
+  is_read?: boolean;
+  is_starred?: boolean;
+  is_answered?: boolean;
+  is_draft?: boolean;
+  larger?: number;
+  smaller?: number;
+  keywords?: string[];
+  not_keywords?: string[];
+  gmail_raw?: string; // X-GM-RAW: Gmail search syntax, Gmail servers only
+}
+
+/** RFC 5256 sort key: ARRIVAL, DATE, FROM, TO, CC, SUBJECT, SIZE (DISPLAYFROM/DISPLAYTO with SORT=DISPLAY). */
+export interface ImapSortKey {
+  key: string;
+  reverse?: boolean;
+}
+
+/** Server-built thread tree; `uid` is null for a missing parent that only groups its children. */
+export interface ImapThreadNode {
+  uid: number | null;
+  children: ImapThreadNode[];
+}
+
 export interface DeltaCheckRequest {
   folder: string;
   last_uid: number;
   uidvalidity: number;
+  /** HIGHESTMODSEQ from the last sync; enables flag/expunge deltas. */
+  highest_modseq?: number | null;
 }
 
 export interface DeltaCheckResult {
@@ -92,6 +237,76 @@ export interface DeltaCheckResult {
   uidvalidity: number;
   new_uids: number[];
   uidvalidity_changed: boolean;
+  highest_modseq: number | null;
+  /** CONDSTORE: messages whose flags changed since `highest_modseq`. */
+  flag_changes: FlagChange[];
+  /** QRESYNC: UIDs expunged since `highest_modseq`. */
+  vanished_uids: number[];
+}
+
+export interface FlagChange {
+  uid: number;
+  flags: string[];
+  is_read: boolean;
+  is_starred: boolean;
+  is_draft: boolean;
+  modseq: number | null;
+}
+
+// ---------- Sync progress types ----------
+
+/** Payload of the `imap-sync-progress` event. */
+export interface SyncProgressEvent {
+  sync_id: string | null;
+  folder: string;
+  fetched: number;
+  total: number;
+}
+
+// ---------- Download types ----------
+
+/**
+ * Where a download is written: an absolute path in the downloads or app data
+ * folder, or a key in the attachment cache.
+ */
+export type DownloadTarget = { destination: string; cacheKey?: undefined } | { destination?: undefined; cacheKey: string };
+
+export interface DownloadResult {
+  path: string;
+  /** Hex SHA-256 of the decoded file contents. */
+  sha256: string;
+  size: number;
+}
+
+/** Payload of the `imap-download-progress` event. */
+export interface DownloadProgressEvent {
+  path: string;
+  /** Bytes received from the server so far (before transfer decoding). */
+  downloaded: number;
+  total: number | null;
+}
+
+// ---------- IDLE push types ----------
+
+/** Payload of the `imap-mailbox-changed` event emitted by IDLE watchers. */
+export interface MailboxChangedEvent {
+  account_id: string;
+  folder: string;
+  change: 'exists' | 'expunge' | 'fetch' | 'resync';
+  exists: number | null;
+  seq: number | null;
+  uid: number | null;
+  flags: string[] | null;
+}
+
+/**
+ * Payload of the `imap-idle-stopped` event: a watcher gave up because
+ * reconnecting can't fix `error` (rejected credentials or certificate).
+ */
+export interface IdleStoppedEvent {
+  account_id: string;
+  folder: string;
+  error: MailErrorPayload;
 }
 
 // ---------- SMTP types ----------
@@ -102,8 +317,15 @@ export interface SmtpConfig {
   security: 'tls' | 'starttls' | 'none';
   username: string;
   password: string;
-  auth_method: 'password' | 'oauth2';
+  auth_method: AuthMethod;
   accept_invalid_certs?: boolean;
+  pinned_cert_sha256?: string; // accept only this certificate; overrides accept_invalid_certs
+  ca_certificates_pem?: string; // CAs to trust besides the system's, e.g. a corporate root
+  client_identity_pkcs12?: string; // base64 PKCS#12 client certificate and key, for mutual TLS
+  client_identity_password?: string;
+  min_tls_version?: string; // "1.0", "1.1", "1.2" or "1.3"; defaults to 1.2
+  proxy?: ProxyConfig; // overrides the global proxy
+  account_id?: string; // lets the backend record the account's protocol trace
 }
 
 export interface SmtpSendResult {
@@ -111,6 +333,22 @@ export interface SmtpSendResult {
   message: string;
 }
 
+// ---------- TLS certificate types ----------
+
+/** The certificate a server presents, to review before pinning its `sha256`. */
+export interface ServerCertificate {
+  sha256: string; // "AB:CD:..."
+  subject: string | null;
+  issuer: string | null;
+  not_before: number | null; // unix seconds
+  not_after: number | null;
+  alt_names: string[];
+  self_signed: boolean;
+  trusted: boolean; // accepted by the system without a pin
+  verify_error: string | null;
+  pem: string;
+}
+
 // ---------- IMAP commands ----------
 
 /**
@@ -118,26 +356,78 @@ export interface SmtpSendResult {
  * Returns a success message string.
  */
 export async function imapTestConnection(config: ImapConfig): Promise<string> {
-  return invoke<string>('imap_test_connection', { config });
+  return invokeMail<string>('imap_test_connection', { config });
+}
+
+/**
+ * Fetch the IMAP server's TLS certificate and fingerprint for pinning.
+ */
+export async function imapFetchCertificate(config: ImapConfig): Promise<ServerCertificate> {
+  return invokeMail<ServerCertificate>('imap_fetch_certificate', { config });
 }
 
 /**
  * List all IMAP folders/mailboxes on the server.
  */
 export async function imapListFolders(config: ImapConfig): Promise<ImapFolder[]> {
-  return invoke<ImapFolder[]>('imap_list_folders', { config });
+  return invokeMail<ImapFolder[]>('imap_list_folders', { config });
+}
+
+/**
+ * Get the server's capabilities (extensions, THREAD algorithms, auth mechanisms, limits).
+ */
+export async function imapGetCapabilities(config: ImapConfig): Promise<ImapCapabilities> {
+  return invokeMail<ImapCapabilities>('imap_get_capabilities', { config });
+}
+
+/**
+ * Create a folder. `name` is plain UTF-8; `parent` is the parent's raw_path (top level if omitted).
+ * `specialUse` (e.g. "\\Archive") is applied when the server supports CREATE-SPECIAL-USE.
+ */
+export async function imapCreateFolder(
+  config: ImapConfig,
+  name: string,
+  parent?: string,
+  specialUse?: string,
+): Promise<ImapFolder> {
+  return invokeMail<ImapFolder>('imap_create_folder', { config, parent, name, specialUse });
+}
+
+/**
+ * Rename a folder within its parent. `newName` is plain UTF-8.
+ */
+export async function imapRenameFolder(
+  config: ImapConfig,
+  rawPath: string,
+  newName: string,
+): Promise<ImapFolder> {
+  return invokeMail<ImapFolder>('imap_rename_folder', { config, rawPath, newName });
+}
+
+export async function imapDeleteFolder(config: ImapConfig, rawPath: string): Promise<void> {
+  return invokeMail<void>('imap_delete_folder', { config, rawPath });
+}
+
+export async function imapSubscribe(config: ImapConfig, rawPath: string): Promise<void> {
+  return invokeMail<void>('imap_subscribe', { config, rawPath });
+}
+
+export async function imapUnsubscribe(config: ImapConfig, rawPath: string): Promise<void> {
+  return invokeMail<void>('imap_unsubscribe', { config, rawPath });
 }
 
 /**
  * Fetch messages from a folder by UID list.
  * Returns parsed messages along with folder status metadata.
+ * With `headersOnly`, bodies are skipped (ENVELOPE + BODYSTRUCTURE only).
  */
 export async function imapFetchMessages(
   config: ImapConfig,
   folder: string,
-  uids: number[]
+  uids: number[],
+  headersOnly?: boolean,
 ): Promise<ImapFetchResult> {
-  return invoke<ImapFetchResult>('imap_fetch_messages', { config, folder, uids });
+  return invokeMail<ImapFetchResult>('imap_fetch_messages', { config, folder, uids, headersOnly });
 }
 
 /**
@@ -148,7 +438,7 @@ export async function imapFetchNewUids(
   folder: string,
   sinceUid: number
 ): Promise<number[]> {
-  return invoke<number[]>('imap_fetch_new_uids', { config, folder, sinceUid });
+  return invokeMail<number[]>('imap_fetch_new_uids', { config, folder, sinceUid });
 }
 
 /**
@@ -159,7 +449,49 @@ export async function imapSearchAllUids(
   config: ImapConfig,
   folder: string
 ): Promise<number[]> {
-  return invoke<number[]>('imap_search_all_uids', { config, folder });
+  return invokeMail<number[]>('imap_search_all_uids', { config, folder });
+}
+
+/**
+ * Search a folder on the server (UID SEARCH) and return matching UIDs, sorted ascending.
+ */
+export async function imapSearch(
+  config: ImapConfig,
+  folder: string,
+  query: ImapSearchQuery,
+): Promise<number[]> {
+  return invokeMail<number[]>('imap_search', { config, folder, query });
+}
+
+/**
+ * Sort a folder on the server (UID SORT) and return matching UIDs in that order.
+ * An empty `sort` means newest arrival first.
+ */
+export async function imapSort(
+  config: ImapConfig,
+  folder: string,
+  sort: ImapSortKey[],
+  query?: ImapSearchQuery,
+): Promise<number[]> {
+  return invokeMail<number[]>('imap_sort', { config, folder, sort, query: query ?? null });
+}
+
+/**
+ * Thread a folder on the server (UID THREAD, REFERENCES by default), covering
+ * messages that haven't been synced locally.
+ */
+export async function imapThread(
+  config: ImapConfig,
+  folder: string,
+  algorithm?: string,
+  query?: ImapSearchQuery,
+): Promise<ImapThreadNode[]> {
+  return invokeMail<ImapThreadNode[]>('imap_thread', {
+    config,
+    folder,
+    algorithm: algorithm ?? null,
+    query: query ?? null,
+  });
 }
 
 /**
@@ -170,12 +502,27 @@ export async function imapFetchMessageBody(
   folder: string,
   uid: number
 ): Promise<ImapMessage> {
-  return invoke<ImapMessage>('imap_fetch_message_body', { config, folder, uid });
+  return invokeMail<ImapMessage>('imap_fetch_message_body', { config, folder, uid });
+}
+
+/**
+ * Download bodies for messages synced header-only, in the given order, until
+ * their combined size would exceed `maxBytes`. Leftover UIDs are returned in
+ * `remaining_uids` for the next pass.
+ */
+export async function imapFetchMessageBodies(
+  config: ImapConfig,
+  folder: string,
+  uids: number[],
+  maxBytes: number,
+): Promise<ImapBodyFetchResult> {
+  return invokeMail<ImapBodyFetchResult>('imap_fetch_message_bodies', { config, folder, uids, maxBytes });
 }
 
 /**
  * Set or remove flags on messages.
- * @param flags - Flag names (e.g. "Seen", "Flagged", "Draft"). Backslash prefix is added automatically.
+ * @param flags - System flags ("Seen", "Flagged", "Draft"; backslash optional) or keywords
+ *   ("$Forwarded", "$Junk", "Work"). Adding a keyword the folder's PERMANENTFLAGS don't allow rejects.
  * @param add - true to add flags, false to remove them.
  */
 export async function imapSetFlags(
@@ -185,45 +532,63 @@ export async function imapSetFlags(
   flags: string[],
   add: boolean
 ): Promise<void> {
-  return invoke<void>('imap_set_flags', { config, folder, uids, flags, add });
+  return invokeMail<void>('imap_set_flags', { config, folder, uids, flags, add });
+}
+
+/**
+ * Add or remove Gmail labels on messages (Gmail-over-IMAP only).
+ * @param labels - System labels with their backslash ("\Important") or user labels
+ */
+export async function imapSetGmailLabels(
+  config: ImapConfig,
+  folder: string,
+  uids: number[],
+  labels: string[],
+  add: boolean
+): Promise<void> {
+  return invokeMail<void>('imap_set_gmail_labels', { config, folder, uids, labels, add });
 }
 
 /**
  * Move messages from one folder to another.
  * Uses MOVE extension if available, falls back to COPY+DELETE.
+ * @returns source → destination UID mapping when the server supports UIDPLUS.
  */
 export async function imapMoveMessages(
   config: ImapConfig,
   folder: string,
   uids: number[],
   destination: string
-): Promise<void> {
-  return invoke<void>('imap_move_messages', { config, folder, uids, destination });
+): Promise<ImapCopyResult> {
+  return invokeMail<ImapCopyResult>('imap_move_messages', { config, folder, uids, destination });
 }
 
 /**
- * Permanently delete messages (flag as Deleted + EXPUNGE).
+ * Permanently delete messages (flag as Deleted + expunge just those UIDs).
+ * Other messages already flagged \Deleted are left in place.
+ * @returns the UIDs that were actually expunged.
  */
 export async function imapDeleteMessages(
   config: ImapConfig,
   folder: string,
   uids: number[]
-): Promise<void> {
-  return invoke<void>('imap_delete_messages', { config, folder, uids });
+): Promise<number[]> {
+  return invokeMail<number[]>('imap_delete_messages', { config, folder, uids });
 }
 
 /**
  * Append a raw message to a folder (for saving sent mail or drafts).
  * @param rawMessage - The full email message encoded as base64url.
  * @param flags - Optional IMAP flags string (e.g. "(\\Seen)" or "(\\Draft)").
+ * @returns the new message's UID when the server supports UIDPLUS.
  */
 export async function imapAppendMessage(
   config: ImapConfig,
   folder: string,
   rawMessage: string,
   flags?: string
-): Promise<void> {
-  return invoke<void>('imap_append_message', { config, folder, flags: flags ?? null, rawMessage });
+): Promise<ImapAppendResult> {
+  return invokeMail<ImapAppendResult>('imap_append_message', { config, folder, flags: flags ?? null, rawMessage });
 }
 
 /**
@@ -233,7 +598,7 @@ export async function imapGetFolderStatus(
   config: ImapConfig,
   folder: string
 ): Promise<ImapFolderStatus> {
-  return invoke<ImapFolderStatus>('imap_get_folder_status', { config, folder });
+  return invokeMail<ImapFolderStatus>('imap_get_folder_status', { config, folder });
 }
 
 /**
@@ -246,7 +611,7 @@ export async function imapFetchAttachment(
   uid: number,
   partId: string
 ): Promise<string> {
-  return invoke<string>('imap_fetch_attachment', { config, folder, uid, partId });
+  return invokeMail<string>('imap_fetch_attachment', { config, folder, uid, partId });
 }
 
 /**
@@ -258,7 +623,49 @@ export async function imapFetchRawMessage(
   folder: string,
   uid: number
 ): Promise<string> {
-  return invoke<string>('imap_fetch_raw_message', { config, folder, uid });
+  return invokeMail<string>('imap_fetch_raw_message', { config, folder, uid });
+}
+
+/**
+ * Stream a decoded attachment to disk instead of returning it over IPC.
+ * Pass either `destination` (an absolute path in the downloads or app data
+ * folder) or `cacheKey` (a file name in the app-data attachment cache). Progress
+ * arrives as `imap-download-progress` events. Local file failures reject with
+ * kind "DiskFull", "PermissionDenied" or "FileError".
+ */
+export async function imapDownloadAttachment(
+  config: ImapConfig,
+  folder: string,
+  uid: number,
+  partId: string,
+  target: DownloadTarget,
+): Promise<DownloadResult> {
+  return invokeMail<DownloadResult>('imap_download_attachment', {
+    config,
+    folder,
+    uid,
+    partId,
+    destination: target.destination,
+    cacheKey: target.cacheKey,
+  });
+}
+
+/**
+ * Stream the raw RFC822 source of a message to disk.
+ */
+export async function imapDownloadRawMessage(
+  config: ImapConfig,
+  folder: string,
+  uid: number,
+  target: DownloadTarget,
+): Promise<DownloadResult> {
+  return invokeMail<DownloadResult>('imap_download_raw_message', {
+    config,
+    folder,
+    uid,
+    destination: target.destination,
+    cacheKey: target.cacheKey,
+  });
 }
 
 /**
@@ -269,20 +676,58 @@ export async function imapDeltaCheck(
   config: ImapConfig,
   folders: DeltaCheckRequest[]
 ): Promise<DeltaCheckResult[]> {
-  return invoke<DeltaCheckResult[]>('imap_delta_check', { config, folders });
+  return invokeMail<DeltaCheckResult[]>('imap_delta_check', { config, folders });
 }
 
 /**
  * Sync a folder in a single IMAP connection: SELECT → UID SEARCH ALL → batched UID FETCH.
  * Returns all UIDs and fetched messages in one round-trip, avoiding the connection storm
  * caused by separate imapSearchAllUids + imapFetchMessages calls.
+ *
+ * Emits `imap-sync-progress` after each batch. With a `syncId` the sync can be stopped
+ * via imapCancelSync, in which case it rejects with a MailError of kind "Cancelled".
  */
 export async function imapSyncFolder(
   config: ImapConfig,
   folder: string,
   batchSize: number,
+  headersOnly?: boolean,
+  syncId?: string,
 ): Promise<ImapFolderSyncResult> {
-  return invoke<ImapFolderSyncResult>('imap_sync_folder', { config, folder, batchSize });
+  return invokeMail<ImapFolderSyncResult>('imap_sync_folder', { config, folder, batchSize, headersOnly, syncId });
+}
+
+/**
+ * Streaming variant of imapSyncFolder: each batch of parsed messages is passed to
+ * `onBatch` as soon as it is fetched, so it can be written to the database right away
+ * instead of holding the whole folder in memory. Fetches `uids` when given, otherwise
+ * every UID in the folder.
+ */
+export async function imapSyncFolderStream(
+  config: ImapConfig,
+  folder: string,
+  batchSize: number,
+  onBatch: (batch: ImapMessageBatch) => void,
+  options: { uids?: number[]; headersOnly?: boolean; syncId?: string } = {},
+): Promise<ImapFolderSyncSummary> {
+  const channel = new Channel<ImapMessageBatch>();
+  channel.onmessage = onBatch;
+  return invokeMail<ImapFolderSyncSummary>('imap_sync_folder_stream', {
+    config,
+    folder,
+    batchSize,
+    uids: options.uids,
+    headersOnly: options.headersOnly,
+    syncId: options.syncId,
+    onBatch: channel,
+  });
+}
+
+/**
+ * Cancel a running imapSyncFolder or imapSyncFolderStream, or every running sync when `syncId` is omitted.
+ */
+export async function imapCancelSync(syncId?: string): Promise<void> {
+  return invokeMail<void>('imap_cancel_sync', { syncId });
 }
 
 /**
@@ -293,7 +738,38 @@ export async function imapRawFetchDiagnostic(
   folder: string,
   uidRange: string,
 ): Promise<string> {
-  return invoke<string>('imap_raw_fetch_diagnostic', { config, folder, uidRange });
+  return invokeMail<string>('imap_raw_fetch_diagnostic', { config, folder, uidRange });
+}
+
+/**
+ * Log out and drop pooled IMAP connections for an account, and restart its
+ * IDLE watchers with `config`. Sessions in use are closed when they are
+ * returned. Call after the account's credentials or settings change or it is
+ * removed.
+ */
+export async function imapCloseConnections(config: ImapConfig): Promise<void> {
+  return invokeMail<void>('imap_close_connections', { config });
+}
+
+/**
+ * Start IDLE watchers for the given folders. Changes are reported through the
+ * `imap-mailbox-changed` event; servers without IDLE are polled instead. A
+ * watcher whose login or certificate is rejected stops and emits `imap-idle-stopped`.
+ * Call again with a fresh config after refreshing an OAuth token.
+ */
+export async function imapIdleStart(
+  accountId: string,
+  config: ImapConfig,
+  folders: string[],
+): Promise<void> {
+  return invokeMail<void>('imap_idle_start', { accountId, config, folders });
+}
+
+/**
+ * Stop the IDLE watcher for one folder, or all of an account's watchers.
+ */
+export async function imapIdleStop(accountId: string, folder?: string): Promise<void> {
+  return invokeMail<void>('imap_idle_stop', { accountId, folder: folder ?? null });
 }
 
 // ---------- SMTP commands ----------
@@ -306,12 +782,63 @@ export async function smtpSendEmail(
   config: SmtpConfig,
   rawEmail: string
 ): Promise<SmtpSendResult> {
-  return invoke<SmtpSendResult>('smtp_send_email', { config, rawEmail });
+  return invokeMail<SmtpSendResult>('smtp_send_email', { config, rawEmail });
 }
 
 /**
  * Test SMTP connectivity by connecting and authenticating.
  */
 export async function smtpTestConnection(config: SmtpConfig): Promise<SmtpSendResult> {
-  return invoke<SmtpSendResult>('smtp_test_connection', { config });
+  return invokeMail<SmtpSendResult>('smtp_test_connection', { config });
+}
+
+/**
+ * Fetch the SMTP server's TLS certificate and fingerprint for pinning.
+ */
+export async function smtpFetchCertificate(config: SmtpConfig): Promise<ServerCertificate> {
+  return invokeMail<ServerCertificate>('smtp_fetch_certificate', { config });
+}
+
+// ---------- Proxy commands ----------
+
+/**
+ * Set or clear the proxy used by accounts without their own and by OAuth
+ * requests. Pooled connections are closed and IDLE watchers reconnect, so
+ * later IMAP traffic goes through the new proxy.
+ */
+export async function proxySetGlobal(proxy: ProxyConfig | null): Promise<void> {
+  return invokeMail<void>('proxy_set_global', { proxy });
+}
+
+// ---------- Protocol trace commands ----------
+
+/**
+ * Start recording the account's IMAP and SMTP traffic, discarding any earlier
+ * trace. Only connections opened afterwards are recorded, so the account's
+ * idle pooled IMAP sessions are closed to trace from a fresh login.
+ */
+export async function protocolTraceStart(accountId: string, maxEntries?: number): Promise<void> {
+  return invokeMail<void>('protocol_trace_start', { accountId, maxEntries: maxEntries ?? null });
+}
+
+/**
+ * Stop recording. The trace is kept until exported or cleared.
+ */
+export async function protocolTraceStop(accountId: string): Promise<void> {
+  return invokeMail<void>('protocol_trace_stop', { accountId });
+}
+
+/**
+ * The recorded trace as text, safe to attach to a bug report: credentials,
+ * tokens and message contents are removed.
+ */
+export async function protocolTraceExport(accountId: string): Promise<string> {
+  return invokeMail<string>('protocol_trace_export', { accountId });
+}
+
+/**
+ * Stop recording and discard the trace.
+ */
+export async function protocolTraceClear(accountId: string): Promise<void> {
+  return invokeMail<void>('protocol_trace_clear', { accountId });
 }
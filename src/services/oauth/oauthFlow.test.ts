@@ -65,6 +65,7 @@ describe("refreshProviderToken", () => {
       clientId: "client-123",
       clientSecret: null,
       scope: microsoftProvider.scopes.join(" "),
+      proxy: null,
     });
     expect(result.access_token).toBe("new-access");
   });
@@ -84,6 +85,7 @@ describe("refreshProviderToken", () => {
       clientId: "yahoo-client",
       clientSecret: null,
       scope: null,
+      proxy: null,
     });
   });
 
@@ -107,6 +109,7 @@ describe("refreshProviderToken", () => {
       clientId: "client",
       clientSecret: "secret-123",
       scope: null,
+      proxy: null,
     });
   });
 
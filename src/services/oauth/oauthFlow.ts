@@ -1,6 +1,8 @@
 import { invoke } from "@tauri-apps/api/core";
 import { openUrl } from "@tauri-apps/plugin-opener";
 import type { OAuthProviderConfig } from "./providers";
+import type { ProxyConfig } from "../imap/tauriCommands";
+import { toMailError } from "../imap/mailError";
 
 const OAUTH_CALLBACK_PORT = 17248;
 
@@ -128,34 +130,46 @@ async function exchangeCode(
   clientSecret?: string,
 ): Promise<TokenResponse> {
   // Use Rust backend for token exchange to avoid CORS issues (required for Microsoft native client)
-  return invoke<TokenResponse>("oauth_exchange_token", {
-    tokenUrl: provider.tokenUrl,
-    code,
-    clientId,
-    redirectUri,
-    codeVerifier: provider.usePkce ? codeVerifier : null,
-    clientSecret: clientSecret || null,
-    scope: provider.id === "microsoft" ? provider.scopes.join(" ") : null,
-  });
+  try {
+    return await invoke<TokenResponse>("oauth_exchange_token", {
+      tokenUrl: provider.tokenUrl,
+      code,
+      clientId,
+      redirectUri,
+      codeVerifier: provider.usePkce ? codeVerifier : null,
+      clientSecret: clientSecret || null,
+      scope: provider.id === "microsoft" ? provider.scopes.join(" ") : null,
+    });
+  } catch (err) {
+    throw toMailError(err);
+  }
 }
 
 /**
- * Refresh an expired access token for a non-Gmail provider.
+ * Refresh an expired access token for a non-Gmail provider. `proxy` is the
+ * account's own proxy; the global one applies otherwise.
  */
 export async function refreshProviderToken(
   provider: OAuthProviderConfig,
   refreshToken: string,
   clientId: string,
   clientSecret?: string,
+  proxy?: ProxyConfig,
 ): Promise<TokenResponse> {
-  // Use Rust backend for token refresh to avoid CORS issues
-  return invoke<TokenResponse>("oauth_refresh_token", {
-    tokenUrl: provider.tokenUrl,
-    refreshToken,
-    clientId,
-    clientSecret: clientSecret || null,
-    scope: provider.id === "microsoft" ? provider.scopes.join(" ") : null,
-  });
+  // Use Rust backend for token refresh to avoid CORS issues; failures reject
+  // with a MailError (AuthFailed when the refresh token is no longer accepted)
+  try {
+    return await invoke<TokenResponse>("oauth_refresh_token", {
+      tokenUrl: provider.tokenUrl,
+      refreshToken,
+      clientId,
+      clientSecret: clientSecret || null,
+      scope: provider.id === "microsoft" ? provider.scopes.join(" ") : null,
+      proxy: proxy ?? null,
+    });
+  } catch (err) {
+    throw toMailError(err);
+  }
 }
 
 function parseIdToken(idToken: string): Record<string, unknown> {
@@ -78,6 +78,7 @@ describe("ensureFreshToken", () => {
       "refresh-token",
       "client-id-123",
       undefined,
+      undefined,
     );
     expect(updateAccountTokens).toHaveBeenCalledWith(
       "acc-1",
@@ -2,6 +2,7 @@ import type { DbAccount } from "../db/accounts";
 import { updateAccountTokens } from "../db/accounts";
 import { getOAuthProvider } from "./providers";
 import { refreshProviderToken } from "./oauthFlow";
+import { parseProxy } from "../imap/imapConfigBuilder";
 
 /** Buffer before expiry to trigger a refresh (5 minutes) */
 const REFRESH_BUFFER_MS = 5 * 60 * 1000;
@@ -50,6 +51,7 @@ export async function ensureFreshToken(account: DbAccount): Promise<string> {
     account.refresh_token,
     account.oauth_client_id,
     account.oauth_client_secret ?? undefined,
+    parseProxy(account),
   );
 
   const newExpiresAt = Math.floor(Date.now() / 1000) + tokens.expires_in;
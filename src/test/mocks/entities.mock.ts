@@ -125,6 +125,13 @@ export function createMockGmailAccount(
     caldav_home_url: null,
     calendar_provider: null,
     accept_invalid_certs: 0,
+    imap_cert_sha256: null,
+    smtp_cert_sha256: null,
+    tls_ca_pem: null,
+    tls_client_pkcs12: null,
+    tls_client_password: null,
+    tls_min_version: null,
+    proxy_json: null,
     ...overrides,
   };
 }
@@ -165,6 +172,13 @@ export function createMockImapAccount(
     caldav_home_url: null,
     calendar_provider: null,
     accept_invalid_certs: 0,
+    imap_cert_sha256: null,
+    smtp_cert_sha256: null,
+    tls_ca_pem: null,
+    tls_client_pkcs12: null,
+    tls_client_password: null,
+    tls_min_version: null,
+    proxy_json: null,
     ...overrides,
   };
 }
@@ -205,6 +219,13 @@ export function createMockDbAccount(
     caldav_home_url: null,
     calendar_provider: null,
     accept_invalid_certs: 0,
+    imap_cert_sha256: null,
+    smtp_cert_sha256: null,
+    tls_ca_pem: null,
+    tls_client_pkcs12: null,
+    tls_client_password: null,
+    tls_min_version: null,
+    proxy_json: null,
     ...overrides,
   };
 }
@@ -229,6 +250,10 @@ export function createMockImapMessage(
     is_read: false,
     is_starred: false,
     is_draft: false,
+    keywords: [],
+    gmail_labels: [],
+    gmail_thread_id: null,
+    gmail_message_id: null,
     body_html: "<p>Hello</p>",
     body_text: "Hello",
     snippet: "Hello",
@@ -237,6 +262,7 @@ export function createMockImapMessage(
     list_unsubscribe_post: null,
     auth_results: null,
     attachments: [],
+    body_loaded: true,
     ...overrides,
   };
 }
@@ -253,6 +279,12 @@ export function createMockImapFolder(
     special_use: null,
     exists: 100,
     unseen: 10,
+    uidnext: null,
+    uidvalidity: null,
+    highest_modseq: null,
+    no_select: false,
+    has_children: false,
+    subscribed: true,
     ...overrides,
   };
 }
@@ -1,4 +1,5 @@
 import { classifyError } from "./networkErrors";
+import { MailError } from "@/services/imap/mailError";
 
 describe("classifyError", () => {
   it("classifies 'Failed to fetch' as network (retryable)", () => {
@@ -84,4 +85,35 @@ describe("classifyError", () => {
     expect(result.type).toBe("network");
     expect(result.isRetryable).toBe(true);
   });
+
+  it("uses the kind and retryable flag of a MailError", () => {
+    const auth = classifyError(new MailError({
+      kind: "AuthFailed",
+      message: "Login failed: no response",
+      server_response: "[AUTHENTICATIONFAILED] Invalid credentials",
+      retryable: false,
+    }));
+    expect(auth).toEqual({ type: "auth", isRetryable: false, message: "Login failed: no response" });
+
+    // Would match the "timeout" pattern, but the backend knows better
+    const quota = classifyError(new MailError({
+      kind: "QuotaExceeded",
+      message: "SMTP send error: transient error (452): 4.2.2 Mailbox full, try after timeout",
+      server_response: "452 4.2.2 Mailbox full, try after timeout",
+      retryable: true,
+    }));
+    expect(quota.type).toBe("quota");
+    expect(quota.isRetryable).toBe(true);
+  });
+
+  it("treats MailError kinds without a matching type as permanent", () => {
+    const result = classifyError(new MailError({
+      kind: "FolderNotFound",
+      message: "Folder Archive not found",
+      server_response: null,
+      retryable: false,
+    }));
+    expect(result.type).toBe("permanent");
+    expect(result.isRetryable).toBe(false);
+  });
 });
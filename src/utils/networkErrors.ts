@@ -1,3 +1,5 @@
+import { MailError, type MailErrorKind } from "@/services/imap/mailError";
+
 export type ErrorType = "network" | "auth" | "quota" | "server" | "permanent";
 
 export interface ClassifiedError {
@@ -21,7 +23,25 @@ const NETWORK_PATTERNS = [
   "net::err",
 ];
 
+const MAIL_ERROR_TYPES: Partial<Record<MailErrorKind, ErrorType>> = {
+  AuthFailed: "auth",
+  QuotaExceeded: "quota",
+  ServerBusy: "server",
+  Timeout: "network",
+  ConnectionRefused: "network",
+  Network: "network",
+};
+
 export function classifyError(error: unknown): ClassifiedError {
+  // IMAP, SMTP and OAuth commands classify their own errors
+  if (error instanceof MailError) {
+    return {
+      type: MAIL_ERROR_TYPES[error.kind] ?? "permanent",
+      isRetryable: error.retryable,
+      message: error.message,
+    };
+  }
+
   const message =
     error instanceof Error ? error.message : String(error ?? "Unknown error");
   const lower = message.toLowerCase();